@@ -0,0 +1,181 @@
+use super::ThrottledStream;
+use crate::configuration::{ConfigurationEventReceiver, ConfigurationEvents, Settings};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{self, Instant};
+use tracing::info;
+
+/// The upload and download limits shared by all our connections. Cloning
+/// gives another handle to the same limits.
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLimiter {
+    upload: Arc<TokenBucket>,
+    download: Arc<TokenBucket>,
+}
+
+impl BandwidthLimiter {
+    /// Makes a limiter with no limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the limits in bytes per second, None meaning unlimited. The
+    /// new limits apply straight away, also to existing connections.
+    pub fn set_limits(&self, upload: Option<u32>, download: Option<u32>) {
+        self.upload.set_rate(upload);
+        self.download.set_rate(download);
+    }
+
+    /// Sets the limits from the settings, which are in KiB/s with 0 meaning
+    /// unlimited as in eMule.
+    pub fn apply_settings(&self, settings: &Settings) {
+        let limit = |kib: u32| (kib > 0).then(|| kib.saturating_mul(1024));
+        let (upload, download) = (limit(settings.upload_limit), limit(settings.download_limit));
+
+        if (upload, download) != (self.upload.rate(), self.download.rate()) {
+            info!(
+                "Bandwidth limits: upload {}, download {}",
+                describe(settings.upload_limit),
+                describe(settings.download_limit)
+            );
+            self.set_limits(upload, download);
+        }
+    }
+
+    /// Keeps the limits in line with the settings, as a Tokio task which
+    /// runs until the Configuration Manager goes away.
+    pub fn follow_settings(
+        &self,
+        mut cfg_evt_receiver: ConfigurationEventReceiver,
+        tokio_handle: &tokio::runtime::Handle,
+    ) {
+        let limiter = self.clone();
+        tokio_handle.spawn(async move {
+            loop {
+                match cfg_evt_receiver.recv().await {
+                    Ok(ConfigurationEvents::SettingsChange(settings)) => {
+                        limiter.apply_settings(&settings)
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Wraps a stream so that what is read from and written to it counts
+    /// against the limits.
+    pub fn throttle<S>(&self, stream: S) -> ThrottledStream<S> {
+        ThrottledStream::new(stream, self.upload.clone(), self.download.clone())
+    }
+}
+
+fn describe(kib: u32) -> String {
+    match kib {
+        0 => "unlimited".to_owned(),
+        kib => format!("{kib} KiB/s"),
+    }
+}
+
+/// A token bucket: tokens (bytes) flow in at the rate, up to a small burst,
+/// and each read or write takes some out. Connections waiting for tokens
+/// queue up and are served in turn, each getting at most a quantum, so a
+/// busy connection cannot starve the others.
+#[derive(Debug, Default)]
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+    /// Waiting connections queue on this. Tokio's mutex is fair, so they
+    /// get their turns in order.
+    queue: tokio::sync::Mutex<()>,
+}
+
+#[derive(Debug, Default)]
+struct BucketState {
+    /// Bytes per second, None for unlimited.
+    rate: Option<u32>,
+    tokens: f64,
+    last_refill: Option<Instant>,
+}
+
+impl TokenBucket {
+    /// The smallest grant, so that slow limits do not chop traffic into
+    /// tiny reads and writes.
+    const MIN_QUANTUM: usize = 512;
+    /// The longest we sleep before looking at the rate again, so that a
+    /// changed limit is picked up quickly.
+    const MAX_WAIT: Duration = Duration::from_millis(100);
+
+    fn rate(&self) -> Option<u32> {
+        self.state.lock().unwrap().rate
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.rate().is_some()
+    }
+
+    fn set_rate(&self, rate: Option<u32>) {
+        let mut state = self.state.lock().unwrap();
+        state.refill(Instant::now());
+        state.rate = rate;
+        state.tokens = state.tokens.min(BucketState::burst(rate) as f64);
+    }
+
+    /// Waits until up to `want` bytes may be transferred and returns how
+    /// many. Without a limit that is all of them, straight away.
+    pub async fn acquire(self: Arc<Self>, want: usize) -> usize {
+        if self.rate().is_none() {
+            return want;
+        }
+
+        let _turn = self.queue.lock().await;
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let Some(rate) = state.rate else {
+                    return want;
+                };
+                state.refill(Instant::now());
+
+                let grant = want.min(BucketState::quantum(rate));
+                if state.tokens >= grant as f64 {
+                    state.tokens -= grant as f64;
+                    return grant;
+                }
+                Duration::from_secs_f64((grant as f64 - state.tokens) / rate as f64)
+            };
+
+            time::sleep(wait.min(Self::MAX_WAIT)).await;
+        }
+    }
+
+    /// Gives back tokens which were granted but not used, for example
+    /// because a read returned fewer bytes than it could have.
+    pub fn refund(&self, unused: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(rate) = state.rate {
+            let burst = BucketState::burst(Some(rate)) as f64;
+            state.tokens = (state.tokens + unused as f64).min(burst);
+        }
+    }
+}
+
+impl BucketState {
+    fn quantum(rate: u32) -> usize {
+        (rate as usize / 16).max(TokenBucket::MIN_QUANTUM)
+    }
+
+    /// How many tokens can build up while nobody uses them: a quarter of
+    /// a second's worth, and always at least a quantum.
+    fn burst(rate: Option<u32>) -> usize {
+        rate.map_or(0, |rate| (rate as usize / 4).max(Self::quantum(rate)))
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let (Some(rate), Some(last)) = (self.rate, self.last_refill) {
+            let added = (now - last).as_secs_f64() * rate as f64;
+            self.tokens = (self.tokens + added).min(Self::burst(self.rate) as f64);
+        }
+        self.last_refill = Some(now);
+    }
+}
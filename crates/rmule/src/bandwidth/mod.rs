@@ -0,0 +1,8 @@
+//! Bandwidth limiting. All peer and server connections share one upload
+//! and one download limit, which can be changed while they are running.
+
+mod limiter;
+mod throttled_stream;
+
+pub use limiter::*;
+pub use throttled_stream::*;
@@ -0,0 +1,194 @@
+use super::TokenBucket;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A stream whose reads and writes wait for tokens from the shared upload
+/// and download buckets. Reads are limited by reading less, which leaves
+/// the data in the socket and so slows down the sender.
+pub struct ThrottledStream<S> {
+    inner: S,
+    upload: Arc<TokenBucket>,
+    download: Arc<TokenBucket>,
+    read_grant: Grant,
+    write_grant: Grant,
+}
+
+/// Tokens for the next read or write: being waited for, or granted but
+/// not yet used because the inner stream was not ready.
+enum Grant {
+    None,
+    Waiting(Pin<Box<dyn Future<Output = usize> + Send>>),
+    Granted(usize),
+}
+
+impl<S> ThrottledStream<S> {
+    pub(super) fn new(inner: S, upload: Arc<TokenBucket>, download: Arc<TokenBucket>) -> Self {
+        Self {
+            inner,
+            upload,
+            download,
+            read_grant: Grant::None,
+            write_grant: Grant::None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl Grant {
+    /// Waits for tokens from the bucket, unless some are already granted.
+    fn poll(
+        &mut self,
+        bucket: &Arc<TokenBucket>,
+        want: usize,
+        cx: &mut Context<'_>,
+    ) -> Poll<usize> {
+        loop {
+            match self {
+                Grant::Granted(n) => return Poll::Ready(*n),
+                Grant::Waiting(acquire) => {
+                    *self = Grant::Granted(ready!(acquire.as_mut().poll(cx)))
+                }
+                Grant::None => *self = Grant::Waiting(Box::pin(bucket.clone().acquire(want))),
+            }
+        }
+    }
+
+    /// Whether the stream can skip the bucket: it is unlimited and we are
+    /// not holding or waiting for tokens from before a limit was lifted.
+    fn bypass(&self, bucket: &TokenBucket) -> bool {
+        matches!(self, Grant::None) && !bucket.is_limited()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ThrottledStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if buf.remaining() == 0 || this.read_grant.bypass(&this.download) {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        let granted = ready!(this.read_grant.poll(&this.download, buf.remaining(), cx));
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(granted.min(buf.remaining())));
+
+        let result = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited));
+        let read = if result.is_ok() {
+            limited.filled().len()
+        } else {
+            0
+        };
+        buf.advance(read);
+        this.download.refund(granted - read);
+        this.read_grant = Grant::None;
+
+        Poll::Ready(result)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if buf.is_empty() || this.write_grant.bypass(&this.upload) {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        let granted = ready!(this.write_grant.poll(&this.upload, buf.len(), cx));
+        let limited = &buf[..granted.min(buf.len())];
+
+        let result = ready!(Pin::new(&mut this.inner).poll_write(cx, limited));
+        let written = *result.as_ref().unwrap_or(&0);
+        this.upload.refund(granted - written);
+        this.write_grant = Grant::None;
+
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bandwidth::BandwidthLimiter;
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Instant;
+
+    // The clock is paused and only moves on when every task is waiting,
+    // so the byte counts do not depend on how busy the machine is.
+    #[tokio::test(start_paused = true)]
+    pub async fn test_connections_share_the_limit_fairly() {
+        let limiter = BandwidthLimiter::new();
+        limiter.set_limits(Some(64 * 1024), None);
+
+        // Two connections upload as fast as they are allowed to for a
+        // second; each should get about half of the limit.
+        let upload = |limiter: BandwidthLimiter| async move {
+            let (ours, mut theirs) = duplex(1024 * 1024);
+            let mut ours = limiter.throttle(ours);
+            let reader = tokio::spawn(async move {
+                let mut buf = vec![0; 65536];
+                let mut total = 0;
+                while let Ok(n) = theirs.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                    total += n;
+                }
+                total
+            });
+
+            let start = Instant::now();
+            let data = vec![0u8; 4096];
+            while start.elapsed() < Duration::from_secs(1) {
+                ours.write_all(&data).await.unwrap();
+            }
+            drop(ours);
+            reader.await.unwrap()
+        };
+
+        let (a, b) = tokio::join!(
+            tokio::spawn(upload(limiter.clone())),
+            tokio::spawn(upload(limiter.clone()))
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+
+        // A second's worth, give or take the write each connection was
+        // making when the second was up. They take turns a quantum
+        // (4 KiB at this limit) at a time.
+        assert!(a + b <= 64 * 1024 + 2 * 4096, "{a} + {b}");
+        assert!(a + b >= 64 * 1024 - 2 * 4096, "{a} + {b}");
+        assert!(a.abs_diff(b) <= 4096, "{a} vs {b}");
+
+        // Lifting the limit applies to new writes straight away.
+        limiter.set_limits(None, None);
+        let (ours, mut theirs) = duplex(1024 * 1024);
+        let mut ours = limiter.throttle(ours);
+        let start = Instant::now();
+        ours.write_all(&vec![1u8; 512 * 1024]).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        let mut buf = vec![0; 512 * 1024];
+        theirs.read_exact(&mut buf).await.unwrap();
+    }
+}
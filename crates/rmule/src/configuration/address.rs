@@ -104,7 +104,7 @@ impl AddressList {
         self.addresses.len() == 0
     }
 
-    pub fn iter(&self) -> std::slice::Iter<Address> {
+    pub fn iter(&self) -> std::slice::Iter<'_, Address> {
         self.into_iter()
     }
 
@@ -0,0 +1,316 @@
+use super::{DownloadPriority, FileSize, PathBuf};
+use crate::times;
+use crate::utils::{SliceExtensions, StringExtensions};
+use anyhow::{bail, Result};
+use regex::Regex;
+use rusqlite::{params, Connection, Row};
+use time::OffsetDateTime;
+use tracing::info;
+
+/// The download categories, as in eMule. Categories say where finished
+/// files go and what priority new downloads get, and new downloads are
+/// put into one automatically by their rules.
+#[derive(Debug, Clone, Default)]
+pub struct CategoryList {
+    categories: Vec<Category>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Category {
+    pub created: OffsetDateTime,
+    pub updated: OffsetDateTime,
+    /// The Id of the category, from the database table. 0 for a category
+    /// which has not been saved yet.
+    pub id: i64,
+    pub name: String,
+    /// The colour to show the category's downloads in, as 0xRRGGBB.
+    pub colour: u32,
+    /// Where finished files go, if not the default downloads directory.
+    pub incoming_directory: Option<PathBuf>,
+    /// The priority new downloads in the category are given.
+    pub default_priority: DownloadPriority,
+    pub rules: CategoryRules,
+}
+
+/// The rules which put a new download into a category. Every rule which
+/// is set must match; a category with no rules is only chosen by hand.
+#[derive(Debug, Clone, Default)]
+pub struct CategoryRules {
+    /// File extensions, without the dot. Matched ignoring case.
+    pub extensions: Vec<String>,
+    pub min_size: Option<FileSize>,
+    pub max_size: Option<FileSize>,
+    pub name_regex: Option<Regex>,
+}
+
+impl PartialEq for CategoryRules {
+    /// Regexes are equal if their patterns are.
+    fn eq(&self, other: &Self) -> bool {
+        self.extensions == other.extensions
+            && self.min_size == other.min_size
+            && self.max_size == other.max_size
+            && self.name_regex.as_ref().map(Regex::as_str)
+                == other.name_regex.as_ref().map(Regex::as_str)
+    }
+}
+
+impl Eq for CategoryRules {}
+
+impl CategoryRules {
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+            && self.min_size.is_none()
+            && self.max_size.is_none()
+            && self.name_regex.is_none()
+    }
+
+    pub fn matches(&self, name: &str, size: u64) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+
+        let extension = name.rsplit_once('.').map(|(_, ext)| ext);
+        (self.extensions.is_empty()
+            || extension
+                .is_some_and(|ext| self.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext))))
+            && self.min_size.is_none_or(|min| size >= *min)
+            && self.max_size.is_none_or(|max| size <= *max)
+            && self.name_regex.as_ref().is_none_or(|re| re.is_match(name))
+    }
+}
+
+impl TryFrom<&Row<'_>> for Category {
+    type Error = anyhow::Error;
+
+    /// Build a Category value from a Rusqlite Row.
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        let extensions = match row.get::<_, Option<String>>("extensions")? {
+            Some(s) => s.split_comma_str_to_vec()?,
+            None => Vec::new(),
+        };
+        let name_regex = match row.get::<_, Option<String>>("name_regex")? {
+            Some(s) => Some(Regex::new(&s)?),
+            None => None,
+        };
+
+        Ok(Self {
+            created: row.get("created")?,
+            updated: row.get("updated")?,
+            id: row.get("id")?,
+            name: row.get("name")?,
+            colour: row.get("colour")?,
+            incoming_directory: row.get("incoming_directory")?,
+            default_priority: row.get("default_priority")?,
+            rules: CategoryRules {
+                extensions,
+                min_size: row.get("min_size")?,
+                max_size: row.get("max_size")?,
+                name_regex,
+            },
+        })
+    }
+}
+
+impl Category {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            created: times::now(),
+            updated: times::now(),
+            id: 0,
+            name: name.into(),
+            colour: 0,
+            incoming_directory: None,
+            default_priority: DownloadPriority::Normal,
+            rules: CategoryRules::default(),
+        }
+    }
+}
+
+impl CategoryList {
+    /// Loads all the categories from the configuration database.
+    pub fn load_all(conn: &Connection) -> Result<Self> {
+        let mut stmt = conn.prepare("SELECT * FROM category ORDER BY id")?;
+
+        let mut categories = Vec::new();
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            categories.push(Category::try_from(row)?);
+        }
+
+        info!("Loaded {} rows from category", categories.len());
+
+        Ok(Self { categories })
+    }
+
+    pub fn len(&self) -> usize {
+        self.categories.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.categories.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Category> {
+        self.categories.iter()
+    }
+
+    pub fn get(&self, id: i64) -> Option<&Category> {
+        self.categories.iter().find(|c| c.id == id)
+    }
+
+    /// The category a new download goes into: the first whose rules match.
+    pub fn categorize(&self, name: &str, size: u64) -> Option<&Category> {
+        self.categories.iter().find(|c| c.rules.matches(name, size))
+    }
+
+    /// Inserts the category if its id is 0, otherwise updates it.
+    pub fn save(&mut self, conn: &Connection, mut category: Category) -> Result<()> {
+        if category.name.trim().is_empty() {
+            bail!("A category needs a name");
+        }
+
+        category.updated = times::now();
+        let extensions = category.rules.extensions.to_comma_string();
+        let name_regex = category.rules.name_regex.as_ref().map(|re| re.as_str());
+
+        if category.id == 0 {
+            category.created = category.updated;
+            conn.execute(
+                r#"INSERT INTO category(created, updated, name, colour, incoming_directory,
+                    default_priority, extensions, min_size, max_size, name_regex)
+                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"#,
+                params![
+                    category.created,
+                    category.updated,
+                    category.name,
+                    category.colour,
+                    category.incoming_directory,
+                    category.default_priority,
+                    extensions,
+                    category.rules.min_size,
+                    category.rules.max_size,
+                    name_regex
+                ],
+            )?;
+            category.id = conn.last_insert_rowid();
+            info!("Inserted category {} called {}", category.id, category.name);
+            self.categories.push(category);
+            return Ok(());
+        }
+
+        let Some(idx) = self.categories.iter().position(|c| c.id == category.id) else {
+            bail!("There is no category {}", category.id);
+        };
+        conn.execute(
+            r#"UPDATE category SET
+                updated = ?1,
+                name = ?2,
+                colour = ?3,
+                incoming_directory = ?4,
+                default_priority = ?5,
+                extensions = ?6,
+                min_size = ?7,
+                max_size = ?8,
+                name_regex = ?9
+            WHERE
+                id = ?10"#,
+            params![
+                category.updated,
+                category.name,
+                category.colour,
+                category.incoming_directory,
+                category.default_priority,
+                extensions,
+                category.rules.min_size,
+                category.rules.max_size,
+                name_regex,
+                category.id
+            ],
+        )?;
+        info!("Updated category {} called {}", category.id, category.name);
+        self.categories[idx] = category;
+        Ok(())
+    }
+
+    pub fn delete(&mut self, conn: &Connection, id: i64) -> Result<()> {
+        conn.execute("DELETE FROM category WHERE id = ?1", [id])?;
+        self.categories.retain(|c| c.id != id);
+        info!("Deleted category {id}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::migrations;
+
+    fn videos() -> Category {
+        let mut videos = Category::new("Videos");
+        videos.colour = 0xFF8000;
+        videos.default_priority = DownloadPriority::High;
+        videos.rules.extensions = vec!["mkv".into(), "avi".into()];
+        videos.rules.min_size = Some(FileSize::new(1000));
+        videos.rules.name_regex = Some(Regex::new("(?i)holiday").unwrap());
+        videos
+    }
+
+    #[test]
+    pub fn test_categories_round_trip() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrations::apply_database_migrations(&conn).unwrap();
+        let mut list = CategoryList::load_all(&conn).unwrap();
+
+        let mut manual = Category::new("Manual");
+        manual.incoming_directory = Some("/data/manual".into());
+        list.save(&conn, manual).unwrap();
+        list.save(&conn, videos()).unwrap();
+
+        let loaded = CategoryList::load_all(&conn).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(1), list.get(1));
+        assert_eq!(loaded.get(2), list.get(2));
+        assert_eq!(
+            loaded.get(1).unwrap().incoming_directory,
+            Some("/data/manual".into())
+        );
+        assert_eq!(loaded.get(2).unwrap().rules, videos().rules);
+    }
+
+    #[test]
+    pub fn test_downloads_are_categorized_when_every_rule_matches() {
+        let list = CategoryList {
+            categories: vec![Category::new("Manual"), videos()],
+        };
+
+        let found = list.categorize("Holiday 2022.MKV", 5000).unwrap();
+        assert_eq!(found.name, "Videos");
+        assert!(list.categorize("Holiday 2022.mkv", 10).is_none());
+        assert!(list.categorize("Holiday 2022.mp3", 5000).is_none());
+        assert!(list.categorize("Work.mkv", 5000).is_none());
+    }
+
+    #[test]
+    pub fn test_categories_without_rules_are_only_chosen_by_hand() {
+        let list = CategoryList {
+            categories: vec![Category::new("Manual")],
+        };
+        assert!(list.categorize("anything.txt", 5000).is_none());
+    }
+
+    #[test]
+    pub fn test_deleted_category_ids_are_not_reused() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrations::apply_database_migrations(&conn).unwrap();
+        let mut list = CategoryList::load_all(&conn).unwrap();
+        list.save(&conn, Category::new("Manual")).unwrap();
+        list.save(&conn, videos()).unwrap();
+
+        // Downloads may still refer to a deleted category, so its id is
+        // not given to the next one.
+        list.delete(&conn, 2).unwrap();
+        list.save(&conn, Category::new("Music")).unwrap();
+        assert!(list.get(2).is_none());
+        assert_eq!(list.get(3).unwrap().name, "Music");
+    }
+}
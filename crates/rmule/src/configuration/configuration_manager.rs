@@ -1,7 +1,12 @@
-use super::{AddressList, ServerList, Settings, TempDirectoryList};
+use super::{
+    Address, AddressList, Category, CategoryList, KadContact, KadContactList, ServerList, Settings,
+    TempDirectoryList,
+};
 use crate::configuration::migrations;
-use crate::configuration::parsing::{self, ParsedServer};
+use crate::configuration::parsing::{self, ParsedKadContact, ParsedServer};
+use crate::connections::Proxy;
 use crate::file;
+use crate::protocol::Ed2kLink;
 use anyhow::{Context, Result};
 use futures::future::join_all;
 use reqwest::Client;
@@ -93,6 +98,26 @@ pub enum ConfigurationCommand {
     Stop,
     /// Commands the Configuration Manager to update it server list.
     UpdateServerList,
+    /// Saves changed settings and tells everybody about them. Settings
+    /// such as the bandwidth limits take effect straight away.
+    SaveSettings(Settings),
+    /// Downloads a nodes.dat file from the URL and adds its contacts to
+    /// the Kad contact list.
+    ImportNodesDat(String),
+    /// Replaces the Kad contact list with the contacts from the routing
+    /// table, which the Kad Manager sends when it stops.
+    SaveKadContacts(Vec<KadContact>),
+    /// Adds the server in a server link to the server list, or the URL in
+    /// a serverlist link to the address list and then downloads it. File
+    /// links are not for us and are ignored.
+    AddEd2kLink(Ed2kLink),
+    /// Inserts a new download category (one with an id of 0) or updates
+    /// an existing one.
+    SaveCategory(Category),
+    /// Deletes the download category with the id. The Download Manager
+    /// takes its downloads out of it, and those without a destination of
+    /// their own are given its incoming directory.
+    DeleteCategory(i64),
 }
 
 /// The set of events that can be emitted by the Configuration Manager.
@@ -103,6 +128,8 @@ pub enum ConfigurationEvents {
     AddressListChange(AddressList),
     TempDirectoryListChange(TempDirectoryList),
     ServerListChange(ServerList),
+    KadContactListChange(KadContactList),
+    CategoryListChange(CategoryList),
 }
 
 /// This is private to the module: all access is via the handle.
@@ -119,6 +146,8 @@ pub struct ConfigurationManager {
     addresses: AddressList,
     servers: ServerList,
     temp_dirs: TempDirectoryList,
+    kad_contacts: KadContactList,
+    categories: CategoryList,
 }
 
 impl ConfigurationManager {
@@ -190,6 +219,8 @@ impl ConfigurationManager {
         let addresses = AddressList::load_all(&conn)?;
         let servers = ServerList::load_all(&conn)?;
         let temp_dirs = TempDirectoryList::load_all(&conn)?;
+        let kad_contacts = KadContactList::load_all(&conn)?;
+        let categories = CategoryList::load_all(&conn)?;
 
         let cfg_mgr = Self {
             tokio_handle,
@@ -202,12 +233,14 @@ impl ConfigurationManager {
             addresses,
             servers,
             temp_dirs,
+            kad_contacts,
+            categories,
         };
 
         Ok(cfg_mgr)
     }
 
-    fn config_db_filename<P: Into<PathBuf>>(config_dir: P) -> PathBuf {
+    pub(crate) fn config_db_filename<P: Into<PathBuf>>(config_dir: P) -> PathBuf {
         let mut p = config_dir.into();
         p.push(Self::CONFIG_DB_NAME);
         p
@@ -215,7 +248,7 @@ impl ConfigurationManager {
 
     /// Get the connection. Most ops can be performed on
     /// a shared connection.
-    pub fn conn(&self) -> Ref<Connection> {
+    pub fn conn(&self) -> Ref<'_, Connection> {
         self.conn.borrow()
     }
 
@@ -251,6 +284,25 @@ impl ConfigurationManager {
         match cmd {
             ConfigurationCommand::Start => self.start()?,
             ConfigurationCommand::UpdateServerList => todo!(),
+            ConfigurationCommand::SaveSettings(settings) => self.save_settings(settings)?,
+            ConfigurationCommand::ImportNodesDat(url) => self.import_nodes_dat(&url)?,
+            ConfigurationCommand::SaveKadContacts(contacts) => {
+                let mut conn = self.conn.borrow_mut();
+                self.kad_contacts.replace_all(&mut conn, contacts)?;
+            }
+            ConfigurationCommand::AddEd2kLink(link) => self.add_ed2k_link(link)?,
+            // A bad category, such as one with a duplicate name, is the
+            // user's mistake and should not stop the Configuration Manager.
+            ConfigurationCommand::SaveCategory(category) => {
+                match self.categories.save(&self.conn.borrow(), category) {
+                    Ok(()) => self.send_category_list()?,
+                    Err(e) => warn!("Saving category failed: {e}"),
+                }
+            }
+            ConfigurationCommand::DeleteCategory(id) => {
+                self.categories.delete(&self.conn.borrow(), id)?;
+                self.send_category_list()?;
+            }
             ConfigurationCommand::Stop => {
                 self.stop();
                 shutdown = true;
@@ -260,6 +312,14 @@ impl ConfigurationManager {
         Ok(shutdown)
     }
 
+    fn save_settings(&mut self, settings: Settings) -> Result<()> {
+        settings.update(&self.conn.borrow())?;
+        self.settings = settings;
+        self.events_sender
+            .send(ConfigurationEvents::SettingsChange(self.settings.clone()))?;
+        Ok(())
+    }
+
     /// Starts the Configuration Manager. Everything is already loaded as
     /// that was done in `new`.
     fn start(&mut self) -> Result<()> {
@@ -307,6 +367,13 @@ impl ConfigurationManager {
         self.events_sender
             .send(ConfigurationEvents::ServerListChange(self.servers.clone()))?;
 
+        self.events_sender
+            .send(ConfigurationEvents::KadContactListChange(
+                self.kad_contacts.clone(),
+            ))?;
+
+        self.send_category_list()?;
+
         // Tell everybody we are done with initial load.
         self.events_sender.send(ConfigurationEvents::InitComplete)?;
 
@@ -323,14 +390,106 @@ impl ConfigurationManager {
         Ok(())
     }
 
+    fn send_category_list(&self) -> Result<()> {
+        self.events_sender
+            .send(ConfigurationEvents::CategoryListChange(
+                self.categories.clone(),
+            ))?;
+        Ok(())
+    }
+
+    fn add_ed2k_link(&mut self, link: Ed2kLink) -> Result<()> {
+        match link {
+            Ed2kLink::Server(addr) => {
+                self.servers.add_server(addr);
+                let mut conn = self.conn.borrow_mut();
+                self.servers.save_all(&mut conn)?;
+            }
+            Ed2kLink::ServerList(url) => {
+                let address = Address::new(url.as_str(), "Added from an ed2k link", true);
+                self.addresses.insert(&self.conn.borrow(), address)?;
+                self.events_sender
+                    .send(ConfigurationEvents::AddressListChange(
+                        self.addresses.clone(),
+                    ))?;
+                self.auto_update_server_list(&vec![url])?;
+            }
+            Ed2kLink::File(file) => {
+                warn!(
+                    "Ignoring file link for {}, it is not a configuration link",
+                    file.name
+                );
+                return Ok(());
+            }
+        }
+
+        self.events_sender
+            .send(ConfigurationEvents::ServerListChange(self.servers.clone()))?;
+        Ok(())
+    }
+
+    /// Imports a nodes.dat file. As with server.met files, a bad download
+    /// is logged rather than treated as an error.
+    fn import_nodes_dat(&mut self, url: &str) -> Result<()> {
+        let Some(client) = self.make_client() else {
+            return Ok(());
+        };
+        let parsed_contacts = match self
+            .tokio_handle
+            .block_on(Self::download_nodes_dat(&client, url))
+        {
+            Ok(parsed_contacts) => parsed_contacts,
+            Err(e) => {
+                warn!("{}", e);
+                return Ok(());
+            }
+        };
+
+        self.kad_contacts
+            .merge_parsed_contacts(&parsed_contacts, url);
+        let mut conn = self.conn.borrow_mut();
+        self.kad_contacts.save_all(&mut conn)?;
+
+        self.events_sender
+            .send(ConfigurationEvents::KadContactListChange(
+                self.kad_contacts.clone(),
+            ))?;
+
+        Ok(())
+    }
+
+    /// Makes a client for downloads, which goes through the proxy in the
+    /// settings if there is one. If the proxy cannot be used for downloads,
+    /// such as a SOCKS4 one, there is no client: downloading around the
+    /// proxy would give away what the user set it up to hide.
+    fn make_client(&self) -> Option<Client> {
+        let mut builder = reqwest::ClientBuilder::new().timeout(Duration::from_secs(30));
+        if let Some(proxy) = Proxy::from_settings(&self.settings) {
+            match proxy.reqwest_proxy() {
+                Ok(proxy) => builder = builder.proxy(proxy),
+                Err(e) => {
+                    warn!("Not downloading, the proxy cannot be used: {e}");
+                    return None;
+                }
+            }
+        }
+
+        match builder.build() {
+            Ok(client) => Some(client),
+            Err(e) => {
+                warn!("Creating a reqwest client for downloads failed: {e}");
+                None
+            }
+        }
+    }
+
     fn download_servers(&self, urls: &Vec<String>) -> Result<Vec<ParsedServer>> {
         info!("Downloading new servers");
         let mut tasks = Vec::new();
 
-        let client = reqwest::ClientBuilder::new()
-            .timeout(Duration::from_secs(30))
-            .build()
-            .with_context(|| "Creating a reqwest client to download server.met failed")?;
+        let Some(client) = self.make_client() else {
+            return Ok(Vec::new());
+        };
 
         for url in urls {
             let url = url.clone();
@@ -360,7 +519,7 @@ impl ConfigurationManager {
             .flatten()
             .collect();
 
-        all_parsed_servers.sort_by(|a, b| a.ip_addr.cmp(&b.ip_addr));
+        all_parsed_servers.sort_by_key(|a| a.ip_addr);
         all_parsed_servers.dedup_by(|a, b| a.ip_addr == b.ip_addr);
 
         info!("Downloaded {} unique servers", all_parsed_servers.len());
@@ -368,18 +527,38 @@ impl ConfigurationManager {
         Ok(all_parsed_servers)
     }
 
-    async fn download_server_met(client: &Client, url: &str) -> Result<Vec<ParsedServer>> {
-        info!("Downloading server.met from {}", url);
+    async fn download(client: &Client, url: &str, what: &str) -> Result<Vec<u8>> {
+        info!("Downloading {} from {}", what, url);
 
         let resp_bytes = client
             .get(url)
             .send()
             .await
-            .with_context(|| format!("Timeout occurred fetching server.met from {url}"))?
+            .with_context(|| format!("Timeout occurred fetching {what} from {url}"))?
             .bytes()
             .await
             .with_context(|| format!("Could not extract bytes from response from {url}"))?;
 
+        Ok(resp_bytes.to_vec())
+    }
+
+    async fn download_nodes_dat(client: &Client, url: &str) -> Result<Vec<ParsedKadContact>> {
+        let resp_bytes = Self::download(client, url, "nodes.dat").await?;
+        let contacts = parsing::parse_nodes_dat(url, &resp_bytes)?;
+
+        info!(
+            "Received {} bytes and {} Kad contacts from {}",
+            resp_bytes.len(),
+            contacts.len(),
+            url
+        );
+
+        Ok(contacts)
+    }
+
+    async fn download_server_met(client: &Client, url: &str) -> Result<Vec<ParsedServer>> {
+        let resp_bytes = Self::download(client, url, "server.met").await?;
+
         let servers = if resp_bytes.is_empty() {
             Vec::new()
         } else {
@@ -402,3 +581,46 @@ impl ConfigurationManager {
         Ok(servers)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::ProxyType;
+    use tokio::time::timeout;
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_start_with_a_proxy_which_cannot_download() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let conn =
+                Connection::open(ConfigurationManager::config_db_filename(dir.path())).unwrap();
+            migrations::apply_database_migrations(&conn).unwrap();
+            let mut settings = Settings::load(&conn).unwrap();
+            settings.auto_update_server_list = true;
+            settings.proxy_type = ProxyType::Socks4;
+            settings.proxy_host = "127.0.0.1".to_owned();
+            settings.update(&conn).unwrap();
+        }
+
+        let handle = ConfigurationManagerHandle::new(dir.path(), tokio::runtime::Handle::current());
+        let mut events = handle.subscribe_to_events();
+        handle
+            .send_command(ConfigurationCommand::Start)
+            .await
+            .unwrap();
+        loop {
+            let evt = timeout(Duration::from_secs(10), events.recv())
+                .await
+                .expect("The Configuration Manager did not start")
+                .unwrap();
+            if let ConfigurationEvents::InitComplete = evt {
+                break;
+            }
+        }
+
+        handle
+            .send_command(ConfigurationCommand::Stop)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,379 @@
+use super::parsing::ParsedKadContact;
+use super::IpAddr;
+use crate::kad::KadId;
+use crate::times;
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection, Row, Statement};
+use std::collections::HashSet;
+use std::net::SocketAddrV4;
+use time::OffsetDateTime;
+use tracing::info;
+
+/// The rmule equivalent of nodes.dat from emule. These are the Kad nodes
+/// we know, from our routing table when Kad was last stopped or imported
+/// from nodes.dat files, which are what we join the Kad network with.
+#[derive(Debug, Clone)]
+pub struct KadContactList {
+    contacts: Vec<KadContact>,
+}
+
+/// A node on the Kad network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KadContact {
+    created: OffsetDateTime,
+    updated: OffsetDateTime,
+    /// The Id of the contact, from the database table.
+    id: i64,
+    /// The URL of the nodes.dat this contact was imported from, or "kad".
+    source: String,
+    /// The Kad id of the node. Natural key of the table.
+    pub kad_id: KadId,
+    /// The IP Address of the node.
+    pub ip_addr: IpAddr,
+    /// The UDP port, on which the node speaks Kad.
+    pub udp_port: u16,
+    /// The TCP port, on which the node accepts connections from other
+    /// clients.
+    pub tcp_port: u16,
+    /// The Kad version the node speaks.
+    pub version: u8,
+    /// The key the node gave us for obfuscating UDP packets to it.
+    pub udp_key: Option<u32>,
+    /// Our public IP Address at the time we were given the udp_key.
+    pub udp_key_ip_addr: Option<IpAddr>,
+    /// Whether the node answered from the address it claimed.
+    pub verified: bool,
+}
+
+impl KadContact {
+    /// The source of contacts which were in our routing table.
+    pub const KAD_SOURCE: &str = "kad";
+
+    /// Makes a contact from our routing table, for saving.
+    pub fn new(kad_id: KadId, addr: SocketAddrV4, tcp_port: u16, version: u8) -> Self {
+        let now = times::now();
+
+        Self {
+            created: now,
+            updated: now,
+            id: 0,
+            source: Self::KAD_SOURCE.to_owned(),
+            kad_id,
+            ip_addr: std::net::IpAddr::V4(*addr.ip()).into(),
+            udp_port: addr.port(),
+            tcp_port,
+            version,
+            udp_key: None,
+            udp_key_ip_addr: None,
+            verified: false,
+        }
+    }
+
+    /// The address Kad packets go to. None if the contact somehow has an
+    /// IPv6 address, which Kad does not support.
+    pub fn udp_socket_addr(&self) -> Option<SocketAddrV4> {
+        match *self.ip_addr {
+            std::net::IpAddr::V4(ip) => Some(SocketAddrV4::new(ip, self.udp_port)),
+            std::net::IpAddr::V6(_) => None,
+        }
+    }
+
+    fn from_parsed(value: &ParsedKadContact, source: &str) -> Self {
+        let addr = SocketAddrV4::new(value.ip_addr, value.udp_port);
+        let mut contact = Self::new(value.kad_id, addr, value.tcp_port, value.version);
+        contact.source = source.to_owned();
+        contact.udp_key = value.udp_key;
+        contact.udp_key_ip_addr = value
+            .udp_key_ip_addr
+            .map(|ip| std::net::IpAddr::V4(ip).into());
+        contact.verified = value.verified;
+        contact
+    }
+
+    /// Copies the details of the node, which may have moved since we last
+    /// saw it, from a newer copy of the contact.
+    fn update_from(&mut self, other: &KadContact) {
+        self.source = other.source.clone();
+        self.ip_addr = other.ip_addr.clone();
+        self.udp_port = other.udp_port;
+        self.tcp_port = other.tcp_port;
+        self.version = other.version;
+        self.udp_key = other.udp_key;
+        self.udp_key_ip_addr = other.udp_key_ip_addr.clone();
+        self.verified = other.verified;
+    }
+}
+
+impl TryFrom<&Row<'_>> for KadContact {
+    type Error = rusqlite::Error;
+
+    /// Build a KadContact value from a Rusqlite Row.
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        let kad_id: Vec<u8> = row.get("kad_id")?;
+        let kad_id = match <[u8; 16]>::try_from(kad_id) {
+            Ok(bytes) => KadId::from_be_bytes(bytes),
+            Err(bytes) => {
+                return Err(rusqlite::Error::FromSqlConversionFailure(
+                    bytes.len(),
+                    rusqlite::types::Type::Blob,
+                    "kad_id must be 16 bytes".into(),
+                ))
+            }
+        };
+
+        Ok(Self {
+            created: row.get("created")?,
+            updated: row.get("updated")?,
+            id: row.get("id")?,
+            source: row.get("source")?,
+            kad_id,
+            ip_addr: row.get("ip_addr")?,
+            udp_port: row.get("udp_port")?,
+            tcp_port: row.get("tcp_port")?,
+            version: row.get("version")?,
+            udp_key: row.get("udp_key")?,
+            udp_key_ip_addr: row.get("udp_key_ip_addr")?,
+            verified: row.get("verified")?,
+        })
+    }
+}
+
+impl KadContactList {
+    /// Loads all the Kad contacts from the configuration database.
+    pub fn load_all(conn: &Connection) -> Result<Self> {
+        let mut stmt = conn.prepare("SELECT * FROM kad_contact")?;
+
+        let mut contacts = Vec::new();
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            contacts.push(KadContact::try_from(row)?);
+        }
+
+        info!("Loaded {} rows from kad_contact", contacts.len());
+
+        Ok(Self { contacts })
+    }
+
+    pub fn len(&self) -> usize {
+        self.contacts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contacts.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, KadContact> {
+        self.into_iter()
+    }
+
+    /// Merges the contacts from a nodes.dat file into the list. Contacts
+    /// are matched on kad_id.
+    pub fn merge_parsed_contacts(&mut self, parsed_contacts: &[ParsedKadContact], source: &str) {
+        let contacts: Vec<_> = parsed_contacts
+            .iter()
+            .map(|pc| KadContact::from_parsed(pc, source))
+            .collect();
+        self.merge_contacts(contacts);
+    }
+
+    fn merge_contacts(&mut self, contacts: Vec<KadContact>) {
+        let mut num_inserted = 0;
+        let mut num_updated = 0;
+
+        for contact in contacts {
+            if let Some(existing) = self
+                .contacts
+                .iter_mut()
+                .find(|c| c.kad_id == contact.kad_id)
+            {
+                existing.update_from(&contact);
+                num_updated += 1;
+            } else {
+                self.contacts.push(contact);
+                num_inserted += 1;
+            }
+        }
+
+        info!("Updated {num_updated} existing Kad contacts, created {num_inserted} new ones (RAM only)");
+    }
+
+    /// Replaces the list with the contacts from our routing table, which
+    /// are the ones known to be alive, and saves it. This is what eMule
+    /// does when it writes nodes.dat on shutdown.
+    pub fn replace_all(&mut self, conn: &mut Connection, contacts: Vec<KadContact>) -> Result<()> {
+        let keep: HashSet<_> = contacts.iter().map(|c| c.kad_id).collect();
+        let (kept, removed): (Vec<_>, Vec<_>) = std::mem::take(&mut self.contacts)
+            .into_iter()
+            .partition(|c| keep.contains(&c.kad_id));
+        self.contacts = kept;
+
+        {
+            let mut stmt = conn.prepare("DELETE FROM kad_contact WHERE id = ?1")?;
+            for contact in removed.iter().filter(|c| c.id != 0) {
+                stmt.execute([contact.id])?;
+            }
+        }
+        info!("Deleted {} rows from the kad_contact table", removed.len());
+
+        self.merge_contacts(contacts);
+        self.save_all(conn)
+    }
+
+    pub fn save_all(&mut self, conn: &mut Connection) -> Result<()> {
+        let txn = conn.transaction()?;
+
+        let mut insert_stmt = txn.prepare(
+            r#"INSERT INTO kad_contact
+                  (
+                  created, updated, source, kad_id, ip_addr, udp_port, tcp_port,
+                  version, udp_key, udp_key_ip_addr, verified
+                  )
+                VALUES
+                  (
+                    ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11
+                  )"#,
+        )?;
+
+        let mut update_stmt = txn.prepare(
+            r#"UPDATE kad_contact SET
+                updated = ?1,
+                source = ?2,
+                ip_addr = ?3,
+                udp_port = ?4,
+                tcp_port = ?5,
+                version = ?6,
+                udp_key = ?7,
+                udp_key_ip_addr = ?8,
+                verified = ?9
+               WHERE
+                id = ?10;"#,
+        )?;
+
+        let mut num_updated = 0;
+        let mut num_inserted = 0;
+
+        for contact in &mut self.contacts {
+            let now = times::now();
+
+            if contact.id == 0 {
+                contact.created = now;
+                contact.updated = now;
+                contact.id = Self::insert_contact(&txn, &mut insert_stmt, contact)?;
+                num_inserted += 1;
+            } else {
+                contact.updated = now;
+                Self::update_contact(&mut update_stmt, contact)?;
+                num_updated += 1;
+            }
+        }
+
+        drop(insert_stmt);
+        drop(update_stmt);
+
+        txn.commit()?;
+
+        info!("Updated {num_updated} and inserted {num_inserted} rows to the kad_contact table");
+
+        Ok(())
+    }
+
+    fn update_contact(stmt: &mut Statement, contact: &KadContact) -> Result<()> {
+        let params = params![
+            contact.updated,
+            contact.source,
+            contact.ip_addr,
+            contact.udp_port,
+            contact.tcp_port,
+            contact.version,
+            contact.udp_key,
+            contact.udp_key_ip_addr,
+            contact.verified,
+            contact.id,
+        ];
+
+        let row_count = stmt.execute(params)?;
+
+        if row_count != 1 {
+            bail!(
+                "Update of Kad contact {} with id {} in kad_contact table failed",
+                contact.id,
+                contact.kad_id
+            );
+        }
+
+        Ok(())
+    }
+
+    fn insert_contact(
+        conn: &Connection,
+        stmt: &mut Statement,
+        contact: &KadContact,
+    ) -> Result<i64> {
+        let params = params![
+            contact.created,
+            contact.updated,
+            contact.source,
+            &contact.kad_id.to_be_bytes()[..],
+            contact.ip_addr,
+            contact.udp_port,
+            contact.tcp_port,
+            contact.version,
+            contact.udp_key,
+            contact.udp_key_ip_addr,
+            contact.verified,
+        ];
+
+        stmt.execute(params)?;
+        Ok(conn.last_insert_rowid())
+    }
+}
+
+impl IntoIterator for KadContactList {
+    type Item = KadContact;
+    type IntoIter = std::vec::IntoIter<KadContact>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.contacts.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a KadContactList {
+    type Item = &'a KadContact;
+    type IntoIter = std::slice::Iter<'a, KadContact>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.contacts.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::migrations;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    pub fn test_replace_all_keeps_only_the_routing_table() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::apply_database_migrations(&conn).unwrap();
+
+        let contact = |n: u8| {
+            let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, n), 4672);
+            KadContact::new(KadId::new(n as u128), addr, 4662, 8)
+        };
+
+        let mut list = KadContactList::load_all(&conn).unwrap();
+        list.merge_contacts(vec![contact(1), contact(2)]);
+        list.save_all(&mut conn).unwrap();
+
+        let mut moved = contact(2);
+        moved.udp_port = 5000;
+        list.replace_all(&mut conn, vec![moved, contact(3)])
+            .unwrap();
+
+        let loaded = KadContactList::load_all(&conn).unwrap();
+        let mut ids: Vec<_> = loaded.iter().map(|c| (c.kad_id, c.udp_port)).collect();
+        ids.sort();
+        assert_eq!(ids, [(KadId::new(2), 5000), (KadId::new(3), 4672)]);
+    }
+}
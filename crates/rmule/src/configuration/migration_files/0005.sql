@@ -0,0 +1,5 @@
+-- Add the user_hash column to the settings table.
+
+-- The hash which identifies us on the ed2k network. It is NULL until
+-- rMule generates one, which happens the first time the settings are loaded.
+ALTER TABLE settings ADD COLUMN user_hash BLOB;
@@ -0,0 +1,5 @@
+-- Add the obfuscation column to the settings table.
+
+-- Whether to obfuscate connections: 0 = disabled, 1 = preferred, 2 = required.
+-- See ObfuscationMode.
+ALTER TABLE settings ADD COLUMN obfuscation INTEGER NOT NULL DEFAULT 1;
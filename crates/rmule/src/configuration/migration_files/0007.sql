@@ -0,0 +1,29 @@
+-- Create the kad_contact table.
+
+-- This is the rmule equivalent of the "nodes.dat" file from emule: the
+-- Kad nodes we know, which we need to join the Kad network again.
+CREATE TABLE kad_contact
+    (
+    id INTEGER PRIMARY KEY,
+    created TEXT NOT NULL,
+    updated TEXT NOT NULL,
+    -- The URL of the nodes.dat this contact was imported from, or "kad" if
+    -- it was in our routing table.
+    source TEXT NOT NULL,
+    -- The Kad id of the node, as 16 big-endian bytes. Natural key of the table.
+    kad_id BLOB NOT NULL UNIQUE,
+    -- IP of the node in string form.
+    ip_addr TEXT NOT NULL,
+    -- The UDP port, on which the node speaks Kad.
+    udp_port INTEGER NOT NULL,
+    -- The TCP port, on which the node accepts connections from other clients.
+    tcp_port INTEGER NOT NULL,
+    -- The Kad version the node speaks.
+    version INTEGER NOT NULL,
+    -- The key the node gave us for obfuscating UDP packets to it.
+    udp_key INTEGER,
+    -- Our public IP Address at the time we were given the udp_key.
+    udp_key_ip_addr TEXT,
+    -- Flag: whether the node answered from the address it claimed.
+    verified INTEGER NOT NULL
+    );
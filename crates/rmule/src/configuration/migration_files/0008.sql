@@ -0,0 +1,5 @@
+-- Add the bandwidth limit columns to the settings table.
+
+-- Upload and download limits in KiB/s, 0 meaning unlimited.
+ALTER TABLE settings ADD COLUMN upload_limit INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE settings ADD COLUMN download_limit INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,8 @@
+-- Add the connection limit columns to the settings table.
+
+-- The most open connections, the most connections opened in any 5 seconds
+-- and the most unanswered connects. 0 means unlimited. The defaults are
+-- eMule's.
+ALTER TABLE settings ADD COLUMN max_connections INTEGER NOT NULL DEFAULT 500;
+ALTER TABLE settings ADD COLUMN max_new_connections_per_5_secs INTEGER NOT NULL DEFAULT 20;
+ALTER TABLE settings ADD COLUMN max_half_open_connections INTEGER NOT NULL DEFAULT 9;
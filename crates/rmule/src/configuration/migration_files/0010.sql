@@ -0,0 +1,9 @@
+-- Add the proxy columns to the settings table.
+
+-- The kind of proxy: 0 none, 1 SOCKS4, 2 SOCKS5 or 3 HTTP CONNECT.
+ALTER TABLE settings ADD COLUMN proxy_type INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE settings ADD COLUMN proxy_host TEXT NOT NULL DEFAULT '';
+ALTER TABLE settings ADD COLUMN proxy_port INTEGER NOT NULL DEFAULT 1080;
+-- An empty user name means the proxy needs no authentication.
+ALTER TABLE settings ADD COLUMN proxy_user TEXT NOT NULL DEFAULT '';
+ALTER TABLE settings ADD COLUMN proxy_password TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,10 @@
+-- Add the listening address and port columns to the settings table.
+
+-- The IP Address to listen on, empty for all of them.
+ALTER TABLE settings ADD COLUMN bind_address TEXT NOT NULL DEFAULT '';
+-- The port other clients connect to, 0 to not accept connections.
+ALTER TABLE settings ADD COLUMN tcp_port INTEGER NOT NULL DEFAULT 4662;
+-- The port for Kad and other clients' UDP packets, 0 to disable UDP.
+ALTER TABLE settings ADD COLUMN udp_port INTEGER NOT NULL DEFAULT 4672;
+-- The port we send UDP packets to servers from, 0 for a random one.
+ALTER TABLE settings ADD COLUMN server_udp_port INTEGER NOT NULL DEFAULT 0;
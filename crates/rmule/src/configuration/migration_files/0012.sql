@@ -0,0 +1,4 @@
+-- Add the port mapping column to the settings table.
+
+-- Whether to ask the router to forward our ports with UPnP or NAT-PMP/PCP.
+ALTER TABLE settings ADD COLUMN port_mapping INTEGER NOT NULL DEFAULT 1;
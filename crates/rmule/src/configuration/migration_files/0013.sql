@@ -0,0 +1,27 @@
+-- Create the category table.
+
+-- Download categories, which say where finished files go and how new
+-- downloads are treated. A new download goes into the first category
+-- (by id) whose rules all match it; a category with no rules is only
+-- ever chosen by hand. Downloads refer to their category by id, so the
+-- id of a deleted category is never given to a new one.
+CREATE TABLE category
+    (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created TEXT NOT NULL,
+    updated TEXT NOT NULL,
+    name TEXT NOT NULL UNIQUE,
+    -- Colour to show the category's downloads in, as 0xRRGGBB.
+    colour INTEGER NOT NULL,
+    -- Where finished files go. NULL means the default downloads directory.
+    incoming_directory TEXT NULL,
+    -- Priority given to new downloads: 0 = low, 1 = normal, 2 = high.
+    default_priority INTEGER NOT NULL DEFAULT 1,
+    -- Rule: comma separated file extensions, without the dot.
+    extensions TEXT NULL,
+    -- Rule: smallest and largest file sizes in bytes.
+    min_size INTEGER NULL,
+    max_size INTEGER NULL,
+    -- Rule: regular expression the file name must match.
+    name_regex TEXT NULL
+    );
@@ -4,8 +4,16 @@ use tracing::{error, info};
 
 use super::sqlite_extensions::ConnectionExtensions;
 
-/// Applies all necessary database migrations to bring the database up to date.
+/// Applies all necessary database migrations to bring the configuration
+/// database up to date.
 pub fn apply_database_migrations(conn: &Connection) -> Result<()> {
+    apply_migrations(conn, &MIGRATIONS)
+}
+
+/// Applies the migrations which have not yet been applied to the database.
+/// Other databases, such as the part databases, use this with their own
+/// migrations; the first must create the version table, as 0000.sql does.
+pub(crate) fn apply_migrations(conn: &Connection, migrations: &[&str]) -> Result<()> {
     let db_version = match conn.table_exists("version") {
         Ok(_) => get_database_version(conn)?,
         Err(_) => 0,
@@ -16,7 +24,7 @@ pub fn apply_database_migrations(conn: &Connection) -> Result<()> {
     // If db_version is 0 it means the 'version' table does not exist. We therefore
     // want to run the first migration, which creates it. And so on.
     let mut num_migrations = 0;
-    for (idx, &mig) in MIGRATIONS
+    for (idx, &mig) in migrations
         .iter()
         .enumerate()
         .filter(|(idx, _)| *idx >= db_version)
@@ -61,12 +69,21 @@ fn apply_migration(idx: usize, conn: &Connection, migration: &str) -> Result<()>
     Ok(())
 }
 
-static MIGRATIONS: [&str; 5] = [
+static MIGRATIONS: [&str; 14] = [
     include_str!("migration_files/0000.sql"),
     include_str!("migration_files/0001.sql"),
     include_str!("migration_files/0002.sql"),
     include_str!("migration_files/0003.sql"),
     include_str!("migration_files/0004.sql"),
+    include_str!("migration_files/0005.sql"),
+    include_str!("migration_files/0006.sql"),
+    include_str!("migration_files/0007.sql"),
+    include_str!("migration_files/0008.sql"),
+    include_str!("migration_files/0009.sql"),
+    include_str!("migration_files/0010.sql"),
+    include_str!("migration_files/0011.sql"),
+    include_str!("migration_files/0012.sql"),
+    include_str!("migration_files/0013.sql"),
 ];
 
 fn get_database_version(conn: &Connection) -> Result<usize> {
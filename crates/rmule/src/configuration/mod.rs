@@ -1,6 +1,8 @@
 mod address;
+mod category;
 mod configuration_manager;
-mod migrations;
+mod kad_contact;
+pub(crate) mod migrations;
 mod parsing;
 mod server;
 mod settings;
@@ -9,7 +11,9 @@ mod sqlite_newtypes;
 mod temp_directory;
 
 pub use address::*;
+pub use category::*;
 pub use configuration_manager::*;
+pub use kad_contact::*;
 pub use server::*;
 pub use settings::*;
 pub use sqlite_newtypes::*;
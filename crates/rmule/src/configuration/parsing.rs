@@ -1,10 +1,11 @@
 //! Implement parsers for legacy file formats
-//! such as server.met.
+//! such as server.met and nodes.dat.
 
 use crate::configuration::{ServerPriority, ServerUdpFlags};
+use crate::kad::KadId;
 use crate::utils::StringExtensions;
 use anyhow::{bail, Context, Result};
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use core::panic;
 use std::io::{Cursor, Read};
 use std::net::{IpAddr, Ipv4Addr};
@@ -126,10 +127,12 @@ fn parse_server(url: &str, input: &mut Cursor<&[u8]>) -> Result<ParsedServer> {
             ParsedTag::UserCount(n) => server.user_count = Some(n),
             ParsedTag::LowIdUserCount(n) => server.low_id_user_count = Some(n),
             ParsedTag::Country(s) => server.country = Some(s),
-            ParsedTag::UDPFlags(n) => server.udp_flags = Some(ServerUdpFlags::try_from(n)?),
+            ParsedTag::UDPFlags(n) => server.udp_flags = Some(ServerUdpFlags::from(n)),
             ParsedTag::LastPingTime(n) => server.last_ping_time = Some(n),
             ParsedTag::UdpKey(n) => server.udp_key = Some(n),
-            ParsedTag::UdpKeyIpAddr(n) => server.udp_key_ip_addr = Some(Ipv4Addr::from(n).into()),
+            ParsedTag::UdpKeyIpAddr(n) => {
+                server.udp_key_ip_addr = Some(Ipv4Addr::from(n.to_le_bytes()).into())
+            }
             ParsedTag::TcpObfuscationPort(n) => server.tcp_obfuscation_port = Some(n),
             ParsedTag::UdpObfuscationPort(n) => server.udp_obfuscation_port = Some(n),
             ParsedTag::Preference(n) => server.priority = Some(ServerPriority::try_from(n)?),
@@ -300,7 +303,9 @@ fn parse_tag(url: &str, input: &mut Cursor<&[u8]>) -> Result<ParsedTag> {
             numeric_tag_value
                 .unwrap_or_else(|| panic!("{url}: UdpKey should have a numeric_tag_value")),
         ),
-        // TODO: This would have been read in LE, probably need to convert to BE!
+        // Like all IP addresses on the ed2k network this is in network byte
+        // order, so having been read as a LE u32 the first octet is in the
+        // low byte.
         Some(0x96) => ParsedTag::UdpKeyIpAddr(
             numeric_tag_value
                 .unwrap_or_else(|| panic!("{url}: UdpKeyIpAddr should have a numeric_tag_value")),
@@ -355,6 +360,140 @@ fn parse_tag(url: &str, input: &mut Cursor<&[u8]>) -> Result<ParsedTag> {
     Ok(tag)
 }
 
+/// A Kad contact from a nodes.dat file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedKadContact {
+    pub kad_id: KadId,
+    pub ip_addr: Ipv4Addr,
+    pub udp_port: u16,
+    pub tcp_port: u16,
+    /// The Kad version the contact speaks. Version 0 files do not record
+    /// it, so those contacts have 0.
+    pub version: u8,
+    /// The key the contact gave us for obfuscating UDP packets to it, and
+    /// our public IP Address at the time. Only version 2 files have these.
+    pub udp_key: Option<u32>,
+    pub udp_key_ip_addr: Option<Ipv4Addr>,
+    /// Whether the contact answered from the address it claimed.
+    pub verified: bool,
+}
+
+/// Parses any of the nodes.dat formats. See CRoutingZone::ReadFile in the
+/// eMule sources. All of them start with a u32 count; in the later versions
+/// that is 0 and is followed by the version and then the real count.
+///
+/// * Version 0 has the id, IP Address, UDP and TCP ports and a contact type
+///   for each contact. Types 4 and above were never written by eMule, so
+///   those contacts are skipped.
+/// * Version 1 has the Kad version of the contact instead of the type.
+/// * Version 2 adds the UDP key, the IP Address it was given for and the
+///   verified flag, and is what eMule (and we) write.
+/// * Version 3 is the bootstrap variant, which is what the nodes.dat
+///   download sites serve: an edition (always 1), the count and then
+///   contacts in the version 1 layout.
+pub fn parse_nodes_dat(url: &str, input: &[u8]) -> Result<Vec<ParsedKadContact>> {
+    let mut input = Cursor::new(input);
+
+    let mut count = input
+        .read_u32::<LittleEndian>()
+        .with_context(|| format!("{url}: Could not read contact count"))?;
+
+    let mut version = 0;
+    if count == 0 {
+        version = input
+            .read_u32::<LittleEndian>()
+            .with_context(|| format!("{url}: Could not read version"))?;
+
+        if version == 3 {
+            let edition = input.read_u32::<LittleEndian>()?;
+            if edition != 1 {
+                bail!("{url}: Bootstrap nodes.dat edition is {edition}, which is not valid");
+            }
+        } else if version > 3 {
+            bail!("{url}: nodes.dat version is {version}, which is not valid");
+        }
+
+        count = input
+            .read_u32::<LittleEndian>()
+            .with_context(|| format!("{url}: Could not read contact count"))?;
+    }
+
+    let mut contacts = Vec::with_capacity((count as usize).min(5000));
+
+    for n in 0..count {
+        let contact = parse_kad_contact(&mut input, version)
+            .with_context(|| format!("{url}: Could not read contact {n} of {count}"))?;
+        contacts.extend(contact);
+    }
+
+    Ok(contacts)
+}
+
+fn parse_kad_contact(input: &mut Cursor<&[u8]>, version: u32) -> Result<Option<ParsedKadContact>> {
+    let kad_id = KadId::read(input)?;
+    // Kad IP Addresses are host order numbers, unlike ed2k ones.
+    let ip_addr = Ipv4Addr::from(input.read_u32::<LittleEndian>()?);
+    let udp_port = input.read_u16::<LittleEndian>()?;
+    let tcp_port = input.read_u16::<LittleEndian>()?;
+    let type_or_version = input.read_u8()?;
+
+    let mut contact = ParsedKadContact {
+        kad_id,
+        ip_addr,
+        udp_port,
+        tcp_port,
+        version: 0,
+        udp_key: None,
+        udp_key_ip_addr: None,
+        verified: false,
+    };
+
+    if version == 0 {
+        if type_or_version >= 4 {
+            return Ok(None);
+        }
+    } else {
+        contact.version = type_or_version;
+    }
+
+    if version == 2 {
+        let udp_key = input.read_u32::<LittleEndian>()?;
+        let udp_key_ip_addr = input.read_u32::<LittleEndian>()?;
+        if udp_key != 0 {
+            contact.udp_key = Some(udp_key);
+            contact.udp_key_ip_addr = Some(Ipv4Addr::from(udp_key_ip_addr));
+        }
+        contact.verified = input.read_u8()? != 0;
+    }
+
+    Ok(Some(contact))
+}
+
+/// Writes a version 2 nodes.dat, which every client since eMule 0.49 reads.
+pub fn make_nodes_dat(contacts: &[ParsedKadContact]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(12 + contacts.len() * 34);
+    out.write_u32::<LittleEndian>(0).unwrap();
+    out.write_u32::<LittleEndian>(2).unwrap();
+    out.write_u32::<LittleEndian>(contacts.len() as u32)
+        .unwrap();
+
+    for contact in contacts {
+        contact.kad_id.write(&mut out);
+        out.write_u32::<LittleEndian>(contact.ip_addr.into())
+            .unwrap();
+        out.write_u16::<LittleEndian>(contact.udp_port).unwrap();
+        out.write_u16::<LittleEndian>(contact.tcp_port).unwrap();
+        out.push(contact.version);
+        out.write_u32::<LittleEndian>(contact.udp_key.unwrap_or_default())
+            .unwrap();
+        let key_ip = contact.udp_key_ip_addr.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        out.write_u32::<LittleEndian>(key_ip.into()).unwrap();
+        out.push(contact.verified.into());
+    }
+
+    out
+}
+
 fn read_string(input: &mut Cursor<&[u8]>, length: usize) -> Result<String> {
     let mut buf = vec![0u8; length];
     input.read_exact(&mut buf)?;
@@ -363,8 +502,11 @@ fn read_string(input: &mut Cursor<&[u8]>, length: usize) -> Result<String> {
 
 #[cfg(test)]
 mod test {
-    use super::parse_servers;
-    use std::net::IpAddr;
+    use super::{make_nodes_dat, parse_nodes_dat, parse_servers, ParsedKadContact};
+    use crate::kad::KadId;
+    use crate::protocol::opcodes::*;
+    use crate::protocol::{write_tag_list, Tag, TagValue};
+    use std::net::{IpAddr, Ipv4Addr};
 
     #[test]
     pub fn test_parse_of_valid_server_data_minimal() {
@@ -440,4 +582,89 @@ mod test {
         assert_eq!(s.version.as_deref(), Some("17.15"));
         assert_eq!(s.ping, Some(47));
     }
+
+    #[test]
+    pub fn test_parse_of_udp_key_ip_addr() {
+        // The IP Address is in network byte order on the wire, like the
+        // server's own.
+        let mut input = vec![0x0E, 1, 0, 0, 0, 10, 0, 0, 1, 0x36, 0x12];
+        write_tag_list(
+            &mut input,
+            &[
+                Tag::new(ST_UDPKEY, TagValue::U32(0xDEADBEEF)),
+                Tag::new(ST_UDPKEYIP, TagValue::U32(u32::from_le_bytes([1, 2, 3, 4]))),
+                Tag::new(ST_UDPPORTOBFUSCATION, TagValue::U32(4673)),
+            ],
+            true,
+        );
+
+        let servers = parse_servers("test.com", &input).unwrap();
+        let s = &servers[0];
+        assert_eq!(s.ip_addr, IpAddr::from([10, 0, 0, 1]));
+        assert_eq!(s.port, 0x1236);
+        assert_eq!(s.udp_key, Some(0xDEADBEEF));
+        assert_eq!(s.udp_key_ip_addr, Some(IpAddr::from([1, 2, 3, 4])));
+        assert_eq!(s.udp_obfuscation_port, Some(4673));
+    }
+
+    fn kad_contact(n: u8, version: u8) -> ParsedKadContact {
+        ParsedKadContact {
+            kad_id: KadId::new((n as u128) << 120 | 0x55),
+            ip_addr: Ipv4Addr::new(10, 0, 0, n),
+            udp_port: 4672,
+            tcp_port: 4662,
+            version,
+            udp_key: None,
+            udp_key_ip_addr: None,
+            verified: false,
+        }
+    }
+
+    /// The contact in the version 0 and 1 layout.
+    fn old_layout(contact: &ParsedKadContact, last_byte: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        contact.kad_id.write(&mut out);
+        out.extend_from_slice(&u32::from(contact.ip_addr).to_le_bytes());
+        out.extend_from_slice(&contact.udp_port.to_le_bytes());
+        out.extend_from_slice(&contact.tcp_port.to_le_bytes());
+        out.push(last_byte);
+        out
+    }
+
+    #[test]
+    pub fn test_nodes_dat_version_2_roundtrip() {
+        let mut contacts = vec![kad_contact(1, 8), kad_contact(2, 9)];
+        contacts[1].udp_key = Some(0xDEADBEEF);
+        contacts[1].udp_key_ip_addr = Some(Ipv4Addr::new(1, 2, 3, 4));
+        contacts[1].verified = true;
+
+        let data = make_nodes_dat(&contacts);
+        assert_eq!(data.len(), 12 + 2 * 34);
+        assert_eq!(parse_nodes_dat("test.com", &data).unwrap(), contacts);
+    }
+
+    #[test]
+    pub fn test_parse_of_old_and_bootstrap_nodes_dat() {
+        // Version 0: the count comes first, and the contact of type 4 is
+        // skipped.
+        let mut data = 2u32.to_le_bytes().to_vec();
+        data.extend(old_layout(&kad_contact(1, 0), 1));
+        data.extend(old_layout(&kad_contact(2, 0), 4));
+        let contacts = parse_nodes_dat("test.com", &data).unwrap();
+        assert_eq!(contacts, [kad_contact(1, 0)]);
+
+        // Version 3, the bootstrap file.
+        let mut data = [0u32, 3, 1, 2]
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect::<Vec<_>>();
+        data.extend(old_layout(&kad_contact(3, 8), 8));
+        data.extend(old_layout(&kad_contact(4, 6), 6));
+        let contacts = parse_nodes_dat("test.com", &data).unwrap();
+        assert_eq!(contacts, [kad_contact(3, 8), kad_contact(4, 6)]);
+
+        // Truncated.
+        assert!(parse_nodes_dat("test.com", &data[..40]).is_err());
+        assert!(parse_nodes_dat("test.com", &[0, 0, 0, 0, 7, 0, 0, 0]).is_err());
+    }
 }
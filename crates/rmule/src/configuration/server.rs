@@ -6,6 +6,7 @@ use anyhow::{bail, Result};
 use bitflags::bitflags;
 use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput};
 use rusqlite::{params, Connection, Row, Statement, ToSql};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use time::OffsetDateTime;
 use tracing::info;
 
@@ -62,13 +63,13 @@ pub struct Server {
     version: Option<String>,
     /// The last time the server was pinged.
     last_ping_time: Option<OffsetDateTime>,
-    /// UNKNOWN
+    /// The key the server gave us for obfuscating UDP packets to it.
     udp_key: Option<u32>,
-    /// UNKNOWN
+    /// Our public IP Address at the time we were given the udp_key.
     udp_key_ip_addr: Option<IpAddr>,
-    /// UNKNOWN
+    /// The port on which the server accepts obfuscated TCP connections.
     tcp_obfuscation_port: Option<u16>,
-    /// UNKNOWN
+    /// The port on which the server accepts obfuscated UDP packets.
     udp_obfuscation_port: Option<u16>,
     /// The DNS name of the server.
     dns_name: Option<String>,
@@ -97,6 +98,18 @@ impl ServerList {
         Ok(Self { servers })
     }
 
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Server> {
+        self.into_iter()
+    }
+
     /// Merges a set of parsed servers (from server.met files) into the
     /// server list. Servers are matched on ip_appr.
     pub fn merge_parsed_servers(&mut self, parsed_servers: &[ParsedServer]) {
@@ -117,6 +130,29 @@ impl ServerList {
         info!("Updated {num_updated} existing servers, created {num_inserted} new ones (RAM only)");
     }
 
+    /// Adds a server the user gave us, for example from an ed2k server
+    /// link. A server already in the list just has its port updated and is
+    /// made active again. Returns true if the server is new.
+    pub fn add_server(&mut self, addr: SocketAddrV4) -> bool {
+        let ip_addr = std::net::IpAddr::V4(*addr.ip());
+        if let Some(s) = self.servers.iter_mut().find(|s| *s.ip_addr == ip_addr) {
+            s.port = addr.port();
+            s.active = true;
+            info!("Updated server {addr} (RAM only)");
+            return false;
+        }
+
+        self.servers.push(Server {
+            source: "manual".to_string(),
+            active: true,
+            ip_addr: ip_addr.into(),
+            port: addr.port(),
+            ..Default::default()
+        });
+        info!("Added server {addr} (RAM only)");
+        true
+    }
+
     pub fn save_all(&mut self, conn: &mut Connection) -> Result<()> {
         let txn = conn.transaction().unwrap();
 
@@ -180,7 +216,7 @@ impl ServerList {
             if server.id == 0 {
                 server.created = now;
                 server.updated = now;
-                let id = Self::insert_server(&*txn, &mut insert_stmt, server)?;
+                let id = Self::insert_server(&txn, &mut insert_stmt, server)?;
                 server.id = id;
                 num_inserted += 1;
             } else {
@@ -354,6 +390,48 @@ impl Default for Server {
 }
 
 impl Server {
+    /// Whether the server is enabled. Inactive servers are never contacted.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// What actions the server supports via UDP. Servers that did not tell us
+    /// are assumed to support nothing beyond the basics.
+    pub fn udp_flags(&self) -> ServerUdpFlags {
+        self.udp_flags.unwrap_or_else(ServerUdpFlags::empty)
+    }
+
+    /// The address to which UDP packets for the server should be sent.
+    /// By convention this is always the TCP port + 4.
+    pub fn udp_socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(*self.ip_addr, self.port.wrapping_add(4))
+    }
+
+    /// The port on which the server accepts obfuscated connections, if it
+    /// has told us about one.
+    pub fn tcp_obfuscation_port(&self) -> Option<u16> {
+        self.tcp_obfuscation_port.filter(|&port| port != 0)
+    }
+
+    /// The address and key to use for obfuscated UDP packets to the server,
+    /// if it supports them. The server made the key for the IP address we
+    /// had when we connected to it, so it is only valid while we still
+    /// have that address.
+    pub fn udp_obfuscation(&self, public_ip: Ipv4Addr) -> Option<(SocketAddr, u32)> {
+        if !self.udp_flags().contains(ServerUdpFlags::UDP_OBFUSCATION) {
+            return None;
+        }
+
+        let key = self.udp_key.filter(|&key| key != 0)?;
+        let port = self.udp_obfuscation_port.filter(|&port| port != 0)?;
+        match self.udp_key_ip_addr.as_deref() {
+            Some(std::net::IpAddr::V4(ip)) if *ip == public_ip => {
+                Some((SocketAddr::new(*self.ip_addr, port), key))
+            }
+            _ => None,
+        }
+    }
+
     fn update_from(&mut self, ps: &ParsedServer) {
         self.source = ps.source.clone();
         self.port = ps.port;
@@ -505,11 +583,7 @@ impl FromSql for ServerUdpFlags {
     fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
         value.as_i64().and_then(|n| {
             // Slightly nasty cast, but in practice safe.
-            let sp = match ServerUdpFlags::try_from(n as u32) {
-                Ok(sp) => sp,
-                Err(_) => return Err(FromSqlError::OutOfRange(n)),
-            };
-            FromSqlResult::Ok(sp)
+            FromSqlResult::Ok(ServerUdpFlags::from(n as u32))
         })
     }
 }
@@ -1,11 +1,12 @@
-use super::PathBuf;
+use super::{PathBuf, UserHash};
 use crate::times;
-use anyhow::Result;
-use rusqlite::{params, Connection, Row};
+use anyhow::{bail, Result};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput};
+use rusqlite::{params, Connection, Row, ToSql};
 use time::OffsetDateTime;
 use tracing::info;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Settings {
     pub created: OffsetDateTime,
     pub updated: OffsetDateTime,
@@ -16,6 +17,40 @@ pub struct Settings {
     pub default_downloads_directory: PathBuf,
     /// Whether to automatically update the list of servers when rMule starts.
     pub auto_update_server_list: bool,
+    /// The hash which identifies us on the ed2k network.
+    pub user_hash: UserHash,
+    /// Whether to obfuscate connections to servers and other clients.
+    pub obfuscation: ObfuscationMode,
+    /// The upload limit in KiB/s, 0 for unlimited.
+    pub upload_limit: u32,
+    /// The download limit in KiB/s, 0 for unlimited.
+    pub download_limit: u32,
+    /// The most open connections, 0 for unlimited.
+    pub max_connections: u32,
+    /// The most connections opened in any 5 seconds, 0 for unlimited.
+    pub max_new_connections_per_5_secs: u32,
+    /// The most unanswered connects, 0 for unlimited.
+    pub max_half_open_connections: u32,
+    /// The proxy server, server and client connections and downloads from
+    /// the web go through.
+    pub proxy_type: ProxyType,
+    pub proxy_host: String,
+    pub proxy_port: u16,
+    /// The user name for the proxy, empty if it needs no authentication.
+    pub proxy_user: String,
+    /// Stored in plaintext in the settings table, as eMule stores it in
+    /// its preferences file.
+    pub proxy_password: String,
+    /// The IP Address we listen on, empty for all of them.
+    pub bind_address: String,
+    /// The port other clients connect to, 0 to not accept connections.
+    pub tcp_port: u16,
+    /// The port for Kad and other clients' UDP packets, 0 to disable UDP.
+    pub udp_port: u16,
+    /// The port we send UDP packets to servers from, 0 for a random one.
+    pub server_udp_port: u16,
+    /// Whether to ask the router to forward our TCP and UDP ports.
+    pub port_mapping: bool,
 }
 
 impl TryFrom<&Row<'_>> for Settings {
@@ -29,6 +64,25 @@ impl TryFrom<&Row<'_>> for Settings {
             nick_name: row.get("nick_name")?,
             default_downloads_directory: row.get("default_downloads_directory")?,
             auto_update_server_list: row.get("auto_update_server_list")?,
+            user_hash: row
+                .get::<_, Option<UserHash>>("user_hash")?
+                .unwrap_or_default(),
+            obfuscation: row.get("obfuscation")?,
+            upload_limit: row.get("upload_limit")?,
+            download_limit: row.get("download_limit")?,
+            max_connections: row.get("max_connections")?,
+            max_new_connections_per_5_secs: row.get("max_new_connections_per_5_secs")?,
+            max_half_open_connections: row.get("max_half_open_connections")?,
+            proxy_type: row.get("proxy_type")?,
+            proxy_host: row.get("proxy_host")?,
+            proxy_port: row.get("proxy_port")?,
+            proxy_user: row.get("proxy_user")?,
+            proxy_password: row.get("proxy_password")?,
+            bind_address: row.get("bind_address")?,
+            tcp_port: row.get("tcp_port")?,
+            udp_port: row.get("udp_port")?,
+            server_udp_port: row.get("server_udp_port")?,
+            port_mapping: row.get("port_mapping")?,
         })
     }
 }
@@ -40,7 +94,17 @@ impl Settings {
         let mut rows = stmt.query([])?;
 
         if let Some(row) = rows.next()? {
-            Ok(Settings::try_from(row)?)
+            let mut settings = Settings::try_from(row)?;
+
+            // Databases created before the user_hash column existed will
+            // not have one yet.
+            if settings.user_hash.is_null() {
+                info!("No user hash in the settings table, generating one");
+                settings.user_hash = UserHash::generate();
+                settings.update(conn)?;
+            }
+
+            Ok(settings)
         } else {
             info!("No settings rows in database, creating default");
             let now = times::now();
@@ -51,6 +115,23 @@ impl Settings {
                 nick_name: "http://www.rMule.org".to_owned(),
                 default_downloads_directory: ddir_pb.into(),
                 auto_update_server_list: true,
+                user_hash: UserHash::generate(),
+                obfuscation: ObfuscationMode::Preferred,
+                upload_limit: 0,
+                download_limit: 0,
+                max_connections: 500,
+                max_new_connections_per_5_secs: 20,
+                max_half_open_connections: 9,
+                proxy_type: ProxyType::None,
+                proxy_host: String::new(),
+                proxy_port: 1080,
+                proxy_user: String::new(),
+                proxy_password: String::new(),
+                bind_address: String::new(),
+                tcp_port: 4662,
+                udp_port: 4672,
+                server_udp_port: 0,
+                port_mapping: true,
             };
 
             default_settings.insert(conn)?;
@@ -64,13 +145,47 @@ impl Settings {
             r#"UPDATE settings SET
                 nick_name = ?1,
                 default_downloads_directory = ?2,
-                auto_update_server_list = ?3
-                updated = ?4
+                auto_update_server_list = ?3,
+                user_hash = ?4,
+                obfuscation = ?5,
+                upload_limit = ?6,
+                download_limit = ?7,
+                max_connections = ?8,
+                max_new_connections_per_5_secs = ?9,
+                max_half_open_connections = ?10,
+                proxy_type = ?11,
+                proxy_host = ?12,
+                proxy_port = ?13,
+                proxy_user = ?14,
+                proxy_password = ?15,
+                bind_address = ?16,
+                tcp_port = ?17,
+                udp_port = ?18,
+                server_udp_port = ?19,
+                port_mapping = ?20,
+                updated = ?21
             "#,
             params![
                 self.nick_name,
                 self.default_downloads_directory,
                 self.auto_update_server_list,
+                self.user_hash,
+                self.obfuscation,
+                self.upload_limit,
+                self.download_limit,
+                self.max_connections,
+                self.max_new_connections_per_5_secs,
+                self.max_half_open_connections,
+                self.proxy_type,
+                self.proxy_host,
+                self.proxy_port,
+                self.proxy_user,
+                self.proxy_password,
+                self.bind_address,
+                self.tcp_port,
+                self.udp_port,
+                self.server_udp_port,
+                self.port_mapping,
                 times::now(),
             ],
         )?;
@@ -81,8 +196,12 @@ impl Settings {
 
     pub fn insert(&self, conn: &Connection) -> Result<()> {
         conn.execute(
-            r#"INSERT INTO settings(created, updated, nick_name, default_downloads_directory, auto_update_server_list)
-                VALUES(?1, ?2, ?3, ?4, ?5);
+            r#"INSERT INTO settings(created, updated, nick_name, default_downloads_directory, auto_update_server_list, user_hash, obfuscation, upload_limit, download_limit,
+                    max_connections, max_new_connections_per_5_secs, max_half_open_connections,
+                    proxy_type, proxy_host, proxy_port, proxy_user, proxy_password,
+                    bind_address, tcp_port, udp_port, server_udp_port, port_mapping)
+                VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                    ?18, ?19, ?20, ?21, ?22);
             "#,
             params![
                 self.created,
@@ -90,6 +209,23 @@ impl Settings {
                 self.nick_name,
                 self.default_downloads_directory,
                 self.auto_update_server_list,
+                self.user_hash,
+                self.obfuscation,
+                self.upload_limit,
+                self.download_limit,
+                self.max_connections,
+                self.max_new_connections_per_5_secs,
+                self.max_half_open_connections,
+                self.proxy_type,
+                self.proxy_host,
+                self.proxy_port,
+                self.proxy_user,
+                self.proxy_password,
+                self.bind_address,
+                self.tcp_port,
+                self.udp_port,
+                self.server_udp_port,
+                self.port_mapping,
             ],
         )?;
 
@@ -97,3 +233,92 @@ impl Settings {
         Ok(())
     }
 }
+
+/// How hard we try to obfuscate our connections. Obfuscation hides the
+/// ed2k protocol from ISPs which throttle it; it is not meant to be secure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObfuscationMode {
+    /// Never obfuscate, and refuse obfuscated incoming connections.
+    Disabled = 0,
+    /// Obfuscate when the other side supports it, otherwise fall back to
+    /// plain connections.
+    Preferred = 1,
+    /// Only make and accept obfuscated connections.
+    Required = 2,
+}
+
+impl ObfuscationMode {
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::Disabled
+    }
+}
+
+impl TryFrom<i64> for ObfuscationMode {
+    type Error = anyhow::Error;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Disabled),
+            1 => Ok(Self::Preferred),
+            2 => Ok(Self::Required),
+            _ => bail!(
+                "The value {value} is outside the expected range (0, 1 or 2) for ObfuscationMode"
+            ),
+        }
+    }
+}
+
+impl ToSql for ObfuscationMode {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as u32))
+    }
+}
+
+impl FromSql for ObfuscationMode {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_i64()
+            .and_then(|n| ObfuscationMode::try_from(n).map_err(|_| FromSqlError::OutOfRange(n)))
+    }
+}
+
+/// The kinds of proxy server we can connect through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyType {
+    /// Connect directly.
+    None = 0,
+    Socks4 = 1,
+    Socks5 = 2,
+    /// A web proxy which allows the CONNECT method.
+    Http = 3,
+}
+
+impl TryFrom<i64> for ProxyType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Socks4),
+            2 => Ok(Self::Socks5),
+            3 => Ok(Self::Http),
+            _ => bail!(
+                "The value {value} is outside the expected range (0, 1, 2 or 3) for ProxyType"
+            ),
+        }
+    }
+}
+
+impl ToSql for ProxyType {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as u32))
+    }
+}
+
+impl FromSql for ProxyType {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_i64()
+            .and_then(|n| ProxyType::try_from(n).map_err(|_| FromSqlError::OutOfRange(n)))
+    }
+}
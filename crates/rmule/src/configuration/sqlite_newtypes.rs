@@ -1,5 +1,8 @@
 use crate::file;
-use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput};
+use crate::protocol::{AichHash, Ed2kHash};
+use crate::utils::parse_hex;
+use anyhow::bail;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
 use rusqlite::ToSql;
 use std::borrow::Cow;
 use std::fmt::Display;
@@ -121,3 +124,241 @@ impl Default for IpAddr {
         std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED).into()
     }
 }
+
+/// The 16 byte hash which identifies us (and every other client) on the ed2k
+/// network. It is generated once and then kept forever, because other clients
+/// use it to remember things such as how much we have uploaded to them.
+/// In the database it is stored as a blob.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct UserHash([u8; 16]);
+
+impl UserHash {
+    pub fn new(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    /// Generates a new random user hash. Bytes 5 and 14 are fixed so that
+    /// other clients recognise us as an eMule-compatible client.
+    pub fn generate() -> Self {
+        let mut bytes: [u8; 16] = rand::random();
+        bytes[5] = 14;
+        bytes[14] = 111;
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// Returns true if this is the all-zeros hash, i.e. it has not been set.
+    pub fn is_null(&self) -> bool {
+        self.0 == [0u8; 16]
+    }
+}
+
+impl ToSql for UserHash {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(&self.0[..]))
+    }
+}
+
+impl FromSql for UserHash {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        blob_to_array(value).map(Self)
+    }
+}
+
+impl Display for UserHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in &self.0 {
+            write!(f, "{b:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for UserHash {
+    type Err = anyhow::Error;
+
+    /// Parses 32 hex digits (either case) into a hash.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_hex(s) {
+            Some(bytes) => Ok(Self(bytes)),
+            None => bail!("'{s}' is not a valid user hash, expected 32 hex digits"),
+        }
+    }
+}
+
+// The file hashes belong to the protocol, but are stored the same way as
+// the user hash: as blobs.
+
+impl ToSql for Ed2kHash {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(&self.as_bytes()[..]))
+    }
+}
+
+impl FromSql for Ed2kHash {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        blob_to_array(value).map(Self::new)
+    }
+}
+
+impl ToSql for AichHash {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(&self.as_bytes()[..]))
+    }
+}
+
+impl FromSql for AichHash {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        blob_to_array(value).map(Self::new)
+    }
+}
+
+/// Reads a blob which must be exactly `N` bytes long.
+fn blob_to_array<const N: usize>(value: ValueRef<'_>) -> FromSqlResult<[u8; N]> {
+    let blob = value.as_blob()?;
+    <[u8; N]>::try_from(blob).map_err(|_| FromSqlError::InvalidBlobSize {
+        expected_size: N,
+        blob_size: blob.len(),
+    })
+}
+
+/// The size of a file in bytes. Files larger than 4 GiB are common, so
+/// sizes are always 64 bits; in the database they are stored as integers,
+/// which SQLite limits to `i64::MAX`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileSize(u64);
+
+impl FileSize {
+    pub fn new(size: u64) -> Self {
+        Self(size)
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0
+    }
+
+    /// Whether the file is too large for old clients and servers, which
+    /// only understand 32 bit sizes.
+    pub fn is_large(&self) -> bool {
+        self.0 > u32::MAX as u64
+    }
+}
+
+impl Deref for FileSize {
+    type Target = u64;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl ToSql for FileSize {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match i64::try_from(self.0) {
+            Ok(size) => Ok(ToSqlOutput::from(size)),
+            Err(e) => Err(rusqlite::Error::ToSqlConversionFailure(Box::new(e))),
+        }
+    }
+}
+
+impl FromSql for FileSize {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let size = value.as_i64()?;
+        match u64::try_from(size) {
+            Ok(size) => FromSqlResult::Ok(Self(size)),
+            Err(_) => FromSqlResult::Err(FromSqlError::OutOfRange(size)),
+        }
+    }
+}
+
+impl From<u64> for FileSize {
+    fn from(rhs: u64) -> Self {
+        Self(rhs)
+    }
+}
+
+impl Display for FileSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Higher priority downloads are given sources and bandwidth first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DownloadPriority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+impl TryFrom<i64> for DownloadPriority {
+    type Error = anyhow::Error;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Low),
+            1 => Ok(Self::Normal),
+            2 => Ok(Self::High),
+            _ => bail!(
+                "The value {value} is outside the expected range (0, 1 or 2) for DownloadPriority"
+            ),
+        }
+    }
+}
+
+impl ToSql for DownloadPriority {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as i64))
+    }
+}
+
+impl FromSql for DownloadPriority {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_i64()
+            .and_then(|n| DownloadPriority::try_from(n).map_err(|_| FromSqlError::OutOfRange(n)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rusqlite::Connection;
+
+    #[test]
+    pub fn test_hashes_and_sizes_round_trip_through_sqlite() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE files(ed2k_hash BLOB, aich_hash BLOB, user_hash BLOB, size INTEGER)",
+            [],
+        )
+        .unwrap();
+
+        let ed2k_hash: Ed2kHash = "31D6CFE0D16AE931B73C59D7E0C089C0".parse().unwrap();
+        let aich_hash = AichHash::new([7; 20]);
+        let user_hash: UserHash = "0123456789abcdef0123456789ABCDEF".parse().unwrap();
+        let size = FileSize::new(5_000_000_000);
+        conn.execute(
+            "INSERT INTO files VALUES(?1, ?2, ?3, ?4)",
+            rusqlite::params![ed2k_hash, aich_hash, user_hash, size],
+        )
+        .unwrap();
+
+        let row: (Ed2kHash, AichHash, UserHash, FileSize) = conn
+            .query_row("SELECT * FROM files", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap();
+        assert_eq!(row, (ed2k_hash, aich_hash, user_hash, size));
+        assert!(size.is_large());
+
+        // A hash of the wrong size is an error, not a truncated hash.
+        let wrong: rusqlite::Result<Ed2kHash> =
+            conn.query_row("SELECT aich_hash FROM files", [], |row| row.get(0));
+        assert!(wrong.is_err());
+        assert!(FileSize::new(u64::MAX).to_sql().is_err());
+    }
+}
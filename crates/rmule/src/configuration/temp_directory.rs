@@ -39,6 +39,13 @@ impl TryFrom<&Row<'_>> for TempDirectory {
     }
 }
 
+impl TempDirectory {
+    /// The directory which holds the part database.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+}
+
 impl TempDirectoryList {
     /// Load all temporary directories from the database.
     pub fn load_all(conn: &Connection) -> Result<Self> {
@@ -65,6 +72,18 @@ impl TempDirectoryList {
         Ok(Self { directories })
     }
 
+    pub fn len(&self) -> usize {
+        self.directories.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.directories.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, TempDirectory> {
+        self.directories.iter()
+    }
+
     /// Inserts a new temp directory. Returns a value with the id field
     /// correctly set from the database.
     pub fn insert(conn: &Connection, path: &Path) -> Result<TempDirectory> {
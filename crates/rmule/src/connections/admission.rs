@@ -0,0 +1,358 @@
+use super::Proxy;
+use crate::configuration::{ConfigurationEventReceiver, ConfigurationEvents, Settings};
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+use tracing::info;
+
+/// Engine-wide limits on TCP connections. Many home and office routers
+/// cannot cope with hundreds of connections, or with many being opened at
+/// once, which is why eMule has the same three limits. A limit of 0 means
+/// unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// The most connections open at once, in either direction.
+    pub max_connections: u32,
+    /// The most connections opened (by us) in any 5 seconds.
+    pub max_new_per_5_secs: u32,
+    /// The most connects which have not yet been answered.
+    pub max_half_open: u32,
+}
+
+impl ConnectionLimits {
+    /// No limits, for tests.
+    pub const UNLIMITED: Self = Self {
+        max_connections: 0,
+        max_new_per_5_secs: 0,
+        max_half_open: 0,
+    };
+
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_connections: settings.max_connections,
+            max_new_per_5_secs: settings.max_new_connections_per_5_secs,
+            max_half_open: settings.max_half_open_connections,
+        }
+    }
+}
+
+impl Default for ConnectionLimits {
+    /// eMule's defaults.
+    fn default() -> Self {
+        Self {
+            max_connections: 500,
+            max_new_per_5_secs: 20,
+            max_half_open: 9,
+        }
+    }
+}
+
+/// Decides when connections may be opened or accepted, and opens them
+/// through the proxy if there is one. Every TCP connection goes through
+/// here, and holds a `ConnectionPermit` for as long as it is open. Cloning
+/// gives another handle to the same limits.
+#[derive(Debug, Clone)]
+pub struct ConnectionAdmission {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: Mutex<State>,
+    /// Woken whenever a connection closes or a connect completes.
+    changed: Notify,
+}
+
+#[derive(Debug)]
+struct State {
+    limits: ConnectionLimits,
+    open: u32,
+    half_open: u32,
+    /// When we opened the connections of the last 5 seconds.
+    recent: VecDeque<Instant>,
+    proxy: Option<Proxy>,
+}
+
+/// Held by an open connection. Dropping it frees its place.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    inner: Arc<Inner>,
+}
+
+impl Default for ConnectionAdmission {
+    fn default() -> Self {
+        Self::new(ConnectionLimits::default())
+    }
+}
+
+impl ConnectionAdmission {
+    const WINDOW: Duration = Duration::from_secs(5);
+
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    limits,
+                    open: 0,
+                    half_open: 0,
+                    recent: VecDeque::new(),
+                    proxy: None,
+                }),
+                changed: Notify::new(),
+            }),
+        }
+    }
+
+    /// Changes the limits. Connections over a lowered limit stay open, but
+    /// no new ones are made until enough have closed.
+    pub fn set_limits(&self, limits: ConnectionLimits) {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.limits != limits {
+            info!("Connection limits: {limits:?}");
+            state.limits = limits;
+            self.inner.changed.notify_waiters();
+        }
+    }
+
+    /// Sets the proxy outgoing connections go through, None to connect
+    /// directly. Connections already open stay as they are.
+    pub fn set_proxy(&self, proxy: Option<Proxy>) {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.proxy != proxy {
+            match &proxy {
+                Some(p) => info!(
+                    "Connecting through {:?} proxy {}:{}",
+                    p.proxy_type, p.host, p.port
+                ),
+                None => info!("Connecting without a proxy"),
+            }
+            state.proxy = proxy;
+        }
+    }
+
+    /// Keeps the limits and proxy in line with the settings, as a Tokio task which
+    /// runs until the Configuration Manager goes away.
+    pub fn follow_settings(
+        &self,
+        mut cfg_evt_receiver: ConfigurationEventReceiver,
+        tokio_handle: &tokio::runtime::Handle,
+    ) {
+        let admission = self.clone();
+        tokio_handle.spawn(async move {
+            loop {
+                match cfg_evt_receiver.recv().await {
+                    Ok(ConfigurationEvents::SettingsChange(settings)) => {
+                        admission.set_limits(ConnectionLimits::from_settings(&settings));
+                        admission.set_proxy(Proxy::from_settings(&settings));
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Opens a TCP connection, first waiting until the limits allow it.
+    pub async fn connect(&self, addr: SocketAddr) -> Result<(TcpStream, ConnectionPermit)> {
+        self.wait_for_turn().await;
+        let permit = ConnectionPermit {
+            inner: self.inner.clone(),
+        };
+        let _half_open = HalfOpen(&self.inner);
+
+        let proxy = self.inner.state.lock().unwrap().proxy.clone();
+        let stream = match proxy {
+            Some(proxy) => proxy.connect(addr).await?,
+            None => TcpStream::connect(addr).await?,
+        };
+        Ok((stream, permit))
+    }
+
+    /// Takes a connection another client opened to us, if we have room for
+    /// it. Incoming connections do not count as new connections, as the
+    /// router has already seen them.
+    pub fn admit_incoming(&self) -> Option<ConnectionPermit> {
+        let mut state = self.inner.state.lock().unwrap();
+        if under(state.open, state.limits.max_connections) {
+            state.open += 1;
+            Some(ConnectionPermit {
+                inner: self.inner.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The number of open connections, including those being connected.
+    pub fn open_connections(&self) -> u32 {
+        self.inner.state.lock().unwrap().open
+    }
+
+    /// Waits until an outgoing connection is allowed and counts it as open
+    /// and half-open.
+    async fn wait_for_turn(&self) {
+        loop {
+            // Registered before looking, so that a wake-up between looking
+            // and waiting is not lost.
+            let changed = self.inner.changed.notified();
+
+            let retry_at = {
+                let mut state = self.inner.state.lock().unwrap();
+                let now = Instant::now();
+                while state
+                    .recent
+                    .front()
+                    .is_some_and(|t| now - *t >= Self::WINDOW)
+                {
+                    state.recent.pop_front();
+                }
+
+                let limits = state.limits;
+                let room = under(state.open, limits.max_connections)
+                    && under(state.half_open, limits.max_half_open);
+                let recent_ok = under(state.recent.len() as u32, limits.max_new_per_5_secs);
+
+                if room && recent_ok {
+                    state.open += 1;
+                    state.half_open += 1;
+                    state.recent.push_back(now);
+                    return;
+                }
+
+                // Only the passing of time makes room in the window.
+                if room {
+                    state.recent.front().map(|t| *t + Self::WINDOW)
+                } else {
+                    None
+                }
+            };
+
+            match retry_at {
+                Some(at) => tokio::select! {
+                    _ = time::sleep_until(at) => {}
+                    _ = changed => {}
+                },
+                None => changed.await,
+            }
+        }
+    }
+}
+
+/// Counts a connect as half-open until it succeeds, fails or is given up.
+struct HalfOpen<'a>(&'a Inner);
+
+impl Drop for HalfOpen<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().half_open -= 1;
+        self.0.changed.notify_waiters();
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.inner.state.lock().unwrap().open -= 1;
+        self.inner.changed.notify_waiters();
+    }
+}
+
+fn under(count: u32, limit: u32) -> bool {
+    limit == 0 || count < limit
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    pub async fn test_open_connections_are_limited_in_both_directions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let admission = ConnectionAdmission::new(ConnectionLimits {
+            max_connections: 2,
+            ..ConnectionLimits::UNLIMITED
+        });
+
+        let (_a, first) = admission.connect(addr).await.unwrap();
+        let incoming = admission.admit_incoming().unwrap();
+        assert_eq!(admission.open_connections(), 2);
+
+        // Full up, in both directions, until one closes.
+        assert!(admission.admit_incoming().is_none());
+        let outgoing = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.connect(addr).await.unwrap() }
+        });
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(!outgoing.is_finished());
+
+        drop(first);
+        let (_b, second) = time::timeout(Duration::from_secs(1), outgoing)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(admission.open_connections(), 2);
+        drop((second, incoming));
+        assert_eq!(admission.open_connections(), 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_new_connections_are_limited_per_5_seconds() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = ConnectionLimits {
+            max_new_per_5_secs: 2,
+            ..ConnectionLimits::UNLIMITED
+        };
+        let admission = ConnectionAdmission::new(limits);
+
+        let (_a, _first) = admission.connect(addr).await.unwrap();
+        let (_b, _second) = admission.connect(addr).await.unwrap();
+
+        // The third connect has to wait for the 5 second window, unless
+        // the limit is lifted.
+        let third = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.connect(addr).await.unwrap() }
+        });
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(!third.is_finished());
+        admission.set_limits(ConnectionLimits::UNLIMITED);
+        let _third = time::timeout(Duration::from_secs(1), third)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(admission.open_connections(), 3);
+    }
+
+    #[tokio::test]
+    pub async fn test_half_open_connections_are_limited() {
+        let admission = ConnectionAdmission::new(ConnectionLimits {
+            max_half_open: 1,
+            ..ConnectionLimits::UNLIMITED
+        });
+
+        // A connect which has not been answered yet.
+        admission.wait_for_turn().await;
+        let connecting = HalfOpen(&admission.inner);
+
+        let next = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.wait_for_turn().await }
+        });
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(!next.is_finished());
+
+        drop(connecting);
+        time::timeout(Duration::from_secs(1), next)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
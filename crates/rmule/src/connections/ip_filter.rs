@@ -0,0 +1,171 @@
+use anyhow::{bail, Context, Result};
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// The file the IP filter is loaded from, in the configuration directory.
+pub const IP_FILTER_FILE: &str = "ipfilter.dat";
+
+/// The IP Addresses we must not connect to or take sources from, as listed
+/// in an eMule ipfilter.dat. Cloning gives another handle to the same list.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    /// Sorted, with overlapping and adjacent ranges merged.
+    ranges: Arc<Vec<RangeInclusive<u32>>>,
+}
+
+impl IpFilter {
+    /// Ranges with an access level below this are blocked, which is eMule's
+    /// default filter level.
+    const FILTER_LEVEL: u32 = 127;
+
+    /// Loads ipfilter.dat from the configuration directory. Without one
+    /// nothing is blocked.
+    pub fn load(config_dir: &Path) -> Self {
+        let path = config_dir.join(IP_FILTER_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                let filter = Self::parse(&text);
+                info!(
+                    "Loaded {} blocked ranges from {}",
+                    filter.ranges.len(),
+                    path.display()
+                );
+                filter
+            }
+            Err(e) => {
+                info!(
+                    "Not filtering IP Addresses, {} not loaded: {e}",
+                    path.display()
+                );
+                Self::default()
+            }
+        }
+    }
+
+    /// Parses the lines of an ipfilter.dat, which look like
+    /// `001.002.003.000 - 001.002.003.255 , 000 , Description`. Comments
+    /// start with `#` or `//`, and bad lines are skipped.
+    pub fn parse(text: &str) -> Self {
+        let mut ranges = Vec::new();
+        let mut bad_lines = 0;
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Ok(Some(range)) => ranges.push(range),
+                Ok(None) => {}
+                Err(_) => bad_lines += 1,
+            }
+        }
+        if bad_lines > 0 {
+            warn!("Skipped {bad_lines} bad lines in the IP filter");
+        }
+
+        ranges.sort_by_key(|r| *r.start());
+        let mut merged: Vec<RangeInclusive<u32>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if *range.start() <= last.end().saturating_add(1) => {
+                    *last = *last.start()..=*last.end().max(range.end());
+                }
+                _ => merged.push(range),
+            }
+        }
+
+        Self {
+            ranges: Arc::new(merged),
+        }
+    }
+
+    pub fn is_blocked(&self, ip: Ipv4Addr) -> bool {
+        let ip = u32::from(ip);
+        let idx = self.ranges.partition_point(|r| *r.end() < ip);
+        self.ranges.get(idx).is_some_and(|r| r.contains(&ip))
+    }
+}
+
+/// Returns the range if the line blocks it, None if its access level
+/// allows it.
+fn parse_line(line: &str) -> Result<Option<RangeInclusive<u32>>> {
+    let mut fields = line.split(',');
+    let range = fields.next().unwrap_or_default();
+    let level = match fields.next() {
+        Some(level) => level.trim().parse::<u32>()?,
+        None => 0,
+    };
+
+    let Some((start, end)) = range.split_once('-') else {
+        bail!("No range in {line}");
+    };
+    let (start, end) = (parse_ip(start)?, parse_ip(end)?);
+    if start > end {
+        bail!("Backwards range in {line}");
+    }
+
+    Ok((level < IpFilter::FILTER_LEVEL).then_some(start..=end))
+}
+
+/// The addresses are padded with zeros, which `Ipv4Addr` does not accept.
+fn parse_ip(text: &str) -> Result<u32> {
+    let octets = text
+        .trim()
+        .split('.')
+        .map(|octet| octet.parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Bad IP Address {text}"))?;
+    let Ok(octets) = <[u8; 4]>::try_from(octets) else {
+        bail!("Bad IP Address {text}");
+    };
+    Ok(u32::from(Ipv4Addr::from(octets)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_blocks_ranges_below_the_filter_level() {
+        let filter = IpFilter::parse(
+            "000.000.000.000 - 000.255.255.255 , 000 , Bogon\n\
+             090.000.000.000 - 090.255.255.255 , 200 , Allowed\n",
+        );
+        assert!(filter.is_blocked(Ipv4Addr::new(0, 1, 2, 3)));
+        assert!(!filter.is_blocked(Ipv4Addr::new(90, 1, 2, 3)));
+    }
+
+    #[test]
+    pub fn test_overlapping_ranges_are_merged() {
+        let filter = IpFilter::parse(
+            "080.001.002.000 - 080.001.002.127 , 100 , Somebody\n\
+             080.001.002.100 - 080.001.003.010 , 050 , Overlapping\n",
+        );
+        assert!(filter.is_blocked(Ipv4Addr::new(80, 1, 2, 0)));
+        assert!(filter.is_blocked(Ipv4Addr::new(80, 1, 2, 200)));
+        assert!(filter.is_blocked(Ipv4Addr::new(80, 1, 3, 10)));
+        assert!(!filter.is_blocked(Ipv4Addr::new(80, 1, 3, 11)));
+        assert!(!filter.is_blocked(Ipv4Addr::new(80, 1, 1, 255)));
+    }
+
+    #[test]
+    pub fn test_comments_and_bad_lines_are_skipped() {
+        let filter = IpFilter::parse(
+            "# A comment\n\
+             // Another\n\
+             this is not a range\n\
+             080.001.002.010 - 080.001.002.000 , 000 , Backwards\n\
+             080.001.002.003 - 080.001.002.003 , 000 , Good\n",
+        );
+        assert!(filter.is_blocked(Ipv4Addr::new(80, 1, 2, 3)));
+        assert!(!filter.is_blocked(Ipv4Addr::new(80, 1, 2, 5)));
+    }
+
+    #[test]
+    pub fn test_no_filter_blocks_nothing() {
+        assert!(!IpFilter::default().is_blocked(Ipv4Addr::new(0, 1, 2, 3)));
+    }
+}
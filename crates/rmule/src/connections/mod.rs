@@ -0,0 +1,12 @@
+//! Admission control for TCP connections: how many may be open, how many
+//! may be opened in a short time and how many may be connecting at once,
+//! the proxy server they go through, and the IP Addresses we must not
+//! connect to at all.
+
+mod admission;
+mod ip_filter;
+mod proxy;
+
+pub use admission::*;
+pub use ip_filter::*;
+pub use proxy::*;
@@ -0,0 +1,297 @@
+use crate::configuration::{ProxyType, Settings};
+use anyhow::{bail, Context, Result};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// A proxy server our TCP connections go through. Kad's UDP traffic does
+/// not, as with eMule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub proxy_type: ProxyType,
+    pub host: String,
+    pub port: u16,
+    /// The user name and password, if the proxy wants them.
+    pub auth: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// The proxy set in the settings, if any.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        if settings.proxy_type == ProxyType::None || settings.proxy_host.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            proxy_type: settings.proxy_type,
+            host: settings.proxy_host.clone(),
+            port: settings.proxy_port,
+            auth: (!settings.proxy_user.is_empty())
+                .then(|| (settings.proxy_user.clone(), settings.proxy_password.clone())),
+        })
+    }
+
+    /// Connects to `target` through the proxy. The stream returned carries
+    /// the connection to the target.
+    pub async fn connect(&self, target: SocketAddr) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Connecting to proxy {}:{} failed", self.host, self.port))?;
+
+        match self.proxy_type {
+            ProxyType::None => {}
+            ProxyType::Socks4 => self.socks4_handshake(&mut stream, target).await?,
+            ProxyType::Socks5 => self.socks5_handshake(&mut stream, target).await?,
+            ProxyType::Http => self.http_handshake(&mut stream, target).await?,
+        }
+
+        Ok(stream)
+    }
+
+    /// The proxy for web downloads such as server.met files.
+    pub fn reqwest_proxy(&self) -> Result<reqwest::Proxy> {
+        let scheme = match self.proxy_type {
+            ProxyType::None => bail!("No proxy"),
+            ProxyType::Socks4 => bail!("SOCKS4 proxies cannot be used for web downloads"),
+            // Host names are looked up by the proxy.
+            ProxyType::Socks5 => "socks5h",
+            ProxyType::Http => "http",
+        };
+
+        let proxy = reqwest::Proxy::all(format!("{scheme}://{}:{}", self.host, self.port))?;
+        Ok(match &self.auth {
+            Some((user, password)) => proxy.basic_auth(user, password),
+            None => proxy,
+        })
+    }
+
+    async fn socks4_handshake(&self, stream: &mut TcpStream, target: SocketAddr) -> Result<()> {
+        let SocketAddr::V4(target) = target else {
+            bail!("SOCKS4 proxies cannot connect to IPv6 addresses");
+        };
+
+        let mut request = vec![4, 1];
+        request.extend_from_slice(&target.port().to_be_bytes());
+        request.extend_from_slice(&target.ip().octets());
+        // SOCKS4 has a user id but no password.
+        if let Some((user, _)) = &self.auth {
+            request.extend_from_slice(user.as_bytes());
+        }
+        request.push(0);
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 8];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 90 {
+            bail!(
+                "SOCKS4 proxy refused the connection to {target} ({})",
+                reply[1]
+            );
+        }
+        Ok(())
+    }
+
+    async fn socks5_handshake(&self, stream: &mut TcpStream, target: SocketAddr) -> Result<()> {
+        const NO_AUTH: u8 = 0;
+        const USER_PASSWORD: u8 = 2;
+
+        let methods: &[u8] = match self.auth {
+            Some(_) => &[NO_AUTH, USER_PASSWORD],
+            None => &[NO_AUTH],
+        };
+        let mut greeting = vec![5, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        stream.write_all(&greeting).await?;
+
+        let mut chosen = [0u8; 2];
+        stream.read_exact(&mut chosen).await?;
+        match (chosen[1], &self.auth) {
+            (NO_AUTH, _) => {}
+            (USER_PASSWORD, Some((user, password))) => {
+                if user.len() > 255 || password.len() > 255 {
+                    bail!("The proxy user name and password must be at most 255 bytes");
+                }
+                let mut login = vec![1, user.len() as u8];
+                login.extend_from_slice(user.as_bytes());
+                login.push(password.len() as u8);
+                login.extend_from_slice(password.as_bytes());
+                stream.write_all(&login).await?;
+
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0 {
+                    bail!("SOCKS5 proxy did not accept our user name and password");
+                }
+            }
+            _ => bail!("SOCKS5 proxy wants an authentication method we do not have"),
+        }
+
+        let mut request = vec![5, 1, 0];
+        match target {
+            SocketAddr::V4(addr) => {
+                request.push(1);
+                request.extend_from_slice(&addr.ip().octets());
+            }
+            SocketAddr::V6(addr) => {
+                request.push(4);
+                request.extend_from_slice(&addr.ip().octets());
+            }
+        }
+        request.extend_from_slice(&target.port().to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            bail!(
+                "SOCKS5 proxy refused the connection to {target} ({})",
+                reply[1]
+            );
+        }
+        // Skip the address the proxy connected from.
+        let bound_len = match reply[3] {
+            1 => 4,
+            3 => stream.read_u8().await? as usize,
+            4 => 16,
+            kind => bail!("SOCKS5 proxy sent an unknown address type {kind}"),
+        };
+        let mut bound = vec![0u8; bound_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    async fn http_handshake(&self, stream: &mut TcpStream, target: SocketAddr) -> Result<()> {
+        // Response headers should be short; anything longer is not a proxy.
+        const MAX_RESPONSE: usize = 8 * 1024;
+
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some((user, password)) = &self.auth {
+            let credentials = base64::encode(format!("{user}:{password}"));
+            request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read a byte at a time so that we do not read past the headers
+        // into what the target sends.
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_RESPONSE {
+                bail!("HTTP proxy sent too long a response");
+            }
+            response.push(stream.read_u8().await?);
+        }
+
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some("200") => Ok(()),
+            _ => bail!("HTTP proxy refused the connection to {target}: {status_line}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Stands in for a proxy of the given type which wants the user name
+    /// "user" and password "secret": checks the handshake for one
+    /// connection and then echoes what it is sent.
+    async fn stand_in_proxy(proxy_type: ProxyType, target: SocketAddr) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let SocketAddr::V4(target) = target else {
+                panic!()
+            };
+            let target_bytes = [&target.port().to_be_bytes()[..], &target.ip().octets()].concat();
+
+            match proxy_type {
+                ProxyType::Socks4 => {
+                    let mut request = [0u8; 13];
+                    stream.read_exact(&mut request).await.unwrap();
+                    assert_eq!(&request[..2], [4, 1]);
+                    assert_eq!(&request[2..8], target_bytes);
+                    assert_eq!(&request[8..], b"user\0");
+                    stream.write_all(&[0, 90, 0, 0, 0, 0, 0, 0]).await.unwrap();
+                }
+                ProxyType::Socks5 => {
+                    let mut greeting = [0u8; 4];
+                    stream.read_exact(&mut greeting).await.unwrap();
+                    assert_eq!(greeting, [5, 2, 0, 2]);
+                    stream.write_all(&[5, 2]).await.unwrap();
+                    let mut login = [0u8; 13];
+                    stream.read_exact(&mut login).await.unwrap();
+                    assert_eq!(login, *b"\x01\x04user\x06secret");
+                    stream.write_all(&[1, 0]).await.unwrap();
+
+                    let mut request = [0u8; 10];
+                    stream.read_exact(&mut request).await.unwrap();
+                    assert_eq!(&request[..4], [5, 1, 0, 1]);
+                    assert_eq!(&request[4..8], target.ip().octets());
+                    assert_eq!(&request[8..], target.port().to_be_bytes());
+                    stream
+                        .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                        .await
+                        .unwrap();
+                }
+                ProxyType::Http => {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        request.push(stream.read_u8().await.unwrap());
+                    }
+                    let request = String::from_utf8(request).unwrap();
+                    assert!(request.starts_with(&format!("CONNECT {target} HTTP/1.1\r\n")));
+                    // "user:secret" in base64.
+                    assert!(request.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+                    stream
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .await
+                        .unwrap();
+                }
+                ProxyType::None => unreachable!(),
+            }
+
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        port
+    }
+
+    async fn connect_through(proxy_type: ProxyType) {
+        let target: SocketAddr = "80.1.2.3:4661".parse().unwrap();
+        let proxy = Proxy {
+            proxy_type,
+            host: "127.0.0.1".to_owned(),
+            port: stand_in_proxy(proxy_type, target).await,
+            auth: Some(("user".to_owned(), "secret".to_owned())),
+        };
+
+        let mut stream = proxy.connect(target).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut echoed = [0u8; 5];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello");
+    }
+
+    #[tokio::test]
+    pub async fn test_connects_through_socks4_proxy() {
+        connect_through(ProxyType::Socks4).await;
+    }
+
+    #[tokio::test]
+    pub async fn test_connects_through_socks5_proxy() {
+        connect_through(ProxyType::Socks5).await;
+    }
+
+    #[tokio::test]
+    pub async fn test_connects_through_http_proxy() {
+        connect_through(ProxyType::Http).await;
+    }
+}
@@ -0,0 +1,206 @@
+use super::{PartDatabase, PartDownload};
+use crate::file;
+use crate::hashing::{Ed2kHasher, PART_SIZE};
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// How assembling a download went, when nothing went wrong on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Assembled {
+    /// The file is complete and has been given this path.
+    Moved(PathBuf),
+    /// The data does not match the file hash, so nothing was written.
+    Corrupt,
+}
+
+/// Writes the download out of the part database into the destination
+/// directory. The data is streamed a part at a time into a temporary file
+/// next to its final name, hashed on the way, and only renamed once the
+/// hash matches. The download is left in the part database.
+///
+/// This does a lot of blocking IO, so it opens its own connection to the
+/// part database and is meant to be run on a thread of its own.
+pub fn assemble(
+    temp_dir: &Path,
+    download: &PartDownload,
+    destination_dir: &Path,
+) -> Result<Assembled> {
+    file::ensure_directory_exists(destination_dir)?;
+    let available = fs2::available_space(destination_dir).with_context(|| {
+        format!(
+            "Cannot find the free space in {}",
+            destination_dir.display()
+        )
+    })?;
+    if available < *download.size {
+        bail!(
+            "There is not enough space in {} for {} ({} bytes needed, {available} free)",
+            destination_dir.display(),
+            download.name,
+            download.size
+        );
+    }
+
+    let db = PartDatabase::open(temp_dir)?;
+    let temp_path = destination_dir.join(format!(".{}.rmule", download.hash));
+    let result = write_and_hash(&db, download, &temp_path).and_then(|matches| {
+        if !matches {
+            return Ok(Assembled::Corrupt);
+        }
+        let path = move_into_place(&temp_path, destination_dir, &safe_file_name(download))?;
+        info!("Completed {}", path.display());
+        Ok(Assembled::Moved(path))
+    });
+
+    // The temporary file is gone if it was moved into place.
+    let _ = fs::remove_file(&temp_path);
+    result
+}
+
+/// Copies the data to the file. Returns true if it matches the file hash.
+fn write_and_hash(db: &PartDatabase, download: &PartDownload, path: &Path) -> Result<bool> {
+    let mut out =
+        File::create(path).with_context(|| format!("Cannot create {}", path.display()))?;
+    let mut hasher = Ed2kHasher::new();
+
+    let mut start = 0;
+    while start < *download.size {
+        let end = (start + PART_SIZE).min(*download.size);
+        let data = db.read_block(download.id, start..end)?;
+        hasher.update(&data);
+        out.write_all(&data)
+            .with_context(|| format!("Writing {} failed", path.display()))?;
+        start = end;
+    }
+
+    out.sync_all()?;
+    Ok(hasher.finish().root == download.hash)
+}
+
+/// Gives the file its name, or `name (1).ext` and so on if that is taken.
+/// Hard linking fails rather than replacing an existing file, which makes
+/// the check and the rename one step.
+fn move_into_place(temp_path: &Path, dir: &Path, name: &str) -> Result<PathBuf> {
+    for n in 0.. {
+        let path = dir.join(numbered_name(name, n));
+        match fs::hard_link(temp_path, &path) {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            // Some file systems, such as FAT, cannot hard link.
+            Err(_) if !path.try_exists()? => {
+                fs::rename(temp_path, &path).with_context(|| {
+                    format!(
+                        "Cannot rename {} to {}",
+                        temp_path.display(),
+                        path.display()
+                    )
+                })?;
+                return Ok(path);
+            }
+            Err(_) => continue,
+        }
+    }
+    unreachable!()
+}
+
+/// `name`, then `name (1)`, `name (2)`... with any extension kept last.
+fn numbered_name(name: &str, n: u32) -> String {
+    if n == 0 {
+        return name.to_string();
+    }
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{} ({n}){}", &name[..dot], &name[dot..]),
+        _ => format!("{name} ({n})"),
+    }
+}
+
+/// File names come from other people, so anything which could take the
+/// file out of the destination directory, or which Windows cannot cope
+/// with, is replaced.
+fn safe_file_name(download: &PartDownload) -> String {
+    let name: String = download
+        .name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    match name.trim() {
+        "" | "." | ".." => download.hash.to_string(),
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::download::PartStatus;
+    use crate::protocol::{Ed2kFileLink, Ed2kHash};
+    use md4::{Digest, Md4};
+
+    /// A complete download of the data, called `name`.
+    fn complete_download(temp_dir: &Path, name: &str, data: &[u8]) -> PartDownload {
+        let hash = Ed2kHash::new(Md4::digest(data).into());
+        let mut db = PartDatabase::open(temp_dir).unwrap();
+        let id = db
+            .add_download(&Ed2kFileLink::new(name, data.len() as u64, hash))
+            .unwrap();
+        db.write_block(id, 0, data).unwrap();
+        let download = db.download(id).unwrap().unwrap();
+        assert_eq!(
+            db.verify_part(&download, 0).unwrap(),
+            Some(PartStatus::Verified)
+        );
+        download
+    }
+
+    #[test]
+    pub fn test_assembles_into_the_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let (temp_dir, destination) = (dir.path().join("temp"), dir.path().join("done"));
+        let data = b"The quick brown fox";
+        let download = complete_download(&temp_dir, "fox.txt", data);
+
+        let assembled = assemble(&temp_dir, &download, &destination).unwrap();
+        let path = destination.join("fox.txt");
+        assert_eq!(assembled, Assembled::Moved(path.clone()));
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    pub fn test_assembles_into_a_free_and_safe_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let (temp_dir, destination) = (dir.path().join("temp"), dir.path().join("done"));
+        let data = b"The quick brown fox";
+        let download = complete_download(&temp_dir, "../fox.txt", data);
+
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(destination.join(".._fox.txt"), b"taken").unwrap();
+        let assembled = assemble(&temp_dir, &download, &destination).unwrap();
+        let path = destination.join(".._fox (1).txt");
+        assert_eq!(assembled, Assembled::Moved(path.clone()));
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert_eq!(fs::read(destination.join(".._fox.txt")).unwrap(), b"taken");
+        assert_eq!(fs::read_dir(&destination).unwrap().count(), 2);
+    }
+
+    #[test]
+    pub fn test_data_which_does_not_match_the_hash_is_not_written_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let (temp_dir, destination) = (dir.path().join("temp"), dir.path().join("done"));
+        let mut download = complete_download(&temp_dir, "fox.txt", b"The quick brown fox");
+
+        download.hash = Ed2kHash::default();
+        assert_eq!(
+            assemble(&temp_dir, &download, &destination).unwrap(),
+            Assembled::Corrupt
+        );
+        assert!(!destination.join("fox.txt").exists());
+    }
+}
@@ -0,0 +1,2154 @@
+use super::{assemble, Assembled, DownloadStatus, PartDatabase, PartDownload, PartStatus};
+use crate::configuration::{
+    CategoryList, ConfigurationEventReceiver, ConfigurationEvents, DownloadPriority,
+    TempDirectoryList, UserHash,
+};
+use crate::connections::IpFilter;
+use crate::hashing::{AichRecoveryData, PART_SIZE};
+use crate::peer::{is_reachable, HelloInfo, PeerMessage, SourceExchange, SOURCE_EXCHANGE2_VERSION};
+use crate::protocol::{AichHash, Ed2kFileLink, Ed2kHash};
+use crate::server::{
+    FoundSource, ServerCommand, ServerCommandSender, ServerEventReceiver, ServerEvents,
+};
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{info, warn};
+
+pub type DownloadCommandSender = mpsc::Sender<DownloadCommand>;
+pub type DownloadCommandReceiver = mpsc::Receiver<DownloadCommand>;
+
+pub type DownloadEventSender = broadcast::Sender<DownloadEvents>;
+pub type DownloadEventReceiver = broadcast::Receiver<DownloadEvents>;
+
+/// The handle type allows commands to be sent to and events to be received
+/// from the Download Manager.
+pub struct DownloadManagerHandle {
+    cmd_sender: DownloadCommandSender,
+    evt_sender: DownloadEventSender,
+    // TODO: We need at least one receiver to be alive to
+    // allow us to send events.
+    evt_receiver: DownloadEventReceiver,
+}
+
+impl DownloadManagerHandle {
+    /// Starts the Download Manager on its own thread, as the part databases
+    /// are SQLite. It opens the part databases when the Configuration
+    /// Manager tells it about the temp directories, and asks the Server
+    /// Manager for sources of the files it is downloading. Sources in the
+    /// IP filter are dropped.
+    pub fn new(
+        cfg_evt_receiver: ConfigurationEventReceiver,
+        server_cmd_sender: ServerCommandSender,
+        server_evt_receiver: ServerEventReceiver,
+        ip_filter: IpFilter,
+        tokio_handle: tokio::runtime::Handle,
+    ) -> Self {
+        let (cmd_sender, cmd_receiver) = mpsc::channel::<DownloadCommand>(256);
+        let (evt_sender, evt_receiver) = broadcast::channel::<DownloadEvents>(256);
+
+        let mut mgr = DownloadManager::new(
+            evt_sender.clone(),
+            cmd_receiver,
+            cfg_evt_receiver,
+            server_cmd_sender,
+            server_evt_receiver,
+            ip_filter,
+            tokio_handle,
+        );
+        std::thread::Builder::new()
+            .name("DownloadMgr".to_owned())
+            .spawn(move || mgr.run())
+            .expect("spawn of DownloadMgr failed");
+
+        Self {
+            cmd_sender,
+            evt_sender,
+            evt_receiver,
+        }
+    }
+
+    /// Sends a command to the Download Manager.
+    pub async fn send_command(&self, cmd: DownloadCommand) -> Result<()> {
+        Ok(self.cmd_sender.send(cmd).await?)
+    }
+
+    /// Synchronously send a command to the Download Manager.
+    pub fn send_command_blocking(&self, cmd: DownloadCommand) -> Result<()> {
+        Ok(self.cmd_sender.blocking_send(cmd)?)
+    }
+
+    /// Create a new subscription to events sent by the Download Manager.
+    pub fn subscribe_to_events(&self) -> DownloadEventReceiver {
+        self.evt_sender.subscribe()
+    }
+
+    /// Creates a new command sender which can be used to send commands
+    /// to the Download Manager.
+    pub fn make_command_sender(&self) -> DownloadCommandSender {
+        self.cmd_sender.clone()
+    }
+}
+
+/// The set of commands that can be sent to the Download Manager. Downloads
+/// are identified by the hash of their file.
+#[derive(Debug)]
+pub enum DownloadCommand {
+    /// Starts downloading the file in the link. Search results convert
+    /// into links. Without a category the download goes into the first
+    /// whose rules match it, and it takes its priority from its category.
+    /// Without a destination the file goes to its category's incoming
+    /// directory, or the default downloads directory.
+    Add {
+        link: Ed2kFileLink,
+        category: Option<i64>,
+        destination: Option<PathBuf>,
+    },
+    /// Pauses a download. It keeps its sources.
+    Pause(Ed2kHash),
+    /// Carries on with a paused or stopped download, asking the servers
+    /// for more sources.
+    Resume(Ed2kHash),
+    /// Stops a download. Unlike pausing, its sources are dropped.
+    StopDownload(Ed2kHash),
+    /// Cancels a download and deletes everything received for it.
+    Cancel(Ed2kHash),
+    SetPriority(Ed2kHash, DownloadPriority),
+    /// Sets the directory the file goes to when it is complete. None means
+    /// the default downloads directory from the settings.
+    SetDestination(Ed2kHash, Option<PathBuf>),
+    /// Moves the download into a category, or out of them all. Its
+    /// priority is left alone.
+    SetCategory(Ed2kHash, Option<i64>),
+    /// Data for the download has arrived from another client.
+    WriteData {
+        hash: Ed2kHash,
+        start: u64,
+        data: Vec<u8>,
+    },
+    /// AICH recovery data for a corrupt part has arrived from another
+    /// client, in answer to `RecoveryDataWanted`. Only the blocks it shows
+    /// to be corrupt are downloaded again.
+    RecoveryData {
+        hash: Ed2kHash,
+        part: u16,
+        recovery: AichRecoveryData,
+    },
+    /// A peer has told us it has the file, by sending its part status. If
+    /// eMule's limits allow it to be asked for the sources it knows,
+    /// `SourcesWanted` is sent.
+    PeerConnected {
+        peer: UserHash,
+        hash: Ed2kHash,
+    },
+    /// A peer sent us an `OP_REQUESTSOURCES2`. It is answered with
+    /// `SourcesToSend`, unless it asked too recently or we have no sources.
+    SourcesRequested {
+        peer: UserHash,
+        hash: Ed2kHash,
+        version: u8,
+    },
+    /// A peer answered our `OP_REQUESTSOURCES2`. Sources from answers we
+    /// did not ask for, and those we cannot or may not connect to, are
+    /// dropped; the rest are added as `SourcesAdded`.
+    SourcesReceived {
+        peer: UserHash,
+        hash: Ed2kHash,
+        sources: Vec<FoundSource>,
+    },
+    /// Stops the Download Manager.
+    Stop,
+}
+
+impl DownloadCommand {
+    /// The command for a message from a peer, if the message is one the
+    /// Download Manager deals with.
+    pub fn from_peer_message(peer: &HelloInfo, msg: PeerMessage) -> Option<Self> {
+        let peer = peer.user_hash;
+        match msg {
+            PeerMessage::FileStatus { hash, .. } => Some(Self::PeerConnected { peer, hash }),
+            PeerMessage::RequestSources2 { hash, version } => Some(Self::SourcesRequested {
+                peer,
+                hash,
+                version,
+            }),
+            PeerMessage::AnswerSources2 { hash, sources, .. } => Some(Self::SourcesReceived {
+                peer,
+                hash,
+                sources,
+            }),
+            PeerMessage::AichAnswer {
+                hash,
+                part,
+                recovery,
+                ..
+            } => Some(Self::RecoveryData {
+                hash,
+                part,
+                recovery,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The set of events that can be emitted by the Download Manager.
+#[derive(Debug, Clone)]
+pub enum DownloadEvents {
+    /// A download has been added, or loaded from a part database.
+    Added {
+        download: PartDownload,
+        completed: u64,
+    },
+    /// The status, priority, destination or category of a download has
+    /// changed.
+    Changed(PartDownload),
+    /// A download has been cancelled.
+    Removed(Ed2kHash),
+    /// More of a download has arrived. These are sent at most once every
+    /// `PROGRESS_INTERVAL` for each download.
+    Progress {
+        hash: Ed2kHash,
+        completed: u64,
+        size: u64,
+    },
+    /// A part of a download with an AICH hash is corrupt. Clients with the
+    /// file should be sent an `AichRequest` for it, and the first answer
+    /// passed on with `RecoveryData`. If none arrives within
+    /// `RECOVERY_TIMEOUT` the whole part is downloaded again.
+    RecoveryDataWanted {
+        hash: Ed2kHash,
+        part: u16,
+        master: AichHash,
+    },
+    /// Sources of a download have been found which it did not have. It now
+    /// has `total` of them, at most `MAX_SOURCES`.
+    SourcesAdded {
+        hash: Ed2kHash,
+        sources: Vec<FoundSource>,
+        total: usize,
+    },
+    /// The peer should be sent an `OP_REQUESTSOURCES2` for the file.
+    SourcesWanted { peer: UserHash, hash: Ed2kHash },
+    /// The peer should be sent the sources in an `OP_ANSWERSOURCES2` of
+    /// the version it asked with.
+    SourcesToSend {
+        peer: UserHash,
+        hash: Ed2kHash,
+        version: u8,
+        sources: Vec<FoundSource>,
+    },
+    /// A command for the download could not be carried out.
+    CommandFailed { hash: Ed2kHash, reason: String },
+    /// The file has been written to its destination and its temp data
+    /// deleted. The download is gone.
+    Completed { hash: Ed2kHash, path: PathBuf },
+    /// The file could not be written to its destination. If its data did
+    /// not match the hash, the download carries on; otherwise it is paused.
+    CompletionFailed { hash: Ed2kHash, reason: String },
+}
+
+impl DownloadEvents {
+    /// The message to send a peer, if this event is for that peer.
+    pub fn peer_message(&self, peer: &HelloInfo) -> Option<PeerMessage> {
+        match self {
+            Self::SourcesWanted { peer: to, hash }
+                if *to == peer.user_hash && peer.supports_source_exchange2() =>
+            {
+                Some(PeerMessage::RequestSources2 {
+                    hash: *hash,
+                    version: SOURCE_EXCHANGE2_VERSION,
+                })
+            }
+            Self::SourcesToSend {
+                peer: to,
+                hash,
+                version,
+                sources,
+            } if *to == peer.user_hash => Some(PeerMessage::AnswerSources2 {
+                hash: *hash,
+                version: *version,
+                sources: sources.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// How often progress events are sent.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a corrupt part waits for AICH recovery data.
+pub const RECOVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The most sources we keep for a download, as eMule does by default.
+pub const MAX_SOURCES: usize = 400;
+
+/// A download we know about, which part database it is in, and the
+/// clients which have its file.
+struct KnownDownload {
+    db_idx: usize,
+    download: PartDownload,
+    completed: u64,
+    sources: Vec<FoundSource>,
+}
+
+/// What woke the Download Manager up.
+enum Input {
+    Command(Option<DownloadCommand>),
+    Configuration(Result<ConfigurationEvents, RecvError>),
+    Server(Result<ServerEvents, RecvError>),
+    ReportProgress,
+    Completion(Ed2kHash, Result<Assembled>),
+}
+
+/// This is private to the module: all access is via the handle.
+struct DownloadManager {
+    tokio_handle: tokio::runtime::Handle,
+    events_sender: DownloadEventSender,
+    commands_receiver: DownloadCommandReceiver,
+    cfg_events_receiver: ConfigurationEventReceiver,
+    server_commands_sender: ServerCommandSender,
+    server_events_receiver: ServerEventReceiver,
+    ip_filter: IpFilter,
+    // The server we have a high id on, if any. Only it can pass on our
+    // callback requests, and only to its own low id clients.
+    callback_server: Option<SocketAddr>,
+    // How often peers may be asked for sources, and answered.
+    source_exchange: SourceExchange,
+    // One per temp directory.
+    databases: Vec<PartDatabase>,
+    downloads: HashMap<Ed2kHash, KnownDownload>,
+    // Downloads which have had data since the last progress events.
+    progressed: HashSet<Ed2kHash>,
+    // Corrupt parts waiting for AICH recovery data, until the deadline.
+    recovering: HashMap<(Ed2kHash, u64), Instant>,
+    // From the settings, for downloads without a destination of their own.
+    default_destination: Option<PathBuf>,
+    categories: CategoryList,
+    // Finished files are assembled on threads of their own, which send
+    // the outcome back here.
+    completion_sender: mpsc::UnboundedSender<(Ed2kHash, Result<Assembled>)>,
+    completion_receiver: mpsc::UnboundedReceiver<(Ed2kHash, Result<Assembled>)>,
+}
+
+impl DownloadManager {
+    fn new(
+        events_sender: DownloadEventSender,
+        commands_receiver: DownloadCommandReceiver,
+        cfg_events_receiver: ConfigurationEventReceiver,
+        server_commands_sender: ServerCommandSender,
+        server_events_receiver: ServerEventReceiver,
+        ip_filter: IpFilter,
+        tokio_handle: tokio::runtime::Handle,
+    ) -> Self {
+        let (completion_sender, completion_receiver) = mpsc::unbounded_channel();
+
+        Self {
+            tokio_handle,
+            events_sender,
+            commands_receiver,
+            cfg_events_receiver,
+            server_commands_sender,
+            server_events_receiver,
+            ip_filter,
+            callback_server: None,
+            source_exchange: SourceExchange::new(),
+            databases: Vec::new(),
+            downloads: HashMap::new(),
+            progressed: HashSet::new(),
+            recovering: HashMap::new(),
+            default_destination: None,
+            categories: CategoryList::default(),
+            completion_sender,
+            completion_receiver,
+        }
+    }
+
+    fn run(&mut self) {
+        let mut next_progress = Instant::now() + PROGRESS_INTERVAL;
+
+        loop {
+            let commands_receiver = &mut self.commands_receiver;
+            let cfg_events_receiver = &mut self.cfg_events_receiver;
+            let server_events_receiver = &mut self.server_events_receiver;
+            let completion_receiver = &mut self.completion_receiver;
+            let input = self.tokio_handle.block_on(async {
+                // Configuration changes come first, so that commands sent
+                // after one see it. Progress comes before commands, which
+                // would otherwise hold it back while data keeps arriving.
+                tokio::select! {
+                    biased;
+                    evt = cfg_events_receiver.recv() => Input::Configuration(evt),
+                    _ = tokio::time::sleep_until(next_progress.into()) => Input::ReportProgress,
+                    cmd = commands_receiver.recv() => Input::Command(cmd),
+                    evt = server_events_receiver.recv() => Input::Server(evt),
+                    // We hold a sender, so this never returns None.
+                    Some((hash, result)) = completion_receiver.recv() => Input::Completion(hash, result),
+                }
+            });
+
+            match input {
+                Input::Command(Some(DownloadCommand::Stop) | None) => break,
+                Input::Command(Some(cmd)) => self.handle_command(cmd),
+                Input::Configuration(Ok(ConfigurationEvents::TempDirectoryListChange(dirs))) => {
+                    self.open_databases(&dirs)
+                }
+                Input::Configuration(Ok(ConfigurationEvents::SettingsChange(settings))) => {
+                    self.default_destination =
+                        Some(settings.default_downloads_directory.to_path_buf())
+                }
+                Input::Configuration(Ok(ConfigurationEvents::CategoryListChange(categories))) => {
+                    self.change_categories(categories)
+                }
+                Input::Configuration(Ok(_)) => {}
+                Input::Configuration(Err(RecvError::Lagged(n))) => {
+                    warn!("Download Manager missed {n} configuration events")
+                }
+                Input::Configuration(Err(RecvError::Closed)) => break,
+                Input::Server(Ok(ServerEvents::SourcesFound { hash, sources })) => {
+                    let sources = sources
+                        .into_iter()
+                        .filter(|s| is_reachable(s, &self.ip_filter))
+                        .collect();
+                    self.add_sources(hash, sources)
+                }
+                // A new server is asked about everything we are downloading.
+                Input::Server(Ok(ServerEvents::Connected { addr, low_id, .. })) => {
+                    self.callback_server = (!low_id).then_some(addr);
+                    for known in self.downloads.values() {
+                        if known.download.status == DownloadStatus::Downloading {
+                            self.get_sources(&known.download);
+                        }
+                    }
+                }
+                Input::Server(Ok(ServerEvents::Disconnected(addr))) => {
+                    if self.callback_server == Some(addr) {
+                        self.callback_server = None;
+                    }
+                }
+                Input::Server(Ok(_)) => {}
+                Input::Server(Err(RecvError::Lagged(n))) => {
+                    warn!("Download Manager missed {n} server events")
+                }
+                Input::Server(Err(RecvError::Closed)) => break,
+                Input::ReportProgress => {
+                    self.expire_recoveries();
+                    self.source_exchange.expire(tokio::time::Instant::now());
+                    self.report_progress();
+                    next_progress = Instant::now() + PROGRESS_INTERVAL;
+                }
+                Input::Completion(hash, result) => {
+                    if let Err(e) = self.completion_finished(hash, result) {
+                        warn!("Finishing download {hash} failed: {e}");
+                    }
+                }
+            }
+        }
+
+        self.report_progress();
+        info!("Download Manager stopped");
+    }
+
+    fn handle_command(&mut self, cmd: DownloadCommand) {
+        let (hash, result) = match cmd {
+            DownloadCommand::Add {
+                link,
+                category,
+                destination,
+            } => (link.hash, self.add(&link, category, destination)),
+            DownloadCommand::Pause(hash) => (
+                hash,
+                self.change(hash, |d| d.status = DownloadStatus::Paused),
+            ),
+            DownloadCommand::Resume(hash) => (hash, self.resume(hash)),
+            DownloadCommand::StopDownload(hash) => (hash, self.stop_download(hash)),
+            DownloadCommand::Cancel(hash) => (hash, self.cancel(hash)),
+            DownloadCommand::SetPriority(hash, priority) => {
+                (hash, self.change(hash, |d| d.priority = priority))
+            }
+            DownloadCommand::SetDestination(hash, destination) => {
+                (hash, self.change(hash, |d| d.destination = destination))
+            }
+            DownloadCommand::SetCategory(hash, category) => {
+                (hash, self.set_category(hash, category))
+            }
+            DownloadCommand::WriteData { hash, start, data } => {
+                (hash, self.write_data(hash, start, &data))
+            }
+            DownloadCommand::RecoveryData {
+                hash,
+                part,
+                recovery,
+            } => (
+                hash,
+                self.recover(hash, part as u64, Some(&recovery))
+                    .and_then(|_| self.complete_if_finished(hash)),
+            ),
+            DownloadCommand::PeerConnected { peer, hash } => {
+                self.peer_connected(peer, hash);
+                return;
+            }
+            DownloadCommand::SourcesRequested {
+                peer,
+                hash,
+                version,
+            } => {
+                self.sources_requested(peer, hash, version);
+                return;
+            }
+            DownloadCommand::SourcesReceived {
+                peer,
+                hash,
+                sources,
+            } => {
+                let now = tokio::time::Instant::now();
+                let sources =
+                    self.source_exchange
+                        .answer_received(peer, hash, sources, &self.ip_filter, now);
+                self.add_sources(hash, sources);
+                return;
+            }
+            DownloadCommand::Stop => return,
+        };
+
+        if let Err(e) = result {
+            warn!("Download {hash}: {e}");
+            send_event(
+                &self.events_sender,
+                DownloadEvents::CommandFailed {
+                    hash,
+                    reason: e.to_string(),
+                },
+            );
+        }
+    }
+
+    /// Opens the part databases in any temp directories we have not seen
+    /// before, and loads their downloads.
+    fn open_databases(&mut self, dirs: &TempDirectoryList) {
+        for dir in dirs.iter() {
+            let path = dir.directory().join(PartDatabase::DB_NAME);
+            if self.databases.iter().any(|db| db.path() == path) {
+                continue;
+            }
+
+            let loaded = PartDatabase::open(dir.directory()).and_then(|db| {
+                let downloads = db
+                    .downloads()?
+                    .into_iter()
+                    .map(|d| Ok((db.completed(d.id)?, d)))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok((db, downloads))
+            });
+            let (db, downloads) = match loaded {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    warn!(
+                        "Cannot open the part database in {}: {e}",
+                        dir.directory().display()
+                    );
+                    continue;
+                }
+            };
+
+            info!(
+                "Loaded {} downloads from {}",
+                downloads.len(),
+                db.path().display()
+            );
+            self.databases.push(db);
+            for (completed, download) in downloads {
+                let hash = download.hash;
+                if download.status == DownloadStatus::Downloading {
+                    self.get_sources(&download);
+                }
+                self.insert(self.databases.len() - 1, download, completed);
+                // Corrupt parts which still have their data were waiting
+                // for recovery data when we were last stopped. Then there
+                // are those which were being completed.
+                let result = self
+                    .recover_corrupt_parts(hash)
+                    .and_then(|_| self.complete_if_finished(hash));
+                if let Err(e) = result {
+                    warn!("Download {hash}: {e}");
+                }
+            }
+        }
+    }
+
+    /// Takes the downloads out of any categories which have been deleted.
+    /// Those without a destination of their own are given the category's
+    /// incoming directory, so they still go where they would have.
+    fn change_categories(&mut self, categories: CategoryList) {
+        let orphans: Vec<_> = self
+            .downloads
+            .values()
+            .filter_map(|known| {
+                let id = known.download.category?;
+                categories.get(id).is_none().then(|| {
+                    let incoming = self
+                        .categories
+                        .get(id)
+                        .and_then(|c| c.incoming_directory.as_ref())
+                        .map(|dir| dir.to_path_buf());
+                    (known.download.hash, incoming)
+                })
+            })
+            .collect();
+        self.categories = categories;
+
+        for (hash, incoming) in orphans {
+            let result = self.save(hash, |d| {
+                d.category = None;
+                d.destination = d.destination.take().or(incoming);
+            });
+            if let Err(e) = result {
+                warn!("Download {hash}: {e}");
+            }
+        }
+    }
+
+    fn add(
+        &mut self,
+        link: &Ed2kFileLink,
+        category: Option<i64>,
+        destination: Option<PathBuf>,
+    ) -> Result<()> {
+        if let Some(known) = self.downloads.get(&link.hash) {
+            bail!("{} is already being downloaded", known.download.name);
+        }
+
+        // New downloads go to the temp directory with the fewest, to
+        // spread the disk IO.
+        let Some(db_idx) = (0..self.databases.len())
+            .min_by_key(|idx| self.downloads.values().filter(|d| d.db_idx == *idx).count())
+        else {
+            bail!("There are no temp directories to download into");
+        };
+
+        let category = match category {
+            Some(id) => match self.categories.get(id) {
+                Some(category) => Some(category),
+                None => bail!("There is no category {id}"),
+            },
+            None => self.categories.categorize(&link.name, link.size),
+        };
+
+        let db = &mut self.databases[db_idx];
+        let id = db.add_download(link)?;
+        let Some(mut download) = db.download(id)? else {
+            bail!("Download {id} vanished from {}", db.path().display());
+        };
+        download.destination = destination;
+        if let Some(category) = category {
+            info!("{} is in category {}", link.name, category.name);
+            download.category = Some(category.id);
+            download.priority = category.default_priority;
+        }
+        db.update_download(&download)?;
+        self.get_sources(&download);
+        self.insert(db_idx, download, 0);
+        Ok(())
+    }
+
+    fn insert(&mut self, db_idx: usize, download: PartDownload, completed: u64) {
+        send_event(
+            &self.events_sender,
+            DownloadEvents::Added {
+                download: download.clone(),
+                completed,
+            },
+        );
+        self.downloads.insert(
+            download.hash,
+            KnownDownload {
+                db_idx,
+                download,
+                completed,
+                sources: Vec::new(),
+            },
+        );
+    }
+
+    /// Applies the change to the download, saves it and tells everybody.
+    /// Downloads being completed cannot be changed.
+    fn change<F>(&mut self, hash: Ed2kHash, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut PartDownload),
+    {
+        if self
+            .downloads
+            .get(&hash)
+            .is_some_and(|known| known.download.status == DownloadStatus::Completing)
+        {
+            bail!("The download is being completed");
+        }
+        self.save(hash, f)
+    }
+
+    fn set_category(&mut self, hash: Ed2kHash, category: Option<i64>) -> Result<()> {
+        if let Some(id) = category {
+            if self.categories.get(id).is_none() {
+                bail!("There is no category {id}");
+            }
+        }
+        self.change(hash, |d| d.category = category)
+    }
+
+    /// As `change`, whatever the download's status.
+    fn save<F>(&mut self, hash: Ed2kHash, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut PartDownload),
+    {
+        let Some(known) = self.downloads.get_mut(&hash) else {
+            bail!("There is no such download");
+        };
+
+        let mut download = known.download.clone();
+        f(&mut download);
+        self.databases[known.db_idx].update_download(&download)?;
+        known.download = download.clone();
+        send_event(&self.events_sender, DownloadEvents::Changed(download));
+        Ok(())
+    }
+
+    /// Carries on with a download. It may have failed to complete, in
+    /// which case this tries again; otherwise it needs sources.
+    fn resume(&mut self, hash: Ed2kHash) -> Result<()> {
+        self.change(hash, |d| d.status = DownloadStatus::Downloading)?;
+        self.complete_if_finished(hash)?;
+        let download = &self.downloads[&hash].download;
+        if download.status == DownloadStatus::Downloading {
+            self.get_sources(download);
+        }
+        Ok(())
+    }
+
+    fn stop_download(&mut self, hash: Ed2kHash) -> Result<()> {
+        self.change(hash, |d| d.status = DownloadStatus::Stopped)?;
+        if let Some(known) = self.downloads.get_mut(&hash) {
+            known.sources.clear();
+        }
+        Ok(())
+    }
+
+    fn cancel(&mut self, hash: Ed2kHash) -> Result<()> {
+        let Some(known) = self.downloads.remove(&hash) else {
+            bail!("There is no such download");
+        };
+        if known.download.status == DownloadStatus::Completing {
+            self.downloads.insert(hash, known);
+            bail!("The download is being completed");
+        }
+
+        self.progressed.remove(&hash);
+        self.recovering.retain(|(h, _), _| *h != hash);
+        if let Err(e) = self.databases[known.db_idx].remove_download(known.download.id) {
+            self.downloads.insert(hash, known);
+            return Err(e);
+        }
+        info!("Cancelled download of {}", known.download.name);
+        send_event(&self.events_sender, DownloadEvents::Removed(hash));
+        Ok(())
+    }
+
+    /// Asks the servers for sources of the file. They arrive as
+    /// `SourcesFound` events. This does not wait for the Server Manager,
+    /// which may be waiting for us: if it is too busy, the file is asked
+    /// about again when the next server is connected to.
+    fn get_sources(&self, download: &PartDownload) {
+        let cmd = ServerCommand::GetSources {
+            hash: download.hash,
+            size: *download.size,
+        };
+        match self.server_commands_sender.try_send(cmd) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!(
+                    "Not asking for sources of {}: the Server Manager is busy",
+                    download.name
+                )
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                warn!(
+                    "Cannot ask for sources of {}: the Server Manager has stopped",
+                    download.name
+                )
+            }
+        }
+    }
+
+    /// Adds the sources the download does not already have, up to
+    /// `MAX_SOURCES`. Stopped downloads have no sources, and downloads
+    /// being completed need none. New low id sources are asked to connect
+    /// to us, if their server will pass the request on.
+    fn add_sources(&mut self, hash: Ed2kHash, sources: Vec<FoundSource>) {
+        let Some(known) = self.downloads.get_mut(&hash) else {
+            return;
+        };
+        if !matches!(
+            known.download.status,
+            DownloadStatus::Downloading | DownloadStatus::Paused
+        ) {
+            return;
+        }
+
+        let mut added = Vec::new();
+        for source in sources {
+            if known.sources.len() >= MAX_SOURCES {
+                break;
+            }
+            if !known.sources.iter().any(|s| is_same_client(s, &source)) {
+                known.sources.push(source.clone());
+                added.push(source);
+            }
+        }
+
+        if added.is_empty() {
+            return;
+        }
+
+        info!(
+            "Found {} new sources of {}",
+            added.len(),
+            known.download.name
+        );
+        let total = known.sources.len();
+        for source in &added {
+            if source.is_low_id() && Some(source.server) == self.callback_server {
+                self.request_callback(source.client_id);
+            }
+        }
+        send_event(
+            &self.events_sender,
+            DownloadEvents::SourcesAdded {
+                hash,
+                sources: added,
+                total,
+            },
+        );
+    }
+
+    /// Asks our server to tell a low id source to connect to us. Like
+    /// `get_sources` this does not wait for the Server Manager.
+    fn request_callback(&self, client_id: u32) {
+        let cmd = ServerCommand::RequestCallback(client_id);
+        match self.server_commands_sender.try_send(cmd) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!("Not asking for a callback from {client_id}: the Server Manager is busy")
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                warn!("Cannot ask for a callback from {client_id}: the Server Manager has stopped")
+            }
+        }
+    }
+
+    /// Asks the peer for the sources it knows, if the download wants more
+    /// and the peer has not been asked too recently.
+    fn peer_connected(&mut self, peer: UserHash, hash: Ed2kHash) {
+        let Some(known) = self.downloads.get(&hash) else {
+            return;
+        };
+        if known.download.status != DownloadStatus::Downloading {
+            return;
+        }
+
+        let now = tokio::time::Instant::now();
+        if self
+            .source_exchange
+            .request(peer, hash, known.sources.len(), MAX_SOURCES, now)
+        {
+            send_event(
+                &self.events_sender,
+                DownloadEvents::SourcesWanted { peer, hash },
+            );
+        }
+    }
+
+    /// Tells the peer the sources we know, unless it asks too often.
+    fn sources_requested(&mut self, peer: UserHash, hash: Ed2kHash, version: u8) {
+        let Some(known) = self.downloads.get(&hash) else {
+            return;
+        };
+        if known.sources.is_empty() {
+            return;
+        }
+
+        let now = tokio::time::Instant::now();
+        if self.source_exchange.answer(peer, hash, now) {
+            send_event(
+                &self.events_sender,
+                DownloadEvents::SourcesToSend {
+                    peer,
+                    hash,
+                    version,
+                    sources: known.sources.clone(),
+                },
+            );
+        }
+    }
+
+    fn write_data(&mut self, hash: Ed2kHash, start: u64, data: &[u8]) -> Result<()> {
+        let Some(known) = self.downloads.get_mut(&hash) else {
+            bail!("There is no such download");
+        };
+
+        let db = &mut self.databases[known.db_idx];
+        let written = db.write_block(known.download.id, start, data)?;
+        if written == 0 {
+            return Ok(());
+        }
+        known.completed += written;
+        self.progressed.insert(hash);
+
+        // Check the parts the data went into which are now complete. The
+        // empty last part of a file which is an exact multiple of the part
+        // size is checked along with the end of the file.
+        let end = start + data.len() as u64;
+        let mut parts = start / PART_SIZE..(end - 1) / PART_SIZE + 1;
+        if end == *known.download.size {
+            parts.end = known.download.part_count();
+        }
+        self.verify_parts(hash, parts)?;
+
+        self.complete_if_finished(hash)
+    }
+
+    /// Checks the parts which are complete against their hashes. Corrupt
+    /// parts of downloads with an AICH hash keep their data while we wait
+    /// for recovery data; the others are discarded straight away.
+    fn verify_parts<I>(&mut self, hash: Ed2kHash, parts: I) -> Result<()>
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        let Some(known) = self.downloads.get_mut(&hash) else {
+            bail!("There is no such download");
+        };
+
+        let db = &mut self.databases[known.db_idx];
+        let mut corrupt = Vec::new();
+        for part in parts {
+            if db.verify_part(&known.download, part)? == Some(PartStatus::Corrupt) {
+                corrupt.push(part);
+            }
+        }
+        if !corrupt.is_empty() {
+            known.completed = db.completed(known.download.id)?;
+        }
+
+        if let Some(master) = known.download.aich_hash {
+            for part in corrupt {
+                self.recovering
+                    .insert((hash, part), Instant::now() + RECOVERY_TIMEOUT);
+                send_event(
+                    &self.events_sender,
+                    DownloadEvents::RecoveryDataWanted {
+                        hash,
+                        part: part as u16,
+                        master,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the parts marked corrupt again, which asks for recovery data
+    /// for those which kept their data.
+    fn recover_corrupt_parts(&mut self, hash: Ed2kHash) -> Result<()> {
+        let Some(known) = self.downloads.get(&hash) else {
+            bail!("There is no such download");
+        };
+        let corrupt: Vec<_> = self.databases[known.db_idx]
+            .parts(known.download.id)?
+            .into_iter()
+            .filter(|p| p.status == PartStatus::Corrupt)
+            .map(|p| p.part)
+            .collect();
+        self.verify_parts(hash, corrupt)
+    }
+
+    /// Discards the corrupt blocks of a part waiting for recovery data, or
+    /// all of it if there is none.
+    fn recover(
+        &mut self,
+        hash: Ed2kHash,
+        part: u64,
+        recovery: Option<&AichRecoveryData>,
+    ) -> Result<()> {
+        if !self.recovering.contains_key(&(hash, part)) {
+            bail!("Part {part} is not waiting for recovery data");
+        }
+        let Some(known) = self.downloads.get_mut(&hash) else {
+            bail!("There is no such download");
+        };
+
+        let db = &mut self.databases[known.db_idx];
+        db.recover_part(&known.download, part, recovery)?;
+        self.recovering.remove(&(hash, part));
+        known.completed = db.completed(known.download.id)?;
+        self.progressed.insert(hash);
+        Ok(())
+    }
+
+    /// Gives up on recovery data which has not arrived in time.
+    fn expire_recoveries(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .recovering
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for (hash, part) in expired {
+            if let Err(e) = self.recover(hash, part, None) {
+                warn!("Download {hash}: {e}");
+            }
+        }
+    }
+
+    /// Once all the data has arrived, writes the file to its destination
+    /// on a thread of its own. Paused and stopped downloads wait until
+    /// they are resumed, and downloads with corrupt parts until they have
+    /// been recovered.
+    fn complete_if_finished(&mut self, hash: Ed2kHash) -> Result<()> {
+        let Some(known) = self.downloads.get(&hash) else {
+            bail!("There is no such download");
+        };
+        if known.completed < *known.download.size
+            || matches!(
+                known.download.status,
+                DownloadStatus::Paused | DownloadStatus::Stopped
+            )
+            || self.recovering.keys().any(|(h, _)| *h == hash)
+        {
+            return Ok(());
+        }
+
+        let category_directory = known
+            .download
+            .category
+            .and_then(|id| self.categories.get(id))
+            .and_then(|c| c.incoming_directory.as_ref())
+            .map(|dir| dir.to_path_buf());
+        let Some(destination) = known
+            .download
+            .destination
+            .clone()
+            .or(category_directory)
+            .or_else(|| self.default_destination.clone())
+        else {
+            bail!("There is no downloads directory to put the file in");
+        };
+        let Some(temp_dir) = self.databases[known.db_idx].path().parent() else {
+            bail!("The part database has no directory");
+        };
+        let temp_dir = temp_dir.to_path_buf();
+
+        self.save(hash, |d| d.status = DownloadStatus::Completing)?;
+        let download = self.downloads[&hash].download.clone();
+        let sender = self.completion_sender.clone();
+        info!(
+            "Completing {} into {}",
+            download.name,
+            destination.display()
+        );
+        std::thread::Builder::new()
+            .name("DownloadCompletion".to_owned())
+            .spawn(move || {
+                let result = assemble(&temp_dir, &download, &destination);
+                let _ = sender.send((hash, result));
+            })?;
+        Ok(())
+    }
+
+    fn completion_finished(&mut self, hash: Ed2kHash, result: Result<Assembled>) -> Result<()> {
+        let Some(known) = self.downloads.get(&hash) else {
+            bail!("There is no such download");
+        };
+        let db_idx = known.db_idx;
+        let id = known.download.id;
+
+        let reason = match result {
+            Ok(Assembled::Moved(path)) => {
+                self.databases[db_idx].remove_download(id)?;
+                self.downloads.remove(&hash);
+                self.progressed.remove(&hash);
+                send_event(
+                    &self.events_sender,
+                    DownloadEvents::Completed { hash, path },
+                );
+                return Ok(());
+            }
+            Ok(Assembled::Corrupt) => {
+                // Only parts we could not check can be to blame, unless
+                // there were none.
+                let db = &mut self.databases[db_idx];
+                let parts = db.parts(id)?;
+                let all_verified = parts.iter().all(|p| p.status == PartStatus::Verified);
+                for part in parts {
+                    if all_verified || part.status != PartStatus::Verified {
+                        db.discard(id, known.download.part_range(part.part))?;
+                        db.set_part_status(id, part.part, PartStatus::Corrupt)?;
+                    }
+                }
+                let completed = db.completed(id)?;
+                self.downloads.get_mut(&hash).unwrap().completed = completed;
+                self.progressed.insert(hash);
+                self.save(hash, |d| d.status = DownloadStatus::Downloading)?;
+                "The data does not match the file hash, so it will be downloaded again".to_string()
+            }
+            Err(e) => {
+                self.save(hash, |d| d.status = DownloadStatus::Paused)?;
+                e.to_string()
+            }
+        };
+
+        warn!("Completing download {hash} failed: {reason}");
+        send_event(
+            &self.events_sender,
+            DownloadEvents::CompletionFailed { hash, reason },
+        );
+        Ok(())
+    }
+
+    fn report_progress(&mut self) {
+        for hash in self.progressed.drain() {
+            if let Some(known) = self.downloads.get(&hash) {
+                send_event(
+                    &self.events_sender,
+                    DownloadEvents::Progress {
+                        hash,
+                        completed: known.completed,
+                        size: *known.download.size,
+                    },
+                );
+            }
+        }
+    }
+}
+
+fn send_event(sender: &DownloadEventSender, evt: DownloadEvents) {
+    if let Err(broadcast::error::SendError(evt)) = sender.send(evt) {
+        warn!(
+            "Nobody is listening for download events, dropping {:?}",
+            evt
+        );
+    }
+}
+
+/// Whether two sources are the same client. Low ids are only unique on the
+/// server which gave them out.
+fn is_same_client(a: &FoundSource, b: &FoundSource) -> bool {
+    a.client_id == b.client_id && a.port == b.port && (!a.is_low_id() || a.server == b.server)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::{migrations, Category};
+    use crate::hashing::{AichHashTree, AichHasher, Ed2kHasher, BLOCK_SIZE};
+    use md4::{Digest, Md4};
+    use rusqlite::Connection;
+    use std::path::Path;
+    use tempfile::TempDir;
+    use tokio::time::timeout;
+
+    /// A Download Manager with a temp directory it has not yet been told
+    /// about, and the channels it talks to the other managers over.
+    struct Fixture {
+        dir: TempDir,
+        handle: DownloadManagerHandle,
+        events: DownloadEventReceiver,
+        cfg_events: broadcast::Sender<ConfigurationEvents>,
+        server_commands: mpsc::Receiver<ServerCommand>,
+        server_events: broadcast::Sender<ServerEvents>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            Self::with_ip_filter(IpFilter::default())
+        }
+
+        fn with_ip_filter(ip_filter: IpFilter) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let (cfg_events, cfg_receiver) = broadcast::channel(16);
+            let (server_sender, server_commands) = mpsc::channel(16);
+            let (server_events, server_receiver) = broadcast::channel(16);
+            let handle = DownloadManagerHandle::new(
+                cfg_receiver,
+                server_sender,
+                server_receiver,
+                ip_filter,
+                tokio::runtime::Handle::current(),
+            );
+            let events = handle.subscribe_to_events();
+
+            Self {
+                dir,
+                handle,
+                events,
+                cfg_events,
+                server_commands,
+                server_events,
+            }
+        }
+
+        fn temp_dir(&self) -> PathBuf {
+            self.dir.path().join("temp")
+        }
+
+        /// Tells the Download Manager about the temp directory, which
+        /// loads any downloads already in it.
+        fn open_temp_dir(&self) {
+            let conn = Connection::open_in_memory().unwrap();
+            migrations::apply_database_migrations(&conn).unwrap();
+            TempDirectoryList::insert(&conn, &self.temp_dir()).unwrap();
+            let dirs = TempDirectoryList::load_all(&conn).unwrap();
+            self.cfg_events
+                .send(ConfigurationEvents::TempDirectoryListChange(dirs))
+                .unwrap();
+        }
+
+        /// Tells the Download Manager the categories are now these.
+        fn change_categories(&self, categories: Vec<Category>) {
+            let conn = Connection::open_in_memory().unwrap();
+            migrations::apply_database_migrations(&conn).unwrap();
+            let mut list = CategoryList::load_all(&conn).unwrap();
+            for category in categories {
+                list.save(&conn, category).unwrap();
+            }
+            self.cfg_events
+                .send(ConfigurationEvents::CategoryListChange(list))
+                .unwrap();
+        }
+
+        async fn send(&self, cmd: DownloadCommand) {
+            self.handle.send_command(cmd).await.unwrap();
+        }
+
+        async fn next_event(&mut self) -> DownloadEvents {
+            timeout(Duration::from_secs(10), self.events.recv())
+                .await
+                .expect("No event from the Download Manager")
+                .unwrap()
+        }
+
+        /// Adds a download of the link, which is then waiting for sources.
+        async fn add_download(&mut self, link: &Ed2kFileLink) {
+            self.send(add(link, None)).await;
+            assert!(matches!(
+                self.next_event().await,
+                DownloadEvents::Added { .. }
+            ));
+        }
+
+        fn send_server_event(&self, evt: ServerEvents) {
+            self.server_events.send(evt).unwrap();
+        }
+
+        /// Asserts that the commands sent so far caused no more events, by
+        /// pausing the download and expecting that to be the next event.
+        async fn assert_no_more_events(&mut self, hash: Ed2kHash) {
+            self.send(DownloadCommand::Pause(hash)).await;
+            let evt = self.next_event().await;
+            assert!(matches!(evt, DownloadEvents::Changed(_)), "{evt:?}");
+        }
+
+        /// The download as saved in the part database.
+        fn saved(&self, hash: Ed2kHash) -> Option<PartDownload> {
+            let db = PartDatabase::open(&self.temp_dir()).unwrap();
+            let downloads = db.downloads().unwrap();
+            downloads.into_iter().find(|d| d.hash == hash)
+        }
+    }
+
+    fn md4(data: &[u8]) -> Ed2kHash {
+        Ed2kHash::new(Md4::digest(data).into())
+    }
+
+    fn add(link: &Ed2kFileLink, destination: Option<&Path>) -> DownloadCommand {
+        DownloadCommand::Add {
+            link: link.clone(),
+            category: None,
+            destination: destination.map(Path::to_path_buf),
+        }
+    }
+
+    /// A file of one part with an AICH hash, its link, and its hash tree.
+    fn aich_file() -> (Vec<u8>, Ed2kFileLink, AichHashTree) {
+        let data: Vec<u8> = (0..400_000u32).map(|i| i as u8).collect();
+        let mut hasher = AichHasher::new();
+        hasher.update(&data);
+        let tree = hasher.finish();
+        let mut link = Ed2kFileLink::new("file.bin", data.len() as u64, md4(&data));
+        link.aich = Some(tree.master_hash());
+        (data, link, tree)
+    }
+
+    fn source(ip: [u8; 4]) -> FoundSource {
+        FoundSource {
+            client_id: u32::from_le_bytes(ip),
+            port: 4662,
+            server: "1.2.3.4:4661".parse().unwrap(),
+            obfuscation: None,
+        }
+    }
+
+    fn write(hash: Ed2kHash, start: u64, data: &[u8]) -> DownloadCommand {
+        DownloadCommand::WriteData {
+            hash,
+            start,
+            data: data.to_vec(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_adding_without_a_temp_directory_fails() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+
+        fx.send(add(&link, None)).await;
+        let DownloadEvents::CommandFailed { reason, .. } = fx.next_event().await else {
+            panic!("Adding a download without a temp directory worked");
+        };
+        assert!(reason.contains("no temp directories"), "{reason}");
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_added_downloads_are_saved_and_ask_for_sources() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        let hash = link.hash;
+        fx.open_temp_dir();
+
+        fx.send(add(&link, None)).await;
+        let DownloadEvents::Added {
+            download,
+            completed,
+        } = fx.next_event().await
+        else {
+            panic!("Expected the download to be added");
+        };
+        assert_eq!((download.hash, completed), (hash, 0));
+        assert_eq!(fx.saved(hash).unwrap().status, DownloadStatus::Downloading);
+        assert!(matches!(
+            fx.server_commands.recv().await,
+            Some(ServerCommand::GetSources { hash: h, size: 1000 }) if h == hash
+        ));
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_adding_a_download_twice_fails() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+
+        fx.send(add(&link, None)).await;
+        let DownloadEvents::CommandFailed { reason, .. } = fx.next_event().await else {
+            panic!("Adding a download twice worked");
+        };
+        assert!(reason.contains("already being downloaded"), "{reason}");
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_status_changes_are_saved() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        let hash = link.hash;
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+        assert!(fx.server_commands.recv().await.is_some());
+
+        for (cmd, status) in [
+            (DownloadCommand::Pause(hash), DownloadStatus::Paused),
+            (DownloadCommand::StopDownload(hash), DownloadStatus::Stopped),
+            (DownloadCommand::Resume(hash), DownloadStatus::Downloading),
+        ] {
+            fx.send(cmd).await;
+            let DownloadEvents::Changed(download) = fx.next_event().await else {
+                panic!("Expected the download to change");
+            };
+            assert_eq!(download.status, status);
+            assert_eq!(fx.saved(hash).unwrap().status, status);
+        }
+        // Resuming asks for sources again.
+        assert!(matches!(
+            fx.server_commands.recv().await,
+            Some(ServerCommand::GetSources { .. })
+        ));
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_cancelling_deletes_the_download_and_its_data() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        let hash = link.hash;
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+        let id = fx.saved(hash).unwrap().id;
+
+        fx.send(write(hash, 0, &[7; 100])).await;
+        fx.send(DownloadCommand::Cancel(hash)).await;
+        loop {
+            match fx.next_event().await {
+                DownloadEvents::Removed(h) if h == hash => break,
+                DownloadEvents::Progress { .. } => {}
+                evt => panic!("Expected the download to be removed, got {evt:?}"),
+            }
+        }
+        assert!(fx.saved(hash).is_none());
+        let db = PartDatabase::open(&fx.temp_dir()).unwrap();
+        assert!(db.gaps(id).unwrap().is_empty());
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_progress_is_reported_once_an_interval() {
+        let mut fx = Fixture::new();
+        let data = vec![3u8; 100];
+        let link = Ed2kFileLink::new("file.bin", 100, md4(&data));
+        let hash = link.hash;
+        fx.open_temp_dir();
+        fx.send(add(&link, None)).await;
+        assert!(matches!(
+            fx.next_event().await,
+            DownloadEvents::Added { .. }
+        ));
+
+        fx.send(write(hash, 0, &data[..10])).await;
+        assert!(matches!(
+            fx.next_event().await,
+            DownloadEvents::Progress {
+                completed: 10,
+                size: 100,
+                ..
+            }
+        ));
+
+        // The writes straight after a report all go into the next one.
+        let reported = Instant::now();
+        for start in [10, 20, 30] {
+            fx.send(write(
+                hash,
+                start,
+                &data[start as usize..start as usize + 10],
+            ))
+            .await;
+        }
+        assert!(matches!(
+            fx.next_event().await,
+            DownloadEvents::Progress { completed: 40, .. }
+        ));
+        assert!(reported.elapsed() >= PROGRESS_INTERVAL / 2);
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_progress_is_reported_while_data_keeps_arriving() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 20_000_000, Ed2kHash::new([1; 16]));
+        let hash = link.hash;
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+
+        // Keeps the command queue full.
+        let sender = fx.handle.make_command_sender();
+        let writer = tokio::spawn(async move {
+            for start in (0..).step_by(100) {
+                if sender.send(write(hash, start, &[0; 100])).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let started = Instant::now();
+        assert!(matches!(
+            fx.next_event().await,
+            DownloadEvents::Progress { .. }
+        ));
+        assert!(started.elapsed() < PROGRESS_INTERVAL * 2);
+
+        writer.abort();
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_completed_downloads_are_moved_to_their_destination() {
+        let mut fx = Fixture::new();
+        let done = fx.dir.path().join("done");
+        let data = b"small file";
+        let link = Ed2kFileLink::new("small.txt", data.len() as u64, md4(data));
+        fx.open_temp_dir();
+        fx.send(add(&link, Some(&done))).await;
+        fx.send(write(link.hash, 0, data)).await;
+
+        let path = loop {
+            match fx.next_event().await {
+                DownloadEvents::Completed { path, .. } => break path,
+                DownloadEvents::Added { .. }
+                | DownloadEvents::Changed(_)
+                | DownloadEvents::Progress { .. } => {}
+                evt => panic!("Expected completion, got {evt:?}"),
+            }
+        };
+        assert_eq!(path, done.join("small.txt"));
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert!(fx.saved(link.hash).is_none());
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_corrupt_parts_are_downloaded_again() {
+        let mut fx = Fixture::new();
+        let done = fx.dir.path().join("done");
+
+        // Two parts, of which only the first has a known hash, so the
+        // second can only be checked against the file hash.
+        let data: Vec<u8> = (0..PART_SIZE + 1000).map(|i| (i % 251) as u8).collect();
+        let (part0, part1) = data.split_at(PART_SIZE as usize);
+        let mut hasher = Ed2kHasher::new();
+        hasher.update(&data);
+        let link = Ed2kFileLink::new("big.bin", data.len() as u64, hasher.finish().root);
+        let hash = link.hash;
+        {
+            let mut db = PartDatabase::open(&fx.temp_dir()).unwrap();
+            let id = db.add_download(&link).unwrap();
+            db.set_part_hashes(id, &[md4(part0)]).unwrap();
+        }
+        fx.open_temp_dir();
+        assert!(matches!(
+            fx.next_event().await,
+            DownloadEvents::Added { .. }
+        ));
+        fx.send(DownloadCommand::SetDestination(hash, Some(done.clone())))
+            .await;
+        assert!(matches!(fx.next_event().await, DownloadEvents::Changed(_)));
+
+        fx.send(write(hash, 0, part0)).await;
+        fx.send(write(hash, PART_SIZE, &[0; 1000])).await;
+        let reason = loop {
+            match fx.next_event().await {
+                DownloadEvents::CompletionFailed { reason, .. } => break reason,
+                DownloadEvents::Changed(_) | DownloadEvents::Progress { .. } => {}
+                evt => panic!("Expected completion to fail, got {evt:?}"),
+            }
+        };
+        assert!(reason.contains("does not match"), "{reason}");
+
+        // Only the part which could not be checked is downloaded again.
+        let download = fx.saved(hash).unwrap();
+        assert_eq!(download.status, DownloadStatus::Downloading);
+        let db = PartDatabase::open(&fx.temp_dir()).unwrap();
+        assert_eq!(
+            db.gaps(download.id).unwrap(),
+            vec![PART_SIZE..data.len() as u64]
+        );
+        let statuses: Vec<_> = db
+            .parts(download.id)
+            .unwrap()
+            .iter()
+            .map(|p| p.status)
+            .collect();
+        assert_eq!(statuses, [PartStatus::Verified, PartStatus::Corrupt]);
+
+        fx.send(write(hash, PART_SIZE, part1)).await;
+        let path = loop {
+            match fx.next_event().await {
+                DownloadEvents::Completed { path, .. } => break path,
+                DownloadEvents::Changed(_) | DownloadEvents::Progress { .. } => {}
+                evt => panic!("Expected completion, got {evt:?}"),
+            }
+        };
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_destinations_which_cannot_be_written_to_pause_the_download() {
+        let mut fx = Fixture::new();
+        let not_a_dir = fx.dir.path().join("file");
+        std::fs::write(&not_a_dir, b"in the way").unwrap();
+        let data = b"small file";
+        let link = Ed2kFileLink::new("small.txt", data.len() as u64, md4(data));
+        fx.open_temp_dir();
+        fx.send(add(&link, Some(&not_a_dir))).await;
+        fx.send(write(link.hash, 0, data)).await;
+
+        loop {
+            match fx.next_event().await {
+                DownloadEvents::CompletionFailed { .. } => break,
+                DownloadEvents::Added { .. }
+                | DownloadEvents::Changed(_)
+                | DownloadEvents::Progress { .. } => {}
+                evt => panic!("Expected completion to fail, got {evt:?}"),
+            }
+        }
+        assert_eq!(fx.saved(link.hash).unwrap().status, DownloadStatus::Paused);
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_connected_peers_are_asked_for_sources_once() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        let peer = UserHash::new([2; 16]);
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+
+        let connected = || DownloadCommand::PeerConnected {
+            peer,
+            hash: link.hash,
+        };
+        fx.send(connected()).await;
+        let DownloadEvents::SourcesWanted { peer: to, hash } = fx.next_event().await else {
+            panic!("Expected the peer to be asked for sources");
+        };
+        assert_eq!((to, hash), (peer, link.hash));
+
+        // Not again so soon.
+        fx.send(connected()).await;
+        fx.assert_no_more_events(link.hash).await;
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_sources_requested_are_sent_once() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        let (alice, bob) = (UserHash::new([2; 16]), UserHash::new([3; 16]));
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+
+        let requested = || DownloadCommand::SourcesRequested {
+            peer: bob,
+            hash: link.hash,
+            version: 4,
+        };
+        // There are no sources to send yet.
+        fx.send(requested()).await;
+        fx.assert_no_more_events(link.hash).await;
+        fx.send(DownloadCommand::Resume(link.hash)).await;
+        assert!(matches!(fx.next_event().await, DownloadEvents::Changed(_)));
+
+        fx.send(DownloadCommand::PeerConnected {
+            peer: alice,
+            hash: link.hash,
+        })
+        .await;
+        assert!(matches!(
+            fx.next_event().await,
+            DownloadEvents::SourcesWanted { .. }
+        ));
+        fx.send(DownloadCommand::SourcesReceived {
+            peer: alice,
+            hash: link.hash,
+            sources: vec![source([80, 1, 2, 3])],
+        })
+        .await;
+        assert!(matches!(
+            fx.next_event().await,
+            DownloadEvents::SourcesAdded { total: 1, .. }
+        ));
+
+        fx.send(requested()).await;
+        let DownloadEvents::SourcesToSend {
+            peer,
+            version,
+            sources,
+            ..
+        } = fx.next_event().await
+        else {
+            panic!("Expected the sources to be sent");
+        };
+        assert_eq!((peer, version), (bob, 4));
+        assert_eq!(sources, [source([80, 1, 2, 3])]);
+
+        // Peers asking too often are ignored.
+        fx.send(requested()).await;
+        fx.assert_no_more_events(link.hash).await;
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_sources_received_drops_blocked_sources() {
+        let filter = IpFilter::parse("080.006.006.006 - 080.006.006.006 , 000 , Test");
+        let mut fx = Fixture::with_ip_filter(filter);
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        let peer = UserHash::new([2; 16]);
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+
+        fx.send(DownloadCommand::PeerConnected {
+            peer,
+            hash: link.hash,
+        })
+        .await;
+        assert!(matches!(
+            fx.next_event().await,
+            DownloadEvents::SourcesWanted { .. }
+        ));
+        fx.send(DownloadCommand::SourcesReceived {
+            peer,
+            hash: link.hash,
+            sources: vec![source([80, 6, 6, 6]), source([80, 1, 2, 3])],
+        })
+        .await;
+        let DownloadEvents::SourcesAdded { sources, total, .. } = fx.next_event().await else {
+            panic!("Expected sources to be added");
+        };
+        assert_eq!(sources, [source([80, 1, 2, 3])]);
+        assert_eq!(total, 1);
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_sources_received_without_asking_are_dropped() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+
+        fx.send(DownloadCommand::SourcesReceived {
+            peer: UserHash::new([2; 16]),
+            hash: link.hash,
+            sources: vec![source([80, 1, 2, 3])],
+        })
+        .await;
+        fx.assert_no_more_events(link.hash).await;
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_connected_servers_are_asked_for_sources() {
+        let mut fx = Fixture::new();
+        let downloading = Ed2kFileLink::new("a.bin", 1000, Ed2kHash::new([1; 16]));
+        let paused = Ed2kFileLink::new("b.bin", 2000, Ed2kHash::new([2; 16]));
+        fx.open_temp_dir();
+        for link in [&downloading, &paused] {
+            fx.add_download(link).await;
+            assert!(fx.server_commands.recv().await.is_some());
+        }
+        fx.send(DownloadCommand::Pause(paused.hash)).await;
+        assert!(matches!(fx.next_event().await, DownloadEvents::Changed(_)));
+
+        fx.send_server_event(ServerEvents::Connected {
+            addr: "1.2.3.4:4661".parse().unwrap(),
+            client_id: 1234,
+            low_id: true,
+        });
+        assert!(matches!(
+            fx.server_commands.recv().await,
+            Some(ServerCommand::GetSources { hash, size: 1000 }) if hash == downloading.hash
+        ));
+        fx.send(DownloadCommand::Stop).await;
+        assert!(fx.server_commands.recv().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_found_sources_in_the_ip_filter_are_dropped() {
+        let filter = IpFilter::parse("080.006.006.006 - 080.006.006.006 , 000 , Test");
+        let mut fx = Fixture::with_ip_filter(filter);
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+
+        fx.send_server_event(ServerEvents::SourcesFound {
+            hash: link.hash,
+            sources: vec![source([80, 6, 6, 6]), source([80, 1, 2, 3])],
+        });
+        let DownloadEvents::SourcesAdded { sources, total, .. } = fx.next_event().await else {
+            panic!("Expected sources to be added");
+        };
+        assert_eq!(sources, [source([80, 1, 2, 3])]);
+        assert_eq!(total, 1);
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_sources_are_added_up_to_the_maximum() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+
+        let sources: Vec<_> = (0..MAX_SOURCES as u16 + 10)
+            .map(|i| {
+                let [a, b] = i.to_be_bytes();
+                source([80, 1, a, b])
+            })
+            .collect();
+        fx.send_server_event(ServerEvents::SourcesFound {
+            hash: link.hash,
+            sources: sources.clone(),
+        });
+        let DownloadEvents::SourcesAdded {
+            sources: added,
+            total,
+            ..
+        } = fx.next_event().await
+        else {
+            panic!("Expected sources to be added");
+        };
+        assert_eq!(added, sources[..MAX_SOURCES]);
+        assert_eq!(total, MAX_SOURCES);
+
+        // There is no room for the rest.
+        fx.send_server_event(ServerEvents::SourcesFound {
+            hash: link.hash,
+            sources: sources[MAX_SOURCES..].to_vec(),
+        });
+        fx.assert_no_more_events(link.hash).await;
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_known_sources_are_not_added_again() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+
+        let low_id = |server: &str| FoundSource {
+            client_id: 1234,
+            server: server.parse().unwrap(),
+            ..source([0; 4])
+        };
+        fx.send_server_event(ServerEvents::SourcesFound {
+            hash: link.hash,
+            sources: vec![source([80, 1, 2, 3]), low_id("1.2.3.4:4661")],
+        });
+        assert!(matches!(
+            fx.next_event().await,
+            DownloadEvents::SourcesAdded { total: 2, .. }
+        ));
+
+        // Low ids are only the same client on the same server.
+        fx.send_server_event(ServerEvents::SourcesFound {
+            hash: link.hash,
+            sources: vec![
+                source([80, 1, 2, 3]),
+                low_id("1.2.3.4:4661"),
+                low_id("5.6.7.8:4661"),
+            ],
+        });
+        let DownloadEvents::SourcesAdded { sources, total, .. } = fx.next_event().await else {
+            panic!("Expected sources to be added");
+        };
+        assert_eq!(sources, [low_id("5.6.7.8:4661")]);
+        assert_eq!(total, 3);
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    /// Adds a download, connects to 1.2.3.4:4661 and finds a high id
+    /// source and low id sources on that server and another.
+    async fn find_low_id_sources(fx: &mut Fixture, low_id: bool) {
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+        assert!(fx.server_commands.recv().await.is_some());
+
+        fx.send_server_event(ServerEvents::Connected {
+            addr: "1.2.3.4:4661".parse().unwrap(),
+            client_id: if low_id { 1000 } else { 0x0A00_000A },
+            low_id,
+        });
+        assert!(matches!(
+            fx.server_commands.recv().await,
+            Some(ServerCommand::GetSources { .. })
+        ));
+
+        let on_server = |client_id, server: &str| FoundSource {
+            client_id,
+            server: server.parse().unwrap(),
+            ..source([0; 4])
+        };
+        fx.send_server_event(ServerEvents::SourcesFound {
+            hash: link.hash,
+            sources: vec![
+                source([80, 1, 2, 3]),
+                on_server(1234, "1.2.3.4:4661"),
+                on_server(5678, "5.6.7.8:4661"),
+            ],
+        });
+        assert!(matches!(
+            fx.next_event().await,
+            DownloadEvents::SourcesAdded { total: 3, .. }
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_low_id_sources_on_our_server_are_asked_to_call_back() {
+        let mut fx = Fixture::new();
+        find_low_id_sources(&mut fx, false).await;
+
+        assert!(matches!(
+            fx.server_commands.try_recv(),
+            Ok(ServerCommand::RequestCallback(1234))
+        ));
+        assert!(fx.server_commands.try_recv().is_err());
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_low_id_sources_are_not_asked_to_call_back_a_low_id() {
+        let mut fx = Fixture::new();
+        find_low_id_sources(&mut fx, true).await;
+
+        assert!(fx.server_commands.try_recv().is_err());
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_recovery_data_discards_only_corrupt_blocks() {
+        let mut fx = Fixture::new();
+        let done = fx.dir.path().join("done");
+        let (data, link, tree) = aich_file();
+        let hash = link.hash;
+        fx.open_temp_dir();
+        fx.send(add(&link, Some(&done))).await;
+        assert!(matches!(
+            fx.next_event().await,
+            DownloadEvents::Added { .. }
+        ));
+
+        let mut corrupt = data.clone();
+        corrupt[200_000] ^= 0xFF;
+        fx.send(write(hash, 0, &corrupt)).await;
+        let DownloadEvents::RecoveryDataWanted { part, master, .. } = fx.next_event().await else {
+            panic!("Expected recovery data to be wanted");
+        };
+        assert_eq!((part, master), (0, tree.master_hash()));
+
+        fx.send(DownloadCommand::RecoveryData {
+            hash,
+            part: 0,
+            recovery: tree.recovery_data(0).unwrap(),
+        })
+        .await;
+        let block = BLOCK_SIZE as usize..2 * BLOCK_SIZE as usize;
+        let DownloadEvents::Progress { completed, .. } = fx.next_event().await else {
+            panic!("Expected the corrupt block to be discarded");
+        };
+        assert_eq!(completed, (data.len() - block.len()) as u64);
+
+        fx.send(write(hash, BLOCK_SIZE, &data[block])).await;
+        let path = loop {
+            match fx.next_event().await {
+                DownloadEvents::Completed { path, .. } => break path,
+                DownloadEvents::Changed(_) | DownloadEvents::Progress { .. } => {}
+                evt => panic!("Expected completion, got {evt:?}"),
+            }
+        };
+        assert_eq!(std::fs::read(path).unwrap(), data);
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_recovery_data_nobody_asked_for_is_refused() {
+        let mut fx = Fixture::new();
+        let (_, link, tree) = aich_file();
+        fx.open_temp_dir();
+        fx.add_download(&link).await;
+
+        fx.send(DownloadCommand::RecoveryData {
+            hash: link.hash,
+            part: 0,
+            recovery: tree.recovery_data(0).unwrap(),
+        })
+        .await;
+        let DownloadEvents::CommandFailed { reason, .. } = fx.next_event().await else {
+            panic!("Unwanted recovery data was used");
+        };
+        assert!(reason.contains("not waiting for recovery data"), "{reason}");
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    /// Waiting out `RECOVERY_TIMEOUT` would take too long, so this drives
+    /// the manager directly.
+    #[tokio::test]
+    pub async fn test_parts_without_recovery_data_in_time_are_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, cfg_receiver) = broadcast::channel(16);
+        let (server_sender, _server_commands) = mpsc::channel(16);
+        let (_, server_receiver) = broadcast::channel(16);
+        let (evt_sender, _events) = broadcast::channel(256);
+        let (_cmd_sender, cmd_receiver) = mpsc::channel(16);
+        let mut mgr = DownloadManager::new(
+            evt_sender,
+            cmd_receiver,
+            cfg_receiver,
+            server_sender,
+            server_receiver,
+            IpFilter::default(),
+            tokio::runtime::Handle::current(),
+        );
+        let conn = Connection::open_in_memory().unwrap();
+        migrations::apply_database_migrations(&conn).unwrap();
+        TempDirectoryList::insert(&conn, dir.path()).unwrap();
+        mgr.open_databases(&TempDirectoryList::load_all(&conn).unwrap());
+
+        let (data, link, _) = aich_file();
+        let hash = link.hash;
+        mgr.handle_command(add(&link, None));
+        let mut corrupt = data.clone();
+        corrupt[200_000] ^= 0xFF;
+        mgr.handle_command(write(hash, 0, &corrupt));
+        assert_eq!(mgr.downloads[&hash].completed, data.len() as u64);
+
+        // Not yet.
+        mgr.expire_recoveries();
+        assert_eq!(mgr.recovering.len(), 1);
+
+        for deadline in mgr.recovering.values_mut() {
+            *deadline = Instant::now();
+        }
+        mgr.expire_recoveries();
+        assert!(mgr.recovering.is_empty());
+        assert_eq!(mgr.downloads[&hash].completed, 0);
+        let id = mgr.downloads[&hash].download.id;
+        assert_eq!(
+            mgr.databases[0].gaps(id).unwrap(),
+            vec![0..data.len() as u64]
+        );
+    }
+
+    /// A category for files ending in .iso, which go to `incoming`.
+    fn isos(incoming: &Path) -> Category {
+        let mut isos = Category::new("ISOs");
+        isos.incoming_directory = Some(incoming.into());
+        isos.default_priority = DownloadPriority::High;
+        isos.rules.extensions = vec!["iso".into()];
+        isos
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_added_downloads_are_categorized_by_the_rules() {
+        let mut fx = Fixture::new();
+        let iso = Ed2kFileLink::new("ubuntu.iso", 1000, Ed2kHash::new([1; 16]));
+        let txt = Ed2kFileLink::new("readme.txt", 1000, Ed2kHash::new([2; 16]));
+        fx.open_temp_dir();
+        fx.change_categories(vec![isos(&fx.dir.path().join("isos"))]);
+
+        fx.send(add(&iso, None)).await;
+        let DownloadEvents::Added { download, .. } = fx.next_event().await else {
+            panic!("Expected the download to be added");
+        };
+        assert_eq!(download.category, Some(1));
+        assert_eq!(download.priority, DownloadPriority::High);
+        assert_eq!(fx.saved(iso.hash).unwrap().category, Some(1));
+
+        fx.send(add(&txt, None)).await;
+        let DownloadEvents::Added { download, .. } = fx.next_event().await else {
+            panic!("Expected the download to be added");
+        };
+        assert_eq!(download.category, None);
+        assert_eq!(download.priority, DownloadPriority::Normal);
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_adding_into_a_missing_category_fails() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        fx.open_temp_dir();
+
+        fx.send(DownloadCommand::Add {
+            link,
+            category: Some(7),
+            destination: None,
+        })
+        .await;
+        let DownloadEvents::CommandFailed { reason, .. } = fx.next_event().await else {
+            panic!("Adding into a missing category worked");
+        };
+        assert!(reason.contains("no category 7"), "{reason}");
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_moving_into_a_missing_category_fails() {
+        let mut fx = Fixture::new();
+        let link = Ed2kFileLink::new("file.bin", 1000, Ed2kHash::new([1; 16]));
+        fx.open_temp_dir();
+        fx.send(add(&link, None)).await;
+        fx.next_event().await;
+
+        fx.send(DownloadCommand::SetCategory(link.hash, Some(7)))
+            .await;
+        let DownloadEvents::CommandFailed { reason, .. } = fx.next_event().await else {
+            panic!("Moving into a missing category worked");
+        };
+        assert!(reason.contains("no category 7"), "{reason}");
+        assert_eq!(fx.saved(link.hash).unwrap().category, None);
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_downloads_of_deleted_categories_still_go_to_their_directory() {
+        let mut fx = Fixture::new();
+        let incoming = fx.dir.path().join("isos");
+        let own = fx.dir.path().join("own");
+        let iso = Ed2kFileLink::new("ubuntu.iso", 1000, Ed2kHash::new([1; 16]));
+        let other = Ed2kFileLink::new("debian.iso", 1000, Ed2kHash::new([2; 16]));
+        fx.open_temp_dir();
+        fx.change_categories(vec![isos(&incoming)]);
+        fx.send(add(&iso, None)).await;
+        fx.send(add(&other, Some(&own))).await;
+        for _ in 0..2 {
+            assert!(matches!(
+                fx.next_event().await,
+                DownloadEvents::Added { .. }
+            ));
+        }
+
+        fx.change_categories(Vec::new());
+        for _ in 0..2 {
+            let DownloadEvents::Changed(download) = fx.next_event().await else {
+                panic!("Expected the download to leave the category");
+            };
+            assert_eq!(download.category, None);
+        }
+        let saved = fx.saved(iso.hash).unwrap();
+        assert_eq!(saved.category, None);
+        assert_eq!(saved.destination, Some(incoming));
+        // A destination of its own is kept.
+        assert_eq!(fx.saved(other.hash).unwrap().destination, Some(own));
+
+        fx.send(DownloadCommand::Stop).await;
+    }
+}
@@ -0,0 +1,52 @@
+-- Create the download, block, gap and part tables.
+
+-- One row per file being downloaded into this database.
+CREATE TABLE download
+    (
+    id INTEGER PRIMARY KEY,
+    created TEXT NOT NULL,
+    updated TEXT NOT NULL,
+    -- The ed2k hash of the file, which is what it is known by.
+    hash BLOB NOT NULL UNIQUE,
+    -- The name the file will be given when it is complete.
+    name TEXT NOT NULL,
+    -- The size of the file in bytes.
+    size INTEGER NOT NULL,
+    -- The AICH master hash, if we know it.
+    aich_hash BLOB NULL
+    );
+
+-- The data received so far. Blocks never overlap: only the parts of a
+-- write which fill a gap are stored. The range is start (inclusive) to
+-- end (exclusive).
+CREATE TABLE block
+    (
+    download_id INTEGER NOT NULL REFERENCES download(id) ON DELETE CASCADE,
+    start INTEGER NOT NULL,
+    end INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (download_id, start)
+    );
+
+-- The ranges of the file we do not have yet. When a download is added
+-- there is one gap covering the whole file.
+CREATE TABLE gap
+    (
+    download_id INTEGER NOT NULL REFERENCES download(id) ON DELETE CASCADE,
+    start INTEGER NOT NULL,
+    end INTEGER NOT NULL,
+    PRIMARY KEY (download_id, start)
+    );
+
+-- One row per part of the file (see PART_SIZE), with its part hash once we
+-- know it and whether the data we have for it has been checked.
+CREATE TABLE part
+    (
+    download_id INTEGER NOT NULL REFERENCES download(id) ON DELETE CASCADE,
+    part INTEGER NOT NULL,
+    -- The part hash, from the link or the hash set another client sent us.
+    hash BLOB NULL,
+    -- 0 = not checked, 1 = verified, 2 = corrupt.
+    status INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (download_id, part)
+    );
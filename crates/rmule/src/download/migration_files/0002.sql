@@ -0,0 +1,10 @@
+-- Add the status, priority and destination columns to the download table.
+
+-- 0 = downloading, 1 = paused, 2 = stopped, 3 = completing (the file is
+-- being written to its destination).
+ALTER TABLE download ADD COLUMN status INTEGER NOT NULL DEFAULT 0;
+-- 0 = low, 1 = normal, 2 = high.
+ALTER TABLE download ADD COLUMN priority INTEGER NOT NULL DEFAULT 1;
+-- The directory the file is moved to when it is complete. NULL means the
+-- default downloads directory from the settings.
+ALTER TABLE download ADD COLUMN destination TEXT NULL;
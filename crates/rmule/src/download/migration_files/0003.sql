@@ -0,0 +1,5 @@
+-- Add the category column to the download table.
+
+-- The id of the download's category in the configuration database, or
+-- NULL for none.
+ALTER TABLE download ADD COLUMN category_id INTEGER NULL;
@@ -0,0 +1,12 @@
+//! Files being downloaded. Rather than eMule's .part and .part.met files,
+//! each temp directory holds a SQLite database (a part database) with the
+//! data received so far for all the downloads in it. The Download Manager
+//! looks after the downloads in all of them.
+
+mod completion;
+mod download_manager;
+mod part_database;
+
+pub use completion::*;
+pub use download_manager::*;
+pub use part_database::*;
@@ -0,0 +1,772 @@
+use crate::configuration::{self, migrations, DownloadPriority, FileSize};
+use crate::file;
+use crate::hashing::{find_corrupt_blocks, AichRecoveryData, MAX_FILE_SIZE, PART_SIZE};
+use crate::protocol::{AichHash, Ed2kFileLink, Ed2kHash};
+use crate::times;
+use anyhow::{bail, Result};
+use md4::{Digest, Md4};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, Row, ToSql};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+static MIGRATIONS: [&str; 4] = [
+    include_str!("migration_files/0000.sql"),
+    include_str!("migration_files/0001.sql"),
+    include_str!("migration_files/0002.sql"),
+    include_str!("migration_files/0003.sql"),
+];
+
+/// A file being downloaded, as recorded in a part database.
+#[derive(Debug, Clone)]
+pub struct PartDownload {
+    pub created: OffsetDateTime,
+    pub updated: OffsetDateTime,
+    /// The Id of the download, from the database table. Ids are only
+    /// unique within one part database.
+    pub id: i64,
+    pub hash: Ed2kHash,
+    pub name: String,
+    pub size: FileSize,
+    pub aich_hash: Option<AichHash>,
+    pub status: DownloadStatus,
+    pub priority: DownloadPriority,
+    /// Where the file goes when it is complete, if not the default
+    /// downloads directory.
+    pub destination: Option<PathBuf>,
+    /// The id of the download's category in the configuration database.
+    pub category: Option<i64>,
+}
+
+impl TryFrom<&Row<'_>> for PartDownload {
+    type Error = rusqlite::Error;
+
+    /// Build a PartDownload value from a Rusqlite Row.
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(Self {
+            created: row.get("created")?,
+            updated: row.get("updated")?,
+            id: row.get("id")?,
+            hash: row.get("hash")?,
+            name: row.get("name")?,
+            size: row.get("size")?,
+            aich_hash: row.get("aich_hash")?,
+            status: row.get("status")?,
+            priority: row.get("priority")?,
+            destination: row
+                .get::<_, Option<configuration::PathBuf>>("destination")?
+                .map(|p| p.to_path_buf()),
+            category: row.get("category_id")?,
+        })
+    }
+}
+
+impl PartDownload {
+    /// The number of parts in the file. As with the part hashes, a file
+    /// which is an exact multiple of `PART_SIZE` has an extra empty part.
+    pub fn part_count(&self) -> u64 {
+        *self.size / PART_SIZE + 1
+    }
+
+    /// The range of the file the part covers.
+    pub fn part_range(&self, part: u64) -> Range<u64> {
+        (part * PART_SIZE).min(*self.size)..((part + 1) * PART_SIZE).min(*self.size)
+    }
+}
+
+/// Whether a download is being worked on. Paused downloads keep their
+/// sources, stopped ones forget them, as in eMule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadStatus {
+    Downloading = 0,
+    Paused = 1,
+    Stopped = 2,
+    /// All the data has arrived and the file is being written to its
+    /// destination.
+    Completing = 3,
+}
+
+impl TryFrom<i64> for DownloadStatus {
+    type Error = anyhow::Error;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Downloading),
+            1 => Ok(Self::Paused),
+            2 => Ok(Self::Stopped),
+            3 => Ok(Self::Completing),
+            _ => {
+                bail!("The value {value} is outside the expected range (0 to 3) for DownloadStatus")
+            }
+        }
+    }
+}
+
+impl ToSql for DownloadStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as i64))
+    }
+}
+
+impl FromSql for DownloadStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_i64()
+            .and_then(|n| DownloadStatus::try_from(n).map_err(|_| FromSqlError::OutOfRange(n)))
+    }
+}
+
+/// What we know about the data of one part.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartStatus {
+    /// Not complete, or complete but not yet checked against its hash.
+    Unchecked = 0,
+    Verified = 1,
+    /// Complete, but it did not match its hash.
+    Corrupt = 2,
+}
+
+impl TryFrom<i64> for PartStatus {
+    type Error = anyhow::Error;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Unchecked),
+            1 => Ok(Self::Verified),
+            2 => Ok(Self::Corrupt),
+            _ => {
+                bail!("The value {value} is outside the expected range (0, 1 or 2) for PartStatus")
+            }
+        }
+    }
+}
+
+impl ToSql for PartStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as i64))
+    }
+}
+
+impl FromSql for PartStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_i64()
+            .and_then(|n| PartStatus::try_from(n).map_err(|_| FromSqlError::OutOfRange(n)))
+    }
+}
+
+/// The hash and status of one part of a download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Part {
+    pub part: u64,
+    pub hash: Option<Ed2kHash>,
+    pub status: PartStatus,
+}
+
+/// The SQLite database in a temp directory which holds the files being
+/// downloaded into it, instead of eMule's .part and .part.met files. Data
+/// is stored in blocks as it arrives, along with the gaps still to fill
+/// and the state of each part.
+pub struct PartDatabase {
+    path: PathBuf,
+    conn: Connection,
+}
+
+impl PartDatabase {
+    pub const DB_NAME: &str = "rmule_temp.sqlite";
+
+    /// Opens the part database in the temp directory, creating the
+    /// directory and the database if necessary.
+    pub fn open(temp_dir: &Path) -> Result<Self> {
+        file::ensure_directory_exists(temp_dir)?;
+        let path = temp_dir.join(Self::DB_NAME);
+        info!("Attempting to open part database {}", path.display());
+
+        let conn = Connection::open(&path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        // Downloads hold whole files, so the space they free must be given
+        // back. This only takes effect on a new database, before the
+        // migrations create its tables.
+        conn.pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+        migrations::apply_migrations(&conn, &MIGRATIONS)?;
+
+        info!("Opened part database {}", path.display());
+        Ok(Self { path, conn })
+    }
+
+    /// The path of the database file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds a download for the file in the link, with a single gap
+    /// covering the whole file. Returns the new download's id.
+    pub fn add_download(&mut self, link: &Ed2kFileLink) -> Result<i64> {
+        // Search results are not checked the way links are.
+        if link.size == 0 || link.size > MAX_FILE_SIZE {
+            bail!("{} bytes is not a valid size for {}", link.size, link.name);
+        }
+        let size = FileSize::new(link.size);
+        let txn = self.conn.transaction()?;
+        let now = times::now();
+
+        txn.execute(
+            r#"INSERT INTO download(created, updated, hash, name, size, aich_hash)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6)"#,
+            params![now, now, link.hash, link.name, size, link.aich],
+        )?;
+        let id = txn.last_insert_rowid();
+
+        txn.execute(
+            "INSERT INTO gap(download_id, start, end) VALUES (?1, 0, ?2)",
+            params![id, size],
+        )?;
+
+        {
+            let mut stmt =
+                txn.prepare("INSERT INTO part(download_id, part, hash) VALUES (?1, ?2, ?3)")?;
+            for part in 0..link.size / PART_SIZE + 1 {
+                let hash = match link.part_hashes.as_slice() {
+                    // A single part file's part hash is its hash.
+                    [] if link.size < PART_SIZE => Some(link.hash),
+                    [] => None,
+                    hashes => hashes.get(part as usize).copied(),
+                };
+                stmt.execute(params![id, part, hash])?;
+            }
+        }
+
+        txn.commit()?;
+        info!(
+            "Added download {id} for {} to {}",
+            link.name,
+            self.path.display()
+        );
+        Ok(id)
+    }
+
+    /// All the downloads in the database.
+    pub fn downloads(&self) -> Result<Vec<PartDownload>> {
+        let mut stmt = self.conn.prepare("SELECT * FROM download ORDER BY id")?;
+        let downloads = stmt
+            .query_map([], |row| PartDownload::try_from(row))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(downloads)
+    }
+
+    pub fn download(&self, id: i64) -> Result<Option<PartDownload>> {
+        Ok(self
+            .conn
+            .query_row("SELECT * FROM download WHERE id = ?1", [id], |row| {
+                PartDownload::try_from(row)
+            })
+            .optional()?)
+    }
+
+    /// Removes the download and all its data.
+    pub fn remove_download(&mut self, id: i64) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM download WHERE id = ?1", [id])?;
+        // Each step of the vacuum frees a page, so it must run to the end.
+        self.conn
+            .pragma_query(None, "incremental_vacuum", |_| Ok(()))?;
+        info!("Removed download {id} from {}", self.path.display());
+        Ok(())
+    }
+
+    /// Stores the data received for the range starting at `start`. Only
+    /// the parts which fill a gap are stored, so receiving the same data
+    /// twice is harmless. Returns the number of new bytes.
+    pub fn write_block(&mut self, id: i64, start: u64, data: &[u8]) -> Result<u64> {
+        let end = start + data.len() as u64;
+        let Some(download) = self.download(id)? else {
+            bail!("There is no download {id} in {}", self.path.display());
+        };
+        if end > *download.size {
+            bail!(
+                "The block {start}..{end} is beyond the end of {} ({} bytes)",
+                download.name,
+                download.size
+            );
+        }
+
+        let txn = self.conn.transaction()?;
+        let gaps = Self::overlapping(&txn, "gap", id, start..end)?;
+        let mut written = 0;
+
+        for gap in gaps {
+            let fill = gap.start.max(start)..gap.end.min(end);
+            let bytes = &data[(fill.start - start) as usize..(fill.end - start) as usize];
+            txn.execute(
+                "INSERT INTO block(download_id, start, end, data) VALUES (?1, ?2, ?3, ?4)",
+                params![id, fill.start, fill.end, bytes],
+            )?;
+
+            txn.execute(
+                "DELETE FROM gap WHERE download_id = ?1 AND start = ?2",
+                params![id, gap.start],
+            )?;
+            for rest in [gap.start..fill.start, fill.end..gap.end] {
+                if !rest.is_empty() {
+                    txn.execute(
+                        "INSERT INTO gap(download_id, start, end) VALUES (?1, ?2, ?3)",
+                        params![id, rest.start, rest.end],
+                    )?;
+                }
+            }
+            written += fill.end - fill.start;
+        }
+
+        if written > 0 {
+            txn.execute(
+                "UPDATE download SET updated = ?1 WHERE id = ?2",
+                params![times::now(), id],
+            )?;
+        }
+        txn.commit()?;
+        Ok(written)
+    }
+
+    /// Reads the data in the range, all of which must have been received.
+    pub fn read_block(&self, id: i64, range: Range<u64>) -> Result<Vec<u8>> {
+        let mut stmt = self.conn.prepare(
+            r#"SELECT start, end, data FROM block
+               WHERE download_id = ?1 AND start < ?3 AND end > ?2
+               ORDER BY start"#,
+        )?;
+        let mut rows = stmt.query(params![id, range.start, range.end])?;
+
+        let mut data = Vec::with_capacity((range.end - range.start) as usize);
+        let mut pos = range.start;
+        while let Some(row) = rows.next()? {
+            let block_start: u64 = row.get(0)?;
+            let block_end: u64 = row.get(1)?;
+            if block_start > pos {
+                break;
+            }
+            let bytes = row.get_ref(2)?.as_blob()?;
+            let to = block_end.min(range.end);
+            data.extend_from_slice(
+                &bytes[(pos - block_start) as usize..(to - block_start) as usize],
+            );
+            pos = to;
+        }
+
+        if pos < range.end {
+            bail!("Download {id} is missing data at {pos}");
+        }
+        Ok(data)
+    }
+
+    /// Saves the status, priority, destination and category of the
+    /// download.
+    pub fn update_download(&mut self, download: &PartDownload) -> Result<()> {
+        let destination = download
+            .destination
+            .as_deref()
+            .map(configuration::PathBuf::from);
+        self.conn.execute(
+            r#"UPDATE download SET
+                updated = ?1,
+                status = ?2,
+                priority = ?3,
+                destination = ?4,
+                category_id = ?5
+            WHERE
+                id = ?6"#,
+            params![
+                times::now(),
+                download.status,
+                download.priority,
+                destination,
+                download.category,
+                download.id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The number of bytes received so far.
+    pub fn completed(&self, id: i64) -> Result<u64> {
+        let (size, missing): (u64, u64) = self.conn.query_row(
+            r#"SELECT d.size, COALESCE(SUM(g.end - g.start), 0)
+               FROM download d LEFT JOIN gap g ON g.download_id = d.id
+               WHERE d.id = ?1
+               GROUP BY d.id"#,
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok(size - missing)
+    }
+
+    /// The ranges still to be downloaded, in order.
+    pub fn gaps(&self, id: i64) -> Result<Vec<Range<u64>>> {
+        Self::overlapping(&self.conn, "gap", id, 0..u64::MAX)
+    }
+
+    /// Throws away the data in the range, such as a corrupt part or blocks,
+    /// so that it is downloaded again.
+    pub fn discard(&mut self, id: i64, range: Range<u64>) -> Result<()> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        let txn = self.conn.transaction()?;
+
+        // Blocks which stick out of the range keep the data outside it.
+        let mut stmt = txn.prepare(
+            r#"SELECT start, end, data FROM block
+               WHERE download_id = ?1 AND start < ?3 AND end > ?2"#,
+        )?;
+        let blocks = stmt
+            .query_map(params![id, range.start, range.end], |row| {
+                Ok((
+                    row.get::<_, u64>(0)?,
+                    row.get::<_, u64>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        drop(stmt);
+
+        for (start, end, data) in blocks {
+            txn.execute(
+                "DELETE FROM block WHERE download_id = ?1 AND start = ?2",
+                params![id, start],
+            )?;
+            for keep in [start..range.start.min(end), range.end.max(start)..end] {
+                if !keep.is_empty() {
+                    let bytes = &data[(keep.start - start) as usize..(keep.end - start) as usize];
+                    txn.execute(
+                        "INSERT INTO block(download_id, start, end, data) VALUES (?1, ?2, ?3, ?4)",
+                        params![id, keep.start, keep.end, bytes],
+                    )?;
+                }
+            }
+        }
+
+        // The new gap swallows any gaps it touches.
+        let touching = Self::overlapping(
+            &txn,
+            "gap",
+            id,
+            range.start.saturating_sub(1)..range.end + 1,
+        )?;
+        let mut gap = range;
+        for old in touching {
+            gap = gap.start.min(old.start)..gap.end.max(old.end);
+            txn.execute(
+                "DELETE FROM gap WHERE download_id = ?1 AND start = ?2",
+                params![id, old.start],
+            )?;
+        }
+        txn.execute(
+            "INSERT INTO gap(download_id, start, end) VALUES (?1, ?2, ?3)",
+            params![id, gap.start, gap.end],
+        )?;
+
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// The hash and status of each part, in order.
+    pub fn parts(&self, id: i64) -> Result<Vec<Part>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT part, hash, status FROM part WHERE download_id = ?1 ORDER BY part")?;
+        let parts = stmt
+            .query_map([id], |row| {
+                Ok(Part {
+                    part: row.get(0)?,
+                    hash: row.get(1)?,
+                    status: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(parts)
+    }
+
+    /// Records the part hashes, once they have been checked against the
+    /// file hash.
+    pub fn set_part_hashes(&mut self, id: i64, part_hashes: &[Ed2kHash]) -> Result<()> {
+        let txn = self.conn.transaction()?;
+        {
+            let mut stmt =
+                txn.prepare("UPDATE part SET hash = ?3 WHERE download_id = ?1 AND part = ?2")?;
+            for (part, hash) in part_hashes.iter().enumerate() {
+                stmt.execute(params![id, part, hash])?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn set_part_status(&mut self, id: i64, part: u64, status: PartStatus) -> Result<()> {
+        self.conn.execute(
+            "UPDATE part SET status = ?3 WHERE download_id = ?1 AND part = ?2",
+            params![id, part, status],
+        )?;
+        Ok(())
+    }
+
+    /// Checks the part against its hash, once all of it has arrived. A
+    /// corrupt part is discarded so that it is downloaded again, unless the
+    /// download has an AICH hash: then its data is kept for `recover_part`
+    /// to find the corrupt blocks in. Returns None if the part is not
+    /// complete or we do not know its hash.
+    pub fn verify_part(
+        &mut self,
+        download: &PartDownload,
+        part: u64,
+    ) -> Result<Option<PartStatus>> {
+        let range = download.part_range(part);
+        let hash: Option<Ed2kHash> = self.conn.query_row(
+            "SELECT hash FROM part WHERE download_id = ?1 AND part = ?2",
+            params![download.id, part],
+            |row| row.get(0),
+        )?;
+        let Some(hash) = hash else {
+            return Ok(None);
+        };
+        if !Self::overlapping(&self.conn, "gap", download.id, range.clone())?.is_empty() {
+            return Ok(None);
+        }
+
+        let data = self.read_block(download.id, range.clone())?;
+        let status = if Ed2kHash::new(Md4::digest(&data).into()) == hash {
+            PartStatus::Verified
+        } else if download.aich_hash.is_some() {
+            warn!("Part {part} of {} is corrupt, recovering it", download.name);
+            PartStatus::Corrupt
+        } else {
+            warn!("Part {part} of {} is corrupt, discarding it", download.name);
+            self.discard(download.id, range)?;
+            PartStatus::Corrupt
+        };
+        self.set_part_status(download.id, part, status)?;
+        Ok(Some(status))
+    }
+
+    /// Discards the blocks of a corrupt part which do not match the AICH
+    /// recovery data another client sent us, so that only they are
+    /// downloaded again. Without recovery data, or if every block matches
+    /// and so the part hash cannot be trusted either, the whole part is
+    /// discarded. Recovery data which does not lead to the download's AICH
+    /// hash is an error, and nothing is discarded.
+    pub fn recover_part(
+        &mut self,
+        download: &PartDownload,
+        part: u64,
+        recovery: Option<&AichRecoveryData>,
+    ) -> Result<()> {
+        let range = download.part_range(part);
+        let corrupt = match (recovery, &download.aich_hash) {
+            (Some(recovery), Some(master)) => {
+                let block_hashes = recovery.verify(master, *download.size, u16::try_from(part)?)?;
+                let data = self.read_block(download.id, range.clone())?;
+                find_corrupt_blocks(&data, &block_hashes)?
+            }
+            _ => Vec::new(),
+        };
+
+        if corrupt.is_empty() {
+            warn!("Discarding all of part {part} of {}", download.name);
+            return self.discard(download.id, range);
+        }
+        info!(
+            "Discarding {} corrupt blocks of part {part} of {}",
+            corrupt.len(),
+            download.name
+        );
+        for block in corrupt {
+            self.discard(
+                download.id,
+                range.start + block.start..range.start + block.end,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The ranges in the table which overlap the range, in order. Blocks
+    /// and gaps never overlap each other, so there is no double counting.
+    fn overlapping(
+        conn: &Connection,
+        table: &str,
+        id: i64,
+        range: Range<u64>,
+    ) -> Result<Vec<Range<u64>>> {
+        // Ranges are stored as integers, which SQLite limits to i64::MAX.
+        let end = range.end.min(i64::MAX as u64);
+        let mut stmt = conn.prepare(&format!(
+            r#"SELECT start, end FROM {table}
+               WHERE download_id = ?1 AND start < ?3 AND end > ?2
+               ORDER BY start"#
+        ))?;
+        let ranges = stmt
+            .query_map(params![id, range.start, end], |row| {
+                Ok(row.get::<_, u64>(0)?..row.get::<_, u64>(1)?)
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ranges)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hashing::{AichHashTree, AichHasher, BLOCK_SIZE};
+
+    fn open_with_download(dir: &Path, size: u64) -> (PartDatabase, i64) {
+        let hash = "31D6CFE0D16AE931B73C59D7E0C089C0".parse().unwrap();
+        let link = Ed2kFileLink::new("file.bin", size, hash);
+        let mut db = PartDatabase::open(dir).unwrap();
+        let id = db.add_download(&link).unwrap();
+        (db, id)
+    }
+
+    #[test]
+    pub fn test_blocks_only_fill_gaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut db, id) = open_with_download(dir.path(), 100);
+        let data: Vec<u8> = (0..100).collect();
+
+        assert_eq!(db.gaps(id).unwrap(), vec![0..100]);
+        assert_eq!(db.write_block(id, 10, &data[10..30]).unwrap(), 20);
+        // Overlapping data is only stored where it fills a gap.
+        assert_eq!(db.write_block(id, 20, &data[20..50]).unwrap(), 20);
+        assert_eq!(db.gaps(id).unwrap(), [0..10, 50..100]);
+        assert_eq!(db.completed(id).unwrap(), 40);
+        assert_eq!(db.read_block(id, 15..45).unwrap(), &data[15..45]);
+        assert!(db.read_block(id, 45..55).is_err());
+    }
+
+    #[test]
+    pub fn test_blocks_past_the_end_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut db, id) = open_with_download(dir.path(), 100);
+        assert!(db.write_block(id, 95, &[0; 10]).is_err());
+        assert_eq!(db.gaps(id).unwrap(), vec![0..100]);
+    }
+
+    #[test]
+    pub fn test_downloads_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..100).collect();
+        let id = {
+            let (mut db, id) = open_with_download(dir.path(), 100);
+            db.write_block(id, 60, &data[60..70]).unwrap();
+            db.set_part_status(id, 0, PartStatus::Corrupt).unwrap();
+            id
+        };
+
+        let db = PartDatabase::open(dir.path()).unwrap();
+        assert_eq!(db.downloads().unwrap()[0].name, "file.bin");
+        assert_eq!(db.gaps(id).unwrap(), [0..60, 70..100]);
+        assert_eq!(db.read_block(id, 60..70).unwrap(), &data[60..70]);
+        let parts = db.parts(id).unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].hash, Some(db.downloads().unwrap()[0].hash));
+        assert_eq!(parts[0].status, PartStatus::Corrupt);
+    }
+
+    #[test]
+    pub fn test_discarding_reopens_gaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut db, id) = open_with_download(dir.path(), 100);
+        let data: Vec<u8> = (0..100).collect();
+        db.write_block(id, 10, &data[10..70]).unwrap();
+
+        db.discard(id, 25..65).unwrap();
+        assert_eq!(db.gaps(id).unwrap(), [0..10, 25..65, 70..100]);
+        assert_eq!(db.read_block(id, 10..25).unwrap(), &data[10..25]);
+        assert_eq!(db.read_block(id, 65..70).unwrap(), &data[65..70]);
+    }
+
+    #[test]
+    pub fn test_removed_downloads_leave_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut db, id) = open_with_download(dir.path(), 100);
+        db.write_block(id, 0, &[1; 50]).unwrap();
+
+        db.remove_download(id).unwrap();
+        assert!(db.downloads().unwrap().is_empty());
+        assert!(db.gaps(id).unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn test_removing_a_download_shrinks_the_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut db, id) = open_with_download(dir.path(), 1_000_000);
+        db.write_block(id, 0, &[1; 1_000_000]).unwrap();
+        let path = db.path().to_owned();
+        let file_size = || std::fs::metadata(&path).unwrap().len();
+        let full = file_size();
+
+        db.remove_download(id).unwrap();
+        assert!(file_size() < full / 10);
+    }
+
+    /// A download of a file with an AICH hash, with one corrupt byte in
+    /// its second block, and the file's hash tree.
+    fn open_with_corrupt_aich_download(dir: &Path) -> (PartDatabase, PartDownload, AichHashTree) {
+        let data: Vec<u8> = (0..400_000u32).map(|i| i as u8).collect();
+        let mut hasher = AichHasher::new();
+        hasher.update(&data);
+        let tree = hasher.finish();
+        let mut link = Ed2kFileLink::new(
+            "file.bin",
+            data.len() as u64,
+            Ed2kHash::new(Md4::digest(&data).into()),
+        );
+        link.aich = Some(tree.master_hash());
+
+        let mut db = PartDatabase::open(dir).unwrap();
+        let id = db.add_download(&link).unwrap();
+        let mut corrupt = data;
+        corrupt[200_000] ^= 0xFF;
+        db.write_block(id, 0, &corrupt).unwrap();
+        let download = db.download(id).unwrap().unwrap();
+        (db, download, tree)
+    }
+
+    #[test]
+    pub fn test_corrupt_parts_with_an_aich_hash_keep_their_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut db, download, _) = open_with_corrupt_aich_download(dir.path());
+
+        assert_eq!(
+            db.verify_part(&download, 0).unwrap(),
+            Some(PartStatus::Corrupt)
+        );
+        assert!(db.gaps(download.id).unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn test_aich_recovery_discards_only_corrupt_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut db, download, tree) = open_with_corrupt_aich_download(dir.path());
+        db.verify_part(&download, 0).unwrap();
+
+        let recovery = tree.recovery_data(0);
+        db.recover_part(&download, 0, recovery.as_ref()).unwrap();
+        assert_eq!(
+            db.gaps(download.id).unwrap(),
+            vec![BLOCK_SIZE..2 * BLOCK_SIZE]
+        );
+    }
+
+    #[test]
+    pub fn test_aich_recovery_data_for_another_file_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut db, download, _) = open_with_corrupt_aich_download(dir.path());
+        db.verify_part(&download, 0).unwrap();
+
+        let other: Vec<u8> = (0..400_000u32).map(|i| (i + 1) as u8).collect();
+        let mut hasher = AichHasher::new();
+        hasher.update(&other);
+        let wrong = hasher.finish().recovery_data(0);
+        assert!(db.recover_part(&download, 0, wrong.as_ref()).is_err());
+        assert!(db.gaps(download.id).unwrap().is_empty());
+    }
+}
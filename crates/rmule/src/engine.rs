@@ -1,6 +1,20 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
+use crate::bandwidth::BandwidthLimiter;
 use crate::configuration::{ConfigurationCommand, ConfigurationManagerHandle};
+use crate::connections::{ConnectionAdmission, IpFilter};
+use crate::download::{DownloadCommand, DownloadManagerHandle};
+use crate::kad::{KadCommand, KadManagerHandle};
+use crate::listener::{ListenerCommand, ListenerManagerHandle};
+use crate::peer::{PeerCommand, PeerManagerHandle};
+use crate::portmap::{
+    PortMappingCommand, PortMappingEvents, PortMappingManagerHandle, PortMappingOptions,
+};
+use crate::search::{SearchCommand, SearchManagerHandle};
+use crate::server::{ServerCommand, ServerManagerHandle};
+use tokio::sync::mpsc;
+use tracing::warn;
 
 /// The rMule Engine. This contains the entire actor system that responds to
 /// commands, emits events, runs downloads, updates configuration etc.
@@ -35,7 +49,15 @@ use crate::configuration::{ConfigurationCommand, ConfigurationManagerHandle};
 /// their modules: all access is via the corresponding XyzManagerHandle.
 pub struct Engine {
     config_dir: PathBuf,
+    tokio_handle: tokio::runtime::Handle,
     cfg_mgr_handle: ConfigurationManagerHandle,
+    download_mgr_handle: DownloadManagerHandle,
+    kad_mgr_handle: KadManagerHandle,
+    listener_mgr_handle: ListenerManagerHandle,
+    peer_mgr_handle: PeerManagerHandle,
+    port_mapping_mgr_handle: PortMappingManagerHandle,
+    search_mgr_handle: SearchManagerHandle,
+    server_mgr_handle: ServerManagerHandle,
 }
 
 impl Engine {
@@ -43,11 +65,101 @@ impl Engine {
         let config_dir = config_dir.into();
 
         // TODO: This will start emitting log events, but not Actor events.
-        let cfg_mgr_handle = ConfigurationManagerHandle::new(&config_dir, tokio_handle);
+        let cfg_mgr_handle = ConfigurationManagerHandle::new(&config_dir, tokio_handle.clone());
+
+        // Every connection shares the bandwidth and connection limits,
+        // which follow the settings.
+        let limiter = BandwidthLimiter::new();
+        limiter.follow_settings(cfg_mgr_handle.subscribe_to_events(), &tokio_handle);
+        let admission = ConnectionAdmission::default();
+        admission.follow_settings(cfg_mgr_handle.subscribe_to_events(), &tokio_handle);
+
+        // The Listener Manager opens the ports in the settings, which the
+        // Kad, Server and Search Managers use.
+        let mut listener_mgr_handle = ListenerManagerHandle::new(
+            cfg_mgr_handle.subscribe_to_events(),
+            admission.clone(),
+            &tokio_handle,
+        );
+        // The Port Mapping Manager forwards the ports the Listener Manager
+        // opens, so it must subscribe before they are opened.
+        let port_mapping_mgr_handle = PortMappingManagerHandle::new(
+            cfg_mgr_handle.subscribe_to_events(),
+            listener_mgr_handle.subscribe_to_events(),
+            PortMappingOptions::default(),
+            &tokio_handle,
+        );
+
+        // The Search and Server Managers need the server list, so they
+        // listen to the Configuration Manager. They must subscribe before the
+        // Start command is sent or they will miss the initial events.
+        // Global searches also need our public IP Address, which only the
+        // Server Manager knows.
+        let server_mgr_handle = ServerManagerHandle::new(
+            cfg_mgr_handle.subscribe_to_events(),
+            listener_mgr_handle.server_udp_socket(),
+            limiter.clone(),
+            admission.clone(),
+            &tokio_handle,
+        );
+        let search_mgr_handle = SearchManagerHandle::new(
+            cfg_mgr_handle.subscribe_to_events(),
+            server_mgr_handle.subscribe_to_events(),
+            listener_mgr_handle.server_udp_socket(),
+            &tokio_handle,
+        );
+        // The Kad Manager keeps its routing table in the config DB.
+        let kad_mgr_handle = KadManagerHandle::new(
+            &config_dir,
+            cfg_mgr_handle.subscribe_to_events(),
+            cfg_mgr_handle.make_command_sender(),
+            listener_mgr_handle.udp_socket(),
+            limiter.clone(),
+            admission.clone(),
+            &tokio_handle,
+        );
+
+        // The Download Manager opens the part databases in the temp
+        // directories from the configuration, and gets sources of its
+        // files from the servers and other clients, except those in
+        // ipfilter.dat.
+        let download_mgr_handle = DownloadManagerHandle::new(
+            cfg_mgr_handle.subscribe_to_events(),
+            server_mgr_handle.make_command_sender(),
+            server_mgr_handle.subscribe_to_events(),
+            IpFilter::load(&config_dir),
+            tokio_handle.clone(),
+        );
+
+        // The Peer Manager talks to the clients which connect to us, and
+        // to those our server or Kad buddy asks us to call back.
+        let incoming = listener_mgr_handle
+            .take_incoming_connections()
+            .expect("The incoming connections have already been taken");
+        let peer_mgr_handle = PeerManagerHandle::new(
+            cfg_mgr_handle.subscribe_to_events(),
+            incoming,
+            server_mgr_handle.subscribe_to_events(),
+            kad_mgr_handle.subscribe_to_events(),
+            kad_mgr_handle.make_command_sender(),
+            download_mgr_handle.subscribe_to_events(),
+            download_mgr_handle.make_command_sender(),
+            limiter,
+            admission,
+            &tokio_handle,
+        );
 
         Self {
             config_dir,
+            tokio_handle,
             cfg_mgr_handle,
+            download_mgr_handle,
+            kad_mgr_handle,
+            listener_mgr_handle,
+            peer_mgr_handle,
+            port_mapping_mgr_handle,
+            search_mgr_handle,
+            server_mgr_handle,
         }
     }
 
@@ -61,8 +173,183 @@ impl Engine {
             .unwrap();
     }
 
+    /// Stops the Engine. The router is told to stop forwarding our ports
+    /// first, as it would otherwise forward them until the mappings
+    /// expire, then the other managers are stopped one at a time, waiting
+    /// for each to finish. The Configuration Manager is stopped last so
+    /// that it saves the contacts the Kad Manager sends it as it stops.
+    pub async fn stop(&self) {
+        // The router may not answer, and we should not hang on exit.
+        const PORT_MAPPING_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+        let mut port_mapping_events = self.port_mapping_mgr_handle.subscribe_to_events();
+        if self
+            .port_mapping_mgr_handle
+            .send_command(PortMappingCommand::Stop)
+            .await
+            .is_ok()
+        {
+            let stopped = tokio::time::timeout(PORT_MAPPING_STOP_TIMEOUT, async {
+                while let Ok(evt) = port_mapping_events.recv().await {
+                    if let PortMappingEvents::Stopped = evt {
+                        break;
+                    }
+                }
+            })
+            .await;
+            if stopped.is_err() {
+                warn!("Timed out deleting port mappings");
+            }
+        }
+
+        let download = self.download_mgr_handle.make_command_sender();
+        stop_manager("Download", download, DownloadCommand::Stop).await;
+        let peer = self.peer_mgr_handle.make_command_sender();
+        stop_manager("Peer", peer, PeerCommand::Stop).await;
+        let kad = self.kad_mgr_handle.make_command_sender();
+        stop_manager("Kad", kad, KadCommand::Stop).await;
+        let search = self.search_mgr_handle.make_command_sender();
+        stop_manager("Search", search, SearchCommand::Stop).await;
+        let server = self.server_mgr_handle.make_command_sender();
+        stop_manager("Server", server, ServerCommand::Stop).await;
+        let listener = self.listener_mgr_handle.make_command_sender();
+        stop_manager("Listener", listener, ListenerCommand::Stop).await;
+        let cfg = self.cfg_mgr_handle.make_command_sender();
+        stop_manager("Configuration", cfg, ConfigurationCommand::Stop).await;
+    }
+
+    /// Synchronously stops the Engine; see `stop`. This must not be called
+    /// from within the Tokio runtime.
+    pub fn stop_blocking(&self) {
+        self.tokio_handle.block_on(self.stop());
+    }
+
     /// Returns a reference to the Configuration Manager handle.
     pub fn configuration_manager_handle(&self) -> &ConfigurationManagerHandle {
         &self.cfg_mgr_handle
     }
+
+    /// Returns a reference to the Download Manager handle.
+    pub fn download_manager_handle(&self) -> &DownloadManagerHandle {
+        &self.download_mgr_handle
+    }
+
+    /// Returns a reference to the Kad Manager handle.
+    pub fn kad_manager_handle(&self) -> &KadManagerHandle {
+        &self.kad_mgr_handle
+    }
+
+    /// Returns a reference to the Listener Manager handle.
+    pub fn listener_manager_handle(&self) -> &ListenerManagerHandle {
+        &self.listener_mgr_handle
+    }
+
+    /// Returns a reference to the Peer Manager handle.
+    pub fn peer_manager_handle(&self) -> &PeerManagerHandle {
+        &self.peer_mgr_handle
+    }
+
+    /// Returns a reference to the Port Mapping Manager handle.
+    pub fn port_mapping_manager_handle(&self) -> &PortMappingManagerHandle {
+        &self.port_mapping_mgr_handle
+    }
+
+    /// Returns a reference to the Search Manager handle.
+    pub fn search_manager_handle(&self) -> &SearchManagerHandle {
+        &self.search_mgr_handle
+    }
+
+    /// Returns a reference to the Server Manager handle.
+    pub fn server_manager_handle(&self) -> &ServerManagerHandle {
+        &self.server_mgr_handle
+    }
+}
+
+/// Sends a manager its Stop command and waits for it to finish, which is
+/// when it drops its end of the command channel. The manager may already
+/// have stopped, which is fine.
+async fn stop_manager<T>(name: &str, commands: mpsc::Sender<T>, stop: T) {
+    // Long enough for the Download Manager to finish writing a block.
+    const MANAGER_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+    if commands.send(stop).await.is_err() {
+        return;
+    }
+    if tokio::time::timeout(MANAGER_STOP_TIMEOUT, commands.closed())
+        .await
+        .is_err()
+    {
+        warn!("Timed out waiting for the {name} Manager to stop");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bandwidth::BandwidthLimiter;
+    use crate::configuration::{
+        migrations, ConfigurationManager, Settings, TempDirectoryList, UserHash,
+    };
+    use crate::listener::{ListenerEvents, ListenerKind};
+    use crate::peer::{HelloInfo, PeerConnection};
+    use rusqlite::Connection;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tokio::time::timeout;
+
+    /// Settings which keep the Engine off the internet and out of the
+    /// real downloads directory, with the TCP port on a free port.
+    fn save_local_settings(config_dir: &std::path::Path) -> Settings {
+        let conn = Connection::open(ConfigurationManager::config_db_filename(config_dir)).unwrap();
+        migrations::apply_database_migrations(&conn).unwrap();
+        TempDirectoryList::insert(&conn, &config_dir.join("temp")).unwrap();
+        let mut settings = Settings::load(&conn).unwrap();
+        let free = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        settings.default_downloads_directory = config_dir.join("incoming").into();
+        settings.tcp_port = free.local_addr().unwrap().port();
+        settings.udp_port = 0;
+        settings.auto_update_server_list = false;
+        settings.port_mapping = false;
+        settings.update(&conn).unwrap();
+        settings
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_peers_connecting_to_us_are_answered() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = save_local_settings(dir.path());
+
+        let engine = Engine::new(dir.path(), tokio::runtime::Handle::current());
+        let mut listener_events = engine.listener_manager_handle().subscribe_to_events();
+        engine.start().await;
+        timeout(Duration::from_secs(10), async {
+            while let Ok(evt) = listener_events.recv().await {
+                if let ListenerEvents::Listening {
+                    kind: ListenerKind::Tcp,
+                    ..
+                } = evt
+                {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("The Engine did not open its TCP port");
+
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, settings.tcp_port));
+        let limiter = BandwidthLimiter::new();
+        let admission = ConnectionAdmission::default();
+        let mut conn = PeerConnection::connect(addr, None, &limiter, &admission)
+            .await
+            .unwrap();
+        let ours = HelloInfo::new(UserHash::new([1; 16]), "client".to_owned(), 0, 4662);
+        let answer = timeout(Duration::from_secs(10), conn.hello(&ours))
+            .await
+            .expect("The Engine did not answer our hello")
+            .unwrap();
+
+        assert_eq!(answer.user_hash, settings.user_hash);
+        assert_eq!(answer.tcp_port, settings.tcp_port);
+
+        engine.stop().await;
+    }
 }
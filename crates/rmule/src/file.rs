@@ -47,6 +47,7 @@ pub fn ensure_directory_exists(dir: &Path) -> Result<()> {
 }
 
 /// Ensures that a path is writable. Path can be a directory or a file.
+#[allow(clippy::permissions_set_readonly_false)]
 pub fn ensure_writable(path: &Path) -> Result<()> {
     let mut perms = std::fs::metadata(path)?.permissions();
     if perms.readonly() {
@@ -154,7 +155,7 @@ fn has_backup_suffix(filename: &str) -> bool {
 /// Makes a guaranteed-absolute path from a filename that may or may not
 /// already be absolute. Returns a Cow::Borrowed if filename is already
 /// absolute, else returns a Cow::Owned.
-pub fn make_absolute<'a, 'b>(filename: &'a Path, directory: &'b Path) -> Cow<'a, Path> {
+pub fn make_absolute<'a>(filename: &'a Path, directory: &Path) -> Cow<'a, Path> {
     if filename.is_absolute() {
         filename.into()
     } else {
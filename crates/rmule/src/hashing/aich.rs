@@ -0,0 +1,415 @@
+use super::PART_SIZE;
+use crate::protocol::AichHash;
+use anyhow::{bail, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::ops::Range;
+
+/// The AICH tree's leaves are blocks of this size. Blocks do not cross part
+/// boundaries, so the last block of each part is smaller.
+pub const BLOCK_SIZE: u64 = 184_320;
+
+const BLOCKS_PER_PART: u64 = PART_SIZE.div_ceil(BLOCK_SIZE);
+
+/// A node of the AICH tree, which covers a range of the file. The tree is
+/// split the way eMule splits it, a part at a time down to the parts and a
+/// block at a time below them, so that everybody's tree has the same shape.
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    start: u64,
+    size: u64,
+    /// What the node's range is split into.
+    base: u64,
+    is_left: bool,
+    /// The path from the root, one bit per level, 1 for left. The root is
+    /// a left branch, so the top bit is always 1.
+    ident: u32,
+}
+
+impl Node {
+    fn root(file_size: u64) -> Self {
+        Self {
+            start: 0,
+            size: file_size,
+            base: Self::base_for(file_size),
+            is_left: true,
+            ident: 1,
+        }
+    }
+
+    fn base_for(size: u64) -> u64 {
+        if size <= PART_SIZE {
+            BLOCK_SIZE
+        } else {
+            PART_SIZE
+        }
+    }
+
+    fn range(&self) -> Range<u64> {
+        self.start..self.start + self.size
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.size <= self.base
+    }
+
+    /// Left branches get the odd unit out.
+    fn children(&self) -> (Self, Self) {
+        let units = self.size.div_ceil(self.base);
+        let left_units = if self.is_left { units + 1 } else { units } / 2;
+        let left_size = left_units * self.base;
+
+        let child = |start, size, is_left| Self {
+            start,
+            size,
+            base: Self::base_for(size),
+            is_left,
+            ident: (self.ident << 1) | is_left as u32,
+        };
+        (
+            child(self.start, left_size, true),
+            child(self.start + left_size, self.size - left_size, false),
+        )
+    }
+
+    /// The leaves under this node, in order.
+    fn leaves(&self, out: &mut Vec<Node>) {
+        if self.is_leaf() {
+            out.push(*self);
+        } else {
+            let (left, right) = self.children();
+            left.leaves(out);
+            right.leaves(out);
+        }
+    }
+
+    /// Walks down to the node covering the part, returning it and the
+    /// nodes next to the path, deepest first.
+    fn find_part(self, part: &Range<u64>) -> (Self, Vec<Self>) {
+        let mut node = self;
+        let mut siblings = Vec::new();
+        while node.range() != *part && !node.is_leaf() {
+            let (left, right) = node.children();
+            if part.start < right.start {
+                siblings.push(right);
+                node = left;
+            } else {
+                siblings.push(left);
+                node = right;
+            }
+        }
+        siblings.reverse();
+        (node, siblings)
+    }
+}
+
+/// Which leaf a block is: each part has the same number of blocks.
+fn block_index(start: u64) -> usize {
+    ((start / PART_SIZE) * BLOCKS_PER_PART + (start % PART_SIZE) / BLOCK_SIZE) as usize
+}
+
+fn part_range(file_size: u64, part: u16) -> Option<Range<u64>> {
+    let start = part as u64 * PART_SIZE;
+    (start < file_size).then(|| start..file_size.min(start + PART_SIZE))
+}
+
+fn combine(left: &AichHash, right: &AichHash) -> AichHash {
+    let mut sha1 = Sha1::new();
+    sha1.update(left.as_bytes());
+    sha1.update(right.as_bytes());
+    AichHash::new(sha1.finalize().into())
+}
+
+/// A file's complete AICH tree. Only the block hashes are kept; the rest
+/// of the tree is worked out from them when needed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AichHashTree {
+    file_size: u64,
+    block_hashes: Vec<AichHash>,
+}
+
+impl AichHashTree {
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// The hash the file is known by, the root of the tree.
+    pub fn master_hash(&self) -> AichHash {
+        self.hash(Node::root(self.file_size))
+    }
+
+    fn hash(&self, node: Node) -> AichHash {
+        if node.is_leaf() {
+            self.block_hashes[block_index(node.start)]
+        } else {
+            let (left, right) = node.children();
+            combine(&self.hash(left), &self.hash(right))
+        }
+    }
+
+    /// What another client needs to check the blocks of a part against the
+    /// master hash: the part's block hashes, and the hashes next to the
+    /// path from the part up to the root. None if there is no such part.
+    pub fn recovery_data(&self, part: u16) -> Option<AichRecoveryData> {
+        let part = part_range(self.file_size, part)?;
+        let (node, siblings) = Node::root(self.file_size).find_part(&part);
+
+        let mut leaves = Vec::new();
+        node.leaves(&mut leaves);
+        let hashes = leaves
+            .into_iter()
+            .chain(siblings)
+            .map(|node| (node.ident, self.hash(node)))
+            .collect();
+        Some(AichRecoveryData { hashes })
+    }
+}
+
+/// Computes an `AichHashTree` from a file's contents, which can be fed in
+/// pieces of any size.
+#[derive(Debug, Clone, Default)]
+pub struct AichHasher {
+    block: Sha1,
+    pos: u64,
+    block_hashes: Vec<AichHash>,
+}
+
+impl AichHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let part_offset = self.pos % PART_SIZE;
+            let room = (BLOCK_SIZE - part_offset % BLOCK_SIZE).min(PART_SIZE - part_offset);
+            let (now, later) = data.split_at((room as usize).min(data.len()));
+            self.block.update(now);
+            self.pos += now.len() as u64;
+            data = later;
+
+            if now.len() as u64 == room {
+                self.finish_block();
+            }
+        }
+    }
+
+    /// Finishes the last block. An empty file has a single empty block.
+    pub fn finish(mut self) -> AichHashTree {
+        let at_boundary = (self.pos % PART_SIZE).is_multiple_of(BLOCK_SIZE);
+        if !at_boundary || self.pos == 0 {
+            self.finish_block();
+        }
+        AichHashTree {
+            file_size: self.pos,
+            block_hashes: self.block_hashes,
+        }
+    }
+
+    fn finish_block(&mut self) {
+        let block = std::mem::take(&mut self.block);
+        self.block_hashes
+            .push(AichHash::new(block.finalize().into()));
+    }
+}
+
+/// The hashes another client sends so that we can repair a part: each is
+/// identified by its path from the root of the tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AichRecoveryData {
+    pub hashes: Vec<(u32, AichHash)>,
+}
+
+impl AichRecoveryData {
+    /// Checks the hashes against the master hash, returning the part's block
+    /// hashes if they are right.
+    pub fn verify(&self, master: &AichHash, file_size: u64, part: u16) -> Result<Vec<AichHash>> {
+        let Some(part) = part_range(file_size, part) else {
+            bail!("AICH recovery data for a part the file does not have");
+        };
+        let hashes: HashMap<_, _> = self.hashes.iter().copied().collect();
+        let root = Node::root(file_size);
+
+        // Nodes over the part are worked out from below, everything else
+        // must have been sent.
+        fn hash(
+            node: Node,
+            part: &Range<u64>,
+            hashes: &HashMap<u32, AichHash>,
+        ) -> Result<AichHash> {
+            let overlaps = node.start < part.end && part.start < node.range().end;
+            if overlaps && !node.is_leaf() {
+                let (left, right) = node.children();
+                Ok(combine(
+                    &hash(left, part, hashes)?,
+                    &hash(right, part, hashes)?,
+                ))
+            } else {
+                match hashes.get(&node.ident) {
+                    Some(hash) => Ok(*hash),
+                    None => bail!("AICH recovery data is missing hash {:#x}", node.ident),
+                }
+            }
+        }
+
+        if hash(root, &part, &hashes)? != *master {
+            bail!("AICH recovery data does not match the master hash {master}");
+        }
+
+        let (node, _) = root.find_part(&part);
+        let mut leaves = Vec::new();
+        node.leaves(&mut leaves);
+        Ok(leaves.iter().map(|leaf| hashes[&leaf.ident]).collect())
+    }
+
+    /// Identifiers are 16 bits unless the file is so large that some do
+    /// not fit, in which case they all go in the 32 bit section.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let wide = self
+            .hashes
+            .iter()
+            .any(|(ident, _)| *ident > u16::MAX as u32);
+        let count = self.hashes.len() as u16;
+
+        out.write_u16::<LittleEndian>(if wide { 0 } else { count })
+            .unwrap();
+        if !wide {
+            for (ident, hash) in &self.hashes {
+                out.write_u16::<LittleEndian>(*ident as u16).unwrap();
+                out.extend_from_slice(hash.as_bytes());
+            }
+        }
+
+        out.write_u16::<LittleEndian>(if wide { count } else { 0 })
+            .unwrap();
+        if wide {
+            for (ident, hash) in &self.hashes {
+                out.write_u32::<LittleEndian>(*ident).unwrap();
+                out.extend_from_slice(hash.as_bytes());
+            }
+        }
+    }
+
+    pub fn read(input: &mut Cursor<&[u8]>) -> Result<Self> {
+        let mut hashes = Vec::new();
+
+        let count = input.read_u16::<LittleEndian>()?;
+        for _ in 0..count {
+            let ident = input.read_u16::<LittleEndian>()? as u32;
+            hashes.push((ident, read_aich_hash(input)?));
+        }
+
+        // Old clients do not send the 32 bit section.
+        if input.position() < input.get_ref().len() as u64 {
+            let count = input.read_u16::<LittleEndian>()?;
+            for _ in 0..count {
+                let ident = input.read_u32::<LittleEndian>()?;
+                hashes.push((ident, read_aich_hash(input)?));
+            }
+        }
+
+        Ok(Self { hashes })
+    }
+}
+
+pub fn read_aich_hash(input: &mut Cursor<&[u8]>) -> Result<AichHash> {
+    let mut bytes = [0u8; AichHash::LEN];
+    std::io::Read::read_exact(input, &mut bytes)?;
+    Ok(AichHash::new(bytes))
+}
+
+/// Finds the blocks of a part which do not match their trusted hashes.
+/// The ranges returned are relative to the start of the part.
+pub fn find_corrupt_blocks(part_data: &[u8], block_hashes: &[AichHash]) -> Result<Vec<Range<u64>>> {
+    let blocks = part_data.chunks(BLOCK_SIZE as usize);
+    if blocks.len() != block_hashes.len() {
+        bail!(
+            "A part of {} bytes has {} blocks, not {}",
+            part_data.len(),
+            blocks.len(),
+            block_hashes.len()
+        );
+    }
+
+    Ok(blocks
+        .zip(block_hashes)
+        .enumerate()
+        .filter(|(_, (block, hash))| Sha1::digest(block).as_slice() != hash.as_bytes())
+        .map(|(idx, (block, _))| {
+            let start = idx as u64 * BLOCK_SIZE;
+            start..start + block.len() as u64
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file_of(size: u64) -> Vec<u8> {
+        (0..size).map(|n| (n * 7 / 1000) as u8).collect()
+    }
+
+    fn tree_of(data: &[u8]) -> AichHashTree {
+        let mut hasher = AichHasher::new();
+        for piece in data.chunks(100_003) {
+            hasher.update(piece);
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    pub fn test_tree_shape_follows_emule() {
+        // A single block is its own master hash.
+        let data = file_of(1000);
+        let tree = tree_of(&data);
+        assert_eq!(tree.master_hash().as_bytes()[..], Sha1::digest(&data)[..]);
+
+        // Blocks restart at each part, and the tree splits at the part.
+        let tree = tree_of(&file_of(PART_SIZE + 10));
+        assert_eq!(tree.block_hashes.len(), BLOCKS_PER_PART as usize + 1);
+        let (left, right) = Node::root(tree.file_size).children();
+        assert_eq!((left.size, right.size), (PART_SIZE, 10));
+        assert_eq!(
+            tree.master_hash(),
+            combine(&tree.hash(left), tree.block_hashes.last().unwrap())
+        );
+
+        // Left branches get the odd part out, right branches do not.
+        let (left, right) = Node::root(7 * PART_SIZE).children();
+        assert_eq!((left.size, right.size), (4 * PART_SIZE, 3 * PART_SIZE));
+        let (left, right) = right.children();
+        assert_eq!((left.size, right.size), (PART_SIZE, 2 * PART_SIZE));
+        assert_eq!((left.ident, right.ident), (0b101, 0b100));
+    }
+
+    #[test]
+    pub fn test_corrupt_blocks_are_found_with_recovery_data() {
+        let mut data = file_of(PART_SIZE * 2 + 500_000);
+        let tree = tree_of(&data);
+        let master = tree.master_hash();
+
+        // The recovery data survives the trip over the wire.
+        let recovery = tree.recovery_data(1).unwrap();
+        let mut wire = Vec::new();
+        recovery.write(&mut wire);
+        let received = AichRecoveryData::read(&mut Cursor::new(&wire[..])).unwrap();
+        assert_eq!(received, recovery);
+
+        let file_size = data.len() as u64;
+        let block_hashes = received.verify(&master, file_size, 1).unwrap();
+        assert_eq!(block_hashes.len(), BLOCKS_PER_PART as usize);
+        assert!(received.verify(&AichHash::default(), file_size, 1).is_err());
+        assert!(received.verify(&master, file_size, 0).is_err());
+
+        // Damage the second block of the part.
+        let part = PART_SIZE as usize..2 * PART_SIZE as usize;
+        data[part.start + BLOCK_SIZE as usize + 5] ^= 0xFF;
+        let corrupt = find_corrupt_blocks(&data[part], &block_hashes).unwrap();
+        assert_eq!(corrupt, vec![BLOCK_SIZE..2 * BLOCK_SIZE]);
+
+        assert!(tree.recovery_data(3).is_none());
+    }
+}
@@ -0,0 +1,138 @@
+use crate::protocol::Ed2kHash;
+use md4::{Digest, Md4};
+
+/// Files are split into parts of this size, each of which is hashed
+/// separately so that a bad part can be downloaded again on its own.
+pub const PART_SIZE: u64 = 9_728_000;
+
+/// The largest file eMule will share or download, 256 GiB.
+pub const MAX_FILE_SIZE: u64 = 256 * 1024 * 1024 * 1024;
+
+/// The hashes of a file: one per part, and the root hash, which is what
+/// the file is known by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ed2kHashSet {
+    pub root: Ed2kHash,
+    /// There are `size / PART_SIZE + 1` of these. When the size is an exact
+    /// multiple of `PART_SIZE` the last is the hash of an empty part, as
+    /// eMule does it.
+    pub part_hashes: Vec<Ed2kHash>,
+}
+
+impl Ed2kHashSet {
+    /// Builds the hash set from the part hashes, which is how we check a
+    /// hash set another client sends us against the root hash we asked for.
+    pub fn from_part_hashes(part_hashes: Vec<Ed2kHash>) -> Self {
+        let root = match part_hashes.as_slice() {
+            [only] => *only,
+            parts => {
+                let mut md4 = Md4::new();
+                for part in parts {
+                    md4.update(part.as_bytes());
+                }
+                Ed2kHash::new(md4.finalize().into())
+            }
+        };
+
+        Self { root, part_hashes }
+    }
+
+    /// The part hashes other clients are sent. A file with a single part
+    /// has none, as its part hash is the root hash.
+    pub fn wire_part_hashes(&self) -> &[Ed2kHash] {
+        match self.part_hashes.len() {
+            1 => &[],
+            _ => &self.part_hashes,
+        }
+    }
+}
+
+/// Computes an `Ed2kHashSet` from a file's contents, which can be fed in
+/// pieces of any size.
+#[derive(Debug, Clone, Default)]
+pub struct Ed2kHasher {
+    part: Md4,
+    part_len: u64,
+    part_hashes: Vec<Ed2kHash>,
+}
+
+impl Ed2kHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let room = (PART_SIZE - self.part_len) as usize;
+            let (now, later) = data.split_at(room.min(data.len()));
+            self.part.update(now);
+            self.part_len += now.len() as u64;
+            data = later;
+
+            if self.part_len == PART_SIZE {
+                let part = std::mem::take(&mut self.part);
+                self.part_hashes.push(Ed2kHash::new(part.finalize().into()));
+                self.part_len = 0;
+            }
+        }
+    }
+
+    /// Finishes the last part, which may be empty.
+    pub fn finish(mut self) -> Ed2kHashSet {
+        self.part_hashes
+            .push(Ed2kHash::new(self.part.finalize().into()));
+        Ed2kHashSet::from_part_hashes(self.part_hashes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn md4(data: &[u8]) -> Ed2kHash {
+        Ed2kHash::new(Md4::digest(data).into())
+    }
+
+    /// The hash of an empty file.
+    fn empty_hash() -> Ed2kHash {
+        "31D6CFE0D16AE931B73C59D7E0C089C0".parse().unwrap()
+    }
+
+    #[test]
+    pub fn test_small_files_are_one_part_known_by_its_md4() {
+        let mut hasher = Ed2kHasher::new();
+        hasher.update(b"abc");
+        let hash_set = hasher.finish();
+        assert_eq!(
+            hash_set.root,
+            "A448017AAF21D8525FC10AE87AA6729D".parse().unwrap()
+        );
+        assert!(hash_set.wire_part_hashes().is_empty());
+    }
+
+    #[test]
+    pub fn test_empty_files_hash_to_the_md4_of_nothing() {
+        let empty = Ed2kHasher::new().finish();
+        assert_eq!(empty.root, empty_hash());
+        assert!(empty.wire_part_hashes().is_empty());
+    }
+
+    #[test]
+    pub fn test_exact_multiples_of_the_part_size_have_an_empty_last_part() {
+        // How the data is fed in makes no difference.
+        let part = vec![0x5A; PART_SIZE as usize];
+        let mut hasher = Ed2kHasher::new();
+        for piece in part.chunks(1_000_003) {
+            hasher.update(piece);
+        }
+        let hash_set = hasher.finish();
+        assert_eq!(hash_set.part_hashes, [md4(&part), empty_hash()]);
+        let mut both = md4(&part).as_bytes().to_vec();
+        both.extend_from_slice(empty_hash().as_bytes());
+        assert_eq!(hash_set.root, md4(&both));
+        assert_eq!(
+            Ed2kHashSet::from_part_hashes(hash_set.part_hashes.clone()),
+            hash_set
+        );
+    }
+}
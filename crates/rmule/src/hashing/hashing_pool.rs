@@ -0,0 +1,238 @@
+use super::{AichHashTree, AichHasher, Ed2kHashSet, Ed2kHasher, PART_SIZE};
+use crate::protocol::Ed2kHash;
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use tokio::sync::{broadcast, oneshot};
+use tracing::{info, warn};
+
+pub type HashingEventSender = broadcast::Sender<HashingEvents>;
+pub type HashingEventReceiver = broadcast::Receiver<HashingEvents>;
+
+/// The set of events that can be emitted by the Hashing Pool.
+#[derive(Debug, Clone)]
+pub enum HashingEvents {
+    /// Sent after each part has been hashed, and at the end.
+    Progress {
+        path: PathBuf,
+        hashed: u64,
+        size: u64,
+    },
+    Finished {
+        path: PathBuf,
+        hash: Ed2kHash,
+    },
+    Failed {
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+/// Everything a file is identified by, which is worked out in one pass
+/// over it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHashes {
+    pub ed2k: Ed2kHashSet,
+    pub aich: AichHashTree,
+}
+
+/// A file waiting to be hashed, and who wants the result.
+struct HashJob {
+    path: PathBuf,
+    reply: oneshot::Sender<Result<FileHashes>>,
+}
+
+/// Hashes files on threads of its own, so that reading and hashing
+/// gigabytes does not hold up the Tokio runtime. Files are read a piece at
+/// a time, so memory use does not grow with the size of the file. Clones
+/// share the same threads.
+#[derive(Clone)]
+pub struct HashingPool {
+    jobs: mpsc::Sender<HashJob>,
+    evt_sender: HashingEventSender,
+}
+
+impl HashingPool {
+    /// How much of the file is read at a time.
+    const READ_SIZE: usize = 256 * 1024;
+
+    /// Starts the given number of hashing threads. They stop when the last
+    /// clone of the pool is dropped.
+    pub fn new(threads: usize) -> Self {
+        let (jobs, job_receiver) = mpsc::channel::<HashJob>();
+        let (evt_sender, _) = broadcast::channel::<HashingEvents>(256);
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        for n in 0..threads.max(1) {
+            let job_receiver = job_receiver.clone();
+            let evt_sender = evt_sender.clone();
+            std::thread::Builder::new()
+                .name(format!("rmule-hasher-{n}"))
+                .spawn(move || Self::work(&job_receiver, &evt_sender))
+                .expect("Unable to start a hashing thread");
+        }
+
+        Self { jobs, evt_sender }
+    }
+
+    /// Hashes the file. Dropping the future stops the hashing at the end
+    /// of the part being hashed.
+    pub async fn hash_file<P: Into<PathBuf>>(&self, path: P) -> Result<FileHashes> {
+        let (reply, result) = oneshot::channel();
+        let job = HashJob {
+            path: path.into(),
+            reply,
+        };
+        self.jobs
+            .send(job)
+            .map_err(|_| anyhow!("The hashing threads have stopped"))?;
+        result.await?
+    }
+
+    /// Create a new subscription to events sent by the Hashing Pool.
+    pub fn subscribe_to_events(&self) -> HashingEventReceiver {
+        self.evt_sender.subscribe()
+    }
+
+    fn work(job_receiver: &Mutex<mpsc::Receiver<HashJob>>, evt_sender: &HashingEventSender) {
+        loop {
+            // The lock is only held while waiting, not while hashing.
+            let job = match job_receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return,
+            };
+            let Ok(job) = job else {
+                return;
+            };
+
+            let result = Self::hash(&job, evt_sender);
+            // Nobody is told about files which were given up on.
+            if job.reply.is_closed() {
+                continue;
+            }
+
+            // Nobody listening for events is fine.
+            let _ = evt_sender.send(match &result {
+                Ok(hashes) => {
+                    info!("Hashed {}: {}", job.path.display(), hashes.ed2k.root);
+                    HashingEvents::Finished {
+                        path: job.path.clone(),
+                        hash: hashes.ed2k.root,
+                    }
+                }
+                Err(e) => {
+                    warn!("Hashing {} failed: {e}", job.path.display());
+                    HashingEvents::Failed {
+                        path: job.path.clone(),
+                        reason: e.to_string(),
+                    }
+                }
+            });
+            let _ = job.reply.send(result);
+        }
+    }
+
+    fn hash(job: &HashJob, evt_sender: &HashingEventSender) -> Result<FileHashes> {
+        let path = &job.path;
+        let (mut file, size) =
+            open(path).with_context(|| format!("Cannot read {}", path.display()))?;
+        let mut ed2k = Ed2kHasher::new();
+        let mut aich = AichHasher::new();
+        let mut buf = vec![0u8; Self::READ_SIZE];
+        let mut hashed = 0u64;
+
+        loop {
+            let len = match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context(format!("Reading {} failed", path.display())),
+            };
+            ed2k.update(&buf[..len]);
+            aich.update(&buf[..len]);
+
+            let part = hashed / PART_SIZE;
+            hashed += len as u64;
+            if hashed / PART_SIZE != part {
+                if job.reply.is_closed() {
+                    bail!("Hashing {} was cancelled", path.display());
+                }
+                let _ = evt_sender.send(HashingEvents::Progress {
+                    path: path.clone(),
+                    hashed,
+                    size,
+                });
+            }
+        }
+
+        if hashed != size {
+            bail!("{} changed while it was being hashed", path.display());
+        }
+        let _ = evt_sender.send(HashingEvents::Progress {
+            path: path.clone(),
+            hashed,
+            size,
+        });
+        Ok(FileHashes {
+            ed2k: ed2k.finish(),
+            aich: aich.finish(),
+        })
+    }
+}
+
+impl Default for HashingPool {
+    /// Hashing is limited by the disk more than the CPU, so a couple of
+    /// threads is plenty.
+    fn default() -> Self {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get().min(2));
+        Self::new(threads)
+    }
+}
+
+fn open(path: &Path) -> Result<(File, u64)> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_hashes_files_and_reports_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        let contents: Vec<u8> = (0..PART_SIZE * 2 + 5).map(|n| n as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+
+        let pool = HashingPool::new(2);
+        let mut events = pool.subscribe_to_events();
+        let hashes = pool.hash_file(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut ed2k = Ed2kHasher::new();
+        ed2k.update(&contents);
+        let mut aich = AichHasher::new();
+        aich.update(&contents);
+        assert_eq!(hashes.ed2k, ed2k.finish());
+        assert_eq!(hashes.aich, aich.finish());
+
+        let mut progress = Vec::new();
+        while let Ok(HashingEvents::Progress { hashed, size, .. }) = events.try_recv() {
+            assert_eq!(size, contents.len() as u64);
+            progress.push(hashed);
+        }
+        // After each of the two full parts, then the end.
+        assert_eq!(progress.len(), 3);
+        assert_eq!(progress.last(), Some(&(contents.len() as u64)));
+
+        assert!(pool.hash_file(&path).await.is_err());
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            HashingEvents::Failed { .. }
+        ));
+    }
+}
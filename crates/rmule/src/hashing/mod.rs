@@ -0,0 +1,12 @@
+//! Computes the hashes which identify files on the ed2k network: the MD4
+//! part hashes, and the SHA-1 AICH tree used to repair corrupt parts.
+//! Hashing a large file takes a while, so it is done on a pool of threads
+//! of its own rather than in the Tokio runtime.
+
+mod aich;
+mod ed2k_hasher;
+mod hashing_pool;
+
+pub use aich::*;
+pub use ed2k_hasher::*;
+pub use hashing_pool::*;
@@ -0,0 +1,160 @@
+use super::{buddy_id, Buddy, KadId};
+use crate::bandwidth::BandwidthLimiter;
+use crate::configuration::UserHash;
+use crate::connections::ConnectionAdmission;
+use crate::peer::{HelloInfo, PeerConnection, PeerMessage};
+use crate::protocol::opcodes::*;
+use crate::protocol::{Ed2kHash, Packet};
+use anyhow::{bail, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Cursor;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tracing::{debug, info, warn};
+
+/// What happens on the TCP connection to our buddy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuddyLinkEvent {
+    /// We are connected to the buddy and have said hello.
+    Up(Buddy),
+    /// The connection failed or was closed.
+    Down,
+    /// A client wants us to connect to it for a file.
+    Callback { addr: SocketAddrV4, file: Ed2kHash },
+}
+
+/// How often we ping the buddy, so that it and any routers in between do
+/// not drop the connection.
+const PING_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Connects to a buddy and keeps the connection up for as long as the
+/// buddy does, passing on the callback requests it forwards. Always ends
+/// with a `BuddyLinkEvent::Down`.
+pub async fn run_buddy_link(
+    buddy: Buddy,
+    our_id: KadId,
+    hello: HelloInfo,
+    obfuscate: bool,
+    limiter: BandwidthLimiter,
+    admission: ConnectionAdmission,
+    events: mpsc::Sender<BuddyLinkEvent>,
+) {
+    let connection = (&limiter, &admission);
+    if let Err(e) = link(buddy, our_id, &hello, obfuscate, connection, &events).await {
+        warn!("Connection to Kad buddy {} lost: {e}", buddy.tcp_addr);
+    }
+    _ = events.send(BuddyLinkEvent::Down).await;
+}
+
+async fn link(
+    buddy: Buddy,
+    our_id: KadId,
+    hello: &HelloInfo,
+    obfuscate: bool,
+    (limiter, admission): (&BandwidthLimiter, &ConnectionAdmission),
+    events: &mpsc::Sender<BuddyLinkEvent>,
+) -> Result<()> {
+    let user_hash = UserHash::new(buddy.user_hash.to_be_bytes());
+    let obfuscate_with = obfuscate.then_some(&user_hash);
+    let addr = buddy.tcp_addr.into();
+    let mut conn = PeerConnection::connect(addr, obfuscate_with, limiter, admission).await?;
+    conn.hello(hello).await?;
+
+    info!("Connected to Kad buddy {}", buddy.tcp_addr);
+    events.send(BuddyLinkEvent::Up(buddy)).await?;
+
+    let our_buddy_id = buddy_id(our_id);
+    let mut pings = time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = pings.tick() => {
+                let ping = PeerMessage::Unknown(Packet::emule(OP_BUDDYPING, Vec::new()));
+                conn.send(&ping).await?;
+            }
+            msg = conn.recv() => match msg? {
+                PeerMessage::Unknown(packet) if packet.opcode == OP_BUDDYPING => {
+                    let pong = PeerMessage::Unknown(Packet::emule(OP_BUDDYPONG, Vec::new()));
+                    conn.send(&pong).await?;
+                }
+                PeerMessage::Unknown(packet) if packet.opcode == OP_BUDDYPONG => {}
+                PeerMessage::Unknown(packet) if packet.opcode == OP_CALLBACK => {
+                    let (check, file, addr) = parse_callback(&packet.payload)?;
+                    if check != our_buddy_id {
+                        bail!("Buddy forwarded a callback for somebody else");
+                    }
+                    events.send(BuddyLinkEvent::Callback { addr, file }).await?;
+                }
+                msg => debug!("Ignoring {msg:?} from Kad buddy {}", buddy.tcp_addr),
+            }
+        }
+    }
+}
+
+/// Makes the OP_CALLBACK a buddy sends its firewalled client when another
+/// client asks, through Kad, to be connected to. `check` is the client's
+/// buddy id.
+pub fn make_callback(check: KadId, file: Ed2kHash, addr: SocketAddrV4) -> PeerMessage {
+    let mut payload = Vec::with_capacity(38);
+    check.write(&mut payload);
+    KadId::from(file).write(&mut payload);
+    payload.extend_from_slice(&addr.ip().octets());
+    payload.write_u16::<LittleEndian>(addr.port()).unwrap();
+    PeerMessage::Unknown(Packet::emule(OP_CALLBACK, payload))
+}
+
+/// Parses an OP_CALLBACK: the buddy id the client found us by, the file
+/// it wants and where it accepts connections. Unlike Kad packets the IP
+/// Address is in network byte order.
+fn parse_callback(payload: &[u8]) -> Result<(KadId, Ed2kHash, SocketAddrV4)> {
+    let mut input = Cursor::new(payload);
+    let check = KadId::read(&mut input)?;
+    let file = KadId::read(&mut input)?;
+    let ip = Ipv4Addr::from(input.read_u32::<LittleEndian>()?.to_le_bytes());
+    let port = input.read_u16::<LittleEndian>()?;
+
+    Ok((
+        check,
+        Ed2kHash::new(file.to_be_bytes()),
+        SocketAddrV4::new(ip, port),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_parse_forwarded_callback() {
+        let check = KadId::new(0x0102_0304_0506_0708_090a_0b0c_0d0e_0f10);
+        let file = KadId::random();
+        let mut payload = Vec::new();
+        check.write(&mut payload);
+        file.write(&mut payload);
+        payload.extend_from_slice(&[192, 168, 1, 2]);
+        payload.extend_from_slice(&4662u16.to_le_bytes());
+
+        let (c, f, addr) = parse_callback(&payload).unwrap();
+        assert_eq!(c, check);
+        assert_eq!(f, Ed2kHash::new(file.to_be_bytes()));
+        assert_eq!(addr, "192.168.1.2:4662".parse().unwrap());
+    }
+
+    #[test]
+    pub fn test_make_callback_round_trips() {
+        let check = KadId::random();
+        let file = Ed2kHash::new([7; 16]);
+        let addr = "192.168.1.2:4662".parse().unwrap();
+
+        let PeerMessage::Unknown(packet) = make_callback(check, file, addr) else {
+            panic!("OP_CALLBACK is not a message of its own");
+        };
+        assert_eq!(packet.opcode, OP_CALLBACK);
+        assert_eq!(
+            parse_callback(&packet.payload).unwrap(),
+            (check, file, addr)
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use super::KadId;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tokio::time::Instant;
+
+/// Whether other clients can connect to us, as far as Kad can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KadFirewallStatus {
+    /// We have not been tested yet.
+    Unknown,
+    /// Other Kad nodes could connect to our TCP port.
+    Open,
+    /// Nobody could connect to our TCP port, and we have no buddy, so
+    /// clients which find us through Kad cannot reach us.
+    Firewalled,
+    /// We are firewalled, but have a buddy which passes on requests for
+    /// us to connect to clients.
+    Buddy(Buddy),
+}
+
+/// An open node which has agreed to pass callback requests on to us. We
+/// keep a TCP connection to it, over which the requests arrive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buddy {
+    /// The buddy's user hash, which is needed for obfuscated connections.
+    pub user_hash: KadId,
+    /// Where the buddy accepts connections from other clients.
+    pub tcp_addr: SocketAddrV4,
+    /// Where the buddy speaks Kad, which is where clients send their
+    /// callback requests.
+    pub udp_addr: SocketAddrV4,
+}
+
+/// The lookup target for finding a buddy, and the id a buddy knows us by:
+/// our Kad id with every bit flipped. Buddies are found this way so that
+/// they are spread evenly over the network rather than all being our
+/// neighbours.
+pub fn buddy_id(our_id: KadId) -> KadId {
+    our_id.distance(&KadId::new(u128::MAX))
+}
+
+/// The result of a firewall check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirewallCheckResult {
+    pub tcp_open: bool,
+    /// Our IP Address as most of the testers saw it.
+    pub public_ip: Option<Ipv4Addr>,
+}
+
+#[derive(Debug, Default)]
+struct Tester {
+    /// The IP Address the tester saw us at.
+    seen_ip: Option<Ipv4Addr>,
+    /// Whether the tester could connect to our TCP port.
+    acked: bool,
+}
+
+/// A test of whether our TCP port can be reached from outside. We ask a few
+/// nodes to connect to it: each answers with the IP Address it sees us at
+/// and then acknowledges separately if it could connect.
+#[derive(Debug)]
+pub struct FirewallCheck {
+    testers: HashMap<SocketAddrV4, Tester>,
+    deadline: Instant,
+}
+
+impl FirewallCheck {
+    /// How many nodes are asked.
+    pub const TESTERS: usize = 4;
+    /// How many of them must be able to connect for us to count as open.
+    /// More than one, so that a single lying node cannot make us think we
+    /// are open.
+    const REQUIRED_ACKS: usize = 2;
+
+    pub fn new(testers: impl IntoIterator<Item = SocketAddrV4>, deadline: Instant) -> Self {
+        Self {
+            testers: testers
+                .into_iter()
+                .map(|addr| (addr, Tester::default()))
+                .collect(),
+            deadline,
+        }
+    }
+
+    pub fn is_tester(&self, addr: &SocketAddrV4) -> bool {
+        self.testers.contains_key(addr)
+    }
+
+    pub fn response(&mut self, from: SocketAddrV4, ip: Ipv4Addr) {
+        if let Some(tester) = self.testers.get_mut(&from) {
+            tester.seen_ip = Some(ip);
+        }
+    }
+
+    pub fn ack(&mut self, from: SocketAddrV4) {
+        if let Some(tester) = self.testers.get_mut(&from) {
+            tester.acked = true;
+        }
+    }
+
+    fn acks(&self) -> usize {
+        self.testers.values().filter(|t| t.acked).count()
+    }
+
+    /// Returns the result once enough testers have connected or the check
+    /// has run out of time. We can only be sure we are firewalled by
+    /// waiting, as nobody tells us they could not connect.
+    pub fn poll(&self, now: Instant) -> Option<FirewallCheckResult> {
+        let required = Self::REQUIRED_ACKS.min(self.testers.len()).max(1);
+        let tcp_open = self.acks() >= required;
+
+        if !tcp_open && now < self.deadline {
+            return None;
+        }
+
+        let mut votes: HashMap<Ipv4Addr, usize> = HashMap::new();
+        for ip in self.testers.values().filter_map(|t| t.seen_ip) {
+            *votes.entry(ip).or_default() += 1;
+        }
+        let public_ip = votes
+            .into_iter()
+            .max_by_key(|(ip, count)| (*count, *ip))
+            .map(|(ip, _)| ip);
+
+        Some(FirewallCheckResult {
+            tcp_open,
+            public_ip,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn testers() -> Vec<SocketAddrV4> {
+        (1..=4)
+            .map(|n| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, n), 4672))
+            .collect()
+    }
+
+    #[test]
+    pub fn test_two_acks_make_us_open() {
+        let now = Instant::now();
+        let testers = testers();
+        let mut check = FirewallCheck::new(testers.clone(), now + Duration::from_secs(10));
+
+        check.ack(testers[0]);
+        assert_eq!(check.poll(now), None);
+        check.ack(testers[2]);
+        assert!(check.poll(now).unwrap().tcp_open);
+    }
+
+    #[test]
+    pub fn test_only_a_timeout_makes_us_firewalled() {
+        let now = Instant::now();
+        let testers = testers();
+        let mut check = FirewallCheck::new(testers.clone(), now + Duration::from_secs(10));
+
+        check.ack(testers[0]);
+        assert_eq!(check.poll(now + Duration::from_secs(9)), None);
+        let result = check.poll(now + Duration::from_secs(11)).unwrap();
+        assert!(!result.tcp_open);
+    }
+
+    #[test]
+    pub fn test_the_public_ip_is_the_one_most_testers_saw() {
+        let now = Instant::now();
+        let testers = testers();
+        let mut check = FirewallCheck::new(testers.clone(), now + Duration::from_secs(10));
+
+        let ours = Ipv4Addr::new(1, 2, 3, 4);
+        check.response(testers[0], ours);
+        check.response(testers[1], ours);
+        check.response(testers[2], Ipv4Addr::new(6, 6, 6, 6));
+        let result = check.poll(now + Duration::from_secs(11)).unwrap();
+        assert_eq!(result.public_ip, Some(ours));
+    }
+}
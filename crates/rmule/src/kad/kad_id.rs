@@ -0,0 +1,120 @@
+use crate::protocol::Ed2kHash;
+use anyhow::Result;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use md4::{Digest, Md4};
+use std::fmt::{Debug, Display};
+use std::io::Cursor;
+
+/// A 128-bit Kademlia id. Nodes, keywords and files all have one, and they
+/// live in the same space: information about an id is stored on the nodes
+/// whose ids are closest to it, closeness being the XOR of the two ids.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KadId(u128);
+
+impl KadId {
+    pub const BITS: u32 = 128;
+
+    pub fn new(value: u128) -> Self {
+        Self(value)
+    }
+
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    /// Hashes (file hashes, user hashes, MD4s of keywords) are turned into
+    /// ids by reading them as big-endian numbers.
+    pub fn from_be_bytes(bytes: [u8; 16]) -> Self {
+        Self(u128::from_be_bytes(bytes))
+    }
+
+    pub fn to_be_bytes(&self) -> [u8; 16] {
+        self.0.to_be_bytes()
+    }
+
+    /// The id under which files with the keyword in their name are
+    /// published: the MD4 of the lowercased keyword.
+    pub fn from_keyword(keyword: &str) -> Self {
+        let hash = Md4::digest(keyword.to_lowercase().as_bytes());
+        Self::from_be_bytes(hash.into())
+    }
+
+    pub fn distance(&self, other: &KadId) -> KadId {
+        Self(self.0 ^ other.0)
+    }
+
+    pub fn leading_zeros(&self) -> u32 {
+        self.0.leading_zeros()
+    }
+
+    /// On the wire an id is four u32s, most significant first, each of
+    /// which is little-endian.
+    pub fn read(input: &mut Cursor<&[u8]>) -> Result<Self> {
+        let mut value = 0u128;
+        for _ in 0..4 {
+            value = (value << 32) | input.read_u32::<LittleEndian>()? as u128;
+        }
+        Ok(Self(value))
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        for shift in [96, 64, 32, 0] {
+            out.write_u32::<LittleEndian>((self.0 >> shift) as u32)
+                .unwrap();
+        }
+    }
+
+    /// The id in its wire form, which is also the form eMule uses as the
+    /// key material for obfuscated Kad packets.
+    pub fn to_wire_bytes(&self) -> [u8; 16] {
+        let mut out = Vec::with_capacity(16);
+        self.write(&mut out);
+        out.try_into().unwrap()
+    }
+}
+
+impl From<Ed2kHash> for KadId {
+    fn from(hash: Ed2kHash) -> Self {
+        Self::from_be_bytes(*hash.as_bytes())
+    }
+}
+
+impl Display for KadId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:032X}", self.0)
+    }
+}
+
+impl Debug for KadId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KadId({self})")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_wire_format_is_four_little_endian_u32s() {
+        let id = KadId::new(0x00112233_44556677_8899AABB_CCDDEEFF);
+        let mut out = Vec::new();
+        id.write(&mut out);
+        assert_eq!(
+            out,
+            [
+                0x33, 0x22, 0x11, 0x00, 0x77, 0x66, 0x55, 0x44, 0xBB, 0xAA, 0x99, 0x88, 0xFF, 0xEE,
+                0xDD, 0xCC
+            ]
+        );
+        assert_eq!(KadId::read(&mut Cursor::new(&out[..])).unwrap(), id);
+    }
+
+    #[test]
+    pub fn test_keyword_ids_are_md4_of_the_lowercase_keyword() {
+        // MD4("abc") from RFC 1320; keywords are case insensitive.
+        let id = KadId::from_keyword("ABC");
+        assert_eq!(id.to_string(), "A448017AAF21D8525FC10AE87AA6729D");
+        assert_eq!(id, KadId::from_keyword("abc"));
+    }
+}
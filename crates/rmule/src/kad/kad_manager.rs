@@ -0,0 +1,1217 @@
+use super::{
+    buddy_id, load_or_create_kad_id, make_buddy_source_entry, make_source_entry, run_buddy_link,
+    Buddy, BuddyLinkEvent, Contact, KadEntry, KadFirewallStatus, KadId, KadNode, KadOptions,
+    KadUdpKey, LookupKind, NodeInput,
+};
+use crate::bandwidth::BandwidthLimiter;
+use crate::configuration::{
+    ConfigurationCommand, ConfigurationCommandSender, ConfigurationEventReceiver,
+    ConfigurationEvents, KadContact, KadContactList, Settings,
+};
+use crate::connections::ConnectionAdmission;
+use crate::listener::UdpSocketWatch;
+use crate::peer::HelloInfo;
+use crate::protocol::opcodes::*;
+use crate::protocol::{Ed2kHash, Tag, TagValue};
+use anyhow::{bail, Result};
+use std::fmt::Display;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+pub type KadCommandSender = mpsc::Sender<KadCommand>;
+pub type KadCommandReceiver = mpsc::Receiver<KadCommand>;
+
+pub type KadEventSender = broadcast::Sender<KadEvents>;
+pub type KadEventReceiver = broadcast::Receiver<KadEvents>;
+
+/// Identifies a lookup. Like search ids they are allocated by the handle,
+/// so that the caller knows which events belong to its lookup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LookupId(u64);
+
+impl LookupId {
+    /// The lookup of our own id which fills the routing table.
+    pub const BOOTSTRAP: LookupId = LookupId(0);
+    /// The lookup of our buddy id, when we are firewalled.
+    pub const FIND_BUDDY: LookupId = LookupId(u64::MAX);
+}
+
+impl Display for LookupId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The handle type allows commands to be sent to and events to be received
+/// from the Kad Manager.
+pub struct KadManagerHandle {
+    cmd_sender: KadCommandSender,
+    evt_sender: KadEventSender,
+    // TODO: We need at least one receiver to be alive to
+    // allow us to send events.
+    evt_receiver: KadEventReceiver,
+    next_lookup_id: AtomicU64,
+}
+
+impl KadManagerHandle {
+    /// Starts the Kad Manager as a Tokio task. Our Kad id is kept in a
+    /// file in the config dir, and the routing table in the config DB.
+    /// Kad runs on the UDP port the Listener Manager opens.
+    pub fn new(
+        config_dir: &Path,
+        cfg_evt_receiver: ConfigurationEventReceiver,
+        cfg_cmd_sender: ConfigurationCommandSender,
+        udp_socket: UdpSocketWatch,
+        limiter: BandwidthLimiter,
+        admission: ConnectionAdmission,
+        tokio_handle: &tokio::runtime::Handle,
+    ) -> Self {
+        let (cmd_sender, cmd_receiver) = mpsc::channel::<KadCommand>(32);
+        let (evt_sender, evt_receiver) = broadcast::channel::<KadEvents>(256);
+
+        let mgr = KadManager::new(
+            evt_sender.clone(),
+            cmd_receiver,
+            cfg_evt_receiver,
+            cfg_cmd_sender,
+            udp_socket,
+            limiter,
+            admission,
+            config_dir.to_owned(),
+        );
+        tokio_handle.spawn(mgr.run());
+
+        Self {
+            cmd_sender,
+            evt_sender,
+            evt_receiver,
+            next_lookup_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Sends a command to the Kad Manager.
+    pub async fn send_command(&self, cmd: KadCommand) -> Result<()> {
+        Ok(self.cmd_sender.send(cmd).await?)
+    }
+
+    /// Synchronously send a command to the Kad Manager.
+    pub fn send_command_blocking(&self, cmd: KadCommand) -> Result<()> {
+        Ok(self.cmd_sender.blocking_send(cmd)?)
+    }
+
+    /// Create a new subscription to events sent by the Kad Manager.
+    pub fn subscribe_to_events(&self) -> KadEventReceiver {
+        self.evt_sender.subscribe()
+    }
+
+    /// Creates a new command sender which can be used to send commands
+    /// to the Kad Manager.
+    pub fn make_command_sender(&self) -> KadCommandSender {
+        self.cmd_sender.clone()
+    }
+
+    /// Allocates an id for a new lookup.
+    pub fn next_lookup_id(&self) -> LookupId {
+        LookupId(self.next_lookup_id.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// The set of commands that can be sent to the Kad Manager.
+#[derive(Debug)]
+pub enum KadCommand {
+    /// Starts our Kad node, using the contacts saved last time.
+    Connect,
+    /// Joins the network via a node we know the address of. This is
+    /// needed the first time, when we do not know anyone.
+    Bootstrap(SocketAddrV4),
+    /// Stops our Kad node, saving the routing table to the config DB.
+    Disconnect,
+    /// Finds the nodes closest to an id.
+    FindNode { lookup_id: LookupId, target: KadId },
+    /// Searches for files with the keyword in their name.
+    SearchKeyword {
+        lookup_id: LookupId,
+        keyword: String,
+    },
+    SearchSources {
+        lookup_id: LookupId,
+        hash: Ed2kHash,
+        size: u64,
+    },
+    SearchNotes {
+        lookup_id: LookupId,
+        hash: Ed2kHash,
+        size: u64,
+    },
+    /// Publishes files under a keyword. Each entry is a file id with (at
+    /// least) its name and size tags.
+    PublishKeyword {
+        lookup_id: LookupId,
+        keyword: String,
+        files: Vec<KadEntry>,
+    },
+    /// Publishes us as a source of a file.
+    PublishSource {
+        lookup_id: LookupId,
+        hash: Ed2kHash,
+        size: u64,
+    },
+    /// Publishes our rating (1-5, or 0 for none) and comment on a file.
+    PublishNotes {
+        lookup_id: LookupId,
+        hash: Ed2kHash,
+        file_name: String,
+        rating: u8,
+        comment: String,
+    },
+    /// Asks a firewalled source to connect to us, through its buddy. The
+    /// buddy's address and id are from the source's Kad entry.
+    RequestCallback {
+        buddy: SocketAddrV4,
+        buddy_id: KadId,
+        hash: Ed2kHash,
+    },
+    /// Tells Kad which firewalled client we are the buddy of, by its
+    /// buddy id, once it has connected to us; or None once it has gone.
+    /// Callback requests for it are passed on as `CallbackForBuddy`.
+    ServeBuddy(Option<KadId>),
+    /// Disconnects and stops the Kad Manager.
+    Stop,
+}
+
+/// The set of events that can be emitted by the Kad Manager.
+#[derive(Debug, Clone)]
+pub enum KadEvents {
+    Connected {
+        id: KadId,
+        port: u16,
+    },
+    ConnectFailed(String),
+    /// Looking ourselves up has finished, and we know this many nodes.
+    Bootstrapped {
+        contacts: usize,
+    },
+    Disconnected,
+    /// Files, sources or notes found by a lookup. Each entry is only
+    /// reported once per lookup.
+    LookupResults {
+        lookup_id: LookupId,
+        entries: Vec<KadEntry>,
+    },
+    /// A lookup is over. `closest` are the nodes found closest to the
+    /// target, `published` how many of them accepted a publish.
+    LookupFinished {
+        lookup_id: LookupId,
+        closest: Vec<Contact>,
+        published: usize,
+    },
+    /// The result of a firewall check, or a change of buddy. `udp_open`
+    /// is whether a node we had not contacted has reached us over UDP.
+    FirewallStatus {
+        status: KadFirewallStatus,
+        udp_open: bool,
+        public_ip: Option<Ipv4Addr>,
+    },
+    /// A node has agreed to be our buddy, since we are firewalled.
+    BuddyOffered(Buddy),
+    /// A client has asked us, through our buddy, to connect to it because
+    /// it wants a file from us.
+    CallbackRequested {
+        addr: SocketAddrV4,
+        file: Ed2kHash,
+    },
+    /// A firewalled client has asked us to be its buddy, and we have
+    /// offered. It should connect to us, saying hello with this user
+    /// hash, and keep the connection open.
+    BuddyRequested {
+        user_hash: KadId,
+        buddy_id: KadId,
+    },
+    /// A client has asked us to tell the firewalled client we are the
+    /// buddy of to connect to it, which is done with an OP_CALLBACK over
+    /// the buddy link.
+    CallbackForBuddy {
+        addr: SocketAddrV4,
+        file: Ed2kHash,
+    },
+}
+
+/// This is private to the module: all access is via the handle.
+struct KadManager {
+    events_sender: KadEventSender,
+    commands_receiver: KadCommandReceiver,
+    cfg_events_receiver: ConfigurationEventReceiver,
+    cfg_commands_sender: ConfigurationCommandSender,
+    // Our own events, which is how we learn what the node found out
+    // about our firewall.
+    own_events_receiver: KadEventReceiver,
+    // The UDP port, which moves when the settings change.
+    udp_socket: UdpSocketWatch,
+    // For the connection to our buddy and testing other nodes' ports.
+    limiter: BandwidthLimiter,
+    admission: ConnectionAdmission,
+    config_dir: PathBuf,
+    // The latest settings we have been told about.
+    settings: Option<Settings>,
+    // The contacts saved last time, plus any imported from nodes.dat files.
+    contacts: Vec<Contact>,
+    // The keys those contacts gave us for obfuscating packets to them.
+    udp_keys: Vec<(SocketAddrV4, KadUdpKey)>,
+    node: Option<KadNode>,
+    // The connection to our buddy, while we are firewalled.
+    buddy_link: Option<JoinHandle<()>>,
+    buddy_events_sender: mpsc::Sender<BuddyLinkEvent>,
+    buddy_events_receiver: mpsc::Receiver<BuddyLinkEvent>,
+}
+
+impl KadManager {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        events_sender: KadEventSender,
+        commands_receiver: KadCommandReceiver,
+        cfg_events_receiver: ConfigurationEventReceiver,
+        cfg_commands_sender: ConfigurationCommandSender,
+        udp_socket: UdpSocketWatch,
+        limiter: BandwidthLimiter,
+        admission: ConnectionAdmission,
+        config_dir: PathBuf,
+    ) -> Self {
+        let (buddy_events_sender, buddy_events_receiver) = mpsc::channel(16);
+
+        Self {
+            own_events_receiver: events_sender.subscribe(),
+            events_sender,
+            commands_receiver,
+            cfg_events_receiver,
+            cfg_commands_sender,
+            udp_socket,
+            limiter,
+            admission,
+            config_dir,
+            settings: None,
+            contacts: Vec::new(),
+            udp_keys: Vec::new(),
+            node: None,
+            buddy_link: None,
+            buddy_events_sender,
+            buddy_events_receiver,
+        }
+    }
+
+    async fn run(mut self) {
+        let mut buf = vec![0u8; 65536];
+
+        loop {
+            tokio::select! {
+                cmd = self.commands_receiver.recv() => {
+                    match cmd {
+                        Some(KadCommand::Stop) | None => break,
+                        Some(cmd) => self.handle_command(cmd).await,
+                    }
+                }
+                evt = self.cfg_events_receiver.recv() => {
+                    match evt {
+                        Ok(ConfigurationEvents::SettingsChange(settings)) => self.settings_changed(settings),
+                        Ok(ConfigurationEvents::KadContactListChange(contacts)) => self.contacts_changed(&contacts),
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(n)) => warn!("Kad Manager missed {n} configuration events"),
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                input = next_input(&mut self.node, &mut buf) => {
+                    if let Some(node) = &mut self.node {
+                        node.handle_input(input, &buf);
+                    }
+                }
+                changed = self.udp_socket.changed() => match changed {
+                    Ok(()) => self.udp_socket_changed().await,
+                    Err(_) => break,
+                },
+                Ok(evt) = self.own_events_receiver.recv() => self.handle_own_event(evt),
+                Some(evt) = self.buddy_events_receiver.recv() => self.handle_buddy_event(evt),
+            }
+        }
+
+        self.disconnect().await;
+        info!("Kad Manager stopped");
+    }
+
+    async fn handle_command(&mut self, cmd: KadCommand) {
+        if let KadCommand::Connect = cmd {
+            self.connect().await;
+            return;
+        }
+        if let KadCommand::Disconnect = cmd {
+            self.disconnect().await;
+            return;
+        }
+
+        let Some(node) = &mut self.node else {
+            warn!("Kad command {cmd:?} while not connected to Kad");
+            return;
+        };
+
+        match cmd {
+            KadCommand::Bootstrap(addr) => node.bootstrap(addr),
+            KadCommand::FindNode { lookup_id, target } => {
+                node.start_lookup(lookup_id, target, LookupKind::FindNode)
+            }
+            KadCommand::SearchKeyword { lookup_id, keyword } => node.start_lookup(
+                lookup_id,
+                KadId::from_keyword(&keyword),
+                LookupKind::Keyword,
+            ),
+            KadCommand::SearchSources {
+                lookup_id,
+                hash,
+                size,
+            } => node.start_lookup(lookup_id, hash.into(), LookupKind::Sources { size }),
+            KadCommand::SearchNotes {
+                lookup_id,
+                hash,
+                size,
+            } => node.start_lookup(lookup_id, hash.into(), LookupKind::Notes { size }),
+            KadCommand::PublishKeyword {
+                lookup_id,
+                keyword,
+                files,
+            } => node.start_lookup(
+                lookup_id,
+                KadId::from_keyword(&keyword),
+                LookupKind::PublishKeyword { entries: files },
+            ),
+            KadCommand::PublishSource {
+                lookup_id,
+                hash,
+                size,
+            } => {
+                let Some(settings) = &self.settings else {
+                    warn!("Cannot publish a source before the settings are loaded");
+                    return;
+                };
+                let source_id = KadId::from_be_bytes(*settings.user_hash.as_bytes());
+                let entry = match node.firewall_status() {
+                    KadFirewallStatus::Buddy(buddy) => make_buddy_source_entry(
+                        source_id,
+                        settings.tcp_port,
+                        size,
+                        buddy_id(node.id()),
+                        &buddy,
+                    ),
+                    _ => make_source_entry(source_id, settings.tcp_port, size),
+                };
+                node.start_lookup(lookup_id, hash.into(), LookupKind::PublishSource { entry })
+            }
+            KadCommand::PublishNotes {
+                lookup_id,
+                hash,
+                file_name,
+                rating,
+                comment,
+            } => {
+                let Some(settings) = &self.settings else {
+                    warn!("Cannot publish notes before the settings are loaded");
+                    return;
+                };
+                let mut tags = vec![Tag::new(FT_FILENAME, TagValue::String(file_name))];
+                if rating > 0 {
+                    tags.push(Tag::new(TAG_FILERATING, TagValue::U8(rating.min(5))));
+                }
+                if !comment.is_empty() {
+                    tags.push(Tag::new(TAG_DESCRIPTION, TagValue::String(comment)));
+                }
+                let entry = KadEntry {
+                    id: KadId::from_be_bytes(*settings.user_hash.as_bytes()),
+                    tags,
+                };
+                node.start_lookup(lookup_id, hash.into(), LookupKind::PublishNotes { entry })
+            }
+            KadCommand::RequestCallback {
+                buddy,
+                buddy_id,
+                hash,
+            } => node.request_callback(buddy, buddy_id, hash.into()),
+            KadCommand::ServeBuddy(buddy_id) => node.set_served_buddy(buddy_id),
+            KadCommand::Connect | KadCommand::Disconnect | KadCommand::Stop => {
+                unreachable!("Handled above or by the run loop")
+            }
+        }
+    }
+
+    async fn connect(&mut self) {
+        if self.node.is_some() {
+            return;
+        }
+
+        match self.start_node().await {
+            Ok(node) => {
+                let id = node.id();
+                let port = node.local_addr().map_or(0, |addr| addr.port());
+                info!(
+                    "Kad started with id {id}, {} known contacts",
+                    node.routing_table().len()
+                );
+                self.node = Some(node);
+                self.send_event(KadEvents::Connected { id, port });
+            }
+            Err(e) => {
+                warn!("Starting Kad failed: {e}");
+                self.send_event(KadEvents::ConnectFailed(e.to_string()));
+            }
+        }
+    }
+
+    async fn start_node(&self) -> Result<KadNode> {
+        let Some(settings) = &self.settings else {
+            bail!("Kad started before the configuration was loaded");
+        };
+        let Some(socket) = self.udp_socket.borrow().clone() else {
+            bail!("The UDP port is not open");
+        };
+
+        let id = load_or_create_kad_id(&self.config_dir)?;
+        let mut node = KadNode::new(
+            socket,
+            id,
+            settings.tcp_port,
+            KadOptions::default(),
+            self.events_sender.clone(),
+            self.admission.clone(),
+        );
+
+        node.set_user_hash(KadId::from_be_bytes(*settings.user_hash.as_bytes()));
+        node.set_obfuscation(settings.obfuscation.is_enabled());
+        node.add_contacts(self.contacts.iter().cloned());
+        node.add_udp_keys(self.udp_keys.iter().copied());
+        if !node.routing_table().is_empty() {
+            node.refresh();
+        }
+
+        Ok(node)
+    }
+
+    fn settings_changed(&mut self, settings: Settings) {
+        if let Some(node) = &mut self.node {
+            node.set_tcp_port(settings.tcp_port);
+            node.set_obfuscation(settings.obfuscation.is_enabled());
+        }
+        self.settings = Some(settings);
+    }
+
+    /// Moves the node to the new UDP port. Kad stops if UDP has been
+    /// turned off.
+    async fn udp_socket_changed(&mut self) {
+        let socket = self.udp_socket.borrow_and_update().clone();
+        match (socket, &mut self.node) {
+            (Some(socket), Some(node)) => node.set_socket(socket),
+            (None, Some(_)) => self.disconnect().await,
+            (_, None) => {}
+        }
+    }
+
+    /// New contacts, from a nodes.dat import, are added to the routing
+    /// table straight away. If we had nobody to talk to until now we can
+    /// join the network with them.
+    fn contacts_changed(&mut self, contacts: &KadContactList) {
+        self.contacts = contacts
+            .iter()
+            .filter_map(|c| {
+                Some(Contact {
+                    id: c.kad_id,
+                    addr: c.udp_socket_addr()?,
+                    tcp_port: c.tcp_port,
+                    version: c.version,
+                })
+            })
+            .collect();
+        self.udp_keys = contacts.iter().filter_map(udp_key).collect();
+
+        if let Some(node) = &mut self.node {
+            let was_empty = node.routing_table().is_empty();
+            node.add_contacts(self.contacts.iter().cloned());
+            node.add_udp_keys(self.udp_keys.iter().copied());
+            if was_empty && !node.routing_table().is_empty() {
+                node.refresh();
+            }
+        }
+    }
+
+    /// Reacts to what the node tells us: a firewall check follows joining
+    /// the network, and a buddy is only needed while we are firewalled.
+    fn handle_own_event(&mut self, evt: KadEvents) {
+        let Some(node) = &mut self.node else {
+            return;
+        };
+
+        match evt {
+            KadEvents::Bootstrapped { .. }
+                if node.firewall_status() == KadFirewallStatus::Unknown =>
+            {
+                node.start_firewall_check()
+            }
+            KadEvents::BuddyOffered(buddy) => self.start_buddy_link(buddy),
+            KadEvents::FirewallStatus {
+                status: KadFirewallStatus::Open,
+                ..
+            } => {
+                if self.buddy_link.is_some() {
+                    info!("No longer firewalled, dropping our Kad buddy");
+                }
+                self.stop_buddy_link();
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_buddy_event(&mut self, evt: BuddyLinkEvent) {
+        match evt {
+            BuddyLinkEvent::Up(buddy) => {
+                if let Some(node) = &mut self.node {
+                    node.set_buddy(Some(buddy));
+                }
+            }
+            BuddyLinkEvent::Down => {
+                self.buddy_link = None;
+                if let Some(node) = &mut self.node {
+                    node.set_buddy(None);
+                }
+            }
+            BuddyLinkEvent::Callback { addr, file } => {
+                self.send_event(KadEvents::CallbackRequested { addr, file })
+            }
+        }
+    }
+
+    fn start_buddy_link(&mut self, buddy: Buddy) {
+        let (Some(node), Some(settings)) = (&self.node, &self.settings) else {
+            return;
+        };
+        if self.buddy_link.is_some() {
+            return;
+        }
+
+        info!("Connecting to Kad buddy {}", buddy.tcp_addr);
+        let mut hello = HelloInfo::new(
+            settings.user_hash,
+            settings.nick_name.clone(),
+            0,
+            settings.tcp_port,
+        );
+        hello.kad_udp_port = node.local_addr().ok().map(|addr| addr.port());
+
+        self.buddy_link = Some(tokio::spawn(run_buddy_link(
+            buddy,
+            node.id(),
+            hello,
+            settings.obfuscation.is_enabled(),
+            self.limiter.clone(),
+            self.admission.clone(),
+            self.buddy_events_sender.clone(),
+        )));
+    }
+
+    fn stop_buddy_link(&mut self) {
+        if let Some(link) = self.buddy_link.take() {
+            link.abort();
+        }
+    }
+
+    async fn disconnect(&mut self) {
+        self.stop_buddy_link();
+
+        let Some(node) = self.node.take() else {
+            return;
+        };
+
+        let contacts: Vec<_> = node
+            .routing_table()
+            .contacts()
+            .map(|c| {
+                let mut contact = KadContact::new(c.id, c.addr, c.tcp_port, c.version);
+                if let Some(key) = node.udp_key(c.addr) {
+                    contact.udp_key = Some(key.key);
+                    contact.udp_key_ip_addr = key.our_ip.map(|ip| std::net::IpAddr::V4(ip).into());
+                }
+                contact
+            })
+            .collect();
+        info!("Saving {} Kad contacts", contacts.len());
+        let cmd = ConfigurationCommand::SaveKadContacts(contacts);
+        if let Err(e) = self.cfg_commands_sender.send(cmd).await {
+            warn!("Cannot save the Kad contacts: {e}");
+        }
+
+        self.send_event(KadEvents::Disconnected);
+    }
+
+    fn send_event(&self, evt: KadEvents) {
+        if let Err(broadcast::error::SendError(evt)) = self.events_sender.send(evt) {
+            warn!("Nobody is listening for Kad events, dropping {:?}", evt);
+        }
+    }
+}
+
+/// The key a contact from nodes.dat gave us, by its address.
+fn udp_key(contact: &KadContact) -> Option<(SocketAddrV4, KadUdpKey)> {
+    let addr = contact.udp_socket_addr()?;
+    let our_ip = match contact.udp_key_ip_addr.as_deref() {
+        Some(std::net::IpAddr::V4(ip)) => Some(*ip),
+        _ => None,
+    };
+    let key = KadUdpKey {
+        key: contact.udp_key?,
+        our_ip,
+    };
+    Some((addr, key))
+}
+
+/// Waits for input for the node, or forever if there is no node.
+async fn next_input(node: &mut Option<KadNode>, buf: &mut [u8]) -> NodeInput {
+    match node {
+        Some(node) => node.next_input(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::UserHash;
+    use crate::connections::ConnectionLimits;
+    use crate::kad::KadPacket;
+    use crate::obfuscation::{
+        decrypt_client_packet, encrypt_client_packet, is_plain_udp_packet, udp_verify_key,
+        ClientUdpKey, OurUdpKeys,
+    };
+    use crate::peer::{HelloInfo, PeerConnection};
+    use crate::protocol::{Ed2kHash, Packet};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, UdpSocket};
+    use tokio::time::timeout;
+
+    type NodeOp = Box<dyn FnOnce(&mut KadNode) + Send>;
+
+    /// A random id in the tolerance zone of the "ubuntu" keyword. Nodes
+    /// only store what is published to targets near them, and on a real
+    /// network there would be plenty of nodes near any target.
+    fn id_near_ubuntu() -> KadId {
+        let zone = u128::from_be_bytes(KadId::from_keyword("ubuntu").to_be_bytes()) >> 120;
+        KadId::new(zone << 120 | rand::random::<u128>() >> 8)
+    }
+
+    /// A node running in its own task, as the Kad Manager would run it.
+    struct TestNode {
+        id: KadId,
+        addr: SocketAddrV4,
+        ops: mpsc::UnboundedSender<NodeOp>,
+        events: KadEventReceiver,
+    }
+
+    impl TestNode {
+        async fn spawn(tcp_port: u16) -> Self {
+            Self::spawn_with_id(tcp_port, id_near_ubuntu()).await
+        }
+
+        async fn spawn_with_id(tcp_port: u16, id: KadId) -> Self {
+            let options = KadOptions {
+                request_timeout: Duration::from_millis(500),
+                lookup_timeout: Duration::from_secs(10),
+                answer_timeout: Duration::from_millis(500),
+                firewall_check_timeout: Duration::from_secs(2),
+            };
+            let (evt_sender, events) = broadcast::channel(1024);
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+            let admission = ConnectionAdmission::new(ConnectionLimits::UNLIMITED);
+            let mut node = KadNode::bind(addr, id, tcp_port, options, evt_sender, admission)
+                .await
+                .unwrap();
+
+            let id = node.id();
+            let SocketAddr::V4(addr) = node.local_addr().unwrap() else {
+                unreachable!()
+            };
+
+            let (ops, mut ops_receiver) = mpsc::unbounded_channel::<NodeOp>();
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 65536];
+                loop {
+                    tokio::select! {
+                        op = ops_receiver.recv() => match op {
+                            Some(op) => op(&mut node),
+                            None => break,
+                        },
+                        input = node.next_input(&mut buf) => node.handle_input(input, &buf),
+                    }
+                }
+            });
+
+            Self {
+                id,
+                addr,
+                ops,
+                events,
+            }
+        }
+
+        fn run(&self, op: impl FnOnce(&mut KadNode) + Send + 'static) {
+            assert!(self.ops.send(Box::new(op)).is_ok());
+        }
+
+        /// Waits for the lookup to finish, returning the results and the
+        /// closest nodes found.
+        async fn lookup(
+            &mut self,
+            lookup_id: LookupId,
+            target: KadId,
+            kind: LookupKind,
+        ) -> (Vec<KadEntry>, Vec<KadId>, usize) {
+            self.run(move |node| node.start_lookup(lookup_id, target, kind));
+
+            let mut results = Vec::new();
+            loop {
+                let evt = timeout(Duration::from_secs(20), self.events.recv())
+                    .await
+                    .expect("lookup timed out")
+                    .unwrap();
+                match evt {
+                    KadEvents::LookupResults {
+                        lookup_id: id,
+                        entries,
+                    } if id == lookup_id => results.extend(entries),
+                    KadEvents::LookupFinished {
+                        lookup_id: id,
+                        closest,
+                        published,
+                    } if id == lookup_id => {
+                        return (results, closest.iter().map(|c| c.id).collect(), published)
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        async fn bootstrap(&mut self, via: Option<SocketAddrV4>) {
+            self.run(move |node| match via {
+                Some(addr) => node.bootstrap(addr),
+                None => node.refresh(),
+            });
+
+            loop {
+                let evt = timeout(Duration::from_secs(20), self.events.recv())
+                    .await
+                    .expect("bootstrap timed out")
+                    .unwrap();
+                if let KadEvents::Bootstrapped { .. } = evt {
+                    return;
+                }
+            }
+        }
+
+        async fn firewall_check(&mut self) -> (KadFirewallStatus, Option<Ipv4Addr>) {
+            self.run(|node| node.start_firewall_check());
+
+            loop {
+                let evt = timeout(Duration::from_secs(20), self.events.recv())
+                    .await
+                    .expect("firewall check timed out")
+                    .unwrap();
+                if let KadEvents::FirewallStatus {
+                    status, public_ip, ..
+                } = evt
+                {
+                    return (status, public_ip);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_lookups_converge_on_a_simulated_network() {
+        let mut nodes = Vec::new();
+        for _ in 0..40 {
+            nodes.push(TestNode::spawn(4662).await);
+        }
+
+        // Everyone joins via the first node, then looks themselves up
+        // again as they would periodically, by which time the network
+        // knows itself.
+        let seed = nodes[0].addr;
+        for node in &mut nodes[1..] {
+            node.bootstrap(Some(seed)).await;
+        }
+        for node in &mut nodes {
+            node.bootstrap(None).await;
+        }
+
+        let all_ids: Vec<_> = nodes.iter().map(|n| n.id).collect();
+        for (n, searcher) in [5, 17, 39].into_iter().enumerate() {
+            let target = KadId::random();
+            let mut expected: Vec<_> = all_ids
+                .iter()
+                .filter(|id| **id != nodes[searcher].id)
+                .cloned()
+                .collect();
+            expected.sort_by_key(|id| id.distance(&target));
+
+            let lookup_id = LookupId(n as u64 + 1);
+            let (_, closest, _) = nodes[searcher]
+                .lookup(lookup_id, target, LookupKind::FindNode)
+                .await;
+            assert_eq!(closest[..3], expected[..3]);
+        }
+
+        // What one node publishes, another can find.
+        let file = KadEntry {
+            id: id_near_ubuntu(),
+            tags: vec![
+                Tag::new(FT_FILENAME, TagValue::String("Ubuntu Linux.iso".to_owned())),
+                Tag::new(FT_FILESIZE, TagValue::U32(1000)),
+            ],
+        };
+        let kind = LookupKind::PublishKeyword {
+            entries: vec![file.clone()],
+        };
+        let (_, _, published) = nodes[3]
+            .lookup(LookupId(10), KadId::from_keyword("ubuntu"), kind)
+            .await;
+        assert!(published > 0);
+
+        let (found, _, _) = nodes[30]
+            .lookup(
+                LookupId(11),
+                KadId::from_keyword("UBUNTU"),
+                LookupKind::Keyword,
+            )
+            .await;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0], file);
+
+        let source = make_source_entry(nodes[7].id, 4662, 1000);
+        let kind = LookupKind::PublishSource { entry: source };
+        nodes[7].lookup(LookupId(12), file.id, kind).await;
+
+        let (found, _, _) = nodes[22]
+            .lookup(LookupId(13), file.id, LookupKind::Sources { size: 1000 })
+            .await;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, nodes[7].id);
+        let ip = found[0].tag(TAG_SOURCEIP).and_then(|t| t.as_u32());
+        assert_eq!(ip, Some(u32::from(Ipv4Addr::LOCALHOST)));
+    }
+
+    #[tokio::test]
+    pub async fn test_obfuscating_nodes_find_what_they_publish() {
+        let mut nodes = Vec::new();
+        for _ in 0..6 {
+            let node = TestNode::spawn(4662).await;
+            node.run(|node| node.set_obfuscation(true));
+            nodes.push(node);
+        }
+        let seed = nodes[0].addr;
+        for node in &mut nodes[1..] {
+            node.bootstrap(Some(seed)).await;
+        }
+        for node in &mut nodes {
+            node.bootstrap(None).await;
+        }
+
+        let file = KadEntry {
+            id: id_near_ubuntu(),
+            tags: vec![Tag::new(
+                FT_FILENAME,
+                TagValue::String("Ubuntu Linux.iso".to_owned()),
+            )],
+        };
+        let kind = LookupKind::PublishKeyword {
+            entries: vec![file.clone()],
+        };
+        let keyword = KadId::from_keyword("ubuntu");
+        let (_, _, published) = nodes[1].lookup(LookupId(1), keyword, kind).await;
+        assert!(published > 0);
+
+        let (found, _, _) = nodes[4]
+            .lookup(LookupId(2), keyword, LookupKind::Keyword)
+            .await;
+        assert_eq!(found, vec![file]);
+    }
+
+    /// An id and its wire form, four little-endian u32s, which eMule keys
+    /// obfuscated Kad packets with.
+    const KNOWN_ID: u128 = 0x00112233_44556677_8899AABB_CCDDEEFF;
+    const KNOWN_ID_WIRE: [u8; 16] = [
+        0x33, 0x22, 0x11, 0x00, 0x77, 0x66, 0x55, 0x44, 0xBB, 0xAA, 0x99, 0x88, 0xFF, 0xEE, 0xDD,
+        0xCC,
+    ];
+
+    #[tokio::test]
+    pub async fn test_obfuscated_packets_are_answered_obfuscated() {
+        const OUR_SECRET: u32 = 0x1234_5678;
+        let node = TestNode::spawn_with_id(4662, KadId::new(KNOWN_ID)).await;
+        node.run(|node| node.set_obfuscation(true));
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        // Keyed by the node's id, which is what a node which knows it uses.
+        let key = ClientUdpKey::Kad {
+            node_id: Some(KNOWN_ID_WIRE),
+            receiver_verify_key: 0,
+            sender_verify_key: udp_verify_key(OUR_SECRET, Ipv4Addr::LOCALHOST),
+        };
+        let ping = KadPacket::Ping.to_packet().to_udp_bytes();
+        let bytes = encrypt_client_packet(&ping, &key);
+        socket.send_to(&bytes, node.addr).await.unwrap();
+
+        let mut buf = vec![0u8; 2048];
+        let (len, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!is_plain_udp_packet(&buf[..len]));
+        // The node does not know us, so it uses the key we gave it.
+        let ours = OurUdpKeys {
+            user_hash: UserHash::default(),
+            kad_id: None,
+            kad_udp_key: OUR_SECRET,
+        };
+        let pong = decrypt_client_packet(&buf[..len], &ours, Ipv4Addr::LOCALHOST).unwrap();
+        let pong = KadPacket::from_packet(&Packet::from_udp_bytes(&pong.packet).unwrap());
+        assert!(matches!(pong, Ok(Some(KadPacket::Pong { .. }))));
+    }
+
+    #[tokio::test]
+    pub async fn test_known_contacts_are_sent_packets_keyed_by_their_id() {
+        use crate::kad::KADEMLIA_VERSION;
+
+        let node = TestNode::spawn(4662).await;
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let SocketAddr::V4(our_addr) = socket.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let us = Contact {
+            id: KadId::new(KNOWN_ID),
+            addr: our_addr,
+            tcp_port: 4662,
+            version: KADEMLIA_VERSION,
+        };
+        node.run(move |node| {
+            node.set_obfuscation(true);
+            node.add_contacts([us]);
+            node.bootstrap(our_addr);
+        });
+
+        let mut buf = vec![0u8; 2048];
+        let (len, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!is_plain_udp_packet(&buf[..len]));
+        let ours = OurUdpKeys {
+            user_hash: UserHash::default(),
+            kad_id: Some(KNOWN_ID_WIRE),
+            kad_udp_key: 0,
+        };
+        let request = decrypt_client_packet(&buf[..len], &ours, Ipv4Addr::LOCALHOST).unwrap();
+        let request = KadPacket::from_packet(&Packet::from_udp_bytes(&request.packet).unwrap());
+        assert!(matches!(request, Ok(Some(_))));
+    }
+
+    #[tokio::test]
+    pub async fn test_answers_nobody_asked_for_are_ignored() {
+        use crate::kad::KADEMLIA_VERSION;
+
+        let node = TestNode::spawn(4662).await;
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let contacts: Vec<_> = (1..=10)
+            .map(|n| Contact {
+                id: KadId::random(),
+                addr: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, n), 4672),
+                tcp_port: 4662,
+                version: KADEMLIA_VERSION,
+            })
+            .collect();
+
+        let unrequested = [
+            KadPacket::Response {
+                target: KadId::random(),
+                contacts: contacts.clone(),
+            },
+            KadPacket::BootstrapResponse {
+                id: KadId::random(),
+                tcp_port: 4662,
+                version: KADEMLIA_VERSION,
+                contacts,
+            },
+            // Packets are handled in order, so once this is answered the
+            // others have been dealt with.
+            KadPacket::Ping,
+        ];
+        for packet in unrequested {
+            let bytes = packet.to_packet().to_udp_bytes();
+            socket.send_to(&bytes, node.addr).await.unwrap();
+        }
+        let mut buf = vec![0u8; 2048];
+        let (len, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let pong = KadPacket::from_packet(&Packet::from_udp_bytes(&buf[..len]).unwrap());
+        assert!(matches!(pong, Ok(Some(KadPacket::Pong { .. }))));
+
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        node.run(move |node| _ = sender.send(node.routing_table().len()));
+        assert_eq!(receiver.await.unwrap(), 0);
+    }
+
+    /// Listens on a port whose client answers hello, as a firewall check
+    /// wants of an open port.
+    async fn hello_answering_port() -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut conn = PeerConnection::new(stream, addr);
+                    let ours = HelloInfo::new(UserHash::new([7; 16]), String::new(), 0, port);
+                    _ = conn.answer_hello(&ours).await;
+                });
+            }
+        });
+        port
+    }
+
+    /// Sets up a network with a node on `tcp_port`, and returns them all
+    /// with that node last.
+    async fn network_with(tcp_port: u16) -> Vec<TestNode> {
+        let mut nodes = Vec::new();
+        for _ in 0..6 {
+            nodes.push(TestNode::spawn(4662).await);
+        }
+        nodes.push(TestNode::spawn(tcp_port).await);
+
+        let seed = nodes[0].addr;
+        for node in &mut nodes[1..] {
+            node.bootstrap(Some(seed)).await;
+        }
+        nodes
+    }
+
+    /// Sends `packet` to `node` from a socket of our own, returning it.
+    async fn send_to(node: &TestNode, packet: KadPacket) -> UdpSocket {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let bytes = packet.to_packet().to_udp_bytes();
+        socket.send_to(&bytes, node.addr).await.unwrap();
+        socket
+    }
+
+    async fn next_event(node: &mut TestNode) -> KadEvents {
+        timeout(Duration::from_secs(5), node.events.recv())
+            .await
+            .expect("no Kad event")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    pub async fn test_firewall_check_tells_open_from_closed_ports() {
+        let open_port = hello_answering_port().await;
+        let closed_port = {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+
+        let mut nodes = network_with(open_port).await;
+        let mut closed = TestNode::spawn(closed_port).await;
+        closed.bootstrap(Some(nodes[0].addr)).await;
+
+        let (status, public_ip) = nodes.last_mut().unwrap().firewall_check().await;
+        assert_eq!(status, KadFirewallStatus::Open);
+        assert_eq!(public_ip, Some(Ipv4Addr::LOCALHOST));
+
+        let (status, _) = closed.firewall_check().await;
+        assert_eq!(status, KadFirewallStatus::Firewalled);
+    }
+
+    #[tokio::test]
+    pub async fn test_ports_which_do_not_answer_hello_are_firewalled() {
+        // Connections to a listener succeed even if it never accepts them,
+        // but nobody says hello.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let silent_port = listener.local_addr().unwrap().port();
+
+        let mut nodes = network_with(silent_port).await;
+
+        let (status, _) = nodes.last_mut().unwrap().firewall_check().await;
+        assert_eq!(status, KadFirewallStatus::Firewalled);
+    }
+
+    #[tokio::test]
+    pub async fn test_open_nodes_offer_to_be_buddies() {
+        let mut nodes = network_with(hello_answering_port().await).await;
+        let open = nodes.last_mut().unwrap();
+        open.firewall_check().await;
+
+        let (buddy_id, user_hash) = (KadId::random(), KadId::random());
+        let request = KadPacket::FindBuddyRequest {
+            buddy_id,
+            user_hash,
+            tcp_port: 4662,
+        };
+        let socket = send_to(open, request).await;
+
+        let mut buf = vec![0u8; 2048];
+        let (len, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = KadPacket::from_packet(&Packet::from_udp_bytes(&buf[..len]).unwrap());
+        assert!(
+            matches!(response, Ok(Some(KadPacket::FindBuddyResponse { check, .. })) if check == buddy_id)
+        );
+        loop {
+            if let KadEvents::BuddyRequested {
+                user_hash: hash,
+                buddy_id: id,
+            } = next_event(open).await
+            {
+                assert_eq!((hash, id), (user_hash, buddy_id));
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_callback_requests_for_our_buddy_client_are_passed_on() {
+        let mut nodes = network_with(hello_answering_port().await).await;
+        let open = nodes.last_mut().unwrap();
+        let buddy_id = KadId::random();
+        open.run(move |node| node.set_served_buddy(Some(buddy_id)));
+
+        // Packets are handled in order, so only the second request can be
+        // passed on.
+        let requests = [
+            (KadId::random(), Ed2kHash::new([1; 16])),
+            (buddy_id, Ed2kHash::new([2; 16])),
+        ];
+        let mut sockets = Vec::new();
+        for (buddy_id, file) in requests {
+            let request = KadPacket::CallbackRequest {
+                buddy_id,
+                file_id: KadId::from(file),
+                tcp_port: 4663,
+            };
+            sockets.push(send_to(open, request).await);
+        }
+
+        loop {
+            if let KadEvents::CallbackForBuddy { addr, file } = next_event(open).await {
+                assert_eq!(file, Ed2kHash::new([2; 16]));
+                assert_eq!(addr, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4663));
+                break;
+            }
+        }
+    }
+}
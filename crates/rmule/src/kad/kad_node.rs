@@ -0,0 +1,989 @@
+use super::{
+    buddy_id, Buddy, Contact, FirewallCheck, FirewallCheckResult, HelloInfo, KadEntry,
+    KadEventSender, KadEvents, KadFirewallStatus, KadId, KadPacket, KadStore, Lookup, LookupId,
+    LookupKind, RoutingTable, StoreKind, K, KADEMLIA_VERSION,
+};
+use crate::configuration::UserHash;
+use crate::connections::ConnectionAdmission;
+use crate::obfuscation::{
+    decrypt_client_packet, encrypt_client_packet, is_plain_udp_packet, udp_verify_key,
+    ClientUdpKey, OurUdpKeys,
+};
+use crate::peer::{self, PeerConnection};
+use crate::protocol::opcodes::*;
+use crate::protocol::{Ed2kHash, Packet, Tag, TagValue};
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use tracing::{debug, warn};
+
+/// Timing for Kad lookups.
+#[derive(Debug, Clone)]
+pub struct KadOptions {
+    /// How long a node has to answer a request before we give up on it.
+    pub request_timeout: Duration,
+    /// How long a lookup may take to find the closest nodes.
+    pub lookup_timeout: Duration,
+    /// How long to collect search results or publish acknowledgements
+    /// once the closest nodes have been asked.
+    pub answer_timeout: Duration,
+    /// How long the nodes testing our TCP port have to connect to it.
+    pub firewall_check_timeout: Duration,
+}
+
+impl Default for KadOptions {
+    fn default() -> Self {
+        Self {
+            request_timeout: Duration::from_secs(3),
+            lookup_timeout: Duration::from_secs(45),
+            answer_timeout: Duration::from_secs(10),
+            firewall_check_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What woke the node up.
+#[derive(Debug)]
+pub enum NodeInput {
+    Datagram(usize, SocketAddr),
+    Tick,
+    /// We could connect to the TCP port of the node at this (UDP) address,
+    /// which asked us to test it.
+    TcpCheckPassed(SocketAddrV4),
+}
+
+/// A key a node gave us for obfuscating packets to it. It was made from
+/// our IP Address as the node saw it, so it only holds while that is
+/// still our public IP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KadUdpKey {
+    pub key: u32,
+    /// Our IP Address when we were given the key, if we knew it.
+    pub our_ip: Option<Ipv4Addr>,
+}
+
+/// A request whose answer would add contacts to our routing table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Awaiting {
+    Bootstrap,
+    Contacts(KadId),
+}
+
+/// A node on the Kad network: our routing table, the information stored
+/// with us and the lookups in progress, over a UDP socket.
+///
+/// Apart from `next_input` everything is synchronous. Packets to send are
+/// queued and go out the next time `next_input` is called, which keeps
+/// the node easy to drive from the Kad Manager's select loop.
+pub struct KadNode {
+    socket: Arc<UdpSocket>,
+    routing: RoutingTable,
+    store: KadStore,
+    lookups: Vec<Lookup>,
+    options: KadOptions,
+    tcp_port: u16,
+    events_sender: KadEventSender,
+    outbox: VecDeque<(Vec<u8>, SocketAddr)>,
+    /// The requests we have sent and until when we accept their answers.
+    /// Answers nobody asked for are dropped, so that a single packet
+    /// cannot fill our routing table with made up contacts.
+    awaiting: HashMap<(SocketAddrV4, Awaiting), Instant>,
+    ticker: Interval,
+    next_expiry: Instant,
+    /// Our user hash, which firewall checks and buddy requests need.
+    user_hash: KadId,
+    firewall: KadFirewallStatus,
+    public_ip: Option<Ipv4Addr>,
+    /// Whether a node we had not contacted has contacted us, which shows
+    /// that our UDP port can be reached from outside.
+    udp_open: bool,
+    /// The IP Addresses we have sent to, until our UDP port is known to
+    /// be open.
+    contacted: HashSet<Ipv4Addr>,
+    firewall_check: Option<FirewallCheck>,
+    next_firewall_check: Option<Instant>,
+    next_buddy_search: Option<Instant>,
+    /// The buddy id of the firewalled client we are the buddy of, whose
+    /// callback requests we pass on.
+    served_buddy: Option<KadId>,
+    /// Connection tests we make for other nodes report back on these.
+    tcp_checks_sender: mpsc::UnboundedSender<SocketAddrV4>,
+    tcp_checks_receiver: mpsc::UnboundedReceiver<SocketAddrV4>,
+    /// Port tests count against the connection limits like any other
+    /// connection.
+    admission: ConnectionAdmission,
+    /// Whether we obfuscate the packets we send. Obfuscated packets are
+    /// always accepted.
+    obfuscate: bool,
+    /// The secret from which the verify keys we hand out are made. A new
+    /// one each run only means nodes fall back to our Kad id for a while.
+    udp_secret: u32,
+    /// The keys nodes gave us for obfuscating packets to them.
+    udp_keys: HashMap<SocketAddrV4, KadUdpKey>,
+}
+
+impl KadNode {
+    /// How often lookups are moved along.
+    const TICK: Duration = Duration::from_millis(100);
+    /// The most entries in one KADEMLIA2_SEARCH_RES.
+    const MAX_ENTRIES_PER_RESPONSE: usize = 50;
+    /// How often we check whether we are still firewalled, as routers get
+    /// reconfigured and addresses change.
+    const FIREWALL_RECHECK: Duration = Duration::from_secs(60 * 60);
+    /// How long we wait before looking for a buddy again when the last
+    /// search found nobody or the buddy went away.
+    const BUDDY_RETRY: Duration = Duration::from_secs(5 * 60);
+    /// How long we try to connect to a node which asked us to test it.
+    const TCP_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+    /// How long after a request its answer is accepted. This is longer
+    /// than lookups wait, since contacts which answer late are still good.
+    const ANSWER_WINDOW: Duration = Duration::from_secs(60);
+    /// The first Kad version which understands obfuscated packets, that
+    /// of eMule 0.49a.
+    const OBFUSCATION_VERSION: u8 = 6;
+
+    /// Makes a node with its own socket.
+    pub async fn bind(
+        addr: SocketAddr,
+        id: KadId,
+        tcp_port: u16,
+        options: KadOptions,
+        events_sender: KadEventSender,
+        admission: ConnectionAdmission,
+    ) -> Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        Ok(Self::new(
+            socket,
+            id,
+            tcp_port,
+            options,
+            events_sender,
+            admission,
+        ))
+    }
+
+    /// Makes a node on a socket which is already open, usually the UDP
+    /// port from the Listener Manager. Nobody else may receive on it.
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        id: KadId,
+        tcp_port: u16,
+        options: KadOptions,
+        events_sender: KadEventSender,
+        admission: ConnectionAdmission,
+    ) -> Self {
+        let mut ticker = time::interval(Self::TICK);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let (tcp_checks_sender, tcp_checks_receiver) = mpsc::unbounded_channel();
+
+        Self {
+            socket,
+            routing: RoutingTable::new(id),
+            store: KadStore::new(id),
+            lookups: Vec::new(),
+            options,
+            tcp_port,
+            events_sender,
+            outbox: VecDeque::new(),
+            awaiting: HashMap::new(),
+            ticker,
+            next_expiry: Instant::now(),
+            user_hash: KadId::default(),
+            firewall: KadFirewallStatus::Unknown,
+            public_ip: None,
+            udp_open: false,
+            contacted: HashSet::new(),
+            firewall_check: None,
+            next_firewall_check: None,
+            next_buddy_search: None,
+            served_buddy: None,
+            tcp_checks_sender,
+            tcp_checks_receiver,
+            admission,
+            obfuscate: false,
+            udp_secret: rand::random(),
+            udp_keys: HashMap::new(),
+        }
+    }
+
+    pub fn id(&self) -> KadId {
+        self.routing.our_id()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Moves the node to another socket, when the UDP port changes.
+    pub fn set_socket(&mut self, socket: Arc<UdpSocket>) {
+        self.socket = socket;
+    }
+
+    /// Sets the TCP port we tell other nodes about, when it changes.
+    pub fn set_tcp_port(&mut self, tcp_port: u16) {
+        self.tcp_port = tcp_port;
+    }
+
+    pub fn routing_table(&self) -> &RoutingTable {
+        &self.routing
+    }
+
+    pub fn set_user_hash(&mut self, user_hash: KadId) {
+        self.user_hash = user_hash;
+    }
+
+    /// Sets whether we obfuscate the packets we send, which nodes older
+    /// than Kad version 6 do not understand and so always get plain.
+    pub fn set_obfuscation(&mut self, obfuscate: bool) {
+        self.obfuscate = obfuscate;
+    }
+
+    /// Adds the keys for obfuscating packets to nodes, from nodes.dat.
+    pub fn add_udp_keys(&mut self, keys: impl IntoIterator<Item = (SocketAddrV4, KadUdpKey)>) {
+        self.udp_keys.extend(keys);
+    }
+
+    /// The key the node at `addr` gave us, to save with its contact.
+    pub fn udp_key(&self, addr: SocketAddrV4) -> Option<KadUdpKey> {
+        self.udp_keys.get(&addr).copied()
+    }
+
+    pub fn firewall_status(&self) -> KadFirewallStatus {
+        self.firewall
+    }
+
+    /// Our IP Address, as seen by the nodes which tested our TCP port.
+    pub fn public_ip(&self) -> Option<Ipv4Addr> {
+        self.public_ip
+    }
+
+    pub fn add_contacts(&mut self, contacts: impl IntoIterator<Item = Contact>) {
+        for contact in contacts {
+            self.routing.add(contact);
+        }
+    }
+
+    /// Joins the network via a node we know the address of but nothing
+    /// else. It answers with its id and some of its contacts, after which
+    /// we look ourselves up to fill the routing table.
+    pub fn bootstrap(&mut self, addr: SocketAddrV4) {
+        self.send(&KadPacket::BootstrapRequest, addr);
+        self.send(&KadPacket::HelloRequest(self.hello()), addr);
+    }
+
+    /// Looks ourselves up, which finds the nodes around us and tells them
+    /// about us.
+    pub fn refresh(&mut self) {
+        if !self.lookups.iter().any(|l| l.id == LookupId::BOOTSTRAP) {
+            self.start_lookup(LookupId::BOOTSTRAP, self.id(), LookupKind::FindNode);
+        }
+    }
+
+    pub fn start_lookup(&mut self, lookup_id: LookupId, target: KadId, kind: LookupKind) {
+        let contacts = self.routing.closest(&target, K);
+        let lookup = Lookup::new(lookup_id, target, kind, contacts, &self.options);
+        self.lookups.push(lookup);
+        self.poll_lookups();
+    }
+
+    /// Asks a few nodes to test whether our TCP port can be reached. The
+    /// result is reported with a `KadEvents::FirewallStatus`, and the check
+    /// is repeated every hour.
+    pub fn start_firewall_check(&mut self) {
+        if self.firewall_check.is_some() {
+            return;
+        }
+
+        let testers: Vec<_> = self
+            .routing
+            .closest(&KadId::random(), FirewallCheck::TESTERS)
+            .into_iter()
+            .map(|c| c.addr)
+            .collect();
+        if testers.is_empty() {
+            return;
+        }
+
+        // We do not offer obfuscation in the connect options, so the
+        // testers connect in plain.
+        let request = KadPacket::FirewalledRequest {
+            tcp_port: self.tcp_port,
+            user_hash: self.user_hash,
+            connect_options: 0,
+        };
+        for addr in &testers {
+            self.send(&request, *addr);
+        }
+
+        let deadline = Instant::now() + self.options.firewall_check_timeout;
+        self.firewall_check = Some(FirewallCheck::new(testers, deadline));
+        self.next_firewall_check = None;
+    }
+
+    /// Records that we have connected to a buddy, or lost the one we had.
+    pub fn set_buddy(&mut self, buddy: Option<Buddy>) {
+        match buddy {
+            Some(buddy) => self.firewall = KadFirewallStatus::Buddy(buddy),
+            // We dropped the buddy because we are no longer firewalled.
+            None if self.firewall == KadFirewallStatus::Open => return,
+            None => {
+                self.firewall = KadFirewallStatus::Firewalled;
+                self.next_buddy_search = Some(Instant::now() + Self::BUDDY_RETRY);
+            }
+        }
+
+        self.send_firewall_status();
+    }
+
+    /// Records which firewalled client, by its buddy id, has its buddy
+    /// link to us, or that it has gone. While we have one nobody else is
+    /// offered to be our buddy client.
+    pub fn set_served_buddy(&mut self, buddy_id: Option<KadId>) {
+        self.served_buddy = buddy_id;
+    }
+
+    /// Asks the buddy of a firewalled client to tell the client to connect
+    /// to us. `buddy_id` is from the client's source entry.
+    pub fn request_callback(&mut self, buddy_addr: SocketAddrV4, buddy_id: KadId, file_id: KadId) {
+        let request = KadPacket::CallbackRequest {
+            buddy_id,
+            file_id,
+            tcp_port: self.tcp_port,
+        };
+        self.send(&request, buddy_addr);
+    }
+
+    /// Sends any queued packets, then waits for the next datagram or tick.
+    /// This is cancel safe, so it can be used in a select.
+    pub async fn next_input(&mut self, buf: &mut [u8]) -> NodeInput {
+        while let Some((data, to)) = self.outbox.front() {
+            if let Err(e) = self.socket.send_to(data, *to).await {
+                debug!("Sending Kad packet to {to} failed: {e}");
+            }
+            self.outbox.pop_front();
+        }
+
+        tokio::select! {
+            recv = self.socket.recv_from(buf) => match recv {
+                Ok((len, from)) => NodeInput::Datagram(len, from),
+                // ICMP errors from dead nodes turn up here on some platforms.
+                Err(_) => NodeInput::Tick,
+            },
+            _ = self.ticker.tick() => NodeInput::Tick,
+            Some(addr) = self.tcp_checks_receiver.recv() => NodeInput::TcpCheckPassed(addr),
+        }
+    }
+
+    pub fn handle_input(&mut self, input: NodeInput, buf: &[u8]) {
+        match input {
+            NodeInput::Datagram(len, SocketAddr::V4(from)) => {
+                if let Err(e) = self.handle_datagram(&buf[..len], from) {
+                    debug!("Bad Kad packet from {from}: {e}");
+                }
+            }
+            NodeInput::Datagram(..) => {}
+            NodeInput::TcpCheckPassed(addr) => self.send(&KadPacket::FirewalledAck, addr),
+            NodeInput::Tick => {
+                self.poll_lookups();
+                self.poll_firewall();
+                if Instant::now() >= self.next_expiry {
+                    self.store.expire();
+                    let now = Instant::now();
+                    self.awaiting.retain(|_, until| *until > now);
+                    self.next_expiry = Instant::now() + Duration::from_secs(60);
+                }
+            }
+        }
+    }
+
+    fn hello(&self) -> HelloInfo {
+        HelloInfo {
+            id: self.id(),
+            tcp_port: self.tcp_port,
+            version: KADEMLIA_VERSION,
+            tags: Vec::new(),
+        }
+    }
+
+    fn send(&mut self, packet: &KadPacket, to: SocketAddrV4) {
+        if !self.udp_open {
+            self.contacted.insert(*to.ip());
+        }
+        let awaiting = match packet {
+            KadPacket::BootstrapRequest => Some(Awaiting::Bootstrap),
+            KadPacket::Request { target, .. } => Some(Awaiting::Contacts(*target)),
+            _ => None,
+        };
+        if let Some(awaiting) = awaiting {
+            let until = Instant::now() + Self::ANSWER_WINDOW;
+            self.awaiting.insert((to, awaiting), until);
+        }
+        let bytes = packet.to_packet().to_udp_bytes();
+        let bytes = self.obfuscate_for(bytes, to);
+        self.outbox.push_back((bytes, to.into()));
+    }
+
+    /// Obfuscates a packet if we want to and know a key for the node: its
+    /// Kad id, if it is in our routing table and new enough, or the key it
+    /// gave us. Otherwise the packet goes plain, as in eMule.
+    fn obfuscate_for(&self, bytes: Vec<u8>, to: SocketAddrV4) -> Vec<u8> {
+        if !self.obfuscate {
+            return bytes;
+        }
+        let node_id = self
+            .routing
+            .contacts()
+            .find(|c| c.addr == to && c.version >= Self::OBFUSCATION_VERSION)
+            .map(|c| c.id.to_wire_bytes());
+        let receiver_verify_key = self
+            .udp_keys
+            .get(&to)
+            .filter(|key| key.our_ip.is_none() || key.our_ip == self.public_ip)
+            .map_or(0, |key| key.key);
+        if node_id.is_none() && receiver_verify_key == 0 {
+            return bytes;
+        }
+
+        let key = ClientUdpKey::Kad {
+            node_id,
+            receiver_verify_key,
+            sender_verify_key: udp_verify_key(self.udp_secret, *to.ip()),
+        };
+        encrypt_client_packet(&bytes, &key)
+    }
+
+    /// Deobfuscates a packet, keeping the key the sender gave us for
+    /// answering it.
+    fn deobfuscate(&mut self, data: &[u8], from: SocketAddrV4) -> Result<Vec<u8>> {
+        let ours = OurUdpKeys {
+            user_hash: UserHash::new(self.user_hash.to_be_bytes()),
+            kad_id: Some(self.id().to_wire_bytes()),
+            kad_udp_key: self.udp_secret,
+        };
+        let Some(decrypted) = decrypt_client_packet(data, &ours, *from.ip()) else {
+            bail!("Cannot deobfuscate the packet");
+        };
+        if let Some((_, sender_verify_key)) = decrypted.kad_verify_keys {
+            if sender_verify_key != 0 {
+                let key = KadUdpKey {
+                    key: sender_verify_key,
+                    our_ip: self.public_ip,
+                };
+                self.udp_keys.insert(from, key);
+            }
+        }
+        Ok(decrypted.packet)
+    }
+
+    /// Whether we asked the node for this answer, which it can then only
+    /// give once.
+    fn was_asked(&mut self, from: SocketAddrV4, awaiting: Awaiting) -> bool {
+        let asked = self
+            .awaiting
+            .remove(&(from, awaiting))
+            .is_some_and(|until| Instant::now() <= until);
+        if !asked {
+            debug!("Dropping unrequested {awaiting:?} answer from {from}");
+        }
+        asked
+    }
+
+    fn send_event(&self, evt: KadEvents) {
+        if let Err(broadcast::error::SendError(evt)) = self.events_sender.send(evt) {
+            warn!("Nobody is listening for Kad events, dropping {:?}", evt);
+        }
+    }
+
+    fn handle_datagram(&mut self, data: &[u8], from: SocketAddrV4) -> Result<()> {
+        let packet = if is_plain_udp_packet(data) {
+            Packet::from_udp_bytes(data)?
+        } else {
+            Packet::from_udp_bytes(&self.deobfuscate(data, from)?)?
+        };
+        if packet.protocol != OP_KADEMLIAHEADER {
+            return Ok(());
+        }
+        let Some(kad_packet) = KadPacket::from_packet(&packet)? else {
+            debug!("Ignoring Kad opcode {:#04x} from {from}", packet.opcode);
+            return Ok(());
+        };
+
+        if !self.udp_open && is_request(&kad_packet) && !self.contacted.contains(from.ip()) {
+            self.udp_open = true;
+            self.contacted.clear();
+            if self.firewall != KadFirewallStatus::Unknown {
+                self.send_firewall_status();
+            }
+        }
+
+        match kad_packet {
+            KadPacket::BootstrapRequest => {
+                let contacts = self
+                    .routing
+                    .closest(&KadId::random(), KadPacket::MAX_BOOTSTRAP_CONTACTS);
+                let answer = KadPacket::BootstrapResponse {
+                    id: self.id(),
+                    tcp_port: self.tcp_port,
+                    version: KADEMLIA_VERSION,
+                    contacts,
+                };
+                self.send(&answer, from);
+            }
+            KadPacket::BootstrapResponse {
+                id,
+                tcp_port,
+                version,
+                contacts,
+            } => {
+                if !self.was_asked(from, Awaiting::Bootstrap) {
+                    return Ok(());
+                }
+                self.routing.add(Contact {
+                    id,
+                    addr: from,
+                    tcp_port,
+                    version,
+                });
+                let our_id = self.id();
+                self.add_contacts(contacts.into_iter().filter(|c| c.id != our_id));
+                self.refresh();
+            }
+            KadPacket::HelloRequest(hello) => {
+                self.add_hello(&hello, from);
+                self.send(&KadPacket::HelloResponse(self.hello()), from);
+            }
+            KadPacket::HelloResponse(hello) => self.add_hello(&hello, from),
+            KadPacket::Request {
+                kind,
+                target,
+                receiver,
+            } => {
+                if receiver == self.id() && kind != 0 {
+                    let contacts = self.routing.closest(&target, kind as usize);
+                    self.send(&KadPacket::Response { target, contacts }, from);
+                }
+            }
+            KadPacket::Response { target, contacts } => {
+                if !self.was_asked(from, Awaiting::Contacts(target)) {
+                    return Ok(());
+                }
+                let contacts: Vec<_> = contacts.into_iter().filter(|c| c.id != self.id()).collect();
+                self.add_contacts(contacts.iter().cloned());
+                if let Some(lookup) = self
+                    .lookups
+                    .iter_mut()
+                    .find(|l| l.target == target && l.is_searching())
+                {
+                    lookup.answered(from, contacts);
+                }
+                self.poll_lookups();
+            }
+            KadPacket::SearchKeyRequest { target, .. } => {
+                self.answer_search(StoreKind::Keyword, target, from)
+            }
+            KadPacket::SearchSourceRequest { target, .. } => {
+                self.answer_search(StoreKind::Source, target, from)
+            }
+            KadPacket::SearchNotesRequest { target, .. } => {
+                self.answer_search(StoreKind::Notes, target, from)
+            }
+            KadPacket::SearchResponse {
+                target, entries, ..
+            } => {
+                let Some(lookup) = self
+                    .lookups
+                    .iter_mut()
+                    .find(|l| l.target == target && l.is_requesting() && l.kind.is_search())
+                else {
+                    return Ok(());
+                };
+
+                let entries = lookup.new_results(entries);
+                if !entries.is_empty() {
+                    let lookup_id = lookup.id;
+                    self.send_event(KadEvents::LookupResults { lookup_id, entries });
+                }
+            }
+            KadPacket::PublishKeyRequest { target, entries } => {
+                let mut load = None;
+                for entry in entries {
+                    load = self
+                        .store
+                        .publish(StoreKind::Keyword, target, entry)
+                        .or(load);
+                }
+                self.acknowledge_publish(target, load, from);
+            }
+            KadPacket::PublishSourceRequest { target, mut entry } => {
+                // Sources are reachable at the address the request came
+                // from, whatever they think their address is.
+                entry.tags.retain(|t| !t.is(TAG_SOURCEIP));
+                entry
+                    .tags
+                    .push(Tag::new(TAG_SOURCEIP, TagValue::U32(u32::from(*from.ip()))));
+                let load = self.store.publish(StoreKind::Source, target, entry);
+                self.acknowledge_publish(target, load, from);
+            }
+            KadPacket::PublishNotesRequest { target, entry } => {
+                let load = self.store.publish(StoreKind::Notes, target, entry);
+                self.acknowledge_publish(target, load, from);
+            }
+            KadPacket::PublishResponse { target, .. } => {
+                if let Some(lookup) = self
+                    .lookups
+                    .iter_mut()
+                    .find(|l| l.target == target && l.is_requesting() && l.kind.is_publish())
+                {
+                    lookup.published += 1;
+                }
+            }
+            KadPacket::Ping => self.send(&KadPacket::Pong { port: from.port() }, from),
+            KadPacket::Pong { .. } => {}
+            KadPacket::FirewalledRequest { tcp_port, .. } => {
+                self.send(&KadPacket::FirewalledResponse { ip: *from.ip() }, from);
+                self.test_tcp_port(SocketAddrV4::new(*from.ip(), tcp_port), from);
+            }
+            KadPacket::FirewalledResponse { ip } => {
+                if let Some(check) = &mut self.firewall_check {
+                    check.response(from, ip);
+                }
+            }
+            KadPacket::FirewalledAck => {
+                if let Some(check) = &mut self.firewall_check {
+                    check.ack(from);
+                }
+            }
+            KadPacket::FindBuddyRequest {
+                buddy_id,
+                user_hash,
+                ..
+            } => {
+                // Only an open node can be reached by the firewalled
+                // client's callers, and we serve one client at a time.
+                if self.firewall == KadFirewallStatus::Open && self.served_buddy.is_none() {
+                    let response = KadPacket::FindBuddyResponse {
+                        check: buddy_id,
+                        user_hash: self.user_hash,
+                        tcp_port: self.tcp_port,
+                    };
+                    self.send(&response, from);
+                    self.send_event(KadEvents::BuddyRequested {
+                        user_hash,
+                        buddy_id,
+                    });
+                } else {
+                    debug!("Not offering to be the buddy of {from}");
+                }
+            }
+            KadPacket::FindBuddyResponse {
+                check,
+                user_hash,
+                tcp_port,
+            } => {
+                let searching = self
+                    .lookups
+                    .iter()
+                    .any(|l| l.id == LookupId::FIND_BUDDY && l.is_requesting());
+                if searching
+                    && check == buddy_id(self.id())
+                    && self.firewall == KadFirewallStatus::Firewalled
+                {
+                    self.send_event(KadEvents::BuddyOffered(Buddy {
+                        user_hash,
+                        tcp_addr: SocketAddrV4::new(*from.ip(), tcp_port),
+                        udp_addr: from,
+                    }));
+                }
+            }
+            KadPacket::CallbackRequest {
+                buddy_id,
+                file_id,
+                tcp_port,
+            } => {
+                if self.served_buddy == Some(buddy_id) {
+                    self.send_event(KadEvents::CallbackForBuddy {
+                        addr: SocketAddrV4::new(*from.ip(), tcp_port),
+                        file: Ed2kHash::new(file_id.to_be_bytes()),
+                    });
+                } else {
+                    debug!("Ignoring callback request from {from}, we are not the buddy");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tests the TCP port of a node which asked us to, in the background.
+    /// As in eMule the port only counts as open if the client behind it
+    /// answers our hello. Only success is reported.
+    fn test_tcp_port(&self, addr: SocketAddrV4, requester: SocketAddrV4) {
+        let sender = self.tcp_checks_sender.clone();
+        let admission = self.admission.clone();
+        let mut hello = peer::HelloInfo::new(
+            UserHash::new(self.user_hash.to_be_bytes()),
+            String::new(),
+            0,
+            self.tcp_port,
+        );
+        hello.kad_udp_port = self.local_addr().ok().map(|addr| addr.port());
+
+        tokio::spawn(async move {
+            let test = async {
+                let (stream, _permit) = admission.connect(addr.into()).await?;
+                let mut conn = PeerConnection::new(stream, addr.into());
+                conn.hello(&hello).await?;
+                anyhow::Ok(())
+            };
+            match time::timeout(Self::TCP_CHECK_TIMEOUT, test).await {
+                Ok(Ok(())) => _ = sender.send(requester),
+                Ok(Err(e)) => debug!("TCP port test of {addr} failed: {e}"),
+                Err(_) => debug!("TCP port test of {addr} timed out"),
+            }
+        });
+    }
+
+    fn poll_firewall(&mut self) {
+        let now = Instant::now();
+
+        if let Some(result) = self.firewall_check.as_ref().and_then(|c| c.poll(now)) {
+            self.firewall_check = None;
+            self.next_firewall_check = Some(now + Self::FIREWALL_RECHECK);
+            self.firewall_checked(result);
+        }
+
+        if self.next_firewall_check.is_some_and(|at| now >= at) {
+            self.start_firewall_check();
+        }
+
+        if self.firewall == KadFirewallStatus::Firewalled
+            && self.next_buddy_search.is_some_and(|at| now >= at)
+        {
+            self.next_buddy_search = None;
+            let kind = LookupKind::FindBuddy {
+                user_hash: self.user_hash,
+                tcp_port: self.tcp_port,
+            };
+            self.start_lookup(LookupId::FIND_BUDDY, buddy_id(self.id()), kind);
+        }
+    }
+
+    fn firewall_checked(&mut self, result: FirewallCheckResult) {
+        self.public_ip = result.public_ip.or(self.public_ip);
+
+        self.firewall = match self.firewall {
+            _ if result.tcp_open => KadFirewallStatus::Open,
+            KadFirewallStatus::Buddy(buddy) => KadFirewallStatus::Buddy(buddy),
+            _ => {
+                self.next_buddy_search = Some(Instant::now());
+                KadFirewallStatus::Firewalled
+            }
+        };
+
+        self.send_firewall_status();
+    }
+
+    fn send_firewall_status(&self) {
+        self.send_event(KadEvents::FirewallStatus {
+            status: self.firewall,
+            udp_open: self.udp_open,
+            public_ip: self.public_ip,
+        });
+    }
+
+    fn add_hello(&mut self, hello: &HelloInfo, from: SocketAddrV4) {
+        self.routing.add(Contact {
+            id: hello.id,
+            addr: from,
+            tcp_port: hello.tcp_port,
+            version: hello.version,
+        });
+    }
+
+    /// Tells the publisher we have stored what it sent. Publishes we
+    /// refused are not answered, as in eMule.
+    fn acknowledge_publish(&mut self, target: KadId, load: Option<u8>, from: SocketAddrV4) {
+        match load {
+            Some(load) => self.send(&KadPacket::PublishResponse { target, load }, from),
+            None => debug!("Refused publish to {target} from {from}"),
+        }
+    }
+
+    fn answer_search(&mut self, kind: StoreKind, target: KadId, from: SocketAddrV4) {
+        let entries = self.store.get(kind, &target);
+        for chunk in entries.chunks(Self::MAX_ENTRIES_PER_RESPONSE) {
+            let answer = KadPacket::SearchResponse {
+                sender: self.id(),
+                target,
+                entries: chunk.to_vec(),
+            };
+            self.send(&answer, from);
+        }
+    }
+
+    fn poll_lookups(&mut self) {
+        let now = Instant::now();
+
+        for mut lookup in std::mem::take(&mut self.lookups) {
+            let actions = lookup.poll(now, &self.options);
+
+            for id in &actions.failed {
+                self.routing.failed(id);
+            }
+
+            for contact in &actions.ask {
+                let request = KadPacket::Request {
+                    kind: lookup.kind.request_type(),
+                    target: lookup.target,
+                    receiver: contact.id,
+                };
+                self.send(&request, contact.addr);
+                // A Kad2 request does not say who it is from, so the nodes
+                // around us only learn about us if we say hello.
+                if lookup.id == LookupId::BOOTSTRAP {
+                    self.send(&KadPacket::HelloRequest(self.hello()), contact.addr);
+                }
+            }
+
+            if let Some(closest) = &actions.converged {
+                if let Some(request) = make_final_request(&lookup) {
+                    for contact in closest {
+                        self.send(&request, contact.addr);
+                    }
+                }
+            }
+
+            if !actions.finished {
+                self.lookups.push(lookup);
+                continue;
+            }
+
+            let evt = if lookup.id == LookupId::BOOTSTRAP {
+                KadEvents::Bootstrapped {
+                    contacts: self.routing.len(),
+                }
+            } else if lookup.id == LookupId::FIND_BUDDY {
+                if self.firewall == KadFirewallStatus::Firewalled {
+                    self.next_buddy_search = Some(now + Self::BUDDY_RETRY);
+                }
+                continue;
+            } else {
+                KadEvents::LookupFinished {
+                    lookup_id: lookup.id,
+                    closest: actions.converged.unwrap_or_default(),
+                    published: lookup.published,
+                }
+            };
+            self.send_event(evt);
+        }
+    }
+}
+
+/// The search or publish request sent to each of the closest nodes once a
+/// lookup has found them.
+fn make_final_request(lookup: &Lookup) -> Option<KadPacket> {
+    let target = lookup.target;
+
+    let packet = match &lookup.kind {
+        LookupKind::FindNode => return None,
+        LookupKind::Keyword => KadPacket::SearchKeyRequest {
+            target,
+            start_position: 0,
+        },
+        LookupKind::Sources { size } => KadPacket::SearchSourceRequest {
+            target,
+            start_position: 0,
+            size: *size,
+        },
+        LookupKind::Notes { size } => KadPacket::SearchNotesRequest {
+            target,
+            size: *size,
+        },
+        LookupKind::PublishKeyword { entries } => KadPacket::PublishKeyRequest {
+            target,
+            entries: entries.clone(),
+        },
+        LookupKind::PublishSource { entry } => KadPacket::PublishSourceRequest {
+            target,
+            entry: entry.clone(),
+        },
+        LookupKind::PublishNotes { entry } => KadPacket::PublishNotesRequest {
+            target,
+            entry: entry.clone(),
+        },
+        LookupKind::FindBuddy {
+            user_hash,
+            tcp_port,
+        } => KadPacket::FindBuddyRequest {
+            buddy_id: target,
+            user_hash: *user_hash,
+            tcp_port: *tcp_port,
+        },
+    };
+
+    Some(packet)
+}
+
+/// Tags describing us as a source of a file, for publishing.
+pub fn make_source_entry(source_id: KadId, tcp_port: u16, size: u64) -> KadEntry {
+    // Source type 1 is a plain high id source, 4 the same for files over
+    // 4GB. The IP Address is filled in by the node we publish to.
+    let source_type = if size > u32::MAX as u64 { 4 } else { 1 };
+    KadEntry {
+        id: source_id,
+        tags: vec![
+            Tag::new(TAG_SOURCETYPE, TagValue::U8(source_type)),
+            Tag::new(TAG_SOURCEPORT, TagValue::U16(tcp_port)),
+        ],
+    }
+}
+
+/// Tags describing us as a firewalled source which can be reached through
+/// its buddy, for publishing. Clients send their callback requests to the
+/// buddy's Kad port with the buddy hash (our buddy id) in them.
+pub fn make_buddy_source_entry(
+    source_id: KadId,
+    tcp_port: u16,
+    size: u64,
+    our_buddy_id: KadId,
+    buddy: &Buddy,
+) -> KadEntry {
+    // Source type 3 is a firewalled source with a buddy, 5 the same for
+    // files over 4GB.
+    let source_type = if size > u32::MAX as u64 { 5 } else { 3 };
+    KadEntry {
+        id: source_id,
+        tags: vec![
+            Tag::new(TAG_SOURCETYPE, TagValue::U8(source_type)),
+            Tag::new(TAG_SOURCEPORT, TagValue::U16(tcp_port)),
+            Tag::new(TAG_SERVERIP, TagValue::U32(u32::from(*buddy.udp_addr.ip()))),
+            Tag::new(TAG_SERVERPORT, TagValue::U16(buddy.udp_addr.port())),
+            Tag::new(TAG_BUDDYHASH, TagValue::String(our_buddy_id.to_string())),
+        ],
+    }
+}
+
+/// Packets which nodes send without us having contacted them first.
+fn is_request(packet: &KadPacket) -> bool {
+    matches!(
+        packet,
+        KadPacket::BootstrapRequest
+            | KadPacket::HelloRequest(_)
+            | KadPacket::Request { .. }
+            | KadPacket::SearchKeyRequest { .. }
+            | KadPacket::SearchSourceRequest { .. }
+            | KadPacket::SearchNotesRequest { .. }
+            | KadPacket::PublishKeyRequest { .. }
+            | KadPacket::PublishSourceRequest { .. }
+            | KadPacket::PublishNotesRequest { .. }
+            | KadPacket::Ping
+            | KadPacket::FirewalledRequest { .. }
+            | KadPacket::FindBuddyRequest { .. }
+            | KadPacket::CallbackRequest { .. }
+    )
+}
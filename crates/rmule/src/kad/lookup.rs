@@ -0,0 +1,296 @@
+use super::{Contact, KadEntry, KadId, KadOptions, LookupId, K};
+use crate::protocol::opcodes::*;
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddrV4;
+use tokio::time::Instant;
+
+/// What a lookup does once it has found the nodes closest to its target.
+#[derive(Debug, Clone)]
+pub enum LookupKind {
+    /// Nothing: the closest nodes are the answer.
+    FindNode,
+    /// Asks them for files with the keyword the target was made from.
+    Keyword,
+    /// Asks them for sources of the file.
+    Sources { size: u64 },
+    /// Asks them for notes (comments and ratings) on the file.
+    Notes { size: u64 },
+    /// Stores files under the keyword on them.
+    PublishKeyword { entries: Vec<KadEntry> },
+    /// Stores us as a source of the file on them.
+    PublishSource { entry: KadEntry },
+    /// Stores our note on the file on them.
+    PublishNotes { entry: KadEntry },
+    /// Asks them to be our buddy. The target is our buddy id.
+    FindBuddy { user_hash: KadId, tcp_port: u16 },
+}
+
+impl LookupKind {
+    /// The KADEMLIA2_REQ type, which also says how many contacts to return.
+    pub fn request_type(&self) -> u8 {
+        match self {
+            Self::FindNode => KADEMLIA_FIND_NODE,
+            Self::Keyword | Self::Sources { .. } | Self::Notes { .. } | Self::FindBuddy { .. } => {
+                KADEMLIA_FIND_VALUE
+            }
+            _ => KADEMLIA_STORE,
+        }
+    }
+
+    pub fn is_search(&self) -> bool {
+        matches!(
+            self,
+            Self::Keyword | Self::Sources { .. } | Self::Notes { .. }
+        )
+    }
+
+    pub fn is_publish(&self) -> bool {
+        matches!(
+            self,
+            Self::PublishKeyword { .. } | Self::PublishSource { .. } | Self::PublishNotes { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CandidateState {
+    NotAsked,
+    Asked(Instant),
+    Answered,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Asking nodes for contacts closer to the target.
+    Searching,
+    /// The closest nodes have been sent the search or publish request, and
+    /// we are collecting their answers until the deadline.
+    Requesting(Instant),
+    Finished,
+}
+
+/// What the node has to do for a lookup after polling it.
+#[derive(Debug, Default)]
+pub struct LookupActions {
+    /// Contacts to send a KADEMLIA2_REQ to.
+    pub ask: Vec<Contact>,
+    /// Contacts which did not answer in time.
+    pub failed: Vec<KadId>,
+    /// Set once, when the closest nodes have been found.
+    pub converged: Option<Vec<Contact>>,
+    pub finished: bool,
+}
+
+/// An iterative Kademlia lookup. We keep the candidates sorted by their
+/// distance to the target and ask the closest ones we have not asked yet
+/// (a few at a time) for even closer contacts. Once the K closest we know
+/// of have all answered there is nobody closer to find.
+#[derive(Debug)]
+pub struct Lookup {
+    pub id: LookupId,
+    pub target: KadId,
+    pub kind: LookupKind,
+    candidates: BTreeMap<KadId, (Contact, CandidateState)>,
+    phase: Phase,
+    deadline: Instant,
+    /// Ids of the results already reported, as several nodes will usually
+    /// return the same ones.
+    seen: HashSet<KadId>,
+    /// How many nodes acknowledged a publish.
+    pub published: usize,
+}
+
+impl Lookup {
+    /// How many requests are outstanding at once.
+    const ALPHA: usize = 3;
+
+    pub fn new(
+        id: LookupId,
+        target: KadId,
+        kind: LookupKind,
+        contacts: Vec<Contact>,
+        options: &KadOptions,
+    ) -> Self {
+        let mut lookup = Self {
+            id,
+            target,
+            kind,
+            candidates: BTreeMap::new(),
+            phase: Phase::Searching,
+            deadline: Instant::now() + options.lookup_timeout,
+            seen: HashSet::new(),
+            published: 0,
+        };
+        lookup.add_candidates(contacts);
+        lookup
+    }
+
+    pub fn is_searching(&self) -> bool {
+        self.phase == Phase::Searching
+    }
+
+    pub fn is_requesting(&self) -> bool {
+        matches!(self.phase, Phase::Requesting(_))
+    }
+
+    fn add_candidates(&mut self, contacts: Vec<Contact>) {
+        for contact in contacts {
+            self.candidates
+                .entry(contact.id.distance(&self.target))
+                .or_insert((contact, CandidateState::NotAsked));
+        }
+    }
+
+    /// Handles a KADEMLIA2_RES. Returns false if we did not ask `from`.
+    pub fn answered(&mut self, from: SocketAddrV4, contacts: Vec<Contact>) -> bool {
+        let asked = self
+            .candidates
+            .values_mut()
+            .find(|(c, state)| c.addr == from && matches!(state, CandidateState::Asked(_)));
+
+        match asked {
+            Some((_, state)) => {
+                *state = CandidateState::Answered;
+                self.add_candidates(contacts);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the entries we have not seen before.
+    pub fn new_results(&mut self, entries: Vec<KadEntry>) -> Vec<KadEntry> {
+        entries
+            .into_iter()
+            .filter(|e| self.seen.insert(e.id))
+            .collect()
+    }
+
+    /// Moves the lookup along: times out requests, picks who to ask next
+    /// and notices when it has converged or finished.
+    pub fn poll(&mut self, now: Instant, options: &KadOptions) -> LookupActions {
+        let mut actions = LookupActions::default();
+
+        match self.phase {
+            Phase::Finished => {
+                actions.finished = true;
+                return actions;
+            }
+            Phase::Requesting(until) => {
+                if now >= until {
+                    self.phase = Phase::Finished;
+                    actions.finished = true;
+                }
+                return actions;
+            }
+            Phase::Searching => {}
+        }
+
+        let mut in_flight = 0;
+        for (contact, state) in self.candidates.values_mut() {
+            if let CandidateState::Asked(at) = *state {
+                if now >= at + options.request_timeout {
+                    *state = CandidateState::Failed;
+                    actions.failed.push(contact.id);
+                } else {
+                    in_flight += 1;
+                }
+            }
+        }
+
+        let closest: Vec<_> = self
+            .candidates
+            .values_mut()
+            .filter(|(_, state)| *state != CandidateState::Failed)
+            .take(K)
+            .collect();
+        let done = closest
+            .iter()
+            .all(|(_, state)| *state == CandidateState::Answered);
+
+        if done || now >= self.deadline {
+            let answered: Vec<_> = self
+                .candidates
+                .values()
+                .filter(|(_, state)| *state == CandidateState::Answered)
+                .map(|(c, _)| c.clone())
+                .take(K)
+                .collect();
+
+            if matches!(self.kind, LookupKind::FindNode) || answered.is_empty() {
+                self.phase = Phase::Finished;
+                actions.finished = true;
+            } else {
+                self.phase = Phase::Requesting(now + options.answer_timeout);
+            }
+            actions.converged = Some(answered);
+            return actions;
+        }
+
+        for (contact, state) in closest {
+            if in_flight >= Self::ALPHA {
+                break;
+            }
+            if *state == CandidateState::NotAsked {
+                *state = CandidateState::Asked(now);
+                actions.ask.push(contact.clone());
+                in_flight += 1;
+            }
+        }
+
+        actions
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    fn contact(id: u128) -> Contact {
+        Contact {
+            id: KadId::new(id),
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, id as u16),
+            tcp_port: 0,
+            version: 8,
+        }
+    }
+
+    #[test]
+    pub fn test_lookup_asks_closer_nodes_until_converged() {
+        let options = KadOptions::default();
+        let contacts = (100..105).map(contact).collect();
+        let mut lookup = Lookup::new(
+            LookupId::BOOTSTRAP,
+            KadId::new(0),
+            LookupKind::FindNode,
+            contacts,
+            &options,
+        );
+
+        let now = Instant::now();
+        let actions = lookup.poll(now, &options);
+        let asked: Vec<_> = actions.ask.iter().map(|c| c.id).collect();
+        assert_eq!(asked, [KadId::new(100), KadId::new(101), KadId::new(102)]);
+
+        // Node 100 knows closer ones, which are asked next.
+        assert!(lookup.answered(contact(100).addr, vec![contact(1), contact(2)]));
+        let actions = lookup.poll(now, &options);
+        let asked: Vec<_> = actions.ask.iter().map(|c| c.id).collect();
+        assert_eq!(asked, [KadId::new(1)]);
+
+        // Everyone else times out, apart from node 1.
+        assert!(lookup.answered(contact(1).addr, vec![]));
+        let later = now + options.request_timeout + Duration::from_millis(1);
+        let actions = lookup.poll(later, &options);
+        assert_eq!(actions.failed.len(), 2);
+        assert_eq!(actions.ask.len(), 3);
+        let actions = lookup.poll(later + options.request_timeout, &options);
+        assert!(actions.finished);
+
+        let found: Vec<_> = actions.converged.unwrap().iter().map(|c| c.id).collect();
+        assert_eq!(found, [KadId::new(1), KadId::new(100)]);
+    }
+}
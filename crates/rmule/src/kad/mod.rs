@@ -0,0 +1,30 @@
+//! The Kademlia (Kad) network, which finds files and sources without any
+//! servers. Every node has a 128-bit id, and information about a keyword
+//! or file is stored on the nodes whose ids are closest to the keyword's or
+//! file's id. Finding those nodes is a lookup: each node knows many nodes
+//! close to itself and a few far away, so asking the closest ones we know
+//! for closer ones gets there in a few steps. See the Kademlia paper by
+//! Maymounkov and Mazières, and the Kademlia directory in the eMule sources
+//! for the wire details (we only speak Kad2).
+
+mod buddy;
+mod firewall;
+mod kad_id;
+mod kad_manager;
+mod kad_node;
+mod lookup;
+mod packets;
+mod preferences;
+mod routing_table;
+mod store;
+
+pub use buddy::*;
+pub use firewall::*;
+pub use kad_id::*;
+pub use kad_manager::*;
+pub use kad_node::*;
+pub use lookup::*;
+pub use packets::*;
+pub use preferences::*;
+pub use routing_table::*;
+pub use store::*;
@@ -0,0 +1,629 @@
+use super::{Contact, KadId};
+use crate::protocol::opcodes::*;
+use crate::protocol::{read_tags, Packet, Tag};
+use anyhow::{bail, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Cursor;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// The Kad version we speak. Version 8 is Kad2 with large files and
+/// notes, as spoken by eMule 0.49c and later.
+pub const KADEMLIA_VERSION: u8 = 8;
+
+/// Something stored in Kad under a target id: a file under a keyword, a
+/// source or a note under a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KadEntry {
+    /// The file (for keywords) or the client (for sources and notes).
+    pub id: KadId,
+    pub tags: Vec<Tag>,
+}
+
+impl KadEntry {
+    pub fn tag(&self, id: u8) -> Option<&Tag> {
+        self.tags.iter().find(|t| t.is(id))
+    }
+}
+
+/// The Kad2 messages we understand. See the KADEMLIA2_xxx opcodes, and the
+/// KADEMLIA_xxx ones Kad2 kept from Kad1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KadPacket {
+    BootstrapRequest,
+    BootstrapResponse {
+        id: KadId,
+        tcp_port: u16,
+        version: u8,
+        contacts: Vec<Contact>,
+    },
+    HelloRequest(HelloInfo),
+    HelloResponse(HelloInfo),
+    /// Asks for the contacts the receiver knows closest to the target.
+    Request {
+        /// One of KADEMLIA_FIND_VALUE, KADEMLIA_STORE or KADEMLIA_FIND_NODE.
+        kind: u8,
+        target: KadId,
+        /// Who we think we are asking, so that a node which has changed id
+        /// does not answer.
+        receiver: KadId,
+    },
+    Response {
+        target: KadId,
+        contacts: Vec<Contact>,
+    },
+    /// Searches for files by keyword. We do not send (and ignore) the
+    /// optional search tree, so all files for the keyword are returned.
+    SearchKeyRequest {
+        target: KadId,
+        start_position: u16,
+    },
+    SearchSourceRequest {
+        target: KadId,
+        start_position: u16,
+        size: u64,
+    },
+    SearchNotesRequest {
+        target: KadId,
+        size: u64,
+    },
+    SearchResponse {
+        sender: KadId,
+        target: KadId,
+        entries: Vec<KadEntry>,
+    },
+    PublishKeyRequest {
+        target: KadId,
+        entries: Vec<KadEntry>,
+    },
+    PublishSourceRequest {
+        target: KadId,
+        entry: KadEntry,
+    },
+    PublishNotesRequest {
+        target: KadId,
+        entry: KadEntry,
+    },
+    PublishResponse {
+        target: KadId,
+        /// How full the answering node's store for the target is, 0-100.
+        load: u8,
+    },
+    Ping,
+    /// The answer to a ping, with the UDP port the ping came from as seen
+    /// by the receiver.
+    Pong {
+        port: u16,
+    },
+    /// Asks the receiver to test whether it can connect to our TCP port.
+    /// It answers with our IP Address straight away, and acknowledges
+    /// separately if connecting worked.
+    FirewalledRequest {
+        tcp_port: u16,
+        user_hash: KadId,
+        /// The CONNECT_OPTIONS bits saying how we accept connections.
+        connect_options: u8,
+    },
+    /// Our IP Address, as seen by the receiver of a FirewalledRequest.
+    FirewalledResponse {
+        ip: Ipv4Addr,
+    },
+    FirewalledAck,
+    /// Asks a node to be our buddy. `buddy_id` is the lookup target,
+    /// which is our Kad id with all the bits flipped.
+    FindBuddyRequest {
+        buddy_id: KadId,
+        user_hash: KadId,
+        tcp_port: u16,
+    },
+    /// Offers to be a buddy. `check` is the buddy id from the request.
+    FindBuddyResponse {
+        check: KadId,
+        user_hash: KadId,
+        tcp_port: u16,
+    },
+    /// Asks a buddy to tell its firewalled client to connect to us.
+    CallbackRequest {
+        buddy_id: KadId,
+        file_id: KadId,
+        tcp_port: u16,
+    },
+}
+
+/// What a node tells another about itself when they first talk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HelloInfo {
+    pub id: KadId,
+    pub tcp_port: u16,
+    pub version: u8,
+    pub tags: Vec<Tag>,
+}
+
+impl KadPacket {
+    /// The most contacts we put in a bootstrap response.
+    pub const MAX_BOOTSTRAP_CONTACTS: usize = 20;
+
+    pub fn to_packet(&self) -> Packet {
+        let mut p = Vec::new();
+
+        let opcode = match self {
+            Self::BootstrapRequest => KADEMLIA2_BOOTSTRAP_REQ,
+            Self::BootstrapResponse {
+                id,
+                tcp_port,
+                version,
+                contacts,
+            } => {
+                id.write(&mut p);
+                p.write_u16::<LittleEndian>(*tcp_port).unwrap();
+                p.push(*version);
+                p.write_u16::<LittleEndian>(contacts.len() as u16).unwrap();
+                for contact in contacts {
+                    write_contact(&mut p, contact);
+                }
+                KADEMLIA2_BOOTSTRAP_RES
+            }
+            Self::HelloRequest(hello) | Self::HelloResponse(hello) => {
+                hello.id.write(&mut p);
+                p.write_u16::<LittleEndian>(hello.tcp_port).unwrap();
+                p.push(hello.version);
+                write_kad_tags(&mut p, &hello.tags);
+                match self {
+                    Self::HelloRequest(_) => KADEMLIA2_HELLO_REQ,
+                    _ => KADEMLIA2_HELLO_RES,
+                }
+            }
+            Self::Request {
+                kind,
+                target,
+                receiver,
+            } => {
+                p.push(*kind);
+                target.write(&mut p);
+                receiver.write(&mut p);
+                KADEMLIA2_REQ
+            }
+            Self::Response { target, contacts } => {
+                target.write(&mut p);
+                p.push(contacts.len() as u8);
+                for contact in contacts {
+                    write_contact(&mut p, contact);
+                }
+                KADEMLIA2_RES
+            }
+            Self::SearchKeyRequest {
+                target,
+                start_position,
+            } => {
+                target.write(&mut p);
+                p.write_u16::<LittleEndian>(start_position & 0x7FFF)
+                    .unwrap();
+                KADEMLIA2_SEARCH_KEY_REQ
+            }
+            Self::SearchSourceRequest {
+                target,
+                start_position,
+                size,
+            } => {
+                target.write(&mut p);
+                p.write_u16::<LittleEndian>(*start_position).unwrap();
+                p.write_u64::<LittleEndian>(*size).unwrap();
+                KADEMLIA2_SEARCH_SOURCE_REQ
+            }
+            Self::SearchNotesRequest { target, size } => {
+                target.write(&mut p);
+                p.write_u64::<LittleEndian>(*size).unwrap();
+                KADEMLIA2_SEARCH_NOTES_REQ
+            }
+            Self::SearchResponse {
+                sender,
+                target,
+                entries,
+            } => {
+                sender.write(&mut p);
+                target.write(&mut p);
+                p.write_u16::<LittleEndian>(entries.len() as u16).unwrap();
+                for entry in entries {
+                    write_entry(&mut p, entry);
+                }
+                KADEMLIA2_SEARCH_RES
+            }
+            Self::PublishKeyRequest { target, entries } => {
+                target.write(&mut p);
+                p.write_u16::<LittleEndian>(entries.len() as u16).unwrap();
+                for entry in entries {
+                    write_entry(&mut p, entry);
+                }
+                KADEMLIA2_PUBLISH_KEY_REQ
+            }
+            Self::PublishSourceRequest { target, entry }
+            | Self::PublishNotesRequest { target, entry } => {
+                target.write(&mut p);
+                write_entry(&mut p, entry);
+                match self {
+                    Self::PublishSourceRequest { .. } => KADEMLIA2_PUBLISH_SOURCE_REQ,
+                    _ => KADEMLIA2_PUBLISH_NOTES_REQ,
+                }
+            }
+            Self::PublishResponse { target, load } => {
+                target.write(&mut p);
+                p.push(*load);
+                KADEMLIA2_PUBLISH_RES
+            }
+            Self::Ping => KADEMLIA2_PING,
+            Self::Pong { port } => {
+                p.write_u16::<LittleEndian>(*port).unwrap();
+                KADEMLIA2_PONG
+            }
+            Self::FirewalledRequest {
+                tcp_port,
+                user_hash,
+                connect_options,
+            } => {
+                p.write_u16::<LittleEndian>(*tcp_port).unwrap();
+                user_hash.write(&mut p);
+                p.push(*connect_options);
+                KADEMLIA_FIREWALLED2_REQ
+            }
+            Self::FirewalledResponse { ip } => {
+                p.write_u32::<LittleEndian>(u32::from(*ip)).unwrap();
+                KADEMLIA_FIREWALLED_RES
+            }
+            Self::FirewalledAck => KADEMLIA_FIREWALLED_ACK_RES,
+            Self::FindBuddyRequest {
+                buddy_id: id,
+                user_hash,
+                tcp_port,
+            }
+            | Self::FindBuddyResponse {
+                check: id,
+                user_hash,
+                tcp_port,
+            } => {
+                id.write(&mut p);
+                user_hash.write(&mut p);
+                p.write_u16::<LittleEndian>(*tcp_port).unwrap();
+                match self {
+                    Self::FindBuddyRequest { .. } => KADEMLIA_FINDBUDDY_REQ,
+                    _ => KADEMLIA_FINDBUDDY_RES,
+                }
+            }
+            Self::CallbackRequest {
+                buddy_id,
+                file_id,
+                tcp_port,
+            } => {
+                buddy_id.write(&mut p);
+                file_id.write(&mut p);
+                p.write_u16::<LittleEndian>(*tcp_port).unwrap();
+                KADEMLIA_CALLBACK_REQ
+            }
+        };
+
+        Packet::kad(opcode, p)
+    }
+
+    /// Parses a Kad packet. Returns Ok(None) for opcodes we do not handle.
+    pub fn from_packet(packet: &Packet) -> Result<Option<Self>> {
+        if packet.protocol != OP_KADEMLIAHEADER {
+            bail!("Protocol {:#04x} is not Kademlia", packet.protocol);
+        }
+
+        let mut input = Cursor::new(&packet.payload[..]);
+        let input = &mut input;
+
+        let kad_packet = match packet.opcode {
+            KADEMLIA2_BOOTSTRAP_REQ => Self::BootstrapRequest,
+            KADEMLIA2_BOOTSTRAP_RES => {
+                let id = KadId::read(input)?;
+                let tcp_port = input.read_u16::<LittleEndian>()?;
+                let version = input.read_u8()?;
+                let count = input.read_u16::<LittleEndian>()?;
+                let contacts = read_contacts(input, count as usize)?;
+                Self::BootstrapResponse {
+                    id,
+                    tcp_port,
+                    version,
+                    contacts,
+                }
+            }
+            KADEMLIA2_HELLO_REQ | KADEMLIA2_HELLO_RES => {
+                let hello = HelloInfo {
+                    id: KadId::read(input)?,
+                    tcp_port: input.read_u16::<LittleEndian>()?,
+                    version: input.read_u8()?,
+                    tags: read_kad_tags(input)?,
+                };
+                if packet.opcode == KADEMLIA2_HELLO_REQ {
+                    Self::HelloRequest(hello)
+                } else {
+                    Self::HelloResponse(hello)
+                }
+            }
+            KADEMLIA2_REQ => Self::Request {
+                kind: input.read_u8()? & 0x1F,
+                target: KadId::read(input)?,
+                receiver: KadId::read(input)?,
+            },
+            KADEMLIA2_RES => {
+                let target = KadId::read(input)?;
+                let count = input.read_u8()?;
+                Self::Response {
+                    target,
+                    contacts: read_contacts(input, count as usize)?,
+                }
+            }
+            KADEMLIA2_SEARCH_KEY_REQ => Self::SearchKeyRequest {
+                target: KadId::read(input)?,
+                // The top bit says a search tree follows.
+                start_position: input.read_u16::<LittleEndian>()? & 0x7FFF,
+            },
+            KADEMLIA2_SEARCH_SOURCE_REQ => Self::SearchSourceRequest {
+                target: KadId::read(input)?,
+                start_position: input.read_u16::<LittleEndian>()?,
+                size: input.read_u64::<LittleEndian>()?,
+            },
+            KADEMLIA2_SEARCH_NOTES_REQ => Self::SearchNotesRequest {
+                target: KadId::read(input)?,
+                size: input.read_u64::<LittleEndian>()?,
+            },
+            KADEMLIA2_SEARCH_RES => {
+                let sender = KadId::read(input)?;
+                let target = KadId::read(input)?;
+                let count = input.read_u16::<LittleEndian>()?;
+                Self::SearchResponse {
+                    sender,
+                    target,
+                    entries: read_entries(input, count as usize)?,
+                }
+            }
+            KADEMLIA2_PUBLISH_KEY_REQ => {
+                let target = KadId::read(input)?;
+                let count = input.read_u16::<LittleEndian>()?;
+                Self::PublishKeyRequest {
+                    target,
+                    entries: read_entries(input, count as usize)?,
+                }
+            }
+            KADEMLIA2_PUBLISH_SOURCE_REQ => Self::PublishSourceRequest {
+                target: KadId::read(input)?,
+                entry: read_entry(input)?,
+            },
+            KADEMLIA2_PUBLISH_NOTES_REQ => Self::PublishNotesRequest {
+                target: KadId::read(input)?,
+                entry: read_entry(input)?,
+            },
+            KADEMLIA2_PUBLISH_RES => Self::PublishResponse {
+                target: KadId::read(input)?,
+                load: input.read_u8()?,
+            },
+            KADEMLIA2_PING => Self::Ping,
+            KADEMLIA2_PONG => Self::Pong {
+                port: input.read_u16::<LittleEndian>()?,
+            },
+            KADEMLIA_FIREWALLED2_REQ => Self::FirewalledRequest {
+                tcp_port: input.read_u16::<LittleEndian>()?,
+                user_hash: KadId::read(input)?,
+                connect_options: input.read_u8()?,
+            },
+            KADEMLIA_FIREWALLED_RES => Self::FirewalledResponse {
+                ip: Ipv4Addr::from(input.read_u32::<LittleEndian>()?),
+            },
+            KADEMLIA_FIREWALLED_ACK_RES => Self::FirewalledAck,
+            // Newer clients add their connect options to both of these,
+            // which we do not need.
+            KADEMLIA_FINDBUDDY_REQ => Self::FindBuddyRequest {
+                buddy_id: KadId::read(input)?,
+                user_hash: KadId::read(input)?,
+                tcp_port: input.read_u16::<LittleEndian>()?,
+            },
+            KADEMLIA_FINDBUDDY_RES => Self::FindBuddyResponse {
+                check: KadId::read(input)?,
+                user_hash: KadId::read(input)?,
+                tcp_port: input.read_u16::<LittleEndian>()?,
+            },
+            KADEMLIA_CALLBACK_REQ => Self::CallbackRequest {
+                buddy_id: KadId::read(input)?,
+                file_id: KadId::read(input)?,
+                tcp_port: input.read_u16::<LittleEndian>()?,
+            },
+            _ => return Ok(None),
+        };
+
+        Ok(Some(kad_packet))
+    }
+}
+
+/// Contacts on the wire. Unlike on the ed2k network, Kad sends IP
+/// addresses as (little-endian) numbers, so 1.2.3.4 is 0x01020304.
+fn write_contact(out: &mut Vec<u8>, contact: &Contact) {
+    contact.id.write(out);
+    out.write_u32::<LittleEndian>(u32::from(*contact.addr.ip()))
+        .unwrap();
+    out.write_u16::<LittleEndian>(contact.addr.port()).unwrap();
+    out.write_u16::<LittleEndian>(contact.tcp_port).unwrap();
+    out.push(contact.version);
+}
+
+fn read_contacts(input: &mut Cursor<&[u8]>, count: usize) -> Result<Vec<Contact>> {
+    let mut contacts = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let id = KadId::read(input)?;
+        let ip = Ipv4Addr::from(input.read_u32::<LittleEndian>()?);
+        let udp_port = input.read_u16::<LittleEndian>()?;
+        contacts.push(Contact {
+            id,
+            addr: SocketAddrV4::new(ip, udp_port),
+            tcp_port: input.read_u16::<LittleEndian>()?,
+            version: input.read_u8()?,
+        });
+    }
+    Ok(contacts)
+}
+
+/// Kad tag lists have a u8 count, and the tags are always in the old
+/// (non-compact) format.
+fn write_kad_tags(out: &mut Vec<u8>, tags: &[Tag]) {
+    out.push(tags.len() as u8);
+    for tag in tags {
+        tag.write(out);
+    }
+}
+
+fn read_kad_tags(input: &mut Cursor<&[u8]>) -> Result<Vec<Tag>> {
+    let count = input.read_u8()?;
+    read_tags(input, count as usize)
+}
+
+fn write_entry(out: &mut Vec<u8>, entry: &KadEntry) {
+    entry.id.write(out);
+    write_kad_tags(out, &entry.tags);
+}
+
+fn read_entry(input: &mut Cursor<&[u8]>) -> Result<KadEntry> {
+    Ok(KadEntry {
+        id: KadId::read(input)?,
+        tags: read_kad_tags(input)?,
+    })
+}
+
+fn read_entries(input: &mut Cursor<&[u8]>, count: usize) -> Result<Vec<KadEntry>> {
+    let mut entries = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        entries.push(read_entry(input)?);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::TagValue;
+
+    fn contact() -> Contact {
+        Contact {
+            id: KadId::new(42),
+            addr: "1.2.3.4:4672".parse().unwrap(),
+            tcp_port: 4662,
+            version: KADEMLIA_VERSION,
+        }
+    }
+
+    fn entry() -> KadEntry {
+        KadEntry {
+            id: KadId::new(7),
+            tags: vec![
+                Tag::new(FT_FILENAME, TagValue::String("ubuntu.iso".to_owned())),
+                Tag::new(FT_FILESIZE, TagValue::U32(1000)),
+            ],
+        }
+    }
+
+    fn assert_roundtrip(kad_packet: KadPacket) {
+        let bytes = kad_packet.to_packet().to_udp_bytes();
+        assert_eq!(bytes[0], OP_KADEMLIAHEADER);
+        let packet = Packet::from_udp_bytes(&bytes).unwrap();
+        assert_eq!(KadPacket::from_packet(&packet).unwrap(), Some(kad_packet));
+    }
+
+    #[test]
+    pub fn test_bootstrap_packets_roundtrip() {
+        assert_roundtrip(KadPacket::BootstrapRequest);
+        assert_roundtrip(KadPacket::BootstrapResponse {
+            id: KadId::new(1),
+            tcp_port: 4662,
+            version: KADEMLIA_VERSION,
+            contacts: vec![contact()],
+        });
+    }
+
+    #[test]
+    pub fn test_hello_packets_roundtrip() {
+        assert_roundtrip(KadPacket::HelloRequest(HelloInfo {
+            id: KadId::new(1),
+            tcp_port: 4662,
+            version: KADEMLIA_VERSION,
+            tags: vec![],
+        }));
+        assert_roundtrip(KadPacket::Pong { port: 4672 });
+    }
+
+    #[test]
+    pub fn test_contact_lookup_packets_roundtrip() {
+        assert_roundtrip(KadPacket::Request {
+            kind: KADEMLIA_FIND_NODE,
+            target: KadId::new(2),
+            receiver: KadId::new(3),
+        });
+        assert_roundtrip(KadPacket::Response {
+            target: KadId::new(2),
+            contacts: vec![contact()],
+        });
+    }
+
+    #[test]
+    pub fn test_search_packets_roundtrip() {
+        assert_roundtrip(KadPacket::SearchSourceRequest {
+            target: KadId::new(2),
+            start_position: 0,
+            size: 5_000_000_000,
+        });
+        assert_roundtrip(KadPacket::SearchResponse {
+            sender: KadId::new(1),
+            target: KadId::new(2),
+            entries: vec![entry()],
+        });
+    }
+
+    #[test]
+    pub fn test_publish_packets_roundtrip() {
+        assert_roundtrip(KadPacket::PublishKeyRequest {
+            target: KadId::new(2),
+            entries: vec![entry()],
+        });
+        assert_roundtrip(KadPacket::PublishNotesRequest {
+            target: KadId::new(2),
+            entry: entry(),
+        });
+    }
+
+    #[test]
+    pub fn test_firewall_packets_roundtrip() {
+        assert_roundtrip(KadPacket::FirewalledRequest {
+            tcp_port: 4662,
+            user_hash: KadId::new(5),
+            connect_options: 1,
+        });
+        assert_roundtrip(KadPacket::FirewalledResponse {
+            ip: Ipv4Addr::new(1, 2, 3, 4),
+        });
+    }
+
+    #[test]
+    pub fn test_buddy_packets_roundtrip() {
+        assert_roundtrip(KadPacket::FindBuddyResponse {
+            check: KadId::new(6),
+            user_hash: KadId::new(5),
+            tcp_port: 4662,
+        });
+        assert_roundtrip(KadPacket::CallbackRequest {
+            buddy_id: KadId::new(6),
+            file_id: KadId::new(7),
+            tcp_port: 4662,
+        });
+    }
+
+    #[test]
+    pub fn test_contact_ip_is_a_number() {
+        let packet = KadPacket::Response {
+            target: KadId::new(0),
+            contacts: vec![Contact {
+                id: KadId::new(0),
+                addr: "1.2.3.4:1".parse().unwrap(),
+                tcp_port: 2,
+                version: 0,
+            }],
+        };
+        let payload = packet.to_packet().payload;
+        assert_eq!(&payload[33..37], &[4, 3, 2, 1]);
+    }
+}
@@ -0,0 +1,31 @@
+use super::KadId;
+use anyhow::Result;
+use std::io::Cursor;
+use std::path::Path;
+
+/// The file in which our Kad id is kept, so that we stay in the same part
+/// of the network (and keep the information stored with us) across runs.
+pub const KAD_PREFERENCES_FILE: &str = "preferencesKad.dat";
+
+/// Loads our Kad id, creating (and saving) a new random one if there is
+/// none yet. preferencesKad.dat starts with our last known IP Address
+/// and a zero u16, which eMule no longer uses either.
+pub fn load_or_create_kad_id(config_dir: &Path) -> Result<KadId> {
+    let path = config_dir.join(KAD_PREFERENCES_FILE);
+
+    if let Ok(data) = std::fs::read(&path) {
+        let mut input = Cursor::new(&data[..]);
+        input.set_position(6);
+        if let Ok(id) = KadId::read(&mut input) {
+            return Ok(id);
+        }
+    }
+
+    let id = KadId::random();
+    let mut data = vec![0u8; 6];
+    id.write(&mut data);
+    data.push(0);
+    std::fs::write(&path, data)?;
+
+    Ok(id)
+}
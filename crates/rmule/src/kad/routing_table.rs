@@ -0,0 +1,187 @@
+use super::KadId;
+use std::collections::VecDeque;
+use std::net::SocketAddrV4;
+
+/// The number of contacts in a bucket, and the number of closest contacts
+/// a lookup tries to find. eMule calls this K too.
+pub const K: usize = 10;
+
+/// Another Kad node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contact {
+    pub id: KadId,
+    /// The node's UDP address, which is where Kad packets go.
+    pub addr: SocketAddrV4,
+    pub tcp_port: u16,
+    /// The Kad version the node speaks.
+    pub version: u8,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    contact: Contact,
+    /// Requests the contact has not answered since it was last heard from.
+    failures: u8,
+}
+
+/// The k-bucket routing table. Contacts are sorted into buckets by the
+/// number of leading bits their id shares with ours, so we know many nodes
+/// close to us and a few far away, which is what makes lookups converge
+/// in a logarithmic number of steps. Each bucket is kept in least recently
+/// seen order, and long lived contacts are preferred over new ones because
+/// nodes that have been up a while tend to stay up.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    our_id: KadId,
+    buckets: Vec<VecDeque<Entry>>,
+}
+
+impl RoutingTable {
+    /// Contacts which fail this many requests in a row are dropped.
+    const MAX_FAILURES: u8 = 2;
+
+    pub fn new(our_id: KadId) -> Self {
+        Self {
+            our_id,
+            buckets: vec![VecDeque::new(); KadId::BITS as usize],
+        }
+    }
+
+    pub fn our_id(&self) -> KadId {
+        self.our_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bucket for an id, or None for our own id.
+    fn bucket_index(&self, id: &KadId) -> Option<usize> {
+        let distance = self.our_id.distance(id);
+        if distance == KadId::default() {
+            None
+        } else {
+            Some((KadId::BITS - 1 - distance.leading_zeros()) as usize)
+        }
+    }
+
+    /// Adds a contact we have heard from, or refreshes it if we already
+    /// know it. Returns false if its bucket is full of good contacts.
+    pub fn add(&mut self, contact: Contact) -> bool {
+        let Some(idx) = self.bucket_index(&contact.id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[idx];
+
+        if let Some(pos) = bucket.iter().position(|e| e.contact.id == contact.id) {
+            bucket.remove(pos);
+        } else if bucket.len() >= K {
+            // Only make room by evicting the least recently seen contact,
+            // and only if it has stopped answering.
+            match bucket.front() {
+                Some(oldest) if oldest.failures > 0 => {
+                    bucket.pop_front();
+                }
+                _ => return false,
+            }
+        }
+
+        bucket.push_back(Entry {
+            contact,
+            failures: 0,
+        });
+        true
+    }
+
+    /// Records that a contact did not answer a request, dropping it if it
+    /// has not answered several.
+    pub fn failed(&mut self, id: &KadId) {
+        let Some(idx) = self.bucket_index(id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[idx];
+
+        if let Some(pos) = bucket.iter().position(|e| e.contact.id == *id) {
+            bucket[pos].failures += 1;
+            if bucket[pos].failures >= Self::MAX_FAILURES {
+                bucket.remove(pos);
+            }
+        }
+    }
+
+    pub fn contains(&self, id: &KadId) -> bool {
+        self.bucket_index(id)
+            .is_some_and(|idx| self.buckets[idx].iter().any(|e| e.contact.id == *id))
+    }
+
+    pub fn contacts(&self) -> impl Iterator<Item = &Contact> {
+        self.buckets.iter().flatten().map(|e| &e.contact)
+    }
+
+    /// The `count` contacts closest to the target.
+    pub fn closest(&self, target: &KadId, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<_> = self.contacts().cloned().collect();
+        contacts.sort_by_key(|c| c.id.distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn contact(id: u128) -> Contact {
+        Contact {
+            id: KadId::new(id),
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4672),
+            tcp_port: 4662,
+            version: 8,
+        }
+    }
+
+    #[test]
+    pub fn test_buckets_fill_and_evict() {
+        let mut table = RoutingTable::new(KadId::new(0));
+        assert!(!table.add(contact(0)));
+
+        // Ids 1 << 100 .. have their top bit at 100, so share a bucket.
+        let base = 1u128 << 100;
+        for n in 0..K as u128 {
+            assert!(table.add(contact(base + n)));
+        }
+        assert!(!table.add(contact(base + 100)));
+        assert_eq!(table.len(), K);
+
+        // A contact which stops answering makes room, but only once it is
+        // the least recently seen.
+        table.failed(&KadId::new(base + 5));
+        assert!(!table.add(contact(base + 100)));
+        table.failed(&KadId::new(base));
+        assert!(table.add(contact(base + 100)));
+        assert!(!table.contains(&KadId::new(base)));
+
+        table.failed(&KadId::new(base + 5));
+        assert!(!table.contains(&KadId::new(base + 5)));
+    }
+
+    #[test]
+    pub fn test_closest_contacts() {
+        let mut table = RoutingTable::new(KadId::new(0));
+        for id in [1, 2, 3, 8, 9, 1 << 64, 1 << 120] {
+            table.add(contact(id));
+        }
+
+        let closest: Vec<_> = table
+            .closest(&KadId::new(10), 3)
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(closest, [KadId::new(8), KadId::new(9), KadId::new(2)]);
+    }
+}
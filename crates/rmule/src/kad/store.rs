@@ -0,0 +1,163 @@
+use super::{KadEntry, KadId};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// What kind of information is stored under a target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StoreKind {
+    Keyword,
+    Source,
+    Notes,
+}
+
+impl StoreKind {
+    /// How long published information is kept. Publishers republish before
+    /// this runs out, so anything older is from a client that has gone.
+    fn lifetime(&self) -> Duration {
+        match self {
+            Self::Keyword | Self::Notes => Duration::from_secs(24 * 60 * 60),
+            Self::Source => Duration::from_secs(5 * 60 * 60),
+        }
+    }
+}
+
+/// The information other nodes have published to us, because our id is
+/// among the closest to its target.
+#[derive(Debug)]
+pub struct KadStore {
+    our_id: KadId,
+    entries: HashMap<(StoreKind, KadId), Vec<(KadEntry, Instant)>>,
+    /// The number of entries for all the targets together.
+    total: usize,
+}
+
+impl KadStore {
+    /// The most entries kept for one target. Publishing to a full target
+    /// replaces the oldest entry.
+    pub const MAX_ENTRIES: usize = 1000;
+    /// The most targets, and entries over all of them, kept at once. When
+    /// the store is full new entries are refused until old ones expire.
+    pub const MAX_TARGETS: usize = 10_000;
+    pub const MAX_TOTAL_ENTRIES: usize = 100_000;
+    /// Like eMule, we only store information for targets which share at
+    /// least this many leading bits with our id (its tolerance zone).
+    /// Anything further away is not ours to keep.
+    pub const TOLERANCE_BITS: u32 = 8;
+
+    pub fn new(our_id: KadId) -> Self {
+        Self {
+            our_id,
+            entries: HashMap::new(),
+            total: 0,
+        }
+    }
+
+    /// Stores an entry, replacing any earlier one with the same id.
+    /// Returns the load for the target as a percentage, or None if the
+    /// entry was refused because the target is too far from our id or the
+    /// store is full.
+    pub fn publish(&mut self, kind: StoreKind, target: KadId, entry: KadEntry) -> Option<u8> {
+        if self.our_id.distance(&target).leading_zeros() < Self::TOLERANCE_BITS {
+            return None;
+        }
+
+        let key = (kind, target);
+        let existing = self.entries.get(&key);
+        if existing.is_none() && self.entries.len() >= Self::MAX_TARGETS {
+            return None;
+        }
+        // Replacing an entry does not make the store any fuller.
+        let replaces = existing.is_some_and(|entries| {
+            entries.len() >= Self::MAX_ENTRIES || entries.iter().any(|(e, _)| e.id == entry.id)
+        });
+        if !replaces && self.total >= Self::MAX_TOTAL_ENTRIES {
+            return None;
+        }
+
+        let expires = Instant::now() + kind.lifetime();
+        let entries = self.entries.entry(key).or_default();
+        let before = entries.len();
+
+        entries.retain(|(e, _)| e.id != entry.id);
+        if entries.len() >= Self::MAX_ENTRIES {
+            entries.remove(0);
+        }
+        entries.push((entry, expires));
+        self.total = self.total + entries.len() - before;
+
+        Some((entries.len() * 100 / Self::MAX_ENTRIES) as u8)
+    }
+
+    pub fn get(&self, kind: StoreKind, target: &KadId) -> Vec<KadEntry> {
+        let now = Instant::now();
+        self.entries
+            .get(&(kind, *target))
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|(_, expires)| *expires > now)
+                    .map(|(entry, _)| entry.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Throws away expired entries.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        for entries in self.entries.values_mut() {
+            entries.retain(|(_, expires)| *expires > now);
+        }
+        self.entries.retain(|_, entries| !entries.is_empty());
+        self.total = self.entries.values().map(Vec::len).sum();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(n: u128) -> KadEntry {
+        KadEntry {
+            id: KadId::new(n),
+            tags: Vec::new(),
+        }
+    }
+
+    #[test]
+    pub fn test_only_targets_near_us_are_stored() {
+        let mut store = KadStore::new(KadId::new(0xAB << 120));
+
+        let far = KadId::new(0xAC << 120);
+        assert_eq!(store.publish(StoreKind::Source, far, entry(1)), None);
+        assert!(store.get(StoreKind::Source, &far).is_empty());
+
+        let near = KadId::new(0xAB << 120 | 5);
+        assert_eq!(store.publish(StoreKind::Source, near, entry(1)), Some(0));
+        assert_eq!(store.publish(StoreKind::Source, near, entry(1)), Some(0));
+        assert_eq!(store.get(StoreKind::Source, &near).len(), 1);
+    }
+
+    #[test]
+    pub fn test_the_number_of_targets_is_bounded() {
+        let mut store = KadStore::new(KadId::new(0xAB << 120));
+        let near = KadId::new(0xAB << 120 | 5);
+        store.publish(StoreKind::Source, near, entry(1)).unwrap();
+
+        for n in 1..KadStore::MAX_TARGETS as u128 {
+            let target = KadId::new(0xAB << 120 | n << 32);
+            assert!(store
+                .publish(StoreKind::Keyword, target, entry(n))
+                .is_some());
+        }
+        let one_too_many = KadId::new(0xAB << 120 | 7);
+        assert_eq!(
+            store.publish(StoreKind::Notes, one_too_many, entry(1)),
+            None
+        );
+        // Targets we already have can still be published to.
+        assert!(store.publish(StoreKind::Source, near, entry(2)).is_some());
+        assert_eq!(store.total, KadStore::MAX_TARGETS + 1);
+    }
+}
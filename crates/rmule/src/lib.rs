@@ -1,9 +1,21 @@
 #![allow(dead_code)] // TEMP: Remove this when done!
 #![forbid(unsafe_code)]
 
+pub mod bandwidth;
 pub mod configuration;
+pub mod connections;
+pub mod download;
 mod engine;
 pub mod file;
+pub mod hashing;
+pub mod kad;
+pub mod listener;
+pub mod obfuscation;
+pub mod peer;
+pub mod portmap;
+pub mod protocol;
+pub mod search;
+pub mod server;
 mod times;
 mod utils;
 
@@ -0,0 +1,621 @@
+use super::ServerUdpSocket;
+use crate::configuration::{ConfigurationEventReceiver, ConfigurationEvents, Settings};
+use crate::connections::{ConnectionAdmission, ConnectionPermit};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+pub type ListenerCommandSender = mpsc::Sender<ListenerCommand>;
+pub type ListenerCommandReceiver = mpsc::Receiver<ListenerCommand>;
+
+pub type ListenerEventSender = broadcast::Sender<ListenerEvents>;
+pub type ListenerEventReceiver = broadcast::Receiver<ListenerEvents>;
+
+/// The UDP socket for Kad and other clients, None while it is not open.
+pub type UdpSocketWatch = watch::Receiver<Option<Arc<UdpSocket>>>;
+/// The socket for server UDP packets, None while it is not open.
+pub type ServerUdpSocketWatch = watch::Receiver<Option<ServerUdpSocket>>;
+
+pub type IncomingConnectionReceiver = mpsc::Receiver<IncomingConnection>;
+
+/// The handle type allows commands to be sent to and events to be received
+/// from the Listener Manager.
+pub struct ListenerManagerHandle {
+    cmd_sender: ListenerCommandSender,
+    evt_sender: ListenerEventSender,
+    // TODO: We need at least one receiver to be alive to
+    // allow us to send events.
+    evt_receiver: ListenerEventReceiver,
+    udp_socket: UdpSocketWatch,
+    server_udp_socket: ServerUdpSocketWatch,
+    // Until whoever handles incoming connections takes it.
+    incoming: Option<IncomingConnectionReceiver>,
+}
+
+impl ListenerManagerHandle {
+    /// Starts the Listener Manager as a Tokio task. It opens the ports in
+    /// the settings once it is told about them, and reopens them when
+    /// they change.
+    pub fn new(
+        cfg_evt_receiver: ConfigurationEventReceiver,
+        admission: ConnectionAdmission,
+        tokio_handle: &tokio::runtime::Handle,
+    ) -> Self {
+        let (cmd_sender, cmd_receiver) = mpsc::channel::<ListenerCommand>(32);
+        let (evt_sender, evt_receiver) = broadcast::channel::<ListenerEvents>(256);
+        let (incoming_sender, incoming) = mpsc::channel(32);
+
+        let mgr = ListenerManager::new(
+            evt_sender.clone(),
+            cmd_receiver,
+            cfg_evt_receiver,
+            admission,
+            incoming_sender,
+        );
+        let udp_socket = mgr.udp_socket.subscribe();
+        let server_udp_socket = mgr.server_udp_socket.subscribe();
+        tokio_handle.spawn(mgr.run());
+
+        Self {
+            cmd_sender,
+            evt_sender,
+            evt_receiver,
+            udp_socket,
+            server_udp_socket,
+            incoming: Some(incoming),
+        }
+    }
+
+    /// Sends a command to the Listener Manager.
+    pub async fn send_command(&self, cmd: ListenerCommand) -> Result<()> {
+        Ok(self.cmd_sender.send(cmd).await?)
+    }
+
+    /// Synchronously send a command to the Listener Manager.
+    pub fn send_command_blocking(&self, cmd: ListenerCommand) -> Result<()> {
+        Ok(self.cmd_sender.blocking_send(cmd)?)
+    }
+
+    /// Create a new subscription to events sent by the Listener Manager.
+    pub fn subscribe_to_events(&self) -> ListenerEventReceiver {
+        self.evt_sender.subscribe()
+    }
+
+    /// Creates a new command sender which can be used to send commands
+    /// to the Listener Manager.
+    pub fn make_command_sender(&self) -> ListenerCommandSender {
+        self.cmd_sender.clone()
+    }
+
+    /// Follows the UDP socket for Kad and other clients.
+    pub fn udp_socket(&self) -> UdpSocketWatch {
+        self.udp_socket.clone()
+    }
+
+    /// Follows the socket for server UDP packets.
+    pub fn server_udp_socket(&self) -> ServerUdpSocketWatch {
+        self.server_udp_socket.clone()
+    }
+
+    /// Takes the receiver for connections other clients make to us. There
+    /// is only one, so this returns None after the first call. Connections
+    /// arriving while nobody has taken it, or which are not taken quickly
+    /// enough, are dropped.
+    pub fn take_incoming_connections(&mut self) -> Option<IncomingConnectionReceiver> {
+        self.incoming.take()
+    }
+}
+
+/// The set of commands that can be sent to the Listener Manager.
+#[derive(Debug)]
+pub enum ListenerCommand {
+    /// Closes all the ports and stops the Listener Manager.
+    Stop,
+}
+
+/// The ports we listen on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ListenerKind {
+    Tcp,
+    Udp,
+    ServerUdp,
+}
+
+impl Display for ListenerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Tcp => "TCP port",
+            Self::Udp => "UDP port",
+            Self::ServerUdp => "server UDP port",
+        })
+    }
+}
+
+/// The set of events that can be emitted by the Listener Manager.
+#[derive(Debug, Clone)]
+pub enum ListenerEvents {
+    /// A port has been opened, at startup or because the settings changed.
+    Listening {
+        kind: ListenerKind,
+        addr: SocketAddr,
+    },
+    /// A port could not be opened. A port which was open before stays open.
+    BindFailed {
+        kind: ListenerKind,
+        port: u16,
+        reason: String,
+    },
+    /// A port has been closed because it was set to 0.
+    Closed(ListenerKind),
+}
+
+/// A connection another client has made to us, which has been admitted
+/// under the connection limits.
+#[derive(Debug)]
+pub struct IncomingConnection {
+    pub stream: TcpStream,
+    pub addr: SocketAddr,
+    pub permit: ConnectionPermit,
+}
+
+/// Where the settings say we listen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ListenerAddrs {
+    ip: IpAddr,
+    tcp_port: u16,
+    udp_port: u16,
+    server_udp_port: u16,
+}
+
+impl ListenerAddrs {
+    fn from_settings(settings: &Settings) -> Result<Self> {
+        let ip = match settings.bind_address.trim() {
+            "" => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            addr => addr
+                .parse()
+                .with_context(|| format!("The bind address {addr} is not an IP Address"))?,
+        };
+
+        Ok(Self {
+            ip,
+            tcp_port: settings.tcp_port,
+            udp_port: settings.udp_port,
+            server_udp_port: settings.server_udp_port,
+        })
+    }
+}
+
+/// This is private to the module: all access is via the handle.
+struct ListenerManager {
+    events_sender: ListenerEventSender,
+    commands_receiver: ListenerCommandReceiver,
+    cfg_events_receiver: ConfigurationEventReceiver,
+    // Incoming connections count against the connection limits.
+    admission: ConnectionAdmission,
+    incoming_sender: mpsc::Sender<IncomingConnection>,
+    // Where each port was last opened, as asked. A port which failed to
+    // open keeps its old entry, so the same settings try it again.
+    addrs: HashMap<ListenerKind, SocketAddr>,
+    tcp_listener: Option<TcpListener>,
+    udp_socket: watch::Sender<Option<Arc<UdpSocket>>>,
+    server_udp_socket: watch::Sender<Option<ServerUdpSocket>>,
+    server_udp_receiver: Option<JoinHandle<()>>,
+}
+
+impl ListenerManager {
+    /// How long we wait after accepting fails, which it does when we run
+    /// out of file descriptors, before trying again.
+    const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+    fn new(
+        events_sender: ListenerEventSender,
+        commands_receiver: ListenerCommandReceiver,
+        cfg_events_receiver: ConfigurationEventReceiver,
+        admission: ConnectionAdmission,
+        incoming_sender: mpsc::Sender<IncomingConnection>,
+    ) -> Self {
+        Self {
+            events_sender,
+            commands_receiver,
+            cfg_events_receiver,
+            admission,
+            incoming_sender,
+            addrs: HashMap::new(),
+            tcp_listener: None,
+            udp_socket: watch::channel(None).0,
+            server_udp_socket: watch::channel(None).0,
+            server_udp_receiver: None,
+        }
+    }
+
+    async fn run(mut self) {
+        loop {
+            tokio::select! {
+                cmd = self.commands_receiver.recv() => {
+                    match cmd {
+                        Some(ListenerCommand::Stop) | None => break,
+                    }
+                }
+                evt = self.cfg_events_receiver.recv() => {
+                    match evt {
+                        Ok(ConfigurationEvents::SettingsChange(settings)) => self.settings_changed(&settings).await,
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(n)) => warn!("Listener Manager missed {n} configuration events"),
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                accepted = accept(&self.tcp_listener) => self.handle_accepted(accepted).await,
+            }
+        }
+
+        self.close_server_udp_socket();
+        info!("Listener Manager stopped");
+    }
+
+    async fn settings_changed(&mut self, settings: &Settings) {
+        match ListenerAddrs::from_settings(settings) {
+            Ok(addrs) => self.listen(addrs).await,
+            Err(e) => {
+                for (kind, port) in [
+                    (ListenerKind::Tcp, settings.tcp_port),
+                    (ListenerKind::Udp, settings.udp_port),
+                    (ListenerKind::ServerUdp, settings.server_udp_port),
+                ] {
+                    self.bind_failed(kind, port, &e);
+                }
+            }
+        }
+    }
+
+    /// Opens whichever ports are new or have changed.
+    async fn listen(&mut self, addrs: ListenerAddrs) {
+        for (kind, port) in [
+            (ListenerKind::Tcp, addrs.tcp_port),
+            (ListenerKind::Udp, addrs.udp_port),
+            (ListenerKind::ServerUdp, addrs.server_udp_port),
+        ] {
+            let addr = SocketAddr::new(addrs.ip, port);
+            if self.addrs.get(&kind) == Some(&addr) {
+                continue;
+            }
+            let opened = match kind {
+                ListenerKind::Tcp => self.listen_tcp(addr).await,
+                ListenerKind::Udp => self.listen_udp(addr).await,
+                ListenerKind::ServerUdp => self.listen_server_udp(addr).await,
+            };
+            if opened {
+                self.addrs.insert(kind, addr);
+            }
+        }
+    }
+
+    /// The old listener stays open until the new one is, unless only the
+    /// IP changes: the old one may hold the port then, so it is closed
+    /// first and opened again if the new one fails.
+    async fn listen_tcp(&mut self, addr: SocketAddr) -> bool {
+        if addr.port() == 0 {
+            if self.tcp_listener.take().is_some() {
+                self.send_event(ListenerEvents::Closed(ListenerKind::Tcp));
+            }
+            return true;
+        }
+
+        let old = self
+            .addrs
+            .get(&ListenerKind::Tcp)
+            .copied()
+            .filter(|old| old.port() == addr.port());
+        let bound = match (TcpListener::bind(addr).await, old) {
+            (Err(_), Some(_)) => {
+                self.tcp_listener = None;
+                TcpListener::bind(addr).await
+            }
+            (bound, _) => bound,
+        };
+
+        match bound {
+            Ok(listener) => {
+                self.listening(ListenerKind::Tcp, listener.local_addr());
+                self.tcp_listener = Some(listener);
+                true
+            }
+            Err(e) => {
+                self.bind_failed(ListenerKind::Tcp, addr.port(), &e.into());
+                if let Some(old) = old {
+                    match TcpListener::bind(old).await {
+                        Ok(listener) => self.tcp_listener = Some(listener),
+                        Err(e) => {
+                            warn!("Opening {} {old} again failed: {e}", ListenerKind::Tcp);
+                            self.addrs.remove(&ListenerKind::Tcp);
+                            self.send_event(ListenerEvents::Closed(ListenerKind::Tcp));
+                        }
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    /// Others may still be using the old socket, so it stays open until
+    /// they have moved to the new one.
+    async fn listen_udp(&mut self, addr: SocketAddr) -> bool {
+        if addr.port() == 0 {
+            if self.udp_socket.send_replace(None).is_some() {
+                self.send_event(ListenerEvents::Closed(ListenerKind::Udp));
+            }
+            return true;
+        }
+
+        match UdpSocket::bind(addr).await {
+            Ok(socket) => {
+                self.listening(ListenerKind::Udp, socket.local_addr());
+                self.udp_socket.send_replace(Some(Arc::new(socket)));
+                true
+            }
+            Err(e) => {
+                self.bind_failed(ListenerKind::Udp, addr.port(), &e.into());
+                false
+            }
+        }
+    }
+
+    /// Port 0 gets a random port, as in eMule.
+    async fn listen_server_udp(&mut self, addr: SocketAddr) -> bool {
+        match UdpSocket::bind(addr).await {
+            Ok(socket) => {
+                self.listening(ListenerKind::ServerUdp, socket.local_addr());
+                self.close_server_udp_socket();
+                let (socket, receiver) = ServerUdpSocket::new(socket);
+                self.server_udp_socket.send_replace(Some(socket));
+                self.server_udp_receiver = Some(receiver);
+                true
+            }
+            Err(e) => {
+                self.bind_failed(ListenerKind::ServerUdp, addr.port(), &e.into());
+                false
+            }
+        }
+    }
+
+    fn close_server_udp_socket(&mut self) {
+        if let Some(receiver) = self.server_udp_receiver.take() {
+            receiver.abort();
+        }
+    }
+
+    async fn handle_accepted(&mut self, accepted: std::io::Result<(TcpStream, SocketAddr)>) {
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Accepting a connection failed: {e}");
+                time::sleep(Self::ACCEPT_RETRY).await;
+                return;
+            }
+        };
+
+        let Some(permit) = self.admission.admit_incoming() else {
+            debug!("Refusing connection from {addr}: too many connections");
+            return;
+        };
+
+        let connection = IncomingConnection {
+            stream,
+            addr,
+            permit,
+        };
+        if self.incoming_sender.try_send(connection).is_err() {
+            debug!("Dropping connection from {addr}: nobody is taking connections");
+        }
+    }
+
+    fn listening(&self, kind: ListenerKind, addr: std::io::Result<SocketAddr>) {
+        if let Ok(addr) = addr {
+            info!("Listening on {kind} {addr}");
+            self.send_event(ListenerEvents::Listening { kind, addr });
+        }
+    }
+
+    fn bind_failed(&self, kind: ListenerKind, port: u16, e: &anyhow::Error) {
+        warn!("Opening {kind} {port} failed: {e}");
+        self.send_event(ListenerEvents::BindFailed {
+            kind,
+            port,
+            reason: e.to_string(),
+        });
+    }
+
+    fn send_event(&self, evt: ListenerEvents) {
+        if let Err(broadcast::error::SendError(evt)) = self.events_sender.send(evt) {
+            warn!(
+                "Nobody is listening for listener events, dropping {:?}",
+                evt
+            );
+        }
+    }
+}
+
+/// Accepts on the listener, if there is one, otherwise waits forever. This
+/// is cancel safe, so it can be used in a select.
+async fn accept(listener: &Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connections::ConnectionLimits;
+
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    fn manager() -> (
+        ListenerManager,
+        ListenerEventReceiver,
+        IncomingConnectionReceiver,
+    ) {
+        let (evt_sender, events) = broadcast::channel(16);
+        let (_cmd_sender, cmd_receiver) = mpsc::channel(1);
+        let (_cfg_sender, cfg_receiver) = broadcast::channel(1);
+        let (incoming_sender, incoming) = mpsc::channel(1);
+        let admission = ConnectionAdmission::new(ConnectionLimits::UNLIMITED);
+        let mgr = ListenerManager::new(
+            evt_sender,
+            cmd_receiver,
+            cfg_receiver,
+            admission,
+            incoming_sender,
+        );
+        (mgr, events, incoming)
+    }
+
+    fn addrs(tcp_port: u16, udp_port: u16) -> ListenerAddrs {
+        ListenerAddrs {
+            ip: Ipv4Addr::LOCALHOST.into(),
+            tcp_port,
+            udp_port,
+            server_udp_port: 0,
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_listen_reports_a_tcp_port_that_is_taken() {
+        let (mut mgr, mut events, _incoming) = manager();
+        let udp_socket = mgr.udp_socket.subscribe();
+
+        let taken = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        mgr.listen(addrs(taken.local_addr().unwrap().port(), 0))
+            .await;
+
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            ListenerEvents::BindFailed {
+                kind: ListenerKind::Tcp,
+                ..
+            }
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            ListenerEvents::Listening {
+                kind: ListenerKind::ServerUdp,
+                ..
+            }
+        ));
+        assert!(udp_socket.borrow().is_none());
+    }
+
+    #[tokio::test]
+    pub async fn test_listen_opens_changed_ports_only() {
+        let (mut mgr, mut events, _incoming) = manager();
+        let udp_socket = mgr.udp_socket.subscribe();
+        let taken = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        mgr.listen(addrs(taken.local_addr().unwrap().port(), 0))
+            .await;
+        while events.try_recv().is_ok() {}
+
+        let (tcp_port, udp_port) = (free_port(), free_port());
+        mgr.listen(addrs(tcp_port, udp_port)).await;
+
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            ListenerEvents::Listening { kind: ListenerKind::Tcp, addr } if addr.port() == tcp_port
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            ListenerEvents::Listening { kind: ListenerKind::Udp, addr } if addr.port() == udp_port
+        ));
+        // The server UDP port has not changed.
+        assert!(events.try_recv().is_err());
+        let udp_addr = udp_socket.borrow().as_ref().unwrap().local_addr().unwrap();
+        assert_eq!(udp_addr.port(), udp_port);
+    }
+
+    #[tokio::test]
+    pub async fn test_a_tcp_port_which_fails_to_open_keeps_the_old_one() {
+        let (mut mgr, mut events, _incoming) = manager();
+        let tcp_port = free_port();
+        mgr.listen(addrs(tcp_port, 0)).await;
+        while events.try_recv().is_ok() {}
+
+        let taken = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        mgr.listen(addrs(taken.local_addr().unwrap().port(), 0))
+            .await;
+
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            ListenerEvents::BindFailed {
+                kind: ListenerKind::Tcp,
+                ..
+            }
+        ));
+        assert!(events.try_recv().is_err());
+        let listener = mgr.tcp_listener.as_ref().unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), tcp_port);
+    }
+
+    #[tokio::test]
+    pub async fn test_a_tcp_port_which_failed_to_open_is_tried_again() {
+        let (mut mgr, mut events, _incoming) = manager();
+        let taken = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let tcp_port = taken.local_addr().unwrap().port();
+        mgr.listen(addrs(tcp_port, 0)).await;
+        while events.try_recv().is_ok() {}
+
+        drop(taken);
+        mgr.listen(addrs(tcp_port, 0)).await;
+
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            ListenerEvents::Listening { kind: ListenerKind::Tcp, addr } if addr.port() == tcp_port
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_changing_only_the_ip_moves_the_tcp_port() {
+        let (mut mgr, mut events, _incoming) = manager();
+        let tcp_port = free_port();
+        let mut any_ip = addrs(tcp_port, 0);
+        any_ip.ip = Ipv4Addr::UNSPECIFIED.into();
+        mgr.listen(any_ip).await;
+        while events.try_recv().is_ok() {}
+
+        mgr.listen(addrs(tcp_port, 0)).await;
+
+        let expected = SocketAddr::from((Ipv4Addr::LOCALHOST, tcp_port));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            ListenerEvents::Listening { kind: ListenerKind::Tcp, addr } if addr == expected
+        ));
+        let listener = mgr.tcp_listener.as_ref().unwrap();
+        assert_eq!(listener.local_addr().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    pub async fn test_accepted_connections_are_handed_on() {
+        let (mut mgr, _events, mut incoming) = manager();
+        let tcp_port = free_port();
+        mgr.listen(addrs(tcp_port, 0)).await;
+
+        let (connected, accepted) = tokio::join!(
+            TcpStream::connect((Ipv4Addr::LOCALHOST, tcp_port)),
+            accept(&mgr.tcp_listener)
+        );
+        mgr.handle_accepted(accepted).await;
+
+        let connection = incoming.try_recv().unwrap();
+        assert_eq!(connection.addr, connected.unwrap().local_addr().unwrap());
+    }
+}
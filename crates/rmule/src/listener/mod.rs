@@ -0,0 +1,9 @@
+//! The sockets other clients and servers reach us on: the TCP port for
+//! incoming connections, the UDP port for Kad and the port we send server
+//! UDP packets from.
+
+mod listener_manager;
+mod server_udp_socket;
+
+pub use listener_manager::*;
+pub use server_udp_socket::*;
@@ -0,0 +1,60 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// A datagram received from a server: who sent it and what it contains.
+pub type ServerDatagram = (SocketAddr, Arc<[u8]>);
+
+/// The socket we send UDP packets to servers from. Global searches and
+/// source queries run at the same time and share the socket, so what
+/// arrives on it is passed to every subscriber, each of which picks out
+/// the answers to its own requests. Cloning gives another handle to the
+/// same socket.
+#[derive(Debug, Clone)]
+pub struct ServerUdpSocket {
+    socket: Arc<UdpSocket>,
+    datagrams: broadcast::Sender<ServerDatagram>,
+}
+
+impl ServerUdpSocket {
+    /// Takes a bound socket, returning it with the task which receives
+    /// on it. The task runs until aborted.
+    pub fn new(socket: UdpSocket) -> (Self, JoinHandle<()>) {
+        let socket = Arc::new(socket);
+        let (datagrams, _) = broadcast::channel(256);
+        let receiver = tokio::spawn(receive(socket.clone(), datagrams.clone()));
+
+        (Self { socket, datagrams }, receiver)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub async fn send_to(&self, data: &[u8], to: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(data, to).await
+    }
+
+    /// Subscribes to the datagrams received from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerDatagram> {
+        self.datagrams.subscribe()
+    }
+}
+
+async fn receive(socket: Arc<UdpSocket>, datagrams: broadcast::Sender<ServerDatagram>) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        match socket.recv_from(&mut buf).await {
+            // Nobody listening just means no requests are waiting for
+            // answers.
+            Ok((len, from)) => _ = datagrams.send((from, buf[..len].into())),
+            // On Windows an ICMP port unreachable from a dead server shows
+            // up as an error here. It is not fatal.
+            Err(e) => debug!("Receiving on the server UDP socket failed: {e}"),
+        }
+    }
+}
@@ -0,0 +1,373 @@
+//! The obfuscation handshakes. See EncryptedStreamSocket.cpp in eMule for
+//! the reference; the comments there describe the packet layouts.
+//!
+//! Client to client, the initiator knows the user hash of the client it is
+//! connecting to (from the server or source exchange) and both keys are
+//! derived from that hash plus some random bytes it sends in the clear.
+//! Client to server, there is no shared secret so the keys come from a
+//! Diffie-Hellman exchange.
+
+use super::{ObfuscatedStream, Rc4};
+use crate::configuration::{ObfuscationMode, UserHash};
+use crate::protocol::opcodes::{OP_EDONKEYPROT, OP_EMULEPROT, OP_PACKEDPROT};
+use anyhow::{bail, Result};
+use md5::{Digest, Md5};
+use num_bigint::BigUint;
+use rand::{Rng, RngCore};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Added to the key material to derive the key for data sent by the side
+/// which opened the connection.
+const MAGICVALUE_REQUESTER: u8 = 34;
+/// Added to the key material to derive the key for data sent by the side
+/// which accepted the connection.
+const MAGICVALUE_SERVER: u8 = 203;
+/// Sent encrypted by both sides, so that each can check it has the right key.
+const MAGICVALUE_SYNC: u32 = 0x835E_6FC4;
+/// The only encryption method there is.
+const ENM_OBFUSCATION: u8 = 0x00;
+
+/// The largest amount of random padding we add to handshake packets, which
+/// stops them all being the same length.
+const MAX_PADDING: u8 = 16;
+
+/// The 768 bit prime used for the Diffie-Hellman exchange with servers.
+const DH768_P: [u8; PRIME_SIZE] = [
+    0xF2, 0xBF, 0x52, 0xC5, 0x5F, 0x58, 0x7A, 0xDD, 0x53, 0x71, 0xA9, 0x36, 0xE8, 0x86, 0xEB, 0x3C,
+    0x62, 0x17, 0xA3, 0x3E, 0xC3, 0x4C, 0xB4, 0x0D, 0xC7, 0x3A, 0x41, 0xA6, 0x43, 0xAF, 0xFC, 0xE7,
+    0x21, 0xFC, 0x28, 0x63, 0x66, 0x53, 0x5B, 0xDB, 0xCE, 0x25, 0x9F, 0x22, 0x86, 0xDA, 0x4A, 0x91,
+    0xB2, 0x07, 0xCB, 0xAA, 0x52, 0x55, 0xD4, 0xF6, 0x1C, 0xCE, 0xAE, 0xD4, 0x5A, 0xD5, 0xE0, 0x74,
+    0x7D, 0xF7, 0x78, 0x18, 0x28, 0x10, 0x5F, 0x34, 0x0F, 0x76, 0x23, 0x87, 0xF8, 0x8B, 0x28, 0x91,
+    0x42, 0xFB, 0x42, 0x68, 0x8F, 0x05, 0x15, 0x0F, 0x54, 0x8B, 0x5F, 0x43, 0x6A, 0xF7, 0x0D, 0xF3,
+];
+const PRIME_SIZE: usize = 96;
+const DH_GENERATOR: u32 = 2;
+/// The size of our secret Diffie-Hellman exponent.
+const DH_SECRET_SIZE: usize = 16;
+
+/// Makes the key for one direction: the MD5 of the key material with a
+/// magic value, saying which direction it is, in the middle.
+fn make_key(before: &[u8], magic: u8, after: &[u8]) -> Rc4 {
+    let mut md5 = Md5::new();
+    md5.update(before);
+    md5.update([magic]);
+    md5.update(after);
+    Rc4::new(&md5.finalize())
+}
+
+/// The first byte of an obfuscated connection is random, but must not look
+/// like the start of a plain packet.
+fn random_marker() -> u8 {
+    let mut rng = rand::thread_rng();
+    loop {
+        let b: u8 = rng.gen();
+        if !is_protocol_byte(b) {
+            return b;
+        }
+    }
+}
+
+fn is_protocol_byte(b: u8) -> bool {
+    matches!(b, OP_EDONKEYPROT | OP_EMULEPROT | OP_PACKEDPROT)
+}
+
+/// Appends a padding length byte and that many random bytes.
+fn write_padding(out: &mut Vec<u8>) {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PADDING);
+    out.push(len);
+    out.extend((0..len).map(|_| rng.gen::<u8>()));
+}
+
+/// Reads and decrypts exactly `buf.len()` bytes.
+async fn read_decrypted<S>(stream: &mut S, rc4: &mut Rc4, buf: &mut [u8]) -> Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    stream.read_exact(buf).await?;
+    rc4.apply(buf);
+    Ok(())
+}
+
+/// Reads the encrypted sync value and checks it, which tells us whether the
+/// two sides agree on the key.
+async fn read_sync<S>(stream: &mut S, rc4: &mut Rc4) -> Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = [0u8; 4];
+    read_decrypted(stream, rc4, &mut buf).await?;
+    if u32::from_le_bytes(buf) != MAGICVALUE_SYNC {
+        bail!("Obfuscation handshake failed: keys do not match");
+    }
+    Ok(())
+}
+
+/// Reads a padding length and skips that much padding.
+async fn skip_padding<S>(stream: &mut S, rc4: &mut Rc4) -> Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut len = [0u8; 1];
+    read_decrypted(stream, rc4, &mut len).await?;
+    let mut padding = vec![0u8; len[0] as usize];
+    read_decrypted(stream, rc4, &mut padding).await
+}
+
+/// Obfuscates a connection we opened to another client, whose user hash
+/// we must already know.
+pub async fn obfuscate_outgoing_peer<S>(
+    mut stream: S,
+    peer_user_hash: &UserHash,
+) -> Result<ObfuscatedStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut random_key_part = [0u8; 4];
+    rand::thread_rng().fill_bytes(&mut random_key_part);
+
+    let hash = peer_user_hash.as_bytes();
+    let mut send = make_key(hash, MAGICVALUE_REQUESTER, &random_key_part);
+    let mut receive = make_key(hash, MAGICVALUE_SERVER, &random_key_part);
+
+    let mut encrypted = MAGICVALUE_SYNC.to_le_bytes().to_vec();
+    encrypted.push(ENM_OBFUSCATION); // Supported methods.
+    encrypted.push(ENM_OBFUSCATION); // Preferred method.
+    write_padding(&mut encrypted);
+    send.apply(&mut encrypted);
+
+    let mut packet = vec![random_marker()];
+    packet.extend_from_slice(&random_key_part);
+    packet.extend_from_slice(&encrypted);
+    stream.write_all(&packet).await?;
+    stream.flush().await?;
+
+    read_sync(&mut stream, &mut receive).await?;
+    let mut method = [0u8; 1];
+    read_decrypted(&mut stream, &mut receive, &mut method).await?;
+    if method[0] != ENM_OBFUSCATION {
+        bail!("Peer chose unknown obfuscation method {}", method[0]);
+    }
+    skip_padding(&mut stream, &mut receive).await?;
+
+    Ok(ObfuscatedStream::encrypted(stream, send, receive))
+}
+
+/// Works out whether a client which connected to us wants obfuscation, and
+/// if so completes the handshake. Plain connections are passed through
+/// unless `mode` requires obfuscation.
+pub async fn accept_peer<S>(
+    mut stream: S,
+    our_user_hash: &UserHash,
+    mode: ObfuscationMode,
+) -> Result<ObfuscatedStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let marker = stream.read_u8().await?;
+
+    if is_protocol_byte(marker) {
+        if mode == ObfuscationMode::Required {
+            bail!("Refusing plain connection, obfuscation is required");
+        }
+        return Ok(ObfuscatedStream::with_prefix(stream, vec![marker]));
+    }
+
+    if mode == ObfuscationMode::Disabled {
+        bail!("Refusing obfuscated connection, obfuscation is disabled");
+    }
+
+    let mut random_key_part = [0u8; 4];
+    stream.read_exact(&mut random_key_part).await?;
+
+    let hash = our_user_hash.as_bytes();
+    let mut receive = make_key(hash, MAGICVALUE_REQUESTER, &random_key_part);
+    let mut send = make_key(hash, MAGICVALUE_SERVER, &random_key_part);
+
+    read_sync(&mut stream, &mut receive).await?;
+    let mut methods = [0u8; 2];
+    read_decrypted(&mut stream, &mut receive, &mut methods).await?;
+    skip_padding(&mut stream, &mut receive).await?;
+
+    let mut answer = MAGICVALUE_SYNC.to_le_bytes().to_vec();
+    answer.push(ENM_OBFUSCATION);
+    write_padding(&mut answer);
+    send.apply(&mut answer);
+    stream.write_all(&answer).await?;
+    stream.flush().await?;
+
+    Ok(ObfuscatedStream::encrypted(stream, send, receive))
+}
+
+/// Obfuscates a connection to a server's obfuscation port.
+pub async fn obfuscate_server<S>(mut stream: S) -> Result<ObfuscatedStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let prime = BigUint::from_bytes_be(&DH768_P);
+    let mut secret = [0u8; DH_SECRET_SIZE];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let secret = BigUint::from_bytes_be(&secret);
+    let public = BigUint::from(DH_GENERATOR).modpow(&secret, &prime);
+
+    let mut packet = vec![random_marker()];
+    packet.extend_from_slice(&to_prime_size(&public));
+    write_padding(&mut packet);
+    stream.write_all(&packet).await?;
+    stream.flush().await?;
+
+    let mut server_public = [0u8; PRIME_SIZE];
+    stream.read_exact(&mut server_public).await?;
+    let shared = BigUint::from_bytes_be(&server_public).modpow(&secret, &prime);
+    let shared = to_prime_size(&shared);
+
+    let mut send = make_key(&shared, MAGICVALUE_REQUESTER, &[]);
+    let mut receive = make_key(&shared, MAGICVALUE_SERVER, &[]);
+
+    read_sync(&mut stream, &mut receive).await?;
+    // The supported and preferred methods; there is only one method.
+    let mut methods = [0u8; 2];
+    read_decrypted(&mut stream, &mut receive, &mut methods).await?;
+    skip_padding(&mut stream, &mut receive).await?;
+
+    let mut answer = MAGICVALUE_SYNC.to_le_bytes().to_vec();
+    answer.push(ENM_OBFUSCATION);
+    write_padding(&mut answer);
+    send.apply(&mut answer);
+    stream.write_all(&answer).await?;
+    stream.flush().await?;
+
+    Ok(ObfuscatedStream::encrypted(stream, send, receive))
+}
+
+/// Big-endian bytes, left padded with zeros to the size of the prime.
+fn to_prime_size(n: &BigUint) -> [u8; PRIME_SIZE] {
+    let bytes = n.to_bytes_be();
+    let mut out = [0u8; PRIME_SIZE];
+    out[PRIME_SIZE - bytes.len()..].copy_from_slice(&bytes);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::duplex;
+
+    /// The server side of the handshake, which rMule never needs except to
+    /// test the client side.
+    async fn accept_server<S>(mut stream: S) -> Result<ObfuscatedStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let prime = BigUint::from_bytes_be(&DH768_P);
+        let secret = BigUint::from(0x1234_5678_9ABC_DEF0u64);
+        let public = BigUint::from(DH_GENERATOR).modpow(&secret, &prime);
+
+        let mut client_public = [0u8; 1 + PRIME_SIZE];
+        stream.read_exact(&mut client_public).await?;
+        let padding = stream.read_u8().await?;
+        stream.read_exact(&mut vec![0u8; padding as usize]).await?;
+
+        let shared = BigUint::from_bytes_be(&client_public[1..]).modpow(&secret, &prime);
+        let shared = to_prime_size(&shared);
+        let mut receive = make_key(&shared, MAGICVALUE_REQUESTER, &[]);
+        let mut send = make_key(&shared, MAGICVALUE_SERVER, &[]);
+
+        let mut encrypted = MAGICVALUE_SYNC.to_le_bytes().to_vec();
+        encrypted.extend_from_slice(&[ENM_OBFUSCATION, ENM_OBFUSCATION]);
+        write_padding(&mut encrypted);
+        send.apply(&mut encrypted);
+        stream.write_all(&to_prime_size(&public)).await?;
+        stream.write_all(&encrypted).await?;
+
+        read_sync(&mut stream, &mut receive).await?;
+        let mut method = [0u8; 1];
+        read_decrypted(&mut stream, &mut receive, &mut method).await?;
+        skip_padding(&mut stream, &mut receive).await?;
+
+        Ok(ObfuscatedStream::encrypted(stream, send, receive))
+    }
+
+    async fn exchange<A, B>(mut a: ObfuscatedStream<A>, mut b: ObfuscatedStream<B>)
+    where
+        A: AsyncRead + AsyncWrite + Unpin,
+        B: AsyncRead + AsyncWrite + Unpin,
+    {
+        let message = b"\xE3\x05\x00\x00\x00\x01hello";
+        a.write_all(message).await.unwrap();
+        a.flush().await.unwrap();
+        let mut buf = [0u8; 11];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, message);
+
+        b.write_all(b"answer").await.unwrap();
+        b.flush().await.unwrap();
+        let mut buf = [0u8; 6];
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"answer");
+    }
+
+    #[tokio::test]
+    pub async fn test_peer_handshake() {
+        let (a, b) = duplex(4096);
+        let hash = UserHash::generate();
+
+        let (a, b) = tokio::join!(
+            obfuscate_outgoing_peer(a, &hash),
+            accept_peer(b, &hash, ObfuscationMode::Required)
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+
+        assert!(a.is_obfuscated() && b.is_obfuscated());
+        exchange(a, b).await;
+    }
+
+    #[tokio::test]
+    pub async fn test_peer_handshake_with_wrong_hash_fails() {
+        let (a, b) = duplex(4096);
+        let (ours, theirs) = (UserHash::new([1; 16]), UserHash::new([2; 16]));
+
+        let (a, b) = tokio::join!(
+            obfuscate_outgoing_peer(a, &theirs),
+            accept_peer(b, &ours, ObfuscationMode::Preferred)
+        );
+
+        assert!(b.is_err());
+        assert!(a.is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_accept_plain_connection() {
+        let (mut a, b) = duplex(4096);
+        a.write_all(&[OP_EDONKEYPROT, 1, 2, 3]).await.unwrap();
+
+        let mut b = accept_peer(b, &UserHash::generate(), ObfuscationMode::Preferred)
+            .await
+            .unwrap();
+        assert!(!b.is_obfuscated());
+
+        // The byte used to detect the protocol must not be lost.
+        let mut buf = [0u8; 4];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [OP_EDONKEYPROT, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    pub async fn test_plain_connection_is_refused_when_obfuscation_is_required() {
+        let (mut a, b) = duplex(4096);
+        a.write_all(&[OP_EDONKEYPROT]).await.unwrap();
+        assert!(
+            accept_peer(b, &UserHash::generate(), ObfuscationMode::Required)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_server_handshake() {
+        let (a, b) = duplex(4096);
+
+        let (a, b) = tokio::join!(obfuscate_server(a), accept_server(b));
+        exchange(a.unwrap(), b.unwrap()).await;
+    }
+}
@@ -0,0 +1,15 @@
+//! Protocol obfuscation. This hides ed2k traffic from ISPs which recognise
+//! and throttle it, by encrypting TCP connections with RC4 under keys agreed
+//! in a short handshake, and UDP packets with per-packet keys. It is a
+//! transport layer: the packet code above it does not know whether its
+//! stream is obfuscated or not.
+
+mod handshake;
+mod obfuscated_stream;
+mod rc4;
+mod udp;
+
+pub use handshake::*;
+pub use obfuscated_stream::*;
+pub use rc4::*;
+pub use udp::*;
@@ -0,0 +1,142 @@
+use super::Rc4;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A stream which encrypts everything written and decrypts everything read,
+/// once the obfuscation handshake has agreed the keys. It can also be
+/// plain, so that connections look the same to the code above whether or
+/// not they ended up obfuscated.
+#[derive(Debug)]
+pub struct ObfuscatedStream<S> {
+    inner: S,
+    /// The send and receive ciphers; None for a plain stream.
+    ciphers: Option<(Rc4, Rc4)>,
+    /// Plaintext bytes which were read while working out whether the other
+    /// side wanted obfuscation, and which must be returned first.
+    prefix: Vec<u8>,
+    /// Encrypted bytes which have been accepted from the caller but not
+    /// yet written to the inner stream.
+    pending: Vec<u8>,
+}
+
+impl<S> ObfuscatedStream<S> {
+    /// Wraps a stream without obfuscating it.
+    pub fn plain(inner: S) -> Self {
+        Self::with_prefix(inner, Vec::new())
+    }
+
+    /// Wraps a plain stream from which some bytes have already been read.
+    pub(super) fn with_prefix(inner: S, prefix: Vec<u8>) -> Self {
+        Self {
+            inner,
+            ciphers: None,
+            prefix,
+            pending: Vec::new(),
+        }
+    }
+
+    pub(super) fn encrypted(inner: S, send: Rc4, receive: Rc4) -> Self {
+        Self {
+            inner,
+            ciphers: Some((send, receive)),
+            prefix: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn is_obfuscated(&self) -> bool {
+        self.ciphers.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> ObfuscatedStream<S> {
+    /// Writes out as much of the pending data as the inner stream will take.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ObfuscatedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.prefix.is_empty() {
+            let n = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix[..n]);
+            this.prefix.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some((_, receive))) = (&poll, &mut this.ciphers) {
+            receive.apply(&mut buf.filled_mut()[before..]);
+        }
+
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ObfuscatedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.ciphers.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // Encrypted bytes cannot be "unwritten", so once the caller's data
+        // is encrypted we own it and must get it all out before taking more.
+        ready!(this.poll_drain(cx))?;
+
+        let (send, _) = this.ciphers.as_mut().unwrap();
+        this.pending.extend_from_slice(buf);
+        send.apply(&mut this.pending);
+
+        // Make a start on writing it. If the inner stream is not ready the
+        // data goes out on the next write or flush.
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
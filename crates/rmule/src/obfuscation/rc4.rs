@@ -0,0 +1,59 @@
+/// The RC4 stream cipher, as used by eMule's protocol obfuscation. RC4 is
+/// long broken as a cipher, but the point of obfuscation is only to make
+/// the traffic unrecognisable, and it is what the rest of the network uses.
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    /// eMule throws away the start of the key stream, which is where RC4's
+    /// known biases are worst.
+    const DISCARD: usize = 1024;
+
+    /// Creates the cipher used for TCP, which discards the start of the
+    /// key stream.
+    pub fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self::without_discard(key);
+        rc4.apply(&mut [0u8; Self::DISCARD]);
+        rc4
+    }
+
+    /// Creates the cipher used for UDP, where eMule does not bother
+    /// discarding anything.
+    pub fn without_discard(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// Encrypts or decrypts (which are the same thing) the data in place.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for b in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *b ^= k;
+        }
+    }
+}
+
+impl std::fmt::Debug for Rc4 {
+    // Do not dump the key state into logs.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Rc4")
+    }
+}
@@ -0,0 +1,392 @@
+//! UDP packet obfuscation. See EncryptedDatagramSocket.cpp in eMule.
+//!
+//! Each datagram carries its own key material in the clear: a random marker
+//! byte and two random bytes, which are combined with something the receiver
+//! knows to make the RC4 key. For servers that is the UDP key the server gave
+//! us; for ed2k clients the receiver's user hash and the sender's IP; for Kad
+//! nodes the receiver's Kad id or a verify key the receiver handed out.
+
+use super::Rc4;
+use crate::configuration::UserHash;
+use crate::protocol::opcodes::*;
+use md5::{Digest, Md5};
+use rand::Rng;
+use std::net::Ipv4Addr;
+
+const MAGICVALUE_UDP: u8 = 91;
+const MAGICVALUE_UDP_SYNC_CLIENT: u32 = 0x395F_2EC1;
+const MAGICVALUE_UDP_SYNC_SERVER: u32 = 0x13EF_24D5;
+const MAGICVALUE_UDP_SERVERCLIENT: u8 = 0xA5;
+const MAGICVALUE_UDP_CLIENTSERVER: u8 = 0x6B;
+
+/// Marker bit set in packets for ed2k clients and clear for Kad nodes.
+const MARKER_ED2K: u8 = 0x01;
+/// Marker bit set in Kad packets keyed with a receiver verify key.
+const MARKER_KAD_VERIFY_KEY: u8 = 0x02;
+
+/// Returns true if the datagram starts with one of the protocol bytes,
+/// i.e. it is a plain packet rather than an obfuscated one.
+pub fn is_plain_udp_packet(data: &[u8]) -> bool {
+    matches!(
+        data.first(),
+        Some(
+            &(OP_EDONKEYPROT
+                | OP_EMULEPROT
+                | OP_PACKEDPROT
+                | OP_KADEMLIAHEADER
+                | OP_KADEMLIAPACKEDPROT)
+        )
+    )
+}
+
+fn make_key(parts: &[&[u8]]) -> Rc4 {
+    let mut md5 = Md5::new();
+    for part in parts {
+        md5.update(part);
+    }
+    Rc4::without_discard(&md5.finalize())
+}
+
+/// Builds an obfuscated datagram: the marker and random key part in the
+/// clear, then the encrypted sync value, an (empty) padding length, any
+/// extra header and the packet itself.
+fn seal(
+    marker: u8,
+    random: [u8; 2],
+    mut rc4: Rc4,
+    sync: u32,
+    extra: &[u8],
+    packet: &[u8],
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(packet.len() + 16);
+    out.extend_from_slice(&[marker, random[0], random[1]]);
+
+    let start = out.len();
+    out.extend_from_slice(&sync.to_le_bytes());
+    // eMule sends no padding on UDP, so neither do we.
+    out.push(0);
+    out.extend_from_slice(extra);
+    out.extend_from_slice(packet);
+    rc4.apply(&mut out[start..]);
+
+    out
+}
+
+/// The reverse of `seal`: checks the sync value and strips the header,
+/// returning the decrypted remainder (extra header and packet).
+fn open(data: &[u8], mut rc4: Rc4, sync: u32) -> Option<Vec<u8>> {
+    if data.len() < 3 + 5 {
+        return None;
+    }
+
+    let mut rest = data[3..].to_vec();
+    rc4.apply(&mut rest);
+
+    if u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) != sync {
+        return None;
+    }
+
+    let padding = rest[4] as usize;
+    rest.get(5 + padding..).map(|r| r.to_vec())
+}
+
+fn random_marker(accept: impl Fn(u8) -> u8) -> u8 {
+    let mut rng = rand::thread_rng();
+    loop {
+        let b = accept(rng.gen());
+        if !is_plain_udp_packet(&[b]) && !matches!(b, OP_UDPRESERVEDPROT1 | OP_UDPRESERVEDPROT2) {
+            return b;
+        }
+    }
+}
+
+/// Obfuscates a packet for a server, using the UDP key the server gave us.
+pub fn encrypt_server_packet(packet: &[u8], server_key: u32) -> Vec<u8> {
+    let random: [u8; 2] = rand::thread_rng().gen();
+    let rc4 = make_key(&[
+        &server_key.to_le_bytes(),
+        &[MAGICVALUE_UDP_CLIENTSERVER],
+        &random,
+    ]);
+    seal(
+        random_marker(|b| b),
+        random,
+        rc4,
+        MAGICVALUE_UDP_SYNC_SERVER,
+        &[],
+        packet,
+    )
+}
+
+/// Deobfuscates a packet from a server. Returns None if the packet is not
+/// obfuscated with the key.
+pub fn decrypt_server_packet(data: &[u8], server_key: u32) -> Option<Vec<u8>> {
+    let random = data.get(1..3)?;
+    let rc4 = make_key(&[
+        &server_key.to_le_bytes(),
+        &[MAGICVALUE_UDP_SERVERCLIENT],
+        random,
+    ]);
+    open(data, rc4, MAGICVALUE_UDP_SYNC_SERVER)
+}
+
+/// Computes the verify key we hand out to the node at `ip`, so that it can
+/// obfuscate packets to us without knowing our Kad id. The secret is our
+/// private Kad UDP key, which never leaves this machine.
+pub fn udp_verify_key(secret: u32, ip: Ipv4Addr) -> u32 {
+    let mut material = [0u8; 8];
+    material[..4].copy_from_slice(&u32::from(ip).to_le_bytes());
+    material[4..].copy_from_slice(&secret.to_le_bytes());
+    let hash = Md5::digest(material);
+
+    let folded = hash
+        .chunks(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .fold(0, |acc, n| acc ^ n);
+    (folded % 0xFFFF_FFFE) + 1
+}
+
+/// Who a packet to another client (ed2k or Kad) is obfuscated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientUdpKey {
+    /// An ed2k client, keyed by its user hash and our (the sender's) IP.
+    Ed2k {
+        user_hash: UserHash,
+        sender_ip: Ipv4Addr,
+    },
+    /// A Kad node. It is keyed by its Kad id if we know it, otherwise by the
+    /// verify key it gave us. Our verify key for it goes along, so that it
+    /// can answer.
+    Kad {
+        node_id: Option<[u8; 16]>,
+        receiver_verify_key: u32,
+        sender_verify_key: u32,
+    },
+}
+
+/// Obfuscates a packet for another client or Kad node.
+pub fn encrypt_client_packet(packet: &[u8], key: &ClientUdpKey) -> Vec<u8> {
+    let random: [u8; 2] = rand::thread_rng().gen();
+
+    match key {
+        ClientUdpKey::Ed2k {
+            user_hash,
+            sender_ip,
+        } => {
+            let rc4 = make_key(&[
+                user_hash.as_bytes(),
+                &sender_ip.octets(),
+                &[MAGICVALUE_UDP],
+                &random,
+            ]);
+            let marker = random_marker(|b| b | MARKER_ED2K);
+            seal(marker, random, rc4, MAGICVALUE_UDP_SYNC_CLIENT, &[], packet)
+        }
+        ClientUdpKey::Kad {
+            node_id,
+            receiver_verify_key,
+            sender_verify_key,
+        } => {
+            let (rc4, marker_bits) = match node_id {
+                Some(id) => (make_key(&[id, &random]), 0),
+                None => (
+                    make_key(&[&receiver_verify_key.to_le_bytes(), &random]),
+                    MARKER_KAD_VERIFY_KEY,
+                ),
+            };
+            let marker =
+                random_marker(|b| (b & !(MARKER_ED2K | MARKER_KAD_VERIFY_KEY)) | marker_bits);
+
+            let mut extra = receiver_verify_key.to_le_bytes().to_vec();
+            extra.extend_from_slice(&sender_verify_key.to_le_bytes());
+            seal(
+                marker,
+                random,
+                rc4,
+                MAGICVALUE_UDP_SYNC_CLIENT,
+                &extra,
+                packet,
+            )
+        }
+    }
+}
+
+/// What we know about ourselves that other clients may have used to
+/// obfuscate packets to us.
+#[derive(Debug, Clone, Copy)]
+pub struct OurUdpKeys {
+    pub user_hash: UserHash,
+    pub kad_id: Option<[u8; 16]>,
+    /// The secret from which our verify keys are made, see `udp_verify_key`.
+    pub kad_udp_key: u32,
+}
+
+/// A deobfuscated packet from another client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecryptedClientPacket {
+    pub packet: Vec<u8>,
+    /// For Kad packets, the receiver (our) and sender verify keys.
+    pub kad_verify_keys: Option<(u32, u32)>,
+}
+
+/// Deobfuscates a packet from another client or Kad node at `sender_ip`.
+/// The marker says which key was probably used, but as in eMule all of them
+/// are tried. Returns None if no key fits.
+pub fn decrypt_client_packet(
+    data: &[u8],
+    ours: &OurUdpKeys,
+    sender_ip: Ipv4Addr,
+) -> Option<DecryptedClientPacket> {
+    #[derive(Clone, Copy)]
+    enum Try {
+        Ed2k,
+        KadId,
+        KadVerifyKey,
+    }
+
+    let marker = *data.first()?;
+    let random = data.get(1..3)?;
+
+    let order = if marker & MARKER_ED2K != 0 {
+        [Try::Ed2k, Try::KadId, Try::KadVerifyKey]
+    } else if marker & MARKER_KAD_VERIFY_KEY != 0 {
+        [Try::KadVerifyKey, Try::KadId, Try::Ed2k]
+    } else {
+        [Try::KadId, Try::KadVerifyKey, Try::Ed2k]
+    };
+
+    for attempt in order {
+        let (rc4, kad) = match attempt {
+            Try::Ed2k => (
+                make_key(&[
+                    ours.user_hash.as_bytes(),
+                    &sender_ip.octets(),
+                    &[MAGICVALUE_UDP],
+                    random,
+                ]),
+                false,
+            ),
+            Try::KadId => match &ours.kad_id {
+                Some(id) => (make_key(&[id, random]), true),
+                None => continue,
+            },
+            Try::KadVerifyKey => {
+                let key = udp_verify_key(ours.kad_udp_key, sender_ip);
+                (make_key(&[&key.to_le_bytes(), random]), true)
+            }
+        };
+
+        let Some(rest) = open(data, rc4, MAGICVALUE_UDP_SYNC_CLIENT) else {
+            continue;
+        };
+
+        if !kad {
+            return Some(DecryptedClientPacket {
+                packet: rest,
+                kad_verify_keys: None,
+            });
+        }
+
+        if rest.len() < 8 {
+            continue;
+        }
+        let receiver = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        let sender = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]);
+        return Some(DecryptedClientPacket {
+            packet: rest[8..].to_vec(),
+            kad_verify_keys: Some((receiver, sender)),
+        });
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PACKET: &[u8] = &[OP_EDONKEYPROT, OP_GLOBSEARCHREQ, 1, 2, 3];
+
+    /// The server's side of the exchange: what it would send us.
+    fn server_reply(packet: &[u8], server_key: u32) -> Vec<u8> {
+        let random = [7, 9];
+        let rc4 = make_key(&[
+            &server_key.to_le_bytes(),
+            &[MAGICVALUE_UDP_SERVERCLIENT],
+            &random,
+        ]);
+        seal(0x42, random, rc4, MAGICVALUE_UDP_SYNC_SERVER, &[], packet)
+    }
+
+    #[test]
+    pub fn test_server_packets() {
+        let sent = encrypt_server_packet(PACKET, 0xDEAD_BEEF);
+        assert!(!is_plain_udp_packet(&sent));
+        assert_eq!(sent.len(), PACKET.len() + 8);
+        assert!(!sent.windows(PACKET.len()).any(|w| w == PACKET));
+
+        let reply = server_reply(PACKET, 0xDEAD_BEEF);
+        assert_eq!(decrypt_server_packet(&reply, 0xDEAD_BEEF).unwrap(), PACKET);
+        assert_eq!(decrypt_server_packet(&reply, 0xDEAD_BEEE), None);
+    }
+
+    #[test]
+    pub fn test_ed2k_client_packets() {
+        let ours = OurUdpKeys {
+            user_hash: UserHash::new([3; 16]),
+            kad_id: None,
+            kad_udp_key: 1234,
+        };
+        let sender_ip = Ipv4Addr::new(10, 1, 2, 3);
+        let key = ClientUdpKey::Ed2k {
+            user_hash: ours.user_hash,
+            sender_ip,
+        };
+
+        let sent = encrypt_client_packet(PACKET, &key);
+        assert_eq!(sent[0] & MARKER_ED2K, MARKER_ED2K);
+
+        let received = decrypt_client_packet(&sent, &ours, sender_ip).unwrap();
+        assert_eq!(received.packet, PACKET);
+        assert_eq!(received.kad_verify_keys, None);
+
+        // The sender's IP is part of the key.
+        assert!(decrypt_client_packet(&sent, &ours, Ipv4Addr::new(10, 1, 2, 4)).is_none());
+    }
+
+    #[test]
+    pub fn test_kad_packets() {
+        let ours = OurUdpKeys {
+            user_hash: UserHash::new([3; 16]),
+            kad_id: Some([4; 16]),
+            kad_udp_key: 0x5555_AAAA,
+        };
+        let sender_ip = Ipv4Addr::new(192, 168, 0, 9);
+        let our_verify_key = udp_verify_key(ours.kad_udp_key, sender_ip);
+        assert_ne!(our_verify_key, 0);
+
+        // Keyed by our Kad id.
+        let by_id = ClientUdpKey::Kad {
+            node_id: ours.kad_id,
+            receiver_verify_key: 0,
+            sender_verify_key: 77,
+        };
+        let sent = encrypt_client_packet(PACKET, &by_id);
+        assert_eq!(sent[0] & (MARKER_ED2K | MARKER_KAD_VERIFY_KEY), 0);
+        let received = decrypt_client_packet(&sent, &ours, sender_ip).unwrap();
+        assert_eq!(received.packet, PACKET);
+        assert_eq!(received.kad_verify_keys, Some((0, 77)));
+
+        // Keyed by the verify key we gave the sender.
+        let by_verify_key = ClientUdpKey::Kad {
+            node_id: None,
+            receiver_verify_key: our_verify_key,
+            sender_verify_key: 77,
+        };
+        let sent = encrypt_client_packet(PACKET, &by_verify_key);
+        assert_eq!(sent[0] & MARKER_KAD_VERIFY_KEY, MARKER_KAD_VERIFY_KEY);
+        let received = decrypt_client_packet(&sent, &ours, sender_ip).unwrap();
+        assert_eq!(received.packet, PACKET);
+        assert_eq!(received.kad_verify_keys, Some((our_verify_key, 77)));
+    }
+}
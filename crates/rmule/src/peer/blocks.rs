@@ -0,0 +1,328 @@
+use super::PeerMessage;
+use crate::protocol::Ed2kHash;
+use anyhow::{bail, Context, Result};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::ops::Range;
+
+/// Downloaders request files in blocks of (at most) this size, and
+/// uploaders compress each block as a whole.
+pub const BLOCK_SIZE: u64 = 184_320;
+
+/// Block data is sent in packets carrying at most this many bytes.
+pub const MAX_PART_PAYLOAD: usize = 10_240;
+
+/// Splits a block of file data into the messages which send it. If
+/// `compress` is set (and the peer supports it) the block is compressed,
+/// unless that does not make it any smaller.
+pub fn make_part_messages(
+    hash: Ed2kHash,
+    start: u64,
+    data: &[u8],
+    compress: bool,
+) -> Vec<PeerMessage> {
+    if compress {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        // Writing to a Vec cannot fail.
+        encoder.write_all(data).unwrap();
+        let packed = encoder.finish().unwrap();
+
+        if packed.len() < data.len() {
+            return packed
+                .chunks(MAX_PART_PAYLOAD)
+                .map(|chunk| PeerMessage::CompressedPart {
+                    hash,
+                    start,
+                    packed_size: packed.len() as u32,
+                    data: chunk.to_vec(),
+                })
+                .collect();
+        }
+    }
+
+    data.chunks(MAX_PART_PAYLOAD)
+        .enumerate()
+        .map(|(i, chunk)| PeerMessage::SendingPart {
+            hash,
+            start: start + (i * MAX_PART_PAYLOAD) as u64,
+            data: chunk.to_vec(),
+        })
+        .collect()
+}
+
+/// Some file data received from a peer, ready to be written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedData {
+    pub hash: Ed2kHash,
+    pub start: u64,
+    pub data: Vec<u8>,
+}
+
+/// Turns `SendingPart` and `CompressedPart` messages back into file data.
+/// Uncompressed data is usable immediately; compressed blocks have to be
+/// collected until they are complete before they can be inflated.
+///
+/// Everything here comes from the peer, so only data for blocks we asked
+/// for is accepted, and the blocks must be asked for with `request` first.
+#[derive(Debug, Default)]
+pub struct BlockAssembler {
+    /// The blocks we have requested and not yet received all of.
+    requested: Vec<RequestedBlock>,
+    /// Compressed blocks being received, with their announced packed size.
+    /// There is at most one per requested block.
+    packed_blocks: HashMap<(Ed2kHash, u64), (u32, Vec<u8>)>,
+}
+
+/// A block we have asked for. Uncompressed data can arrive in any order, so
+/// we keep track of which parts of it are still to come.
+#[derive(Debug)]
+struct RequestedBlock {
+    hash: Ed2kHash,
+    range: Range<u64>,
+    remaining: Vec<Range<u64>>,
+}
+
+impl BlockAssembler {
+    /// How many blocks can be outstanding at once. eMule asks for three at
+    /// a time and asks for more as they arrive.
+    pub const MAX_REQUESTED_BLOCKS: usize = 12;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that we have asked for a block, as one of the ranges in a
+    /// `RequestParts` message.
+    pub fn request(&mut self, hash: Ed2kHash, range: Range<u64>) -> Result<()> {
+        if range.is_empty() || range.end - range.start > BLOCK_SIZE {
+            bail!("Cannot request {range:?}, blocks are at most {BLOCK_SIZE} bytes");
+        }
+        if self.requested.len() >= Self::MAX_REQUESTED_BLOCKS {
+            bail!(
+                "There are already {} blocks requested",
+                self.requested.len()
+            );
+        }
+        self.requested.push(RequestedBlock {
+            hash,
+            remaining: vec![range.clone()],
+            range,
+        });
+        Ok(())
+    }
+
+    /// Adds a message. Returns the data if it completes something, and None
+    /// if more of a compressed block is still to come or the message has
+    /// nothing to do with data. Data we did not ask for is an error.
+    pub fn add(&mut self, msg: PeerMessage) -> Result<Option<ReceivedData>> {
+        match msg {
+            PeerMessage::SendingPart { hash, start, data } => {
+                let end = start.saturating_add(data.len() as u64);
+                // The data must all be in one part of a block which is still
+                // to come, so nothing is accepted twice.
+                let Some((idx, part)) = self.requested.iter().enumerate().find_map(|(i, block)| {
+                    let part = block
+                        .remaining
+                        .iter()
+                        .position(|r| r.start <= start && end <= r.end);
+                    part.filter(|_| block.hash == hash).map(|part| (i, part))
+                }) else {
+                    bail!("Received {start}..{end} of {hash}, which was not requested");
+                };
+
+                // Usually this just shrinks the part from the front, but
+                // out of order data splits it in two.
+                let block = &mut self.requested[idx];
+                let part = block.remaining.swap_remove(part);
+                block.remaining.extend(
+                    [part.start..start, end..part.end]
+                        .into_iter()
+                        .filter(|r| !r.is_empty()),
+                );
+                if block.remaining.is_empty() {
+                    self.requested.remove(idx);
+                }
+
+                Ok(Some(ReceivedData { hash, start, data }))
+            }
+            PeerMessage::CompressedPart {
+                hash,
+                start,
+                packed_size,
+                data,
+            } => {
+                // Compressed blocks are sent whole, starting where we asked,
+                // so none of the block can have arrived uncompressed.
+                let Some(idx) = self.requested.iter().position(|block| {
+                    block.hash == hash
+                        && block.range.start == start
+                        && block.remaining == [block.range.clone()]
+                }) else {
+                    bail!(
+                        "Received a compressed block at {start} of {hash}, which was not requested"
+                    );
+                };
+                if packed_size as u64 > MAX_PACKED_SIZE {
+                    bail!("Compressed block at {start} claims to be {packed_size} bytes");
+                }
+
+                let (size, packed) = self
+                    .packed_blocks
+                    .entry((hash, start))
+                    .or_insert_with(|| (packed_size, Vec::new()));
+                if *size != packed_size || packed.len() + data.len() > packed_size as usize {
+                    bail!("Compressed block at {start} is larger than the {packed_size} bytes announced");
+                }
+                packed.extend_from_slice(&data);
+
+                if packed.len() < packed_size as usize {
+                    return Ok(None);
+                }
+
+                let (_, packed) = self.packed_blocks.remove(&(hash, start)).unwrap();
+                let range = self.requested.remove(idx).range;
+
+                // A block inflates to at most BLOCK_SIZE; anything more is
+                // not what we asked for.
+                let mut data = Vec::new();
+                ZlibDecoder::new(&packed[..])
+                    .take(BLOCK_SIZE + 1)
+                    .read_to_end(&mut data)
+                    .with_context(|| format!("Inflating block at {start} failed"))?;
+                if data.len() as u64 > range.end - range.start {
+                    bail!("Compressed block at {start} is larger than the {range:?} requested");
+                }
+
+                Ok(Some(ReceivedData { hash, start, data }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Forgets the requested blocks and any partly received compressed
+    /// ones, e.g. after the transfer was cancelled.
+    pub fn clear(&mut self) {
+        self.requested.clear();
+        self.packed_blocks.clear();
+    }
+}
+
+/// The most a BLOCK_SIZE block can take up compressed, when it does not
+/// compress at all. This is zlib's compressBound.
+const MAX_PACKED_SIZE: u64 =
+    BLOCK_SIZE + (BLOCK_SIZE >> 12) + (BLOCK_SIZE >> 14) + (BLOCK_SIZE >> 25) + 13;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hash() -> Ed2kHash {
+        Ed2kHash::new([1; 16])
+    }
+
+    fn sending(start: u64, len: usize) -> PeerMessage {
+        PeerMessage::SendingPart {
+            hash: hash(),
+            start,
+            data: vec![7; len],
+        }
+    }
+
+    fn compressed(packed_size: u32, data: Vec<u8>) -> PeerMessage {
+        PeerMessage::CompressedPart {
+            hash: hash(),
+            start: 0,
+            packed_size,
+            data,
+        }
+    }
+
+    #[test]
+    pub fn test_blocks_larger_than_block_size_cannot_be_requested() {
+        let mut assembler = BlockAssembler::new();
+        assert!(assembler.request(hash(), 0..BLOCK_SIZE + 1).is_err());
+        assert!(assembler.request(hash(), 0..0).is_err());
+        assert!(assembler.request(hash(), 0..BLOCK_SIZE).is_ok());
+    }
+
+    #[test]
+    pub fn test_only_so_many_blocks_can_be_requested() {
+        let mut assembler = BlockAssembler::new();
+        for _ in 0..BlockAssembler::MAX_REQUESTED_BLOCKS {
+            assembler.request(hash(), 0..100).unwrap();
+        }
+        assert!(assembler.request(hash(), 0..100).is_err());
+    }
+
+    #[test]
+    pub fn test_data_outside_the_requested_blocks_is_rejected() {
+        let mut assembler = BlockAssembler::new();
+        assembler.request(hash(), 5000..6000).unwrap();
+        assert!(assembler.add(sending(0, 1000)).is_err());
+        // Starts inside the block but runs past its end.
+        assert!(assembler.add(sending(5500, 1000)).is_err());
+        let other_file = PeerMessage::SendingPart {
+            hash: Ed2kHash::new([2; 16]),
+            start: 5000,
+            data: vec![7; 1000],
+        };
+        assert!(assembler.add(other_file).is_err());
+    }
+
+    #[test]
+    pub fn test_data_in_order_completes_the_block() {
+        let mut assembler = BlockAssembler::new();
+        assembler.request(hash(), 5000..6000).unwrap();
+        assert!(assembler.add(sending(5000, 600)).unwrap().is_some());
+        assert!(assembler.add(sending(5600, 400)).unwrap().is_some());
+        // That was all of it, so more is not wanted.
+        assert!(assembler.add(sending(5600, 400)).is_err());
+        assert!(assembler.requested.is_empty());
+    }
+
+    #[test]
+    pub fn test_data_out_of_order_completes_the_block() {
+        let mut assembler = BlockAssembler::new();
+        assembler.request(hash(), 0..3000).unwrap();
+        assert!(assembler.add(sending(1000, 1000)).unwrap().is_some());
+        assert!(assembler.add(sending(2000, 1000)).unwrap().is_some());
+        assert!(assembler.add(sending(0, 1000)).unwrap().is_some());
+        // The block no longer takes up one of the request slots.
+        assert!(assembler.requested.is_empty());
+    }
+
+    #[test]
+    pub fn test_data_already_received_is_rejected() {
+        let mut assembler = BlockAssembler::new();
+        assembler.request(hash(), 0..3000).unwrap();
+        assembler.add(sending(1000, 1000)).unwrap();
+        assert!(assembler.add(sending(1000, 1000)).is_err());
+        // Overlapping what has arrived, at either end.
+        assert!(assembler.add(sending(500, 1000)).is_err());
+        assert!(assembler.add(sending(1500, 1000)).is_err());
+        assert_eq!(assembler.requested[0].remaining.len(), 2);
+    }
+
+    #[test]
+    pub fn test_compressed_blocks_cannot_claim_more_than_a_block_compresses_to() {
+        let mut assembler = BlockAssembler::new();
+        assembler.request(hash(), 0..500).unwrap();
+        assert!(assembler.add(compressed(u32::MAX, vec![0; 10])).is_err());
+    }
+
+    #[test]
+    pub fn test_compressed_blocks_cannot_inflate_to_more_than_was_requested() {
+        let mut assembler = BlockAssembler::new();
+        assembler.request(hash(), 0..500).unwrap();
+        let PeerMessage::CompressedPart {
+            packed_size, data, ..
+        } = make_part_messages(hash(), 0, &[7; 1000], true).remove(0)
+        else {
+            panic!("Expected a compressed block");
+        };
+        assert!(assembler.add(compressed(packed_size, data)).is_err());
+    }
+}
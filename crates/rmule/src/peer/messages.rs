@@ -0,0 +1,646 @@
+use super::{read_exchanged_sources, write_exchanged_sources};
+use crate::configuration::{ObfuscationMode, UserHash};
+use crate::hashing::{read_aich_hash, AichRecoveryData};
+use crate::protocol::opcodes::*;
+use crate::protocol::{
+    read_bytes, read_hash, read_tag_list, read_u16_string, write_tag_list, write_u16_string,
+    AichHash, Ed2kHash, Packet, Tag, TagName, TagValue, EDONKEY_VERSION, EMULE_VERSION,
+};
+use crate::server::FoundSource;
+use anyhow::{bail, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::Range;
+
+/// What a client tells another about itself in OP_HELLO and OP_HELLOANSWER.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HelloInfo {
+    pub user_hash: UserHash,
+    /// The client's id on its server: its IP address or a low id.
+    pub client_id: u32,
+    pub tcp_port: u16,
+    pub nick_name: String,
+    /// The CT_VERSION tag, always 0x3C for anything modern.
+    pub version: u32,
+    /// The CT_EMULE_VERSION tag, sent by eMule compatible clients.
+    pub emule_version: Option<u32>,
+    pub udp_port: Option<u16>,
+    pub kad_udp_port: Option<u16>,
+    /// The CT_EMULE_MISCOPTIONS1 and 2 tags, which describe what the client
+    /// supports. Use the accessor methods rather than picking at the bits.
+    pub misc_options1: u32,
+    pub misc_options2: u32,
+    /// The server the client is connected to, if any.
+    pub server: Option<SocketAddrV4>,
+}
+
+impl HelloInfo {
+    // Bit positions within CT_EMULE_MISCOPTIONS1.
+    const MO1_AICH_VERSION_SHIFT: u32 = 29;
+    const MO1_UNICODE: u32 = 1 << 28;
+    const MO1_DATA_COMPRESSION_SHIFT: u32 = 20;
+
+    // Bit positions within CT_EMULE_MISCOPTIONS2.
+    const MO2_KAD_VERSION_MASK: u32 = 0x0F;
+    const MO2_LARGE_FILES: u32 = 1 << 4;
+    const MO2_SUPPORTS_CRYPT: u32 = 1 << 7;
+    const MO2_REQUESTS_CRYPT: u32 = 1 << 8;
+    const MO2_REQUIRES_CRYPT: u32 = 1 << 9;
+    const MO2_SOURCE_EX2: u32 = 1 << 10;
+
+    /// Describes ourselves, with the capabilities rMule supports.
+    pub fn new(user_hash: UserHash, nick_name: String, client_id: u32, tcp_port: u16) -> Self {
+        Self {
+            user_hash,
+            client_id,
+            tcp_port,
+            nick_name,
+            version: EDONKEY_VERSION,
+            emule_version: Some(EMULE_VERSION),
+            udp_port: None,
+            kad_udp_port: None,
+            misc_options1: (1 << Self::MO1_AICH_VERSION_SHIFT)
+                | Self::MO1_UNICODE
+                | (1 << Self::MO1_DATA_COMPRESSION_SHIFT),
+            misc_options2: Self::MO2_LARGE_FILES | Self::MO2_SOURCE_EX2,
+            server: None,
+        }
+    }
+
+    /// Sets the bits which tell the peer how we feel about obfuscation.
+    pub fn set_obfuscation(&mut self, mode: ObfuscationMode) {
+        let bits = match mode {
+            ObfuscationMode::Disabled => 0,
+            ObfuscationMode::Preferred => Self::MO2_SUPPORTS_CRYPT | Self::MO2_REQUESTS_CRYPT,
+            ObfuscationMode::Required => {
+                Self::MO2_SUPPORTS_CRYPT | Self::MO2_REQUESTS_CRYPT | Self::MO2_REQUIRES_CRYPT
+            }
+        };
+        self.misc_options2 &=
+            !(Self::MO2_SUPPORTS_CRYPT | Self::MO2_REQUESTS_CRYPT | Self::MO2_REQUIRES_CRYPT);
+        self.misc_options2 |= bits;
+    }
+
+    pub fn supports_unicode(&self) -> bool {
+        self.misc_options1 & Self::MO1_UNICODE != 0
+    }
+
+    /// Whether the client can receive OP_COMPRESSEDPART.
+    pub fn supports_compression(&self) -> bool {
+        (self.misc_options1 >> Self::MO1_DATA_COMPRESSION_SHIFT) & 0x0F != 0
+    }
+
+    /// Whether the client can send and answer AICH recovery requests.
+    pub fn supports_aich(&self) -> bool {
+        (self.misc_options1 >> Self::MO1_AICH_VERSION_SHIFT) & 0x07 != 0
+    }
+
+    pub fn supports_large_files(&self) -> bool {
+        self.misc_options2 & Self::MO2_LARGE_FILES != 0
+    }
+
+    pub fn supports_crypt(&self) -> bool {
+        self.misc_options2 & Self::MO2_SUPPORTS_CRYPT != 0
+    }
+
+    pub fn requests_crypt(&self) -> bool {
+        self.misc_options2 & Self::MO2_REQUESTS_CRYPT != 0
+    }
+
+    pub fn requires_crypt(&self) -> bool {
+        self.misc_options2 & Self::MO2_REQUIRES_CRYPT != 0
+    }
+
+    pub fn supports_source_exchange2(&self) -> bool {
+        self.misc_options2 & Self::MO2_SOURCE_EX2 != 0
+    }
+
+    pub fn kad_version(&self) -> u8 {
+        (self.misc_options2 & Self::MO2_KAD_VERSION_MASK) as u8
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.user_hash.as_bytes());
+        out.write_u32::<LittleEndian>(self.client_id).unwrap();
+        out.write_u16::<LittleEndian>(self.tcp_port).unwrap();
+
+        let mut tags = vec![
+            Tag::new(CT_NAME, TagValue::String(self.nick_name.clone())),
+            Tag::new(CT_VERSION, TagValue::U32(self.version)),
+        ];
+        if self.udp_port.is_some() || self.kad_udp_port.is_some() {
+            let ports =
+                ((self.kad_udp_port.unwrap_or(0) as u32) << 16) | self.udp_port.unwrap_or(0) as u32;
+            tags.push(Tag::new(CT_EMULE_UDPPORTS, TagValue::U32(ports)));
+        }
+        tags.push(Tag::new(
+            CT_EMULE_MISCOPTIONS1,
+            TagValue::U32(self.misc_options1),
+        ));
+        tags.push(Tag::new(
+            CT_EMULE_MISCOPTIONS2,
+            TagValue::U32(self.misc_options2),
+        ));
+        if let Some(v) = self.emule_version {
+            tags.push(Tag::new(CT_EMULE_VERSION, TagValue::U32(v)));
+        }
+        write_tag_list(out, &tags, false);
+
+        let (ip, port) = match self.server {
+            Some(s) => (*s.ip(), s.port()),
+            None => (Ipv4Addr::UNSPECIFIED, 0),
+        };
+        out.extend_from_slice(&ip.octets());
+        out.write_u16::<LittleEndian>(port).unwrap();
+    }
+
+    fn read(input: &mut Cursor<&[u8]>) -> Result<Self> {
+        let mut user_hash = [0u8; 16];
+        input.read_exact(&mut user_hash)?;
+        let client_id = input.read_u32::<LittleEndian>()?;
+        let tcp_port = input.read_u16::<LittleEndian>()?;
+
+        let mut info = Self {
+            user_hash: UserHash::new(user_hash),
+            client_id,
+            tcp_port,
+            nick_name: String::new(),
+            version: 0,
+            emule_version: None,
+            udp_port: None,
+            kad_udp_port: None,
+            misc_options1: 0,
+            misc_options2: 0,
+            server: None,
+        };
+
+        for tag in read_tag_list(input)? {
+            let TagName::Id(id) = tag.name else { continue };
+            match id {
+                CT_NAME => info.nick_name = tag.as_str().unwrap_or_default().to_owned(),
+                CT_VERSION => info.version = tag.as_u32().unwrap_or_default(),
+                CT_EMULE_VERSION => info.emule_version = tag.as_u32(),
+                CT_EMULE_MISCOPTIONS1 => info.misc_options1 = tag.as_u32().unwrap_or_default(),
+                CT_EMULE_MISCOPTIONS2 => info.misc_options2 = tag.as_u32().unwrap_or_default(),
+                CT_EMULE_UDPPORTS => {
+                    let ports = tag.as_u32().unwrap_or_default();
+                    info.udp_port = Some(ports as u16).filter(|&p| p != 0);
+                    info.kad_udp_port = Some((ports >> 16) as u16).filter(|&p| p != 0);
+                }
+                _ => {}
+            }
+        }
+
+        // Some clients stop after the tags.
+        let mut ip = [0u8; 4];
+        if input.read_exact(&mut ip).is_ok() {
+            let port = input.read_u16::<LittleEndian>().unwrap_or(0);
+            if ip != [0; 4] {
+                info.server = Some(SocketAddrV4::new(Ipv4Addr::from(ip), port));
+            }
+        }
+
+        Ok(info)
+    }
+}
+
+/// Which parts of a file a client has, from OP_FILESTATUS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartStatus {
+    /// The client has the whole file. On the wire this is a part count of 0.
+    Complete,
+    /// One flag per ed2k part.
+    Partial(Vec<bool>),
+}
+
+impl PartStatus {
+    pub fn has_part(&self, part: usize) -> bool {
+        match self {
+            PartStatus::Complete => true,
+            PartStatus::Partial(parts) => parts.get(part).copied().unwrap_or(false),
+        }
+    }
+}
+
+/// The messages which make up the client to client protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    /// Opens a conversation. Answered with `HelloAnswer`.
+    Hello(HelloInfo),
+    HelloAnswer(HelloInfo),
+    /// Asks for the name of a file, which is how a downloader says which
+    /// file it is interested in. Answered with `FileName` or `FileNotFound`.
+    FileRequest(Ed2kHash),
+    FileName {
+        hash: Ed2kHash,
+        name: String,
+    },
+    FileNotFound(Ed2kHash),
+    /// Selects the file that following requests are about. Answered with
+    /// `FileStatus`.
+    SetFileId(Ed2kHash),
+    FileStatus {
+        hash: Ed2kHash,
+        parts: PartStatus,
+    },
+    HashSetRequest(Ed2kHash),
+    HashSetAnswer {
+        hash: Ed2kHash,
+        part_hashes: Vec<Ed2kHash>,
+    },
+    /// Asks to be put into the upload queue.
+    StartUploadRequest(Ed2kHash),
+    /// Tells a downloader where it is in the upload queue.
+    QueueRank(u16),
+    /// Tells a downloader that it can start requesting blocks.
+    AcceptUpload,
+    CancelTransfer,
+    OutOfPartRequests,
+    /// Asks for up to three byte ranges of a file. The ends are exclusive.
+    RequestParts {
+        hash: Ed2kHash,
+        ranges: Vec<Range<u64>>,
+    },
+    /// Some uncompressed file data.
+    SendingPart {
+        hash: Ed2kHash,
+        start: u64,
+        data: Vec<u8>,
+    },
+    /// A piece of a compressed block. A block is compressed as a whole and
+    /// the compressed data split over several messages; `packed_size` is the
+    /// size of the whole compressed block and `start` the offset of the
+    /// (uncompressed) block in the file.
+    CompressedPart {
+        hash: Ed2kHash,
+        start: u64,
+        packed_size: u32,
+        data: Vec<u8>,
+    },
+    /// Asks for the sources the peer knows of a file it is downloading,
+    /// in up to the given source exchange version. Answered with
+    /// `AnswerSources2`, or not at all if the peer asked too recently.
+    RequestSources2 {
+        hash: Ed2kHash,
+        version: u8,
+    },
+    /// Up to `MAX_EXCHANGED_SOURCES` sources of a file, in the given
+    /// source exchange version.
+    AnswerSources2 {
+        hash: Ed2kHash,
+        version: u8,
+        sources: Vec<FoundSource>,
+    },
+    /// Asks for the AICH hashes needed to repair a corrupt part, which must
+    /// lead up to the master hash we trust. Answered with `AichAnswer`, or
+    /// `AichNotAvailable` if the peer does not have them.
+    AichRequest {
+        hash: Ed2kHash,
+        part: u16,
+        master: AichHash,
+    },
+    AichAnswer {
+        hash: Ed2kHash,
+        part: u16,
+        master: AichHash,
+        recovery: AichRecoveryData,
+    },
+    AichNotAvailable(Ed2kHash),
+    /// Anything we do not (yet) understand.
+    Unknown(Packet),
+}
+
+impl PeerMessage {
+    /// The most ranges which fit into a single OP_REQUESTPARTS.
+    pub const MAX_REQUESTED_RANGES: usize = 3;
+
+    /// Converts the message to a packet. Only the first three ranges of
+    /// a `RequestParts` are sent.
+    pub fn to_packet(&self) -> Packet {
+        let mut p = Vec::new();
+
+        match self {
+            PeerMessage::Hello(info) => {
+                // The length of the user hash, for historical reasons.
+                p.push(16);
+                info.write(&mut p);
+                Packet::edonkey(OP_HELLO, p)
+            }
+            PeerMessage::HelloAnswer(info) => {
+                info.write(&mut p);
+                Packet::edonkey(OP_HELLOANSWER, p)
+            }
+            PeerMessage::FileRequest(hash) => hash_packet(OP_REQUESTFILENAME, hash),
+            PeerMessage::FileName { hash, name } => {
+                p.extend_from_slice(hash.as_bytes());
+                write_u16_string(&mut p, name);
+                Packet::edonkey(OP_REQFILENAMEANSWER, p)
+            }
+            PeerMessage::FileNotFound(hash) => hash_packet(OP_FILEREQANSNOFIL, hash),
+            PeerMessage::SetFileId(hash) => hash_packet(OP_SETREQFILEID, hash),
+            PeerMessage::FileStatus { hash, parts } => {
+                p.extend_from_slice(hash.as_bytes());
+                match parts {
+                    PartStatus::Complete => p.write_u16::<LittleEndian>(0).unwrap(),
+                    PartStatus::Partial(parts) => {
+                        p.write_u16::<LittleEndian>(parts.len() as u16).unwrap();
+                        for chunk in parts.chunks(8) {
+                            let byte = chunk
+                                .iter()
+                                .enumerate()
+                                .fold(0u8, |b, (i, &has)| b | ((has as u8) << i));
+                            p.push(byte);
+                        }
+                    }
+                }
+                Packet::edonkey(OP_FILESTATUS, p)
+            }
+            PeerMessage::HashSetRequest(hash) => hash_packet(OP_HASHSETREQUEST, hash),
+            PeerMessage::HashSetAnswer { hash, part_hashes } => {
+                p.extend_from_slice(hash.as_bytes());
+                p.write_u16::<LittleEndian>(part_hashes.len() as u16)
+                    .unwrap();
+                for h in part_hashes {
+                    p.extend_from_slice(h.as_bytes());
+                }
+                Packet::edonkey(OP_HASHSETANSWER, p)
+            }
+            PeerMessage::StartUploadRequest(hash) => hash_packet(OP_STARTUPLOADREQ, hash),
+            PeerMessage::QueueRank(rank) => {
+                p.write_u16::<LittleEndian>(*rank).unwrap();
+                p.extend_from_slice(&[0; 10]);
+                Packet::emule(OP_QUEUERANKING, p)
+            }
+            PeerMessage::AcceptUpload => Packet::edonkey(OP_ACCEPTUPLOADREQ, p),
+            PeerMessage::CancelTransfer => Packet::edonkey(OP_CANCELTRANSFER, p),
+            PeerMessage::OutOfPartRequests => Packet::edonkey(OP_OUTOFPARTREQS, p),
+            PeerMessage::RequestParts { hash, ranges } => {
+                p.extend_from_slice(hash.as_bytes());
+                let mut slots = [0..0, 0..0, 0..0];
+                for (slot, range) in slots.iter_mut().zip(ranges) {
+                    *slot = range.clone();
+                }
+
+                if slots.iter().any(|r| r.end > u32::MAX as u64) {
+                    slots
+                        .iter()
+                        .for_each(|r| p.write_u64::<LittleEndian>(r.start).unwrap());
+                    slots
+                        .iter()
+                        .for_each(|r| p.write_u64::<LittleEndian>(r.end).unwrap());
+                    Packet::emule(OP_REQUESTPARTS_I64, p)
+                } else {
+                    slots
+                        .iter()
+                        .for_each(|r| p.write_u32::<LittleEndian>(r.start as u32).unwrap());
+                    slots
+                        .iter()
+                        .for_each(|r| p.write_u32::<LittleEndian>(r.end as u32).unwrap());
+                    Packet::edonkey(OP_REQUESTPARTS, p)
+                }
+            }
+            PeerMessage::SendingPart { hash, start, data } => {
+                p.extend_from_slice(hash.as_bytes());
+                let end = start + data.len() as u64;
+                let large = end > u32::MAX as u64;
+                if large {
+                    p.write_u64::<LittleEndian>(*start).unwrap();
+                    p.write_u64::<LittleEndian>(end).unwrap();
+                } else {
+                    p.write_u32::<LittleEndian>(*start as u32).unwrap();
+                    p.write_u32::<LittleEndian>(end as u32).unwrap();
+                }
+                p.extend_from_slice(data);
+
+                if large {
+                    Packet::emule(OP_SENDINGPART_I64, p)
+                } else {
+                    Packet::edonkey(OP_SENDINGPART, p)
+                }
+            }
+            PeerMessage::CompressedPart {
+                hash,
+                start,
+                packed_size,
+                data,
+            } => {
+                p.extend_from_slice(hash.as_bytes());
+                let opcode = if *start > u32::MAX as u64 {
+                    p.write_u64::<LittleEndian>(*start).unwrap();
+                    OP_COMPRESSEDPART_I64
+                } else {
+                    p.write_u32::<LittleEndian>(*start as u32).unwrap();
+                    OP_COMPRESSEDPART
+                };
+                p.write_u32::<LittleEndian>(*packed_size).unwrap();
+                p.extend_from_slice(data);
+                Packet::emule(opcode, p)
+            }
+            PeerMessage::RequestSources2 { hash, version } => {
+                p.push(*version);
+                // Options, of which none are defined.
+                p.write_u16::<LittleEndian>(0).unwrap();
+                p.extend_from_slice(hash.as_bytes());
+                Packet::emule(OP_REQUESTSOURCES2, p)
+            }
+            PeerMessage::AnswerSources2 {
+                hash,
+                version,
+                sources,
+            } => {
+                p.push(*version);
+                p.extend_from_slice(hash.as_bytes());
+                write_exchanged_sources(&mut p, *version, sources);
+                Packet::emule(OP_ANSWERSOURCES2, p)
+            }
+            PeerMessage::AichRequest { hash, part, master } => {
+                p.extend_from_slice(hash.as_bytes());
+                p.write_u16::<LittleEndian>(*part).unwrap();
+                p.extend_from_slice(master.as_bytes());
+                Packet::emule(OP_AICHREQUEST, p)
+            }
+            PeerMessage::AichAnswer {
+                hash,
+                part,
+                master,
+                recovery,
+            } => {
+                p.extend_from_slice(hash.as_bytes());
+                p.write_u16::<LittleEndian>(*part).unwrap();
+                p.extend_from_slice(master.as_bytes());
+                recovery.write(&mut p);
+                Packet::emule(OP_AICHANSWER, p)
+            }
+            // The answer with nothing after the file hash.
+            PeerMessage::AichNotAvailable(hash) => {
+                Packet::emule(OP_AICHANSWER, hash.as_bytes().to_vec())
+            }
+            PeerMessage::Unknown(packet) => packet.clone(),
+        }
+    }
+
+    /// Parses a packet received from a peer. Packets we do not understand
+    /// become `Unknown` rather than errors, so that the conversation can go
+    /// on; packets we do understand but which are malformed are errors.
+    pub fn from_packet(packet: Packet) -> Result<Self> {
+        let mut input = Cursor::new(&packet.payload[..]);
+
+        let msg = match (packet.protocol, packet.opcode) {
+            (OP_EDONKEYPROT, OP_HELLO) => {
+                if input.read_u8()? != 16 {
+                    bail!("OP_HELLO with an unexpected user hash length");
+                }
+                PeerMessage::Hello(HelloInfo::read(&mut input)?)
+            }
+            (OP_EDONKEYPROT, OP_HELLOANSWER) => {
+                PeerMessage::HelloAnswer(HelloInfo::read(&mut input)?)
+            }
+            (OP_EDONKEYPROT, OP_REQUESTFILENAME) => {
+                // Clients supporting extended requests append more data,
+                // which we do not need.
+                PeerMessage::FileRequest(read_hash(&mut input)?)
+            }
+            (OP_EDONKEYPROT, OP_REQFILENAMEANSWER) => PeerMessage::FileName {
+                hash: read_hash(&mut input)?,
+                name: read_u16_string(&mut input)?,
+            },
+            (OP_EDONKEYPROT, OP_FILEREQANSNOFIL) => {
+                PeerMessage::FileNotFound(read_hash(&mut input)?)
+            }
+            (OP_EDONKEYPROT, OP_SETREQFILEID) => PeerMessage::SetFileId(read_hash(&mut input)?),
+            (OP_EDONKEYPROT, OP_FILESTATUS) => {
+                let hash = read_hash(&mut input)?;
+                let count = input.read_u16::<LittleEndian>()? as usize;
+                let parts = if count == 0 {
+                    PartStatus::Complete
+                } else {
+                    let bytes = read_bytes(&mut input, count.div_ceil(8))?;
+                    PartStatus::Partial(
+                        (0..count)
+                            .map(|i| bytes[i / 8] & (1 << (i % 8)) != 0)
+                            .collect(),
+                    )
+                };
+                PeerMessage::FileStatus { hash, parts }
+            }
+            (OP_EDONKEYPROT, OP_HASHSETREQUEST) => {
+                PeerMessage::HashSetRequest(read_hash(&mut input)?)
+            }
+            (OP_EDONKEYPROT, OP_HASHSETANSWER) => {
+                let hash = read_hash(&mut input)?;
+                let count = input.read_u16::<LittleEndian>()? as usize;
+                let part_hashes = (0..count)
+                    .map(|_| read_hash(&mut input))
+                    .collect::<Result<_>>()?;
+                PeerMessage::HashSetAnswer { hash, part_hashes }
+            }
+            (OP_EDONKEYPROT, OP_STARTUPLOADREQ) => {
+                PeerMessage::StartUploadRequest(read_hash(&mut input)?)
+            }
+            (OP_EDONKEYPROT, OP_QUEUERANK) => {
+                let rank = input.read_u32::<LittleEndian>()?;
+                PeerMessage::QueueRank(rank.min(u16::MAX as u32) as u16)
+            }
+            (OP_EMULEPROT, OP_QUEUERANKING) => {
+                PeerMessage::QueueRank(input.read_u16::<LittleEndian>()?)
+            }
+            (OP_EDONKEYPROT, OP_ACCEPTUPLOADREQ) => PeerMessage::AcceptUpload,
+            (OP_EDONKEYPROT, OP_CANCELTRANSFER) => PeerMessage::CancelTransfer,
+            (OP_EDONKEYPROT, OP_OUTOFPARTREQS) => PeerMessage::OutOfPartRequests,
+            (OP_EDONKEYPROT, OP_REQUESTPARTS) | (OP_EMULEPROT, OP_REQUESTPARTS_I64) => {
+                let hash = read_hash(&mut input)?;
+                let mut offsets = [0u64; 6];
+                for offset in offsets.iter_mut() {
+                    *offset = if packet.opcode == OP_REQUESTPARTS {
+                        input.read_u32::<LittleEndian>()? as u64
+                    } else {
+                        input.read_u64::<LittleEndian>()?
+                    };
+                }
+                let ranges = (0..3)
+                    .map(|i| offsets[i]..offsets[i + 3])
+                    .filter(|r| !r.is_empty())
+                    .collect();
+                PeerMessage::RequestParts { hash, ranges }
+            }
+            (OP_EDONKEYPROT, OP_SENDINGPART) | (OP_EMULEPROT, OP_SENDINGPART_I64) => {
+                let hash = read_hash(&mut input)?;
+                let (start, end) = if packet.opcode == OP_SENDINGPART {
+                    (
+                        input.read_u32::<LittleEndian>()? as u64,
+                        input.read_u32::<LittleEndian>()? as u64,
+                    )
+                } else {
+                    (
+                        input.read_u64::<LittleEndian>()?,
+                        input.read_u64::<LittleEndian>()?,
+                    )
+                };
+                let data = packet.payload[input.position() as usize..].to_vec();
+                if end < start || end - start != data.len() as u64 {
+                    bail!("Sending part for {start}..{end} has {} bytes", data.len());
+                }
+                PeerMessage::SendingPart { hash, start, data }
+            }
+            (OP_EMULEPROT, OP_COMPRESSEDPART) | (OP_EMULEPROT, OP_COMPRESSEDPART_I64) => {
+                let hash = read_hash(&mut input)?;
+                let start = if packet.opcode == OP_COMPRESSEDPART {
+                    input.read_u32::<LittleEndian>()? as u64
+                } else {
+                    input.read_u64::<LittleEndian>()?
+                };
+                let packed_size = input.read_u32::<LittleEndian>()?;
+                let data = packet.payload[input.position() as usize..].to_vec();
+                PeerMessage::CompressedPart {
+                    hash,
+                    start,
+                    packed_size,
+                    data,
+                }
+            }
+            (OP_EMULEPROT, OP_REQUESTSOURCES2) => {
+                let version = input.read_u8()?;
+                let _options = input.read_u16::<LittleEndian>()?;
+                PeerMessage::RequestSources2 {
+                    version,
+                    hash: read_hash(&mut input)?,
+                }
+            }
+            (OP_EMULEPROT, OP_ANSWERSOURCES2) => {
+                let version = input.read_u8()?;
+                let hash = read_hash(&mut input)?;
+                PeerMessage::AnswerSources2 {
+                    hash,
+                    version,
+                    sources: read_exchanged_sources(&mut input, version)?,
+                }
+            }
+            (OP_EMULEPROT, OP_AICHREQUEST) => PeerMessage::AichRequest {
+                hash: read_hash(&mut input)?,
+                part: input.read_u16::<LittleEndian>()?,
+                master: read_aich_hash(&mut input)?,
+            },
+            (OP_EMULEPROT, OP_AICHANSWER) => {
+                let hash = read_hash(&mut input)?;
+                if input.position() == packet.payload.len() as u64 {
+                    PeerMessage::AichNotAvailable(hash)
+                } else {
+                    PeerMessage::AichAnswer {
+                        hash,
+                        part: input.read_u16::<LittleEndian>()?,
+                        master: read_aich_hash(&mut input)?,
+                        recovery: AichRecoveryData::read(&mut input)?,
+                    }
+                }
+            }
+            _ => PeerMessage::Unknown(packet),
+        };
+
+        Ok(msg)
+    }
+}
+
+fn hash_packet(opcode: u8, hash: &Ed2kHash) -> Packet {
+    Packet::edonkey(opcode, hash.as_bytes().to_vec())
+}
@@ -0,0 +1,14 @@
+//! The client to client ("peer") protocol, which is how file data actually
+//! moves around the ed2k network.
+
+mod blocks;
+mod messages;
+mod peer_connection;
+mod peer_manager;
+mod source_exchange;
+
+pub use blocks::*;
+pub use messages::*;
+pub use peer_connection::*;
+pub use peer_manager::*;
+pub use source_exchange::*;
@@ -0,0 +1,416 @@
+use super::{make_part_messages, HelloInfo, PeerMessage};
+use crate::bandwidth::{BandwidthLimiter, ThrottledStream};
+use crate::configuration::{ObfuscationMode, UserHash};
+use crate::connections::{ConnectionAdmission, ConnectionPermit};
+use crate::obfuscation::{accept_peer, obfuscate_outgoing_peer, ObfuscatedStream};
+use crate::protocol::{Ed2kHash, Packet};
+use anyhow::{bail, Context, Result};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// A connection to another client over TCP, as made by `connect` and
+/// `accept`.
+pub type TcpPeerConnection = PeerConnection<ObfuscatedStream<ThrottledStream<TcpStream>>>;
+
+/// A TCP connection to another client. Like the server connection it is
+/// generic over the stream, so that it can run over an obfuscated
+/// transport or a proxy tunnel.
+pub struct PeerConnection<S> {
+    stream: S,
+    addr: SocketAddr,
+    /// What the peer told us about itself, once we have said hello.
+    peer_info: Option<HelloInfo>,
+    /// Our place in the connection limits, freed when the connection is
+    /// dropped.
+    permit: Option<ConnectionPermit>,
+}
+
+impl TcpPeerConnection {
+    /// How long we wait for the peer to accept the TCP connection, and for
+    /// the obfuscation handshake if there is one.
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Opens a TCP connection to a peer. The connection is obfuscated if we
+    /// are given the peer's user hash, which is the key to the handshake.
+    pub async fn connect(
+        addr: SocketAddr,
+        obfuscate_with: Option<&UserHash>,
+        limiter: &BandwidthLimiter,
+        admission: &ConnectionAdmission,
+    ) -> Result<Self> {
+        let connect = async {
+            let (stream, permit) = admission.connect(addr).await?;
+            let stream = limiter.throttle(stream);
+            let stream = match obfuscate_with {
+                Some(user_hash) => obfuscate_outgoing_peer(stream, user_hash).await?,
+                None => ObfuscatedStream::plain(stream),
+            };
+            anyhow::Ok((stream, permit))
+        };
+
+        let (stream, permit) = time::timeout(Self::CONNECT_TIMEOUT, connect)
+            .await
+            .with_context(|| format!("Timed out connecting to peer {addr}"))??;
+
+        let mut conn = Self::new(stream, addr);
+        conn.permit = Some(permit);
+        Ok(conn)
+    }
+
+    /// Takes a connection which a peer opened to us, completing the
+    /// obfuscation handshake if the peer started one. The permit is from
+    /// `ConnectionAdmission::admit_incoming`.
+    pub async fn accept(
+        stream: TcpStream,
+        addr: SocketAddr,
+        our_user_hash: &UserHash,
+        mode: ObfuscationMode,
+        limiter: &BandwidthLimiter,
+        permit: ConnectionPermit,
+    ) -> Result<Self> {
+        let stream = time::timeout(
+            Self::CONNECT_TIMEOUT,
+            accept_peer(limiter.throttle(stream), our_user_hash, mode),
+        )
+        .await
+        .with_context(|| format!("Timed out accepting connection from peer {addr}"))??;
+
+        let mut conn = Self::new(stream, addr);
+        conn.permit = Some(permit);
+        Ok(conn)
+    }
+
+    pub fn is_obfuscated(&self) -> bool {
+        self.stream.is_obfuscated()
+    }
+}
+
+impl<S> PeerConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// How long we wait for the other side of the hello exchange.
+    const HELLO_TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub fn new(stream: S, addr: SocketAddr) -> Self {
+        Self {
+            stream,
+            addr,
+            peer_info: None,
+            permit: None,
+        }
+    }
+
+    /// The address of the peer at the other end of the connection.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// What the peer told us about itself in the hello exchange.
+    pub fn peer_info(&self) -> Option<&HelloInfo> {
+        self.peer_info.as_ref()
+    }
+
+    /// Says hello to a peer we connected to and waits for its answer.
+    pub async fn hello(&mut self, ours: &HelloInfo) -> Result<&HelloInfo> {
+        self.send(&PeerMessage::Hello(ours.clone())).await?;
+
+        let answer = time::timeout(Self::HELLO_TIMEOUT, self.recv())
+            .await
+            .with_context(|| format!("Timed out waiting for hello answer from {}", self.addr))??;
+
+        match answer {
+            PeerMessage::HelloAnswer(info) => Ok(self.peer_info.insert(info)),
+            msg => bail!("Expected a hello answer from {}, got {:?}", self.addr, msg),
+        }
+    }
+
+    /// Waits for a peer which connected to us to say hello, and answers.
+    pub async fn answer_hello(&mut self, ours: &HelloInfo) -> Result<&HelloInfo> {
+        let hello = time::timeout(Self::HELLO_TIMEOUT, self.recv())
+            .await
+            .with_context(|| format!("Timed out waiting for hello from {}", self.addr))??;
+
+        let info = match hello {
+            PeerMessage::Hello(info) => info,
+            msg => bail!("Expected a hello from {}, got {:?}", self.addr, msg),
+        };
+
+        self.send(&PeerMessage::HelloAnswer(ours.clone())).await?;
+        Ok(self.peer_info.insert(info))
+    }
+
+    /// Sends a message to the peer.
+    pub async fn send(&mut self, msg: &PeerMessage) -> Result<()> {
+        msg.to_packet().write_to(&mut self.stream).await
+    }
+
+    /// Receives the next message from the peer.
+    pub async fn recv(&mut self) -> Result<PeerMessage> {
+        let packet = Packet::read_from(&mut self.stream).await?;
+        PeerMessage::from_packet(packet)
+    }
+
+    /// Sends a block of file data, compressed if the peer supports it and
+    /// `compress` is set. Already compressed files (archives, video) gain
+    /// nothing from compression, so the caller decides.
+    pub async fn send_block(
+        &mut self,
+        hash: Ed2kHash,
+        start: u64,
+        data: &[u8],
+        compress: bool,
+    ) -> Result<()> {
+        let compress = compress
+            && self
+                .peer_info
+                .as_ref()
+                .is_some_and(|info| info.supports_compression());
+
+        for msg in make_part_messages(hash, start, data, compress) {
+            self.send(&msg).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hashing::AichRecoveryData;
+    use crate::peer::{BlockAssembler, PartStatus, SOURCE_EXCHANGE2_VERSION};
+    use crate::protocol::AichHash;
+    use crate::server::{FoundSource, SourceObfuscation};
+    use tokio::net::TcpListener;
+
+    /// Connects two peers. If `user_hash` is given the connection is
+    /// obfuscated, keyed with it as the accepting side's hash.
+    async fn connected_pair(
+        user_hash: Option<&UserHash>,
+    ) -> (TcpPeerConnection, TcpPeerConnection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let limiter = BandwidthLimiter::new();
+        let admission = ConnectionAdmission::default();
+        let connect = PeerConnection::connect(addr, user_hash, &limiter, &admission);
+
+        let (outgoing, incoming) = tokio::join!(connect, async {
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            match user_hash {
+                Some(our_hash) => {
+                    let mode = ObfuscationMode::Required;
+                    let permit = admission.admit_incoming().unwrap();
+                    PeerConnection::accept(stream, peer_addr, our_hash, mode, &limiter, permit)
+                        .await
+                }
+                // Accepting reads the first byte to see whether it is
+                // obfuscated, which would wait for the hello.
+                None => Ok(PeerConnection::new(
+                    ObfuscatedStream::plain(limiter.throttle(stream)),
+                    peer_addr,
+                )),
+            }
+        });
+
+        (outgoing.unwrap(), incoming.unwrap())
+    }
+
+    fn info(n: u8, name: &str) -> HelloInfo {
+        let mut info = HelloInfo::new(
+            UserHash::new([n; 16]),
+            name.to_owned(),
+            0x0A00_0000 + n as u32,
+            4662,
+        );
+        info.udp_port = Some(4672);
+        info
+    }
+
+    #[tokio::test]
+    pub async fn test_hello_exchange() {
+        let (mut downloader, mut uploader) = connected_pair(None).await;
+        let (d, u) = (info(1, "down"), info(2, "up"));
+
+        let (from_uploader, from_downloader) =
+            tokio::join!(downloader.hello(&d), uploader.answer_hello(&u));
+
+        assert_eq!(from_uploader.unwrap(), &u);
+        assert_eq!(from_downloader.unwrap(), &d);
+        assert!(downloader.peer_info().unwrap().supports_compression());
+        assert!(uploader.peer_info().unwrap().supports_large_files());
+        assert!(uploader.peer_info().unwrap().supports_aich());
+    }
+
+    #[tokio::test]
+    pub async fn test_file_requests_and_upload_queue() {
+        let (mut downloader, mut uploader) = connected_pair(None).await;
+        let hash = Ed2kHash::new([5; 16]);
+        let part_hashes = vec![Ed2kHash::new([6; 16]), Ed2kHash::new([7; 16])];
+        let parts = PartStatus::Partial(vec![true, false]);
+
+        let conversation = [
+            (
+                PeerMessage::FileRequest(hash),
+                PeerMessage::FileName {
+                    hash,
+                    name: "file.iso".into(),
+                },
+            ),
+            (
+                PeerMessage::SetFileId(hash),
+                PeerMessage::FileStatus {
+                    hash,
+                    parts: parts.clone(),
+                },
+            ),
+            (
+                PeerMessage::HashSetRequest(hash),
+                PeerMessage::HashSetAnswer { hash, part_hashes },
+            ),
+            (
+                PeerMessage::StartUploadRequest(hash),
+                PeerMessage::QueueRank(17),
+            ),
+            (
+                PeerMessage::AichRequest {
+                    hash,
+                    part: 1,
+                    master: AichHash::new([9; 20]),
+                },
+                PeerMessage::AichAnswer {
+                    hash,
+                    part: 1,
+                    master: AichHash::new([9; 20]),
+                    recovery: AichRecoveryData {
+                        hashes: vec![
+                            (0b110, AichHash::new([1; 20])),
+                            (0b10, AichHash::new([2; 20])),
+                        ],
+                    },
+                },
+            ),
+            (
+                PeerMessage::AichRequest {
+                    hash,
+                    part: 0,
+                    master: AichHash::new([9; 20]),
+                },
+                PeerMessage::AichNotAvailable(hash),
+            ),
+            (
+                PeerMessage::RequestSources2 {
+                    hash,
+                    version: SOURCE_EXCHANGE2_VERSION,
+                },
+                PeerMessage::AnswerSources2 {
+                    hash,
+                    version: SOURCE_EXCHANGE2_VERSION,
+                    sources: vec![FoundSource {
+                        client_id: u32::from_le_bytes([80, 1, 2, 3]),
+                        port: 4662,
+                        server: "1.2.3.4:4661".parse().unwrap(),
+                        obfuscation: Some(SourceObfuscation::from_options(
+                            0x01,
+                            Some(UserHash::new([3; 16])),
+                        )),
+                    }],
+                },
+            ),
+        ];
+
+        for (request, answer) in conversation {
+            downloader.send(&request).await.unwrap();
+            assert_eq!(uploader.recv().await.unwrap(), request);
+            uploader.send(&answer).await.unwrap();
+            assert_eq!(downloader.recv().await.unwrap(), answer);
+        }
+
+        uploader.send(&PeerMessage::AcceptUpload).await.unwrap();
+        assert_eq!(downloader.recv().await.unwrap(), PeerMessage::AcceptUpload);
+        assert!(parts.has_part(0) && !parts.has_part(1));
+    }
+
+    #[tokio::test]
+    pub async fn test_request_and_send_blocks() {
+        // Run this one obfuscated, so that a good amount of data goes
+        // through the obfuscation layer.
+        let user_hash = UserHash::generate();
+        let (mut downloader, mut uploader) = connected_pair(Some(&user_hash)).await;
+        assert!(downloader.is_obfuscated() && uploader.is_obfuscated());
+        let (d, u) = (info(1, "down"), info(2, "up"));
+        let (hello, _) = tokio::join!(downloader.hello(&d), uploader.answer_hello(&u));
+        hello.unwrap();
+
+        let hash = Ed2kHash::new([8; 16]);
+        // One compressible block and one which is not, so both kinds of
+        // part message get sent.
+        let text: Vec<u8> = b"rMule ".iter().copied().cycle().take(50_000).collect();
+        let noise: Vec<u8> = (0..30_000u32)
+            .map(|n| (n.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let text_start = 5_000_000_000;
+        let noise_start = 1000;
+
+        let request = PeerMessage::RequestParts {
+            hash,
+            ranges: vec![
+                text_start..text_start + 50_000,
+                noise_start..noise_start + 30_000,
+            ],
+        };
+        downloader.send(&request).await.unwrap();
+        assert_eq!(uploader.recv().await.unwrap(), request);
+
+        let send = async {
+            uploader
+                .send_block(hash, text_start, &text, true)
+                .await
+                .unwrap();
+            uploader
+                .send_block(hash, noise_start, &noise, true)
+                .await
+                .unwrap();
+        };
+
+        let receive = async {
+            let mut assembler = BlockAssembler::new();
+            assembler
+                .request(hash, text_start..text_start + 50_000)
+                .unwrap();
+            assembler
+                .request(hash, noise_start..noise_start + 30_000)
+                .unwrap();
+            let mut received = Vec::new();
+            let mut total = 0;
+            while total < text.len() + noise.len() {
+                let msg = downloader.recv().await.unwrap();
+                if let Some(data) = assembler.add(msg).unwrap() {
+                    total += data.data.len();
+                    received.push(data);
+                }
+            }
+            received
+        };
+
+        let ((), received) = tokio::join!(send, receive);
+
+        // The text is compressed so it arrives in one piece.
+        assert_eq!(received[0].start, text_start);
+        assert_eq!(received[0].data, text);
+
+        let mut noise_received = Vec::new();
+        for (i, data) in received[1..].iter().enumerate() {
+            assert_eq!(
+                data.start,
+                noise_start + (i * crate::peer::MAX_PART_PAYLOAD) as u64
+            );
+            noise_received.extend_from_slice(&data.data);
+        }
+        assert_eq!(noise_received, noise);
+    }
+}
@@ -0,0 +1,849 @@
+use super::{HelloInfo, PeerConnection, PeerMessage, TcpPeerConnection};
+use crate::bandwidth::BandwidthLimiter;
+use crate::configuration::{
+    ConfigurationEventReceiver, ConfigurationEvents, ObfuscationMode, Settings, UserHash,
+};
+use crate::connections::ConnectionAdmission;
+use crate::download::{
+    DownloadCommand, DownloadCommandSender, DownloadEventReceiver, DownloadEvents,
+};
+use crate::kad::{make_callback, KadCommand, KadCommandSender, KadEventReceiver, KadEvents, KadId};
+use crate::listener::{IncomingConnection, IncomingConnectionReceiver};
+use crate::protocol::opcodes::*;
+use crate::protocol::{Ed2kHash, Packet};
+use crate::server::{ServerEventReceiver, ServerEvents, SourceObfuscation};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+pub type PeerCommandSender = mpsc::Sender<PeerCommand>;
+pub type PeerCommandReceiver = mpsc::Receiver<PeerCommand>;
+
+pub type PeerEventSender = broadcast::Sender<PeerEvents>;
+pub type PeerEventReceiver = broadcast::Receiver<PeerEvents>;
+
+/// The handle type allows commands to be sent to and events to be received
+/// from the Peer Manager.
+pub struct PeerManagerHandle {
+    cmd_sender: PeerCommandSender,
+    evt_sender: PeerEventSender,
+    // TODO: We need at least one receiver to be alive to
+    // allow us to send events.
+    evt_receiver: PeerEventReceiver,
+}
+
+impl PeerManagerHandle {
+    /// Starts the Peer Manager as a Tokio task. It takes the connections
+    /// the Listener Manager accepts and answers their hello as we are
+    /// described in the settings, and connects to the clients our server
+    /// or Kad buddy asks us to call back. It keeps the connection of the
+    /// client Kad makes us the buddy of. Source exchange messages are
+    /// passed between the peers and the Download Manager.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        cfg_evt_receiver: ConfigurationEventReceiver,
+        incoming: IncomingConnectionReceiver,
+        server_evt_receiver: ServerEventReceiver,
+        kad_evt_receiver: KadEventReceiver,
+        kad_cmd_sender: KadCommandSender,
+        download_evt_receiver: DownloadEventReceiver,
+        download_cmd_sender: DownloadCommandSender,
+        limiter: BandwidthLimiter,
+        admission: ConnectionAdmission,
+        tokio_handle: &tokio::runtime::Handle,
+    ) -> Self {
+        let (cmd_sender, cmd_receiver) = mpsc::channel::<PeerCommand>(32);
+        let (evt_sender, evt_receiver) = broadcast::channel::<PeerEvents>(256);
+
+        let mgr = PeerManager::new(
+            evt_sender.clone(),
+            cmd_receiver,
+            cfg_evt_receiver,
+            incoming,
+            server_evt_receiver,
+            kad_evt_receiver,
+            kad_cmd_sender,
+            download_evt_receiver,
+            download_cmd_sender,
+            limiter,
+            admission,
+        );
+        tokio_handle.spawn(mgr.run());
+
+        Self {
+            cmd_sender,
+            evt_sender,
+            evt_receiver,
+        }
+    }
+
+    /// Sends a command to the Peer Manager.
+    pub async fn send_command(&self, cmd: PeerCommand) -> Result<()> {
+        Ok(self.cmd_sender.send(cmd).await?)
+    }
+
+    /// Synchronously send a command to the Peer Manager.
+    pub fn send_command_blocking(&self, cmd: PeerCommand) -> Result<()> {
+        Ok(self.cmd_sender.blocking_send(cmd)?)
+    }
+
+    /// Create a new subscription to events sent by the Peer Manager.
+    pub fn subscribe_to_events(&self) -> PeerEventReceiver {
+        self.evt_sender.subscribe()
+    }
+
+    /// Creates a new command sender which can be used to send commands
+    /// to the Peer Manager.
+    pub fn make_command_sender(&self) -> PeerCommandSender {
+        self.cmd_sender.clone()
+    }
+}
+
+/// The set of commands that can be sent to the Peer Manager.
+#[derive(Debug)]
+pub enum PeerCommand {
+    /// Closes every peer connection and stops the Peer Manager.
+    Stop,
+}
+
+/// The set of events that can be emitted by the Peer Manager.
+#[derive(Debug, Clone)]
+pub enum PeerEvents {
+    /// A peer has said hello.
+    Connected { addr: SocketAddr, info: HelloInfo },
+    /// The connection to a peer has closed, or failed before the hello.
+    Disconnected { addr: SocketAddr, reason: String },
+}
+
+/// This is private to the module: all access is via the handle.
+struct PeerManager {
+    events_sender: PeerEventSender,
+    commands_receiver: PeerCommandReceiver,
+    cfg_events_receiver: ConfigurationEventReceiver,
+    incoming: IncomingConnectionReceiver,
+    server_events_receiver: ServerEventReceiver,
+    kad_events_receiver: KadEventReceiver,
+    kad_commands_sender: KadCommandSender,
+    download_events_receiver: DownloadEventReceiver,
+    download_commands_sender: DownloadCommandSender,
+    // Our own events, which is how we learn who has said hello.
+    own_events_receiver: PeerEventReceiver,
+    // Shared with every other connection.
+    limiter: BandwidthLimiter,
+    admission: ConnectionAdmission,
+    // The latest settings we have been told about, which say who we are.
+    settings: Option<Settings>,
+    // Our id on the server we are connected to, and its address, which
+    // we tell peers in our hello.
+    server: Option<(u32, SocketAddr)>,
+    // The firewalled client Kad last offered to be the buddy of, by its
+    // user hash, and its buddy id.
+    buddy_request: Option<(UserHash, KadId)>,
+    // The client we are the buddy of, once it has connected to us.
+    buddy: Option<ServedBuddy>,
+    // One task per connection. Dropping the set ends them all.
+    sessions: JoinSet<()>,
+    // Messages to send on each connection, by the peer's address.
+    outboxes: HashMap<SocketAddr, mpsc::Sender<PeerMessage>>,
+    // What the peers on those connections said about themselves.
+    peers: HashMap<SocketAddr, HelloInfo>,
+}
+
+/// A firewalled client which keeps a connection to us, over which we pass
+/// on the callback requests other clients send us through Kad.
+#[derive(Debug, Clone, Copy)]
+struct ServedBuddy {
+    addr: SocketAddr,
+    buddy_id: KadId,
+}
+
+impl PeerManager {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        events_sender: PeerEventSender,
+        commands_receiver: PeerCommandReceiver,
+        cfg_events_receiver: ConfigurationEventReceiver,
+        incoming: IncomingConnectionReceiver,
+        server_events_receiver: ServerEventReceiver,
+        kad_events_receiver: KadEventReceiver,
+        kad_commands_sender: KadCommandSender,
+        download_events_receiver: DownloadEventReceiver,
+        download_commands_sender: DownloadCommandSender,
+        limiter: BandwidthLimiter,
+        admission: ConnectionAdmission,
+    ) -> Self {
+        Self {
+            own_events_receiver: events_sender.subscribe(),
+            events_sender,
+            commands_receiver,
+            cfg_events_receiver,
+            incoming,
+            server_events_receiver,
+            kad_events_receiver,
+            kad_commands_sender,
+            download_events_receiver,
+            download_commands_sender,
+            limiter,
+            admission,
+            settings: None,
+            server: None,
+            buddy_request: None,
+            buddy: None,
+            sessions: JoinSet::new(),
+            outboxes: HashMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    async fn run(mut self) {
+        loop {
+            // Configuration changes come first, so that whatever arrives
+            // after one sees it.
+            tokio::select! {
+                biased;
+                evt = self.cfg_events_receiver.recv() => match evt {
+                    Ok(ConfigurationEvents::SettingsChange(settings)) => self.settings = Some(settings),
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) => warn!("Peer Manager missed {n} configuration events"),
+                    Err(RecvError::Closed) => break,
+                },
+                cmd = self.commands_receiver.recv() => match cmd {
+                    Some(PeerCommand::Stop) | None => break,
+                },
+                evt = self.server_events_receiver.recv() => match evt {
+                    Ok(ServerEvents::Connected { addr, client_id, .. }) => {
+                        self.server = Some((client_id, addr))
+                    }
+                    Ok(ServerEvents::Disconnected(_)) => self.server = None,
+                    Ok(ServerEvents::CallbackRequested(request)) => {
+                        let addr = request.addr.into();
+                        info!("Calling back {addr}, as our server asked");
+                        self.connect(addr, request.obfuscation);
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) => warn!("Peer Manager missed {n} server events"),
+                    Err(RecvError::Closed) => break,
+                },
+                evt = self.kad_events_receiver.recv() => match evt {
+                    Ok(KadEvents::CallbackRequested { addr, file }) => {
+                        info!("Calling back {addr}, which wants {file}, as our Kad buddy asked");
+                        self.connect(addr.into(), None);
+                    }
+                    Ok(KadEvents::BuddyRequested { user_hash, buddy_id }) => {
+                        let user_hash = UserHash::new(user_hash.to_be_bytes());
+                        self.buddy_request = Some((user_hash, buddy_id));
+                    }
+                    Ok(KadEvents::CallbackForBuddy { addr, file }) => self.forward_callback(addr, file),
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) => warn!("Peer Manager missed {n} Kad events"),
+                    Err(RecvError::Closed) => break,
+                },
+                Ok(evt) = self.own_events_receiver.recv() => self.handle_own_event(evt).await,
+                evt = self.download_events_receiver.recv() => match evt {
+                    Ok(evt) => self.send_to_peers(&evt),
+                    Err(RecvError::Lagged(n)) => warn!("Peer Manager missed {n} download events"),
+                    Err(RecvError::Closed) => break,
+                },
+                Some(connection) = self.incoming.recv() => self.accept(connection),
+                Some(_) = self.sessions.join_next() => self.session_ended().await,
+            }
+        }
+
+        self.sessions.shutdown().await;
+        info!("Peer Manager stopped");
+    }
+
+    /// Starts a session on a connection a peer opened to us.
+    fn accept(&mut self, connection: IncomingConnection) {
+        let IncomingConnection {
+            stream,
+            addr,
+            permit,
+        } = connection;
+        let Some(settings) = &self.settings else {
+            warn!("Dropping connection from {addr} before the settings are loaded");
+            return;
+        };
+        let mode = settings.obfuscation;
+        let Some(session) = self.make_session(addr) else {
+            return;
+        };
+
+        self.sessions.spawn(async move {
+            let user_hash = session.ours.user_hash;
+            let limiter = &session.limiter;
+            match PeerConnection::accept(stream, addr, &user_hash, mode, limiter, permit).await {
+                Ok(conn) => session.run(conn, false).await,
+                Err(e) => session.disconnected(addr, &e),
+            }
+        });
+    }
+
+    /// Connects to a client which asked, through our server or Kad buddy,
+    /// for us to connect to it, since it cannot accept connections.
+    fn connect(&mut self, addr: SocketAddr, obfuscation: Option<SourceObfuscation>) {
+        let Some(settings) = &self.settings else {
+            warn!("Not connecting to {addr} before the settings are loaded");
+            return;
+        };
+        let obfuscate_with = match obfuscation_key(settings.obfuscation, obfuscation) {
+            Ok(key) => key,
+            Err(e) => {
+                warn!("Not connecting to {addr}: {e}");
+                return;
+            }
+        };
+        let Some(session) = self.make_session(addr) else {
+            return;
+        };
+
+        self.sessions.spawn(async move {
+            let (limiter, admission) = (&session.limiter, &session.admission);
+            match PeerConnection::connect(addr, obfuscate_with.as_ref(), limiter, admission).await {
+                Ok(conn) => session.run(conn, true).await,
+                Err(e) => session.disconnected(addr, &e),
+            }
+        });
+    }
+
+    /// What a connection task needs, or None before the settings say who
+    /// we are.
+    fn make_session(&mut self, addr: SocketAddr) -> Option<Session> {
+        let settings = self.settings.as_ref()?;
+        let client_id = self.server.map_or(0, |(client_id, _)| client_id);
+        let mut ours = HelloInfo::new(
+            settings.user_hash,
+            settings.nick_name.clone(),
+            client_id,
+            settings.tcp_port,
+        );
+        ours.set_obfuscation(settings.obfuscation);
+        ours.server = match self.server {
+            Some((_, SocketAddr::V4(addr))) => Some(addr),
+            _ => None,
+        };
+
+        let (outbox_sender, outbox) = mpsc::channel(16);
+        self.outboxes.insert(addr, outbox_sender);
+
+        Some(Session {
+            ours,
+            limiter: self.limiter.clone(),
+            admission: self.admission.clone(),
+            events_sender: self.events_sender.clone(),
+            download_commands_sender: self.download_commands_sender.clone(),
+            outbox,
+        })
+    }
+
+    /// Remembers who a peer which has said hello is. It may be the
+    /// firewalled client we offered to be the buddy of, in which case Kad
+    /// starts passing on its callback requests.
+    async fn handle_own_event(&mut self, evt: PeerEvents) {
+        let PeerEvents::Connected { addr, info } = evt else {
+            return;
+        };
+        // The connection may have closed again already.
+        if self.outboxes.contains_key(&addr) {
+            self.peers.insert(addr, info.clone());
+        }
+        let Some((user_hash, buddy_id)) = self.buddy_request else {
+            return;
+        };
+        if info.user_hash != user_hash {
+            return;
+        }
+
+        info!("We are now the Kad buddy of {addr}");
+        self.buddy_request = None;
+        self.buddy = Some(ServedBuddy { addr, buddy_id });
+        self.serve_buddy(Some(buddy_id)).await;
+    }
+
+    /// Forgets the connections which have closed, including that of the
+    /// client we are the buddy of.
+    async fn session_ended(&mut self) {
+        self.outboxes.retain(|_, outbox| !outbox.is_closed());
+        self.peers
+            .retain(|addr, _| self.outboxes.contains_key(addr));
+
+        if let Some(buddy) = self.buddy {
+            if !self.outboxes.contains_key(&buddy.addr) {
+                info!("Our Kad buddy client {} has gone", buddy.addr);
+                self.buddy = None;
+                self.serve_buddy(None).await;
+            }
+        }
+    }
+
+    async fn serve_buddy(&self, buddy_id: Option<KadId>) {
+        let cmd = KadCommand::ServeBuddy(buddy_id);
+        if let Err(e) = self.kad_commands_sender.send(cmd).await {
+            warn!("Cannot tell Kad about our buddy client: {e}");
+        }
+    }
+
+    /// Sends the peers the Download Manager has messages for them.
+    fn send_to_peers(&self, evt: &DownloadEvents) {
+        for (addr, info) in &self.peers {
+            let (Some(msg), Some(outbox)) = (evt.peer_message(info), self.outboxes.get(addr))
+            else {
+                continue;
+            };
+            if outbox.try_send(msg).is_err() {
+                warn!("Dropping message for peer {addr}, which is busy");
+            }
+        }
+    }
+
+    /// Tells the client we are the buddy of to connect to a client which
+    /// wants a file from it.
+    fn forward_callback(&self, addr: SocketAddrV4, file: Ed2kHash) {
+        let Some(buddy) = self.buddy else {
+            return;
+        };
+        let Some(outbox) = self.outboxes.get(&buddy.addr) else {
+            return;
+        };
+
+        debug!("Passing on callback request from {addr} to {}", buddy.addr);
+        if outbox
+            .try_send(make_callback(buddy.buddy_id, file, addr))
+            .is_err()
+        {
+            warn!("Dropping callback request from {addr}, our buddy client is busy");
+        }
+    }
+}
+
+/// The user hash to obfuscate a connection to a client with: the client's
+/// own, if both it and we want obfuscation. A client which does not tell
+/// us its hash, or does not support obfuscation, can only be connected to
+/// if we do not require it.
+fn obfuscation_key(
+    mode: ObfuscationMode,
+    theirs: Option<SourceObfuscation>,
+) -> Result<Option<UserHash>> {
+    let key = theirs
+        .filter(|theirs| mode.is_enabled() && theirs.supported)
+        .and_then(|theirs| theirs.user_hash);
+    if key.is_none() && mode == ObfuscationMode::Required {
+        bail!("We require obfuscation, which the client cannot do");
+    }
+    Ok(key)
+}
+
+/// What a connection task needs to talk to a peer.
+struct Session {
+    // How we describe ourselves in the hello exchange.
+    ours: HelloInfo,
+    limiter: BandwidthLimiter,
+    admission: ConnectionAdmission,
+    events_sender: PeerEventSender,
+    download_commands_sender: DownloadCommandSender,
+    // What the Peer Manager wants sent to the peer.
+    outbox: mpsc::Receiver<PeerMessage>,
+}
+
+impl Session {
+    /// Says hello, first if we opened the connection and in answer
+    /// otherwise, and then talks to the peer until either side closes the
+    /// connection.
+    async fn run(mut self, mut conn: TcpPeerConnection, we_connected: bool) {
+        let addr = conn.addr();
+        let hello = if we_connected {
+            conn.hello(&self.ours).await
+        } else {
+            conn.answer_hello(&self.ours).await
+        };
+        let result = match hello {
+            Ok(_) => self.talk(&mut conn).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            self.disconnected(addr, &e);
+        }
+    }
+
+    async fn talk(&mut self, conn: &mut TcpPeerConnection) -> Result<()> {
+        let addr = conn.addr();
+        let Some(info) = conn.peer_info().cloned() else {
+            bail!("Peer {addr} has not said hello");
+        };
+        info!("Peer {} ({addr}) said hello", info.nick_name);
+        send_event(
+            &self.events_sender,
+            PeerEvents::Connected {
+                addr,
+                info: info.clone(),
+            },
+        );
+
+        loop {
+            tokio::select! {
+                msg = self.outbox.recv() => match msg {
+                    Some(msg) => conn.send(&msg).await?,
+                    None => bail!("The Peer Manager has stopped"),
+                },
+                msg = conn.recv() => match msg? {
+                    // Buddy links are kept open with pings.
+                    PeerMessage::Unknown(packet) if packet.opcode == OP_BUDDYPING => {
+                        let pong = PeerMessage::Unknown(Packet::emule(OP_BUDDYPONG, Vec::new()));
+                        conn.send(&pong).await?;
+                    }
+                    msg => match DownloadCommand::from_peer_message(&info, msg) {
+                        Some(cmd) => self.download_commands_sender.send(cmd).await?,
+                        None => debug!("Ignoring message from peer {addr}"),
+                    },
+                },
+            }
+        }
+    }
+
+    fn disconnected(&self, addr: SocketAddr, e: &anyhow::Error) {
+        debug!("Connection to peer {addr} closed: {e}");
+        send_event(
+            &self.events_sender,
+            PeerEvents::Disconnected {
+                addr,
+                reason: e.to_string(),
+            },
+        );
+    }
+}
+
+fn send_event(sender: &PeerEventSender, evt: PeerEvents) {
+    if let Err(broadcast::error::SendError(evt)) = sender.send(evt) {
+        warn!("Nobody is listening for peer events, dropping {:?}", evt);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::migrations;
+    use crate::connections::ConnectionLimits;
+    use crate::obfuscation::ObfuscatedStream;
+    use crate::server::CallbackRequest;
+    use rusqlite::Connection;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::timeout;
+
+    struct Fixture {
+        handle: PeerManagerHandle,
+        settings: Settings,
+        server_events: broadcast::Sender<ServerEvents>,
+        kad_events: broadcast::Sender<KadEvents>,
+        kad_commands: mpsc::Receiver<KadCommand>,
+        download_events: broadcast::Sender<DownloadEvents>,
+        download_commands: mpsc::Receiver<DownloadCommand>,
+        incoming: mpsc::Sender<IncomingConnection>,
+        admission: ConnectionAdmission,
+        // Kept so that the manager does not stop.
+        _cfg_events: broadcast::Sender<ConfigurationEvents>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let conn = Connection::open_in_memory().unwrap();
+            migrations::apply_database_migrations(&conn).unwrap();
+            let settings = Settings::load(&conn).unwrap();
+
+            let (cfg_events, cfg_receiver) = broadcast::channel(16);
+            let (incoming, incoming_receiver) = mpsc::channel(16);
+            let (server_events, server_receiver) = broadcast::channel(16);
+            let (kad_events, kad_receiver) = broadcast::channel(16);
+            let (kad_commands_sender, kad_commands) = mpsc::channel(16);
+            let (download_events, download_receiver) = broadcast::channel(16);
+            let (download_commands_sender, download_commands) = mpsc::channel(16);
+            let admission = ConnectionAdmission::new(ConnectionLimits::UNLIMITED);
+            let handle = PeerManagerHandle::new(
+                cfg_receiver,
+                incoming_receiver,
+                server_receiver,
+                kad_receiver,
+                kad_commands_sender,
+                download_receiver,
+                download_commands_sender,
+                BandwidthLimiter::new(),
+                admission.clone(),
+                &tokio::runtime::Handle::current(),
+            );
+            cfg_events
+                .send(ConfigurationEvents::SettingsChange(settings.clone()))
+                .unwrap();
+
+            Self {
+                handle,
+                settings,
+                server_events,
+                kad_events,
+                kad_commands,
+                download_events,
+                download_commands,
+                incoming,
+                admission,
+                _cfg_events: cfg_events,
+            }
+        }
+
+        /// Connects to the Peer Manager as if through the Listener
+        /// Manager, and says hello as the client with `user_hash`.
+        async fn connect(&self, user_hash: UserHash) -> TcpPeerConnection {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap());
+            let (client, (stream, addr)) = tokio::try_join!(client, listener.accept()).unwrap();
+            let permit = self.admission.admit_incoming().unwrap();
+            self.incoming
+                .send(IncomingConnection {
+                    stream,
+                    addr,
+                    permit,
+                })
+                .await
+                .unwrap();
+
+            let stream = ObfuscatedStream::plain(BandwidthLimiter::new().throttle(client));
+            let mut conn = PeerConnection::new(stream, listener.local_addr().unwrap());
+            let theirs = HelloInfo::new(user_hash, "firewalled".to_owned(), 1234, 4662);
+            timeout(Duration::from_secs(10), conn.hello(&theirs))
+                .await
+                .expect("We did not answer hello")
+                .unwrap();
+            conn
+        }
+
+        async fn download_command(&mut self) -> DownloadCommand {
+            timeout(Duration::from_secs(10), self.download_commands.recv())
+                .await
+                .expect("No download command")
+                .unwrap()
+        }
+
+        async fn kad_command(&mut self) -> KadCommand {
+            timeout(Duration::from_secs(10), self.kad_commands.recv())
+                .await
+                .expect("No Kad command")
+                .unwrap()
+        }
+
+        /// Has our server ask us to call back a client listening on a
+        /// port of its own, and returns what we say to it.
+        async fn call_back(&self) -> HelloInfo {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+                unreachable!()
+            };
+            self.server_events
+                .send(ServerEvents::CallbackRequested(CallbackRequest {
+                    addr,
+                    obfuscation: None,
+                }))
+                .unwrap();
+
+            answer_call_back(&listener).await
+        }
+    }
+
+    /// Waits for us to connect to `listener`, and returns our hello.
+    async fn answer_call_back(listener: &TcpListener) -> HelloInfo {
+        let (stream, peer_addr) = timeout(Duration::from_secs(10), listener.accept())
+            .await
+            .expect("We did not call back")
+            .unwrap();
+        let stream = ObfuscatedStream::plain(BandwidthLimiter::new().throttle(stream));
+        let mut conn = PeerConnection::new(stream, peer_addr);
+        let theirs = HelloInfo::new(UserHash::new([7; 16]), "low".to_owned(), 1234, 4662);
+        conn.answer_hello(&theirs).await.unwrap().clone()
+    }
+
+    #[tokio::test]
+    pub async fn test_callback_requests_are_connected_to_and_greeted() {
+        let fx = Fixture::new();
+        let mut events = fx.handle.subscribe_to_events();
+
+        let ours = fx.call_back().await;
+
+        assert_eq!(ours.user_hash, fx.settings.user_hash);
+        assert_eq!(ours.tcp_port, fx.settings.tcp_port);
+        let evt = timeout(Duration::from_secs(10), events.recv())
+            .await
+            .unwrap();
+        assert!(
+            matches!(&evt, Ok(PeerEvents::Connected { info, .. }) if info.client_id == 1234),
+            "{evt:?}"
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_our_hello_has_our_server_and_id_on_it() {
+        let fx = Fixture::new();
+        fx.server_events
+            .send(ServerEvents::Connected {
+                addr: "1.2.3.4:4661".parse().unwrap(),
+                client_id: 0x0A00_000A,
+                low_id: false,
+            })
+            .unwrap();
+
+        let ours = fx.call_back().await;
+
+        assert_eq!(ours.client_id, 0x0A00_000A);
+        assert_eq!(ours.server, Some("1.2.3.4:4661".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    pub async fn test_kad_callback_requests_are_connected_to_and_greeted() {
+        let fx = Fixture::new();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!()
+        };
+        fx.kad_events
+            .send(KadEvents::CallbackRequested {
+                addr,
+                file: Ed2kHash::new([3; 16]),
+            })
+            .unwrap();
+
+        let ours = answer_call_back(&listener).await;
+
+        assert_eq!(ours.user_hash, fx.settings.user_hash);
+    }
+
+    #[tokio::test]
+    pub async fn test_the_client_kad_offered_to_be_buddy_of_is_served() {
+        let mut fx = Fixture::new();
+        let user_hash = UserHash::new([7; 16]);
+        let buddy_id = KadId::random();
+        fx.kad_events
+            .send(KadEvents::BuddyRequested {
+                user_hash: KadId::from_be_bytes(*user_hash.as_bytes()),
+                buddy_id,
+            })
+            .unwrap();
+
+        let conn = fx.connect(user_hash).await;
+
+        assert!(
+            matches!(fx.kad_command().await, KadCommand::ServeBuddy(Some(id)) if id == buddy_id)
+        );
+        drop(conn);
+        assert!(matches!(
+            fx.kad_command().await,
+            KadCommand::ServeBuddy(None)
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_callbacks_for_our_buddy_client_are_passed_on() {
+        let mut fx = Fixture::new();
+        let user_hash = UserHash::new([7; 16]);
+        let buddy_id = KadId::random();
+        fx.kad_events
+            .send(KadEvents::BuddyRequested {
+                user_hash: KadId::from_be_bytes(*user_hash.as_bytes()),
+                buddy_id,
+            })
+            .unwrap();
+        let mut conn = fx.connect(user_hash).await;
+        fx.kad_command().await;
+
+        let addr = "10.1.2.3:4662".parse().unwrap();
+        let file = Ed2kHash::new([3; 16]);
+        fx.kad_events
+            .send(KadEvents::CallbackForBuddy { addr, file })
+            .unwrap();
+
+        let msg = timeout(Duration::from_secs(10), conn.recv())
+            .await
+            .expect("The callback was not passed on")
+            .unwrap();
+        assert_eq!(msg, make_callback(buddy_id, file, addr));
+    }
+
+    #[tokio::test]
+    pub async fn test_buddy_pings_are_answered() {
+        let fx = Fixture::new();
+        let mut conn = fx.connect(UserHash::new([7; 16])).await;
+
+        let ping = PeerMessage::Unknown(Packet::emule(OP_BUDDYPING, Vec::new()));
+        conn.send(&ping).await.unwrap();
+
+        let msg = timeout(Duration::from_secs(10), conn.recv())
+            .await
+            .expect("The ping was not answered")
+            .unwrap();
+        assert!(matches!(msg, PeerMessage::Unknown(packet) if packet.opcode == OP_BUDDYPONG));
+    }
+
+    #[tokio::test]
+    pub async fn test_source_exchange_requests_go_to_the_download_manager() {
+        let mut fx = Fixture::new();
+        let mut conn = fx.connect(UserHash::new([7; 16])).await;
+
+        let hash = Ed2kHash::new([3; 16]);
+        let request = PeerMessage::RequestSources2 { hash, version: 4 };
+        conn.send(&request).await.unwrap();
+
+        let cmd = fx.download_command().await;
+        assert!(
+            matches!(cmd, DownloadCommand::SourcesRequested { peer, hash: h, version: 4 }
+                if peer == UserHash::new([7; 16]) && h == hash),
+            "{cmd:?}"
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_download_manager_messages_go_to_their_peer() {
+        let mut fx = Fixture::new();
+        let (alice, bob) = (UserHash::new([7; 16]), UserHash::new([8; 16]));
+        let mut conn = fx.connect(alice).await;
+        // Once this arrives we have said who alice is.
+        let hash = Ed2kHash::new([3; 16]);
+        let request = PeerMessage::RequestSources2 { hash, version: 4 };
+        conn.send(&request).await.unwrap();
+        fx.download_command().await;
+
+        // Only the second is for alice.
+        for (peer, hash) in [(bob, Ed2kHash::new([4; 16])), (alice, hash)] {
+            fx.download_events
+                .send(DownloadEvents::SourcesWanted { peer, hash })
+                .unwrap();
+        }
+
+        let msg = timeout(Duration::from_secs(10), conn.recv())
+            .await
+            .expect("The request was not sent")
+            .unwrap();
+        assert!(
+            matches!(msg, PeerMessage::RequestSources2 { hash: h, .. } if h == hash),
+            "{msg:?}"
+        );
+    }
+
+    #[test]
+    pub fn test_obfuscation_key_is_the_clients_hash_if_both_want_it() {
+        let theirs = SourceObfuscation::from_options(0x01, Some(UserHash::new([9; 16])));
+
+        let key = obfuscation_key(ObfuscationMode::Preferred, Some(theirs)).unwrap();
+        assert_eq!(key, Some(UserHash::new([9; 16])));
+        let key = obfuscation_key(ObfuscationMode::Disabled, Some(theirs)).unwrap();
+        assert_eq!(key, None);
+    }
+
+    #[test]
+    pub fn test_obfuscation_key_is_needed_if_we_require_obfuscation() {
+        assert_eq!(
+            obfuscation_key(ObfuscationMode::Preferred, None).unwrap(),
+            None
+        );
+        assert!(obfuscation_key(ObfuscationMode::Required, None).is_err());
+    }
+}
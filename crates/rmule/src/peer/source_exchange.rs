@@ -0,0 +1,338 @@
+use crate::configuration::UserHash;
+use crate::connections::IpFilter;
+use crate::protocol::Ed2kHash;
+use crate::server::{is_low_id, FoundSource, SourceObfuscation};
+use anyhow::{bail, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The source exchange 2 version we speak. Version 2 added the user hash,
+/// 3 "hybrid" ids and 4 the obfuscation options.
+pub const SOURCE_EXCHANGE2_VERSION: u8 = 4;
+
+/// The most sources we put into one OP_ANSWERSOURCES2, as eMule does.
+pub const MAX_EXCHANGED_SOURCES: usize = 500;
+
+/// Writes the sources of an OP_ANSWERSOURCES2 in the given version's format.
+pub(super) fn write_exchanged_sources(out: &mut Vec<u8>, version: u8, sources: &[FoundSource]) {
+    let sources = &sources[..sources.len().min(MAX_EXCHANGED_SOURCES)];
+    out.write_u16::<LittleEndian>(sources.len() as u16).unwrap();
+
+    for source in sources {
+        // From version 3 high ids are sent in host byte order, which
+        // eMule calls hybrid ids.
+        let id = if version >= 3 && !source.is_low_id() {
+            source.client_id.swap_bytes()
+        } else {
+            source.client_id
+        };
+        out.write_u32::<LittleEndian>(id).unwrap();
+        out.write_u16::<LittleEndian>(source.port).unwrap();
+
+        let server = match source.server {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        };
+        out.write_u32::<LittleEndian>(u32::from_le_bytes(server.ip().octets()))
+            .unwrap();
+        out.write_u16::<LittleEndian>(server.port()).unwrap();
+
+        let obfuscation = source.obfuscation.as_ref();
+        if version >= 2 {
+            let user_hash = obfuscation.and_then(|o| o.user_hash).unwrap_or_default();
+            out.extend_from_slice(user_hash.as_bytes());
+        }
+        if version >= 4 {
+            out.push(obfuscation.map(|o| o.options()).unwrap_or(0));
+        }
+    }
+}
+
+/// Reads the sources of an OP_ANSWERSOURCES2.
+pub(super) fn read_exchanged_sources(
+    input: &mut Cursor<&[u8]>,
+    version: u8,
+) -> Result<Vec<FoundSource>> {
+    if version == 0 || version > SOURCE_EXCHANGE2_VERSION {
+        bail!("Unsupported source exchange version {version}");
+    }
+
+    let count = input.read_u16::<LittleEndian>()? as usize;
+    let mut sources = Vec::with_capacity(count.min(MAX_EXCHANGED_SOURCES));
+
+    for _ in 0..count {
+        let id = input.read_u32::<LittleEndian>()?;
+        let client_id = if version >= 3 && !is_low_id(id) {
+            id.swap_bytes()
+        } else {
+            id
+        };
+        let port = input.read_u16::<LittleEndian>()?;
+        let server_ip = Ipv4Addr::from(input.read_u32::<LittleEndian>()?.to_le_bytes());
+        let server_port = input.read_u16::<LittleEndian>()?;
+
+        let user_hash = if version >= 2 {
+            let mut buf = [0u8; 16];
+            input.read_exact(&mut buf)?;
+            Some(UserHash::new(buf))
+        } else {
+            None
+        };
+        let options = if version >= 4 { input.read_u8()? } else { 0 };
+
+        sources.push(FoundSource {
+            client_id,
+            port,
+            server: SocketAddrV4::new(server_ip, server_port).into(),
+            obfuscation: user_hash.map(|h| SourceObfuscation::from_options(options, Some(h))),
+        });
+    }
+
+    Ok(sources)
+}
+
+/// Decides when we may ask peers for the sources they know of a file, and
+/// when we answer such requests, with eMule's limits: asking too often gets
+/// us banned by other clients, and answering everything would let any
+/// client use us to flood others. Rare files are asked about more often,
+/// as this is how they get enough sources.
+///
+/// Peers are identified by their user hash. Call `expire` now and then to
+/// forget requests which no longer limit anything.
+#[derive(Debug, Default)]
+pub struct SourceExchange {
+    /// When we last asked a peer about a file.
+    asked_peers: HashMap<(UserHash, Ed2kHash), Instant>,
+    /// When we last asked anybody about a file.
+    asked_files: HashMap<Ed2kHash, Instant>,
+    /// When we last answered a peer about a file.
+    answered: HashMap<(UserHash, Ed2kHash), Instant>,
+}
+
+impl SourceExchange {
+    /// How long before we ask the same peer about the same file again.
+    const PEER_INTERVAL: Duration = Duration::from_secs(40 * 60);
+    /// How long before we ask anybody about the same file again.
+    const FILE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+    /// Both intervals are this many times longer for files which are not
+    /// rare.
+    const COMMON_PENALTY: u32 = 4;
+    /// Files with at most this many sources are rare.
+    const RARE_FILE: usize = 50;
+    /// Files with at most this many sources are very rare, and every peer
+    /// is asked about them regardless of when others were last asked.
+    const VERY_RARE_FILE: usize = Self::RARE_FILE / 5;
+    /// How long an answer counts as solicited after we asked.
+    const ANSWER_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether we may ask a peer for the sources of a file we know
+    /// `sources` sources of and want at most `max_sources` of. If so the
+    /// request is recorded, and the peer should be sent an
+    /// `OP_REQUESTSOURCES2`.
+    pub fn request(
+        &mut self,
+        peer: UserHash,
+        hash: Ed2kHash,
+        sources: usize,
+        max_sources: usize,
+        now: Instant,
+    ) -> bool {
+        if sources >= max_sources {
+            return false;
+        }
+
+        let since_peer = self.asked_peers.get(&(peer, hash)).map(|t| now - *t);
+        let since_file = self.asked_files.get(&hash).map(|t| now - *t);
+        let waited = |since: Option<Duration>, interval: Duration| match since {
+            Some(since) => since > interval,
+            None => true,
+        };
+
+        let allowed = if sources <= Self::VERY_RARE_FILE {
+            waited(since_peer, Self::PEER_INTERVAL)
+        } else if sources <= Self::RARE_FILE {
+            waited(since_peer, Self::PEER_INTERVAL) && waited(since_file, Self::FILE_INTERVAL)
+        } else {
+            waited(since_peer, Self::PEER_INTERVAL * Self::COMMON_PENALTY)
+                && waited(since_file, Self::FILE_INTERVAL * Self::COMMON_PENALTY)
+        };
+
+        if allowed {
+            self.asked_peers.insert((peer, hash), now);
+            self.asked_files.insert(hash, now);
+        }
+        allowed
+    }
+
+    /// Whether we answer a peer's request for the sources of a file. Peers
+    /// asking more often than eMule would are ignored.
+    pub fn answer(&mut self, peer: UserHash, hash: Ed2kHash, now: Instant) -> bool {
+        match self.answered.get(&(peer, hash)) {
+            Some(at) if now - *at <= Self::PEER_INTERVAL => false,
+            _ => {
+                self.answered.insert((peer, hash), now);
+                true
+            }
+        }
+    }
+
+    /// Takes the sources a peer sent us for a file, returning those worth
+    /// trying. Answers we did not ask for are dropped, as are sources
+    /// which are not `is_reachable`.
+    pub fn answer_received(
+        &self,
+        peer: UserHash,
+        hash: Ed2kHash,
+        sources: Vec<FoundSource>,
+        filter: &IpFilter,
+        now: Instant,
+    ) -> Vec<FoundSource> {
+        match self.asked_peers.get(&(peer, hash)) {
+            Some(at) if now - *at <= Self::ANSWER_TIMEOUT => {}
+            _ => return Vec::new(),
+        }
+
+        sources
+            .into_iter()
+            .filter(|s| is_reachable(s, filter))
+            .collect()
+    }
+
+    /// Forgets requests and answers old enough to no longer limit anything.
+    pub fn expire(&mut self, now: Instant) {
+        let longest = Self::PEER_INTERVAL * Self::COMMON_PENALTY;
+        self.asked_peers.retain(|_, at| now - *at <= longest);
+        self.asked_files
+            .retain(|_, at| now - *at <= Self::FILE_INTERVAL * Self::COMMON_PENALTY);
+        self.answered
+            .retain(|_, at| now - *at <= Self::PEER_INTERVAL);
+    }
+}
+
+/// Whether we can connect to a source, however we heard of it: it must
+/// have a port, and neither its IP Address nor, for a low id, the server
+/// which has to pass on a callback may be blocked by the IP filter.
+pub fn is_reachable(source: &FoundSource, filter: &IpFilter) -> bool {
+    let ip = match (source.ip_addr(), source.server) {
+        (Some(ip), _) => ip,
+        (None, SocketAddr::V4(server)) => *server.ip(),
+        (None, SocketAddr::V6(_)) => return false,
+    };
+    source.port != 0 && is_good_ip(ip) && !filter.is_blocked(ip)
+}
+
+/// Whether an address can be a client on the internet.
+fn is_good_ip(ip: Ipv4Addr) -> bool {
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.octets()[0] == 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn source(ip: [u8; 4], port: u16) -> FoundSource {
+        FoundSource {
+            client_id: u32::from_le_bytes(ip),
+            port,
+            server: "1.2.3.4:4661".parse().unwrap(),
+            obfuscation: Some(SourceObfuscation::from_options(
+                0x03,
+                Some(UserHash::new([9; 16])),
+            )),
+        }
+    }
+
+    #[test]
+    pub fn test_rare_files_are_asked_about_more_often() {
+        let mut sx = SourceExchange::new();
+        let (alice, bob) = (UserHash::new([1; 16]), UserHash::new([2; 16]));
+        let hash = Ed2kHash::new([7; 16]);
+        let now = Instant::now();
+        let minutes = |m: u64| now + Duration::from_secs(m * 60);
+
+        // A rare file: each peer every 40 minutes, the file every 5.
+        assert!(sx.request(alice, hash, 20, 400, now));
+        assert!(!sx.request(alice, hash, 20, 400, minutes(1)));
+        assert!(!sx.request(bob, hash, 20, 400, minutes(1)));
+        assert!(sx.request(bob, hash, 20, 400, minutes(6)));
+        assert!(!sx.request(alice, hash, 20, 400, minutes(39)));
+        assert!(sx.request(alice, hash, 5, 400, minutes(41)));
+    }
+
+    #[test]
+    pub fn test_files_with_enough_sources_are_not_asked_about() {
+        let mut sx = SourceExchange::new();
+        let hash = Ed2kHash::new([7; 16]);
+        assert!(!sx.request(UserHash::new([1; 16]), hash, 400, 400, Instant::now()));
+    }
+
+    #[test]
+    pub fn test_peers_are_answered_once_an_interval() {
+        let mut sx = SourceExchange::new();
+        let (alice, bob) = (UserHash::new([1; 16]), UserHash::new([2; 16]));
+        let hash = Ed2kHash::new([7; 16]);
+        let now = Instant::now();
+        let minutes = |m: u64| now + Duration::from_secs(m * 60);
+
+        assert!(sx.answer(bob, hash, now));
+        assert!(!sx.answer(bob, hash, minutes(10)));
+        assert!(sx.answer(alice, hash, minutes(10)));
+        assert!(sx.answer(bob, hash, minutes(41)));
+    }
+
+    #[test]
+    pub fn test_unreachable_sources_are_dropped() {
+        let mut sx = SourceExchange::new();
+        let alice = UserHash::new([1; 16]);
+        let hash = Ed2kHash::new([7; 16]);
+        let now = Instant::now();
+        assert!(sx.request(alice, hash, 20, 400, now));
+
+        let sources = vec![
+            source([80, 1, 2, 3], 4662),
+            source([127, 0, 0, 1], 4662),
+            source([80, 1, 2, 4], 0),
+            source([80, 6, 6, 6], 4662),
+            // A low id, reachable through its server.
+            FoundSource {
+                client_id: 1234,
+                ..source([0; 4], 4662)
+            },
+        ];
+        let filter = IpFilter::parse("080.006.006.006 - 080.006.006.006 , 000 , Test");
+        let accepted = sx.answer_received(alice, hash, sources.clone(), &filter, now);
+        assert_eq!(accepted, [sources[0].clone(), sources[4].clone()]);
+    }
+
+    #[test]
+    pub fn test_answers_we_did_not_ask_for_are_dropped() {
+        let mut sx = SourceExchange::new();
+        let (alice, bob) = (UserHash::new([1; 16]), UserHash::new([2; 16]));
+        let hash = Ed2kHash::new([7; 16]);
+        let now = Instant::now();
+        let minutes = |m: u64| now + Duration::from_secs(m * 60);
+        assert!(sx.request(alice, hash, 20, 400, now));
+
+        let sources = vec![source([80, 1, 2, 3], 4662)];
+        let filter = IpFilter::default();
+        assert!(sx
+            .answer_received(bob, hash, sources.clone(), &filter, now)
+            .is_empty());
+        // Nor answers which come too late.
+        assert!(sx
+            .answer_received(alice, hash, sources, &filter, minutes(6))
+            .is_empty());
+    }
+}
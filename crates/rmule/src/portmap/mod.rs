@@ -0,0 +1,85 @@
+//! Asks the router to forward our TCP and UDP ports to us, so that other
+//! clients can connect and we get a high ID. We speak UPnP IGD, which most
+//! home routers support, and NAT-PMP and its successor PCP, which Apple
+//! and many open source routers use.
+
+mod nat_pmp;
+mod port_mapping_manager;
+mod upnp;
+
+pub use nat_pmp::*;
+pub use port_mapping_manager::*;
+pub use upnp::*;
+
+use std::fmt::Display;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+/// The protocol of a forwarded port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MappingProtocol {
+    Tcp,
+    Udp,
+}
+
+impl MappingProtocol {
+    /// The IANA protocol number, as PCP wants.
+    fn ip_protocol(&self) -> u8 {
+        match self {
+            Self::Tcp => 6,
+            Self::Udp => 17,
+        }
+    }
+
+    fn nat_pmp_opcode(&self) -> u8 {
+        match self {
+            Self::Tcp => 2,
+            Self::Udp => 1,
+        }
+    }
+
+    /// The name UPnP wants.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Tcp => "TCP",
+            Self::Udp => "UDP",
+        }
+    }
+}
+
+impl Display for MappingProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How a port was forwarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingMethod {
+    Upnp,
+    NatPmp,
+    Pcp,
+}
+
+impl Display for MappingMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Upnp => "UPnP",
+            Self::NatPmp => "NAT-PMP",
+            Self::Pcp => "PCP",
+        })
+    }
+}
+
+/// A port the router forwards to us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub protocol: MappingProtocol,
+    pub internal_port: u16,
+    /// Where other clients reach us.
+    pub external: SocketAddrV4,
+    /// How long the router keeps the mapping unless it is renewed. Zero
+    /// means it is kept until deleted.
+    pub lifetime: Duration,
+    pub method: MappingMethod,
+}
@@ -0,0 +1,307 @@
+use super::{MappingMethod, MappingProtocol, PortMapping};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+
+/// The port NAT-PMP and PCP gateways listen on.
+pub const NAT_PMP_PORT: u16 = 5351;
+
+/// A router which speaks PCP (RFC 6887) or its predecessor NAT-PMP (RFC
+/// 6886). We try PCP first and fall back to NAT-PMP if the router does
+/// not understand it.
+#[derive(Debug)]
+pub struct NatPmpGateway {
+    addr: SocketAddr,
+    /// Whether the router only speaks NAT-PMP, once we know.
+    nat_pmp_only: bool,
+    /// PCP renews and deletes mappings by the nonce they were made with.
+    nonces: HashMap<(MappingProtocol, u16), [u8; 12]>,
+}
+
+impl NatPmpGateway {
+    /// The delays between retries: RFC 6886 starts at 250 ms and doubles.
+    /// We give up sooner than it suggests, as a router which does not
+    /// answer at once is unlikely to answer at all.
+    const RETRIES: [Duration; 4] = [
+        Duration::from_millis(250),
+        Duration::from_millis(500),
+        Duration::from_millis(1000),
+        Duration::from_millis(2000),
+    ];
+
+    const PCP_VERSION: u8 = 2;
+    const PCP_MAP: u8 = 1;
+    const UNSUPPORTED_VERSION: u8 = 1;
+
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            nat_pmp_only: false,
+            nonces: HashMap::new(),
+        }
+    }
+
+    /// Asks for (or renews) a mapping of the same external port to our
+    /// internal port. A lifetime of zero deletes the mapping.
+    pub async fn map(
+        &mut self,
+        protocol: MappingProtocol,
+        internal_port: u16,
+        lifetime: Duration,
+    ) -> Result<PortMapping> {
+        if !self.nat_pmp_only {
+            match self.map_pcp(protocol, internal_port, lifetime).await? {
+                Some(mapping) => return Ok(mapping),
+                None => self.nat_pmp_only = true,
+            }
+        }
+
+        self.map_nat_pmp(protocol, internal_port, lifetime).await
+    }
+
+    /// Deletes a mapping we made.
+    pub async fn unmap(&mut self, mapping: &PortMapping) -> Result<()> {
+        self.map(mapping.protocol, mapping.internal_port, Duration::ZERO)
+            .await?;
+        self.nonces
+            .remove(&(mapping.protocol, mapping.internal_port));
+        Ok(())
+    }
+
+    /// Returns None if the router only speaks NAT-PMP.
+    async fn map_pcp(
+        &mut self,
+        protocol: MappingProtocol,
+        internal_port: u16,
+        lifetime: Duration,
+    ) -> Result<Option<PortMapping>> {
+        let socket = self.socket().await?;
+        // The router checks that the address in the request is the one
+        // the request came from.
+        let SocketAddr::V4(local) = socket.local_addr()? else {
+            bail!("PCP gateway {} is not an IPv4 Address", self.addr);
+        };
+        let nonce = *self
+            .nonces
+            .entry((protocol, internal_port))
+            .or_insert_with(rand::random);
+
+        let mut request = vec![Self::PCP_VERSION, Self::PCP_MAP, 0, 0];
+        request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+        request.extend_from_slice(&local.ip().to_ipv6_mapped().octets());
+        request.extend_from_slice(&nonce);
+        request.extend_from_slice(&[protocol.ip_protocol(), 0, 0, 0]);
+        request.extend_from_slice(&internal_port.to_be_bytes());
+        request.extend_from_slice(&internal_port.to_be_bytes());
+        request.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+        let response = Self::request(&socket, &request, |r| {
+            // A NAT-PMP router answers with its own version, 0.
+            r.len() >= 4 && (r[0] == 0 || (r.len() >= 60 && r[24..36] == nonce))
+        })
+        .await?;
+
+        if response[0] == 0 || response[3] == Self::UNSUPPORTED_VERSION {
+            return Ok(None);
+        }
+        if response[1] != Self::PCP_MAP | 0x80 {
+            bail!("PCP gateway {} sent an unexpected response", self.addr);
+        }
+        if response[3] != 0 {
+            bail!(
+                "PCP gateway {} refused the mapping: {}",
+                self.addr,
+                pcp_result(response[3])
+            );
+        }
+
+        let lifetime = u32::from_be_bytes(response[4..8].try_into().unwrap());
+        let external_port = u16::from_be_bytes([response[42], response[43]]);
+        let ip: [u8; 16] = response[44..60].try_into().unwrap();
+        let Some(external_ip) = Ipv6Addr::from(ip).to_ipv4_mapped() else {
+            bail!("PCP gateway {} mapped us to an IPv6 Address", self.addr);
+        };
+
+        Ok(Some(PortMapping {
+            protocol,
+            internal_port,
+            external: SocketAddrV4::new(external_ip, external_port),
+            lifetime: Duration::from_secs(lifetime as u64),
+            method: MappingMethod::Pcp,
+        }))
+    }
+
+    async fn map_nat_pmp(
+        &self,
+        protocol: MappingProtocol,
+        internal_port: u16,
+        lifetime: Duration,
+    ) -> Result<PortMapping> {
+        let socket = self.socket().await?;
+        let opcode = protocol.nat_pmp_opcode();
+
+        let mut request = vec![0, opcode, 0, 0];
+        request.extend_from_slice(&internal_port.to_be_bytes());
+        // When deleting the external port must be 0.
+        let external_port = if lifetime.is_zero() { 0 } else { internal_port };
+        request.extend_from_slice(&external_port.to_be_bytes());
+        request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+
+        let response = Self::request(&socket, &request, |r| {
+            r.len() >= 16 && r[1] == opcode | 0x80
+        })
+        .await?;
+        nat_pmp_result(&response, self.addr)?;
+        let external_port = u16::from_be_bytes([response[10], response[11]]);
+        let lifetime = u32::from_be_bytes(response[12..16].try_into().unwrap());
+
+        // NAT-PMP only tells us the external address when asked.
+        let response = Self::request(&socket, &[0, 0], |r| r.len() >= 12 && r[1] == 0x80).await?;
+        nat_pmp_result(&response, self.addr)?;
+        let external_ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+
+        Ok(PortMapping {
+            protocol,
+            internal_port,
+            external: SocketAddrV4::new(external_ip, external_port),
+            lifetime: Duration::from_secs(lifetime as u64),
+            method: MappingMethod::NatPmp,
+        })
+    }
+
+    async fn socket(&self) -> Result<UdpSocket> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(self.addr).await?;
+        Ok(socket)
+    }
+
+    /// Sends a request until an answer which `is_answer` accepts arrives.
+    async fn request(
+        socket: &UdpSocket,
+        request: &[u8],
+        is_answer: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<u8>> {
+        let mut buf = [0u8; 1100];
+
+        for wait in Self::RETRIES {
+            socket.send(request).await?;
+            let deadline = time::Instant::now() + wait;
+            while let Ok(received) = time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                // Errors are usually ICMP port unreachable, from a router
+                // which does not speak the protocol at all.
+                let len = received?;
+                if is_answer(&buf[..len]) {
+                    return Ok(buf[..len].to_vec());
+                }
+            }
+        }
+
+        bail!("No answer from {}", socket.peer_addr()?)
+    }
+}
+
+fn nat_pmp_result(response: &[u8], gateway: SocketAddr) -> Result<()> {
+    let result = match u16::from_be_bytes([response[2], response[3]]) {
+        0 => return Ok(()),
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown error",
+    };
+    bail!("NAT-PMP gateway {gateway} refused the mapping: {result}")
+}
+
+fn pcp_result(result: u8) -> &'static str {
+    match result {
+        2 => "not authorized",
+        3 => "malformed request",
+        4 => "unsupported opcode",
+        5 => "unsupported option",
+        6 => "malformed option",
+        7 => "network failure",
+        8 => "no resources",
+        9 => "unsupported protocol",
+        10 => "user exceeded quota",
+        11 => "cannot provide external address",
+        12 => "address mismatch",
+        13 => "excessive remote peers",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::*;
+
+    /// Stands in for a router, speaking PCP or only NAT-PMP. Its external
+    /// address is 203.0.113.7, and it maps port n to port n + 1.
+    pub(crate) async fn mock_gateway(speaks_pcp: bool) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let request = &buf[..len];
+                let mut response = vec![request[0], request[1] | 0x80, 0, 0];
+
+                match (request[0], request[1]) {
+                    (2, 1) if speaks_pcp => {
+                        response.extend_from_slice(&request[4..8]);
+                        response.extend_from_slice(&[0; 16]);
+                        response.extend_from_slice(&request[24..42]);
+                        let port = u16::from_be_bytes([request[40], request[41]]);
+                        response.extend_from_slice(&(port + 1).to_be_bytes());
+                        let ip = Ipv4Addr::new(203, 0, 113, 7);
+                        response.extend_from_slice(&ip.to_ipv6_mapped().octets());
+                    }
+                    (2, _) => response = vec![0, request[1] | 0x80, 0, 1, 0, 0, 0, 0],
+                    (0, 0) => response.extend_from_slice(&[0, 0, 0, 9, 203, 0, 113, 7]),
+                    (0, 1 | 2) => {
+                        let port = u16::from_be_bytes([request[4], request[5]]);
+                        response.extend_from_slice(&[0, 0, 0, 9]);
+                        response.extend_from_slice(&request[4..6]);
+                        response.extend_from_slice(&(port + 1).to_be_bytes());
+                        response.extend_from_slice(&request[8..12]);
+                    }
+                    _ => continue,
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    pub async fn test_maps_with_pcp_or_nat_pmp() {
+        for (speaks_pcp, method) in [(true, MappingMethod::Pcp), (false, MappingMethod::NatPmp)] {
+            let mut gateway = NatPmpGateway::new(mock_gateway(speaks_pcp).await);
+            let lifetime = Duration::from_secs(3600);
+
+            let mapping = gateway
+                .map(MappingProtocol::Tcp, 4662, lifetime)
+                .await
+                .unwrap();
+            assert_eq!(
+                mapping,
+                PortMapping {
+                    protocol: MappingProtocol::Tcp,
+                    internal_port: 4662,
+                    external: "203.0.113.7:4663".parse().unwrap(),
+                    lifetime,
+                    method,
+                }
+            );
+            assert_eq!(gateway.nat_pmp_only, !speaks_pcp);
+
+            gateway.unmap(&mapping).await.unwrap();
+        }
+    }
+}
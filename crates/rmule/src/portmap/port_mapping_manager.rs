@@ -0,0 +1,633 @@
+use super::{MappingProtocol, NatPmpGateway, PortMapping, UpnpGateway, NAT_PMP_PORT, SSDP_ADDR};
+use crate::configuration::{ConfigurationEventReceiver, ConfigurationEvents, Settings};
+use crate::listener::{ListenerEventReceiver, ListenerEvents, ListenerKind};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{self, Instant};
+use tracing::{debug, info, warn};
+
+pub type PortMappingCommandSender = mpsc::Sender<PortMappingCommand>;
+pub type PortMappingCommandReceiver = mpsc::Receiver<PortMappingCommand>;
+
+pub type PortMappingEventSender = broadcast::Sender<PortMappingEvents>;
+pub type PortMappingEventReceiver = broadcast::Receiver<PortMappingEvents>;
+
+/// The handle type allows commands to be sent to and events to be received
+/// from the Port Mapping Manager.
+pub struct PortMappingManagerHandle {
+    cmd_sender: PortMappingCommandSender,
+    evt_sender: PortMappingEventSender,
+    // TODO: We need at least one receiver to be alive to
+    // allow us to send events.
+    evt_receiver: PortMappingEventReceiver,
+}
+
+impl PortMappingManagerHandle {
+    /// Starts the Port Mapping Manager as a Tokio task. It forwards the
+    /// ports the Listener Manager opens while the settings allow it.
+    pub fn new(
+        cfg_evt_receiver: ConfigurationEventReceiver,
+        listener_evt_receiver: ListenerEventReceiver,
+        options: PortMappingOptions,
+        tokio_handle: &tokio::runtime::Handle,
+    ) -> Self {
+        let (cmd_sender, cmd_receiver) = mpsc::channel::<PortMappingCommand>(32);
+        let (evt_sender, evt_receiver) = broadcast::channel::<PortMappingEvents>(256);
+
+        let mgr = PortMappingManager::new(
+            evt_sender.clone(),
+            cmd_receiver,
+            cfg_evt_receiver,
+            listener_evt_receiver,
+            options,
+        );
+        tokio_handle.spawn(mgr.run());
+
+        Self {
+            cmd_sender,
+            evt_sender,
+            evt_receiver,
+        }
+    }
+
+    /// Sends a command to the Port Mapping Manager.
+    pub async fn send_command(&self, cmd: PortMappingCommand) -> Result<()> {
+        Ok(self.cmd_sender.send(cmd).await?)
+    }
+
+    /// Synchronously send a command to the Port Mapping Manager.
+    pub fn send_command_blocking(&self, cmd: PortMappingCommand) -> Result<()> {
+        Ok(self.cmd_sender.blocking_send(cmd)?)
+    }
+
+    /// Create a new subscription to events sent by the Port Mapping Manager.
+    pub fn subscribe_to_events(&self) -> PortMappingEventReceiver {
+        self.evt_sender.subscribe()
+    }
+
+    /// Creates a new command sender which can be used to send commands
+    /// to the Port Mapping Manager.
+    pub fn make_command_sender(&self) -> PortMappingCommandSender {
+        self.cmd_sender.clone()
+    }
+}
+
+/// Where to look for the router and how long to ask for mappings. The
+/// defaults are right for a real network; tests point them at stand-ins.
+#[derive(Debug, Clone)]
+pub struct PortMappingOptions {
+    /// Where UPnP searches are sent.
+    pub ssdp_addr: SocketAddr,
+    /// How long to wait for a UPnP router to answer.
+    pub discovery_timeout: Duration,
+    /// Where NAT-PMP and PCP requests are sent. None uses the default
+    /// gateway.
+    pub nat_pmp_gateway: Option<SocketAddr>,
+    /// How long to ask the router to keep each mapping. We renew them
+    /// when half of it has passed.
+    pub lifetime: Duration,
+}
+
+impl Default for PortMappingOptions {
+    fn default() -> Self {
+        Self {
+            ssdp_addr: SSDP_ADDR,
+            discovery_timeout: Duration::from_secs(3),
+            nat_pmp_gateway: None,
+            lifetime: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// The set of commands that can be sent to the Port Mapping Manager.
+#[derive(Debug)]
+pub enum PortMappingCommand {
+    /// Deletes all our mappings from the router and stops the Port Mapping
+    /// Manager. `Stopped` is sent once the router has been told.
+    Stop,
+}
+
+/// The set of events that can be emitted by the Port Mapping Manager.
+#[derive(Debug, Clone)]
+pub enum PortMappingEvents {
+    /// The router forwards a port to us, or has renewed the mapping. The
+    /// mapping says where other clients can reach us.
+    Mapped(PortMapping),
+    /// The router could not be found or would not forward the port. We
+    /// try again later.
+    MappingFailed {
+        protocol: MappingProtocol,
+        port: u16,
+        reason: String,
+    },
+    /// The mapping has been deleted because the port closed or port
+    /// mapping was turned off.
+    Unmapped(PortMapping),
+    /// All mappings have been deleted and the Port Mapping Manager has
+    /// stopped.
+    Stopped,
+}
+
+/// The router we found, and how to talk to it.
+#[derive(Debug)]
+enum Gateway {
+    Upnp(UpnpGateway),
+    NatPmp(NatPmpGateway),
+}
+
+impl Gateway {
+    async fn map(
+        &mut self,
+        protocol: MappingProtocol,
+        internal_port: u16,
+        lifetime: Duration,
+    ) -> Result<PortMapping> {
+        match self {
+            Self::Upnp(gateway) => gateway.map(protocol, internal_port, lifetime).await,
+            Self::NatPmp(gateway) => gateway.map(protocol, internal_port, lifetime).await,
+        }
+    }
+
+    async fn unmap(&mut self, mapping: &PortMapping) -> Result<()> {
+        match self {
+            Self::Upnp(gateway) => gateway.unmap(mapping).await,
+            Self::NatPmp(gateway) => gateway.unmap(mapping).await,
+        }
+    }
+}
+
+/// A mapping we hold or want, and when to renew it or try again. Mappings
+/// which never expire are not renewed.
+#[derive(Debug)]
+struct ActiveMapping {
+    port: u16,
+    // None until the router has agreed to forward the port.
+    mapping: Option<PortMapping>,
+    renew_at: Option<Instant>,
+    // Renewals which have failed in a row, which makes us wait longer
+    // before the next try.
+    failures: u32,
+}
+
+/// This is private to the module: all access is via the handle.
+struct PortMappingManager {
+    events_sender: PortMappingEventSender,
+    commands_receiver: PortMappingCommandReceiver,
+    cfg_events_receiver: ConfigurationEventReceiver,
+    listener_events_receiver: ListenerEventReceiver,
+    options: PortMappingOptions,
+    // Off until the settings say otherwise.
+    enabled: bool,
+    // The ports we listen on, which we want forwarded.
+    ports: HashMap<MappingProtocol, u16>,
+    mappings: HashMap<MappingProtocol, ActiveMapping>,
+    // Found when first needed, and looked for again if it stops working.
+    gateway: Option<Gateway>,
+}
+
+impl PortMappingManager {
+    /// How long after a failed renewal we try again. Each failure in a
+    /// row doubles it, up to `MAX_RENEW_RETRY`.
+    const RENEW_RETRY: Duration = Duration::from_secs(60);
+    const MAX_RENEW_RETRY: Duration = Duration::from_secs(16 * 60);
+
+    fn new(
+        events_sender: PortMappingEventSender,
+        commands_receiver: PortMappingCommandReceiver,
+        cfg_events_receiver: ConfigurationEventReceiver,
+        listener_events_receiver: ListenerEventReceiver,
+        options: PortMappingOptions,
+    ) -> Self {
+        Self {
+            events_sender,
+            commands_receiver,
+            cfg_events_receiver,
+            listener_events_receiver,
+            options,
+            enabled: false,
+            ports: HashMap::new(),
+            mappings: HashMap::new(),
+            gateway: None,
+        }
+    }
+
+    async fn run(mut self) {
+        loop {
+            let next_renewal = self.next_renewal();
+            tokio::select! {
+                cmd = self.commands_receiver.recv() => {
+                    match cmd {
+                        Some(PortMappingCommand::Stop) | None => break,
+                    }
+                }
+                evt = self.cfg_events_receiver.recv() => {
+                    match evt {
+                        Ok(ConfigurationEvents::SettingsChange(settings)) => self.settings_changed(&settings).await,
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(n)) => warn!("Port Mapping Manager missed {n} configuration events"),
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                evt = self.listener_events_receiver.recv() => {
+                    match evt {
+                        Ok(ListenerEvents::Listening { kind, addr }) => {
+                            if let Some(protocol) = protocol_of(kind) {
+                                self.port_changed(protocol, addr.port()).await;
+                            }
+                        }
+                        Ok(ListenerEvents::Closed(kind)) => {
+                            if let Some(protocol) = protocol_of(kind) {
+                                self.port_closed(protocol).await;
+                            }
+                        }
+                        Ok(ListenerEvents::BindFailed { .. }) => {}
+                        Err(RecvError::Lagged(n)) => warn!("Port Mapping Manager missed {n} listener events"),
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                _ = sleep_until(next_renewal) => self.renew_due().await,
+            }
+        }
+
+        self.stop().await;
+        info!("Port Mapping Manager stopped");
+    }
+
+    async fn settings_changed(&mut self, settings: &Settings) {
+        if settings.port_mapping == self.enabled {
+            return;
+        }
+
+        self.enabled = settings.port_mapping;
+        let ports: Vec<_> = self.ports.iter().map(|(&p, &port)| (p, port)).collect();
+        for (protocol, port) in ports {
+            if self.enabled {
+                self.map(protocol, port).await;
+            } else {
+                self.unmap(protocol).await;
+            }
+        }
+    }
+
+    async fn port_changed(&mut self, protocol: MappingProtocol, port: u16) {
+        self.ports.insert(protocol, port);
+        if !self.enabled {
+            return;
+        }
+
+        if let Some(active) = self.mappings.get(&protocol) {
+            if active.port == port {
+                return;
+            }
+            self.unmap(protocol).await;
+        }
+        self.map(protocol, port).await;
+    }
+
+    async fn port_closed(&mut self, protocol: MappingProtocol) {
+        self.ports.remove(&protocol);
+        self.unmap(protocol).await;
+    }
+
+    /// Asks the router for a mapping, or renews the one we have.
+    async fn map(&mut self, protocol: MappingProtocol, port: u16) {
+        let result = match self.gateway.as_mut() {
+            Some(gateway) => gateway.map(protocol, port, self.options.lifetime).await,
+            None => match self.find_gateway().await {
+                Ok(gateway) => {
+                    self.gateway
+                        .insert(gateway)
+                        .map(protocol, port, self.options.lifetime)
+                        .await
+                }
+                Err(e) => Err(e),
+            },
+        };
+
+        match result {
+            Ok(mapping) => {
+                info!(
+                    "Router forwards {protocol} port {port} to us from {} with {}",
+                    mapping.external, mapping.method
+                );
+                // Renew at half time, so that a lost request can be retried.
+                let renew_at =
+                    (!mapping.lifetime.is_zero()).then(|| Instant::now() + mapping.lifetime / 2);
+                self.mappings.insert(
+                    protocol,
+                    ActiveMapping {
+                        port,
+                        mapping: Some(mapping.clone()),
+                        renew_at,
+                        failures: 0,
+                    },
+                );
+                self.send_event(PortMappingEvents::Mapped(mapping));
+            }
+            Err(e) => {
+                warn!("Forwarding {protocol} port {port} failed: {e}");
+                // A mapping we are renewing may still hold for a while, so
+                // it is kept. Either way it is tried again later.
+                let active = self.mappings.entry(protocol).or_insert(ActiveMapping {
+                    port,
+                    mapping: None,
+                    renew_at: None,
+                    failures: 0,
+                });
+                active.failures += 1;
+                let retry = Self::RENEW_RETRY * 2u32.pow(active.failures.min(5) - 1);
+                active.renew_at = Some(Instant::now() + retry.min(Self::MAX_RENEW_RETRY));
+                // The router may have restarted or gone; look again next time.
+                self.gateway = None;
+                self.send_event(PortMappingEvents::MappingFailed {
+                    protocol,
+                    port,
+                    reason: e.to_string(),
+                });
+            }
+        }
+    }
+
+    async fn unmap(&mut self, protocol: MappingProtocol) {
+        let Some(mapping) = self
+            .mappings
+            .remove(&protocol)
+            .and_then(|active| active.mapping)
+        else {
+            return;
+        };
+
+        // The router is forgotten when a request fails, but it may still
+        // hold the mapping.
+        if self.gateway.is_none() {
+            match self.find_gateway().await {
+                Ok(gateway) => self.gateway = Some(gateway),
+                Err(e) => warn!("Deleting the {protocol} port mapping failed: {e}"),
+            }
+        }
+        if let Some(gateway) = self.gateway.as_mut() {
+            match gateway.unmap(&mapping).await {
+                Ok(()) => info!(
+                    "Router no longer forwards {protocol} port {}",
+                    mapping.internal_port
+                ),
+                // It expires by itself in the end.
+                Err(e) => warn!("Deleting the {protocol} port mapping failed: {e}"),
+            }
+        }
+        self.send_event(PortMappingEvents::Unmapped(mapping));
+    }
+
+    /// Looks for a UPnP router, falling back to NAT-PMP/PCP at the default
+    /// gateway. Whether that speaks NAT-PMP or PCP is only known once we
+    /// ask it for a mapping.
+    async fn find_gateway(&self) -> Result<Gateway> {
+        match UpnpGateway::discover(self.options.ssdp_addr, self.options.discovery_timeout).await {
+            Ok(gateway) => return Ok(Gateway::Upnp(gateway)),
+            Err(e) => debug!("No UPnP router: {e}"),
+        }
+
+        let addr = self
+            .options
+            .nat_pmp_gateway
+            .or_else(default_gateway)
+            .ok_or_else(|| anyhow!("No router found"))?;
+        Ok(Gateway::NatPmp(NatPmpGateway::new(addr)))
+    }
+
+    fn next_renewal(&self) -> Option<Instant> {
+        self.mappings.values().filter_map(|m| m.renew_at).min()
+    }
+
+    async fn renew_due(&mut self) {
+        let now = Instant::now();
+        let due: Vec<_> = self
+            .mappings
+            .iter()
+            .filter(|(_, m)| m.renew_at.is_some_and(|at| at <= now))
+            .map(|(&protocol, m)| (protocol, m.port))
+            .collect();
+
+        for (protocol, port) in due {
+            debug!("Renewing the {protocol} port mapping");
+            self.map(protocol, port).await;
+        }
+    }
+
+    /// Deletes all our mappings, so that the router does not forward ports
+    /// nobody is listening on.
+    async fn stop(&mut self) {
+        let protocols: Vec<_> = self.mappings.keys().copied().collect();
+        for protocol in protocols {
+            self.unmap(protocol).await;
+        }
+        self.send_event(PortMappingEvents::Stopped);
+    }
+
+    fn send_event(&self, evt: PortMappingEvents) {
+        if let Err(broadcast::error::SendError(evt)) = self.events_sender.send(evt) {
+            warn!(
+                "Nobody is listening for port mapping events, dropping {:?}",
+                evt
+            );
+        }
+    }
+}
+
+/// The protocol of the ports worth forwarding. Servers answer on the
+/// server UDP port without help.
+fn protocol_of(kind: ListenerKind) -> Option<MappingProtocol> {
+    match kind {
+        ListenerKind::Tcp => Some(MappingProtocol::Tcp),
+        ListenerKind::Udp => Some(MappingProtocol::Udp),
+        ListenerKind::ServerUdp => None,
+    }
+}
+
+/// Sleeps until the instant, if there is one, otherwise forever. This is
+/// cancel safe, so it can be used in a select.
+async fn sleep_until(instant: Option<Instant>) {
+    match instant {
+        Some(instant) => time::sleep_until(instant).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The router's address, from the routing table. Only Linux is supported
+/// for now; elsewhere the gateway must be given in the options.
+fn default_gateway() -> Option<SocketAddr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        // Iface, Destination, Gateway, ... with addresses in hex, in
+        // network byte order.
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        let ip = Ipv4Addr::from(gateway.to_le_bytes());
+        Some(SocketAddr::from((ip, NAT_PMP_PORT)))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::portmap::nat_pmp::test::mock_gateway;
+    use crate::portmap::MappingMethod;
+    use tokio::net::UdpSocket;
+
+    /// A manager whose router is a stand-in speaking PCP, with the events
+    /// it sends. Nobody answers UPnP searches, so that router is used.
+    async fn make_manager() -> (PortMappingManager, PortMappingEventReceiver) {
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let options = PortMappingOptions {
+            ssdp_addr: silent.local_addr().unwrap(),
+            discovery_timeout: Duration::from_millis(50),
+            nat_pmp_gateway: Some(mock_gateway(true).await),
+            lifetime: Duration::from_secs(3600),
+        };
+        let (evt_sender, events) = broadcast::channel(16);
+        // The other ends are dropped, as the tests do not run the loop.
+        let (_, cmd_receiver) = mpsc::channel(1);
+        let (_, cfg_receiver) = broadcast::channel(1);
+        let (_, listener_receiver) = broadcast::channel(1);
+        let mgr = PortMappingManager::new(
+            evt_sender,
+            cmd_receiver,
+            cfg_receiver,
+            listener_receiver,
+            options,
+        );
+        (mgr, events)
+    }
+
+    fn make_renewal_due(mgr: &mut PortMappingManager) {
+        mgr.mappings
+            .get_mut(&MappingProtocol::Tcp)
+            .unwrap()
+            .renew_at = Some(Instant::now());
+    }
+
+    #[tokio::test]
+    pub async fn test_ports_are_mapped_renewed_and_unmapped_on_stop() {
+        let (mut mgr, mut events) = make_manager().await;
+
+        // Nothing is mapped until the settings allow it.
+        mgr.port_changed(MappingProtocol::Tcp, 4662).await;
+        assert!(events.try_recv().is_err());
+        mgr.enabled = true;
+        mgr.port_changed(MappingProtocol::Tcp, 4662).await;
+        let PortMappingEvents::Mapped(mapping) = events.try_recv().unwrap() else {
+            panic!("Expected a mapping");
+        };
+        assert_eq!(mapping.external, "203.0.113.7:4663".parse().unwrap());
+        assert_eq!(mapping.method, MappingMethod::Pcp);
+
+        // Renewal is due at half time.
+        let renew_at = mgr.next_renewal().unwrap();
+        assert!(renew_at <= Instant::now() + Duration::from_secs(1800));
+        make_renewal_due(&mut mgr);
+        mgr.renew_due().await;
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            PortMappingEvents::Mapped(renewed) if renewed == mapping
+        ));
+        assert!(mgr.next_renewal().unwrap() > renew_at);
+
+        mgr.stop().await;
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            PortMappingEvents::Unmapped(unmapped) if unmapped == mapping
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            PortMappingEvents::Stopped
+        ));
+    }
+
+    /// Points the manager at a router which does not answer.
+    async fn make_router_gone(mgr: &mut PortMappingManager) {
+        let gone = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let gone = gone.local_addr().unwrap();
+        mgr.gateway = Some(Gateway::NatPmp(NatPmpGateway::new(gone)));
+    }
+
+    #[tokio::test]
+    pub async fn test_failed_renewals_are_tried_again_later() {
+        let (mut mgr, mut events) = make_manager().await;
+        mgr.enabled = true;
+        mgr.port_changed(MappingProtocol::Tcp, 4662).await;
+        let PortMappingEvents::Mapped(mapping) = events.try_recv().unwrap() else {
+            panic!("Expected a mapping");
+        };
+
+        // The router stops answering.
+        make_router_gone(&mut mgr).await;
+        make_renewal_due(&mut mgr);
+        mgr.renew_due().await;
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            PortMappingEvents::MappingFailed { port: 4662, .. }
+        ));
+        let retry_at = mgr.next_renewal().unwrap();
+        assert!(retry_at > Instant::now() + Duration::from_secs(30));
+        assert!(retry_at <= Instant::now() + PortMappingManager::RENEW_RETRY);
+
+        // When it is back, the retry finds it again.
+        make_renewal_due(&mut mgr);
+        mgr.renew_due().await;
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            PortMappingEvents::Mapped(renewed) if renewed == mapping
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_mappings_which_failed_at_first_are_tried_again_later() {
+        let (mut mgr, mut events) = make_manager().await;
+        mgr.enabled = true;
+        make_router_gone(&mut mgr).await;
+        mgr.port_changed(MappingProtocol::Tcp, 4662).await;
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            PortMappingEvents::MappingFailed { port: 4662, .. }
+        ));
+        let retry_at = mgr.next_renewal().unwrap();
+        assert!(retry_at <= Instant::now() + PortMappingManager::RENEW_RETRY);
+
+        make_renewal_due(&mut mgr);
+        mgr.renew_due().await;
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            PortMappingEvents::Mapped(mapping) if mapping.internal_port == 4662
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_mappings_are_deleted_after_the_router_failed() {
+        let (mut mgr, mut events) = make_manager().await;
+        mgr.enabled = true;
+        mgr.port_changed(MappingProtocol::Tcp, 4662).await;
+        let PortMappingEvents::Mapped(mapping) = events.try_recv().unwrap() else {
+            panic!("Expected a mapping");
+        };
+        make_router_gone(&mut mgr).await;
+        make_renewal_due(&mut mgr);
+        mgr.renew_due().await;
+        events.try_recv().unwrap();
+        assert!(mgr.gateway.is_none());
+
+        // The router is looked for again to delete the mapping.
+        mgr.port_closed(MappingProtocol::Tcp).await;
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            PortMappingEvents::Unmapped(unmapped) if unmapped == mapping
+        ));
+        assert!(mgr.gateway.is_some());
+    }
+}
@@ -0,0 +1,408 @@
+use super::{MappingMethod, MappingProtocol, PortMapping};
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::{Client, StatusCode, Url};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+
+/// Where UPnP devices listen for SSDP searches.
+pub const SSDP_ADDR: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900));
+
+/// A router which forwards ports when asked over UPnP, an Internet Gateway
+/// Device in UPnP terms.
+#[derive(Debug)]
+pub struct UpnpGateway {
+    client: Client,
+    control_url: Url,
+    /// The WANIPConnection or WANPPPConnection service we talk to.
+    service_type: String,
+    /// Our address on the router's network, which is where it forwards
+    /// ports to.
+    local_ip: Ipv4Addr,
+}
+
+impl UpnpGateway {
+    /// The device types we search for. Routers which are IGD version 2
+    /// usually answer to version 1 too, but not all do.
+    const DEVICE_TYPES: [&str; 2] = [
+        "urn:schemas-upnp-org:device:InternetGatewayDevice:1",
+        "urn:schemas-upnp-org:device:InternetGatewayDevice:2",
+    ];
+    /// The services which can forward ports, in order of preference.
+    const SERVICE_TYPES: [&str; 3] = [
+        "urn:schemas-upnp-org:service:WANIPConnection:2",
+        "urn:schemas-upnp-org:service:WANIPConnection:1",
+        "urn:schemas-upnp-org:service:WANPPPConnection:1",
+    ];
+    const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+    /// The router only supports mappings which never expire.
+    const ONLY_PERMANENT_LEASES: u32 = 725;
+
+    /// Finds a router by sending an SSDP search to `ssdp_addr`, which is
+    /// usually `SSDP_ADDR`, and waiting up to `timeout` for an answer.
+    pub async fn discover(ssdp_addr: SocketAddr, timeout: Duration) -> Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        for device_type in Self::DEVICE_TYPES {
+            let search = format!(
+                "M-SEARCH * HTTP/1.1\r\n\
+                 HOST: {SSDP_ADDR}\r\n\
+                 MAN: \"ssdp:discover\"\r\n\
+                 MX: 2\r\n\
+                 ST: {device_type}\r\n\r\n"
+            );
+            socket.send_to(search.as_bytes(), ssdp_addr).await?;
+        }
+
+        let mut buf = [0u8; 2048];
+        let location = time::timeout(timeout, async {
+            loop {
+                let (len, _) = socket.recv_from(&mut buf).await?;
+                let response = String::from_utf8_lossy(&buf[..len]);
+                if let Some(location) = header(&response, "location") {
+                    return anyhow::Ok(location.to_owned());
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("No UPnP router answered"))??;
+
+        Self::from_description(&location)
+            .await
+            .with_context(|| format!("Bad UPnP router at {location}"))
+    }
+
+    async fn from_description(location: &str) -> Result<Self> {
+        let location = Url::parse(location)?;
+        // Requests to the router must not go to a proxy.
+        let client = Client::builder()
+            .timeout(Self::HTTP_TIMEOUT)
+            .no_proxy()
+            .build()?;
+        let description = client
+            .get(location.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let services: Vec<_> = description
+            .split("<service>")
+            .skip(1)
+            .filter_map(|service| {
+                let service_type = element(service, "serviceType")?;
+                let control_url = element(service, "controlURL")?;
+                Some((service_type, control_url))
+            })
+            .collect();
+        let Some((service_type, control_url)) = Self::SERVICE_TYPES.iter().find_map(|wanted| {
+            services
+                .iter()
+                .find(|(service_type, _)| service_type == wanted)
+        }) else {
+            bail!("The router cannot forward ports");
+        };
+
+        // Old devices give a base for relative URLs.
+        let base = match element(&description, "URLBase") {
+            Some(base) => Url::parse(base)?,
+            None => location.clone(),
+        };
+        let control_url = base.join(control_url)?;
+
+        Ok(Self {
+            client,
+            local_ip: local_ip_towards(&control_url).await?,
+            control_url,
+            service_type: service_type.to_string(),
+        })
+    }
+
+    /// Asks the router to forward the same external port to our internal
+    /// port, or renews the mapping. Routers which only support mappings
+    /// which never expire get one of those.
+    pub async fn map(
+        &self,
+        protocol: MappingProtocol,
+        internal_port: u16,
+        lifetime: Duration,
+    ) -> Result<PortMapping> {
+        let mut lease = lifetime.as_secs() as u32;
+        loop {
+            let result = self
+                .call(
+                    "AddPortMapping",
+                    &[
+                        ("NewRemoteHost", ""),
+                        ("NewExternalPort", &internal_port.to_string()),
+                        ("NewProtocol", protocol.name()),
+                        ("NewInternalPort", &internal_port.to_string()),
+                        ("NewInternalClient", &self.local_ip.to_string()),
+                        ("NewEnabled", "1"),
+                        (
+                            "NewPortMappingDescription",
+                            &format!("rMule {}", protocol.name()),
+                        ),
+                        ("NewLeaseDuration", &lease.to_string()),
+                    ],
+                )
+                .await;
+
+            match result {
+                Ok(_) => break,
+                Err(UpnpError::Fault(Self::ONLY_PERMANENT_LEASES)) if lease != 0 => lease = 0,
+                Err(e) => bail!(
+                    "Forwarding {} port {internal_port} failed: {e}",
+                    protocol.name()
+                ),
+            }
+        }
+
+        Ok(PortMapping {
+            protocol,
+            internal_port,
+            external: SocketAddrV4::new(self.external_ip().await?, internal_port),
+            lifetime: Duration::from_secs(lease as u64),
+            method: MappingMethod::Upnp,
+        })
+    }
+
+    pub async fn unmap(&self, mapping: &PortMapping) -> Result<()> {
+        self.call(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", ""),
+                ("NewExternalPort", &mapping.external.port().to_string()),
+                ("NewProtocol", mapping.protocol.name()),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn external_ip(&self) -> Result<Ipv4Addr> {
+        let response = self.call("GetExternalIPAddress", &[]).await?;
+        let ip = element(&response, "NewExternalIPAddress")
+            .ok_or_else(|| anyhow!("The router did not say what its IP Address is"))?;
+        ip.parse()
+            .with_context(|| format!("The router's IP Address {ip} is not valid"))
+    }
+
+    /// Calls one of the service's actions with SOAP, returning the body of
+    /// the response.
+    async fn call(&self, action: &str, args: &[(&str, &str)]) -> Result<String, UpnpError> {
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+            .collect();
+        let body = format!(
+            r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body><u:{action} xmlns:u="{service}">{args}</u:{action}></s:Body>
+</s:Envelope>"#,
+            service = self.service_type
+        );
+
+        let response = self
+            .client
+            .post(self.control_url.clone())
+            .header("Content-Type", r#"text/xml; charset="utf-8""#)
+            .header("SOAPAction", format!(r#""{}#{action}""#, self.service_type))
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| UpnpError::Other(e.into()))?;
+
+        let status = response.status();
+        let text = response
+            .text()
+            .await
+            .map_err(|e| UpnpError::Other(e.into()))?;
+        match status {
+            StatusCode::OK => Ok(text),
+            _ => match element(&text, "errorCode").and_then(|code| code.parse().ok()) {
+                Some(code) => Err(UpnpError::Fault(code)),
+                None => Err(UpnpError::Other(anyhow!("HTTP status {status}"))),
+            },
+        }
+    }
+}
+
+/// Why a UPnP action failed.
+#[derive(Debug)]
+enum UpnpError {
+    /// The router refused, with a UPnP error code.
+    Fault(u32),
+    Other(anyhow::Error),
+}
+
+impl std::fmt::Display for UpnpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // 718 is the common one: the port is forwarded to somebody else.
+            Self::Fault(718) => f.write_str("the port is already forwarded to another device"),
+            Self::Fault(code) => write!(f, "UPnP error {code}"),
+            Self::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for UpnpError {}
+
+/// Finds the value of a header in an HTTP response. Header names are not
+/// case sensitive.
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Finds the text of the first element with the given name. Routers send
+/// simple enough XML that this is all the parsing we need; namespace
+/// prefixes are ignored.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    loop {
+        let start = rest.find('<')?;
+        rest = &rest[start + 1..];
+        let end = rest.find('>')?;
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+
+        let tag_name = tag.split_whitespace().next().unwrap_or_default();
+        let local_name = tag_name.rsplit(':').next().unwrap_or_default();
+        if local_name == name && !tag.ends_with('/') {
+            let close = rest.find("</")?;
+            return Some(rest[..close].trim());
+        }
+    }
+}
+
+/// Our IP Address on the network the URL's host is on.
+async fn local_ip_towards(url: &Url) -> Result<Ipv4Addr> {
+    let host = url.host_str().ok_or_else(|| anyhow!("{url} has no host"))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    // Connecting a UDP socket sends nothing, but picks the local address.
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect((host, port)).await?;
+    match socket.local_addr()? {
+        SocketAddr::V4(addr) => Ok(*addr.ip()),
+        SocketAddr::V6(_) => bail!("The router is not on an IPv4 network"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Stands in for a router: answers SSDP searches and serves a device
+    /// description and the WANIPConnection service over HTTP. It only
+    /// supports permanent leases. Returns the SSDP address and a log of
+    /// the actions called.
+    async fn mock_router() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let http = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let http_addr = http.local_addr().unwrap();
+        let ssdp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let ssdp_addr = ssdp.local_addr().unwrap();
+        let actions = Arc::new(Mutex::new(Vec::new()));
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            let (_, from) = ssdp.recv_from(&mut buf).await.unwrap();
+            let answer = format!(
+                "HTTP/1.1 200 OK\r\nST: upnp:rootdevice\r\nLocation: http://{http_addr}/desc.xml\r\n\r\n"
+            );
+            ssdp.send_to(answer.as_bytes(), from).await.unwrap();
+        });
+
+        let log = actions.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = http.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                // Enough for the small requests we get.
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n")
+                    || (request.starts_with(b"POST") && !request.ends_with(b"</s:Envelope>"))
+                {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8(request).unwrap();
+
+                let (status, body) = if request.starts_with("GET /desc.xml") {
+                    ("200 OK", "<root><device><serviceList>\
+                        <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType><controlURL>/l3f</controlURL></service>\
+                        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType><controlURL>/ctl/IPConn</controlURL></service>\
+                        </serviceList></device></root>".to_owned())
+                } else {
+                    assert!(request.starts_with("POST /ctl/IPConn"));
+                    let action = header(&request, "soapaction").unwrap();
+                    let action = action
+                        .trim_matches('"')
+                        .split('#')
+                        .nth(1)
+                        .unwrap()
+                        .to_owned();
+                    let lease = element(&request, "NewLeaseDuration")
+                        .unwrap_or("0")
+                        .to_owned();
+                    log.lock().unwrap().push(format!("{action} {lease}"));
+
+                    match action.as_str() {
+                        "AddPortMapping" if lease != "0" => ("500 Internal Server Error",
+                            "<s:Envelope><s:Body><s:Fault><detail><UPnPError><errorCode>725</errorCode></UPnPError></detail></s:Fault></s:Body></s:Envelope>".to_owned()),
+                        "GetExternalIPAddress" => ("200 OK",
+                            "<s:Envelope><s:Body><u:GetExternalIPAddressResponse><NewExternalIPAddress>203.0.113.7</NewExternalIPAddress></u:GetExternalIPAddressResponse></s:Body></s:Envelope>".to_owned()),
+                        _ => ("200 OK", "<s:Envelope><s:Body/></s:Envelope>".to_owned()),
+                    }
+                };
+
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (ssdp_addr, actions)
+    }
+
+    #[tokio::test]
+    pub async fn test_maps_ports_on_a_router() {
+        let (ssdp_addr, actions) = mock_router().await;
+
+        let gateway = UpnpGateway::discover(ssdp_addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(gateway.control_url.path(), "/ctl/IPConn");
+        assert_eq!(gateway.local_ip, Ipv4Addr::LOCALHOST);
+
+        let mapping = gateway
+            .map(MappingProtocol::Udp, 4672, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(mapping.external, "203.0.113.7:4672".parse().unwrap());
+        // The router only supports permanent leases.
+        assert_eq!(mapping.lifetime, Duration::ZERO);
+
+        gateway.unmap(&mapping).await.unwrap();
+        assert_eq!(
+            *actions.lock().unwrap(),
+            [
+                "AddPortMapping 3600",
+                "AddPortMapping 0",
+                "GetExternalIPAddress 0",
+                "DeletePortMapping 0"
+            ]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use anyhow::{bail, Result};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// A 20-byte SHA-1 hash in a file's AICH tree. The root of the tree, the
+/// master hash, lets corrupt parts be repaired a block at a time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AichHash([u8; 20]);
+
+impl AichHash {
+    pub const LEN: usize = 20;
+
+    const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    pub fn new(bytes: [u8; 20]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+}
+
+impl From<[u8; 20]> for AichHash {
+    fn from(bytes: [u8; 20]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl Display for AichHash {
+    /// AICH hashes are written in base 32, as in ed2k links. 20 bytes are
+    /// exactly 32 digits, so there is no padding.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for chunk in self.0.chunks(5) {
+            let bits = chunk.iter().fold(0u64, |bits, &b| (bits << 8) | b as u64);
+            for digit in (0..8).rev() {
+                let idx = (bits >> (digit * 5)) & 0x1F;
+                write!(f, "{}", Self::BASE32[idx as usize] as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for AichHash {
+    type Err = anyhow::Error;
+
+    /// Parses 32 base 32 digits (either case) into a hash.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 32 || !s.is_ascii() {
+            bail!("'{s}' is not a valid AICH hash, expected 32 base 32 digits");
+        }
+
+        let mut bytes = [0u8; 20];
+        for (chunk, out) in s.as_bytes().chunks(8).zip(bytes.chunks_mut(5)) {
+            let mut bits = 0u64;
+            for c in chunk {
+                let Some(digit) = Self::BASE32
+                    .iter()
+                    .position(|d| *d == c.to_ascii_uppercase())
+                else {
+                    bail!("'{s}' is not a valid AICH hash, expected 32 base 32 digits");
+                };
+                bits = (bits << 5) | digit as u64;
+            }
+            out.copy_from_slice(&bits.to_be_bytes()[3..]);
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_base32_round_trip() {
+        let hash = AichHash::new(*b"12345678901234567890");
+        let text = hash.to_string();
+        assert_eq!(text, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(text.to_lowercase().parse::<AichHash>().unwrap(), hash);
+        assert!("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJ1"
+            .parse::<AichHash>()
+            .is_err());
+    }
+}
@@ -0,0 +1,47 @@
+use crate::utils::parse_hex;
+use anyhow::{bail, Result};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// The 16-byte MD4-based hash which identifies a file on the ed2k network.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ed2kHash([u8; 16]);
+
+impl Ed2kHash {
+    pub const LEN: usize = 16;
+
+    pub fn new(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl From<[u8; 16]> for Ed2kHash {
+    fn from(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl Display for Ed2kHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in &self.0 {
+            write!(f, "{b:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Ed2kHash {
+    type Err = anyhow::Error;
+
+    /// Parses 32 hex digits (either case) into a hash.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_hex(s) {
+            Some(bytes) => Ok(Self(bytes)),
+            None => bail!("'{s}' is not a valid ed2k hash, expected 32 hex digits"),
+        }
+    }
+}
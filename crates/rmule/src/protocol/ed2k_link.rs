@@ -0,0 +1,334 @@
+use super::{AichHash, Ed2kHash};
+use crate::hashing::{Ed2kHashSet, FileHashes, MAX_FILE_SIZE, PART_SIZE};
+use anyhow::{bail, Context, Result};
+use std::fmt::Display;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+
+/// The links people paste to share files and servers, for example
+/// `ed2k://|file|name|size|hash|/`. The fields are separated by `|`, and
+/// the link ends with `|/`. See http://wiki.amule.org/wiki/Ed2k_link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ed2kLink {
+    File(Ed2kFileLink),
+    /// `ed2k://|server|ip|port|/`
+    Server(SocketAddrV4),
+    /// `ed2k://|serverlist|url|/`, where the URL is that of a server.met.
+    ServerList(String),
+}
+
+/// A file to download, as given in a file link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ed2kFileLink {
+    pub name: String,
+    pub size: u64,
+    pub hash: Ed2kHash,
+    /// The AICH master hash, from the optional `h=` field.
+    pub aich: Option<AichHash>,
+    /// The part hashes, from the optional `p=` field. These are checked
+    /// against the hash when the link is parsed.
+    pub part_hashes: Vec<Ed2kHash>,
+    /// Clients known to have the file, from the optional
+    /// `/|sources,ip:port,...|/` section.
+    pub sources: Vec<SocketAddrV4>,
+}
+
+impl Ed2kFileLink {
+    pub fn new<S: Into<String>>(name: S, size: u64, hash: Ed2kHash) -> Self {
+        Self {
+            name: name.into(),
+            size,
+            hash,
+            aich: None,
+            part_hashes: Vec::new(),
+            sources: Vec::new(),
+        }
+    }
+
+    /// The link for a file we have hashed, with everything another client
+    /// needs to check what it downloads.
+    pub fn from_file_hashes<S: Into<String>>(name: S, hashes: &FileHashes) -> Self {
+        let mut link = Self::new(name, hashes.aich.file_size(), hashes.ed2k.root);
+        link.aich = Some(hashes.aich.master_hash());
+        link.part_hashes = hashes.ed2k.wire_part_hashes().to_vec();
+        link
+    }
+
+    fn parse(fields: &[&str]) -> Result<Self> {
+        let [name, size, hash, optional @ ..] = fields else {
+            bail!("A file link needs a name, a size and a hash");
+        };
+
+        let name = url_decode(name)?;
+        if name.is_empty() {
+            bail!("The file name is empty");
+        }
+        let size = match size.parse::<u64>() {
+            Ok(size) if size > 0 && size <= MAX_FILE_SIZE => size,
+            _ => bail!("'{size}' is not a valid file size"),
+        };
+        let mut link = Self::new(name, size, hash.parse()?);
+
+        for field in optional {
+            if let Some(aich) = field.strip_prefix("h=") {
+                link.aich = Some(aich.parse()?);
+            } else if let Some(part_hashes) = field.strip_prefix("p=") {
+                link.part_hashes = part_hashes
+                    .split(':')
+                    .map(str::parse)
+                    .collect::<Result<_>>()?;
+            } else if let Some(sources) = field.strip_prefix("sources,") {
+                for source in sources.split(',').filter(|s| !s.is_empty()) {
+                    link.sources.push(
+                        source
+                            .parse()
+                            .with_context(|| format!("'{source}' is not a valid source"))?,
+                    );
+                }
+            }
+            // Anything else, such as `/` or the `s=` web sources, is of no
+            // use to us.
+        }
+
+        if !link.part_hashes.is_empty() {
+            let expected = (link.size / PART_SIZE + 1) as usize;
+            if link.part_hashes.len() != expected
+                || Ed2kHashSet::from_part_hashes(link.part_hashes.clone()).root != link.hash
+            {
+                bail!("The part hashes do not match the file hash");
+            }
+        }
+
+        Ok(link)
+    }
+}
+
+impl Display for Ed2kFileLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ed2k://|file|{}|{}|{}|",
+            url_encode(&self.name, b"|/"),
+            self.size,
+            self.hash
+        )?;
+        if let Some(aich) = self.aich {
+            write!(f, "h={aich}|")?;
+        }
+        if !self.part_hashes.is_empty() {
+            let part_hashes: Vec<_> = self.part_hashes.iter().map(|h| h.to_string()).collect();
+            write!(f, "p={}|", part_hashes.join(":"))?;
+        }
+        write!(f, "/")?;
+        if !self.sources.is_empty() {
+            write!(f, "|sources")?;
+            for source in &self.sources {
+                write!(f, ",{source}")?;
+            }
+            write!(f, "|/")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Ed2kLink {
+    type Err = anyhow::Error;
+
+    /// Magnet links for ed2k files are accepted too, and come back as
+    /// file links.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.starts_with(Ed2kFileLink::MAGNET_SCHEME) {
+            return Ok(Self::File(Ed2kFileLink::from_magnet(s)?));
+        }
+        Self::parse(s).with_context(|| format!("'{s}' is not a valid ed2k link"))
+    }
+}
+
+impl Ed2kLink {
+    const SCHEME: &str = "ed2k://|";
+
+    fn parse(s: &str) -> Result<Self> {
+        let Some(rest) = s
+            .get(..Self::SCHEME.len())
+            .filter(|scheme| scheme.eq_ignore_ascii_case(Self::SCHEME))
+            .map(|_| &s[Self::SCHEME.len()..])
+        else {
+            bail!("It does not start with {}", Self::SCHEME);
+        };
+        let Some(rest) = rest.strip_suffix("|/") else {
+            bail!("It does not end with |/");
+        };
+
+        let fields: Vec<_> = rest.split('|').collect();
+        match fields.as_slice() {
+            [kind, fields @ ..] if kind.eq_ignore_ascii_case("file") => {
+                Ok(Self::File(Ed2kFileLink::parse(fields)?))
+            }
+            [kind, ip, port] if kind.eq_ignore_ascii_case("server") => {
+                let ip: Ipv4Addr = ip
+                    .parse()
+                    .with_context(|| format!("'{ip}' is not an IPv4 address"))?;
+                let port = match port.parse::<u16>() {
+                    Ok(port) if port != 0 => port,
+                    _ => bail!("'{port}' is not a valid port"),
+                };
+                Ok(Self::Server(SocketAddrV4::new(ip, port)))
+            }
+            [kind, url] if kind.eq_ignore_ascii_case("serverlist") => {
+                let lower = url.to_ascii_lowercase();
+                if !lower.starts_with("http://") && !lower.starts_with("https://") {
+                    bail!("'{url}' is not an http URL");
+                }
+                Ok(Self::ServerList(url.to_string()))
+            }
+            _ => bail!("It is not a file, server or serverlist link"),
+        }
+    }
+}
+
+impl Display for Ed2kLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(file) => write!(f, "{file}"),
+            Self::Server(addr) => write!(f, "ed2k://|server|{}|{}|/", addr.ip(), addr.port()),
+            Self::ServerList(url) => write!(f, "ed2k://|serverlist|{url}|/"),
+        }
+    }
+}
+
+/// Decodes `%XX` escapes. eMule escapes names as UTF-8.
+pub(super) fn url_decode(s: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+        let hex = [input.next(), input.next()];
+        let Some(b) = hex
+            .iter()
+            .map(|digit| digit.and_then(|d| (d as char).to_digit(16)))
+            .try_fold(0u8, |acc, digit| Some(acc << 4 | digit? as u8))
+        else {
+            bail!("'{s}' has a bad % escape");
+        };
+        bytes.push(b);
+    }
+
+    String::from_utf8(bytes).with_context(|| format!("'{s}' is not UTF-8"))
+}
+
+/// Escapes the reserved bytes, which would break the link, and anything
+/// else which would not survive being pasted around.
+pub(super) fn url_encode(s: &str, reserved: &[u8]) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'%' => encoded.push_str("%25"),
+            b if reserved.contains(&b) => encoded.push_str(&format!("%{b:02X}")),
+            b if b.is_ascii_graphic() => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EMPTY_HASH: &str = "31D6CFE0D16AE931B73C59D7E0C089C0";
+
+    /// A link with every optional part, whose hash is that of its part
+    /// hashes.
+    fn full_file_link() -> (String, Vec<Ed2kHash>) {
+        let part_hashes: Vec<Ed2kHash> = vec![
+            "A448017AAF21D8525FC10AE87AA6729D".parse().unwrap(),
+            EMPTY_HASH.parse().unwrap(),
+        ];
+        let hash = Ed2kHashSet::from_part_hashes(part_hashes.clone()).root;
+        let text = format!(
+            "ed2k://|file|Some%20File%7Cname%C3%A9.iso|9728001|{hash}|\
+            h=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ|\
+            p=A448017AAF21D8525FC10AE87AA6729D:{EMPTY_HASH}|/\
+            |sources,1.2.3.4:4662,5.6.7.8:4672|/"
+        );
+        (text, part_hashes)
+    }
+
+    #[test]
+    pub fn test_file_links_are_parsed_and_generated() {
+        let (text, part_hashes) = full_file_link();
+
+        let Ed2kLink::File(file) = text.parse().unwrap() else {
+            panic!("Expected a file link");
+        };
+        assert_eq!(file.name, "Some File|nameé.iso");
+        assert_eq!(file.size, 9_728_001);
+        assert_eq!(
+            file.hash,
+            Ed2kHashSet::from_part_hashes(part_hashes.clone()).root
+        );
+        assert_eq!(
+            file.aich,
+            Some("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".parse().unwrap())
+        );
+        assert_eq!(file.part_hashes, part_hashes);
+        assert_eq!(file.to_string(), text);
+    }
+
+    #[test]
+    pub fn test_file_link_sources_are_parsed() {
+        let (text, _) = full_file_link();
+
+        let Ed2kLink::File(file) = text.parse().unwrap() else {
+            panic!("Expected a file link");
+        };
+        assert_eq!(
+            file.sources,
+            [
+                "1.2.3.4:4662".parse().unwrap(),
+                "5.6.7.8:4672".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_server_links_are_parsed_and_generated() {
+        let server: Ed2kLink = "ED2K://|Server|176.123.5.89|4725|/".parse().unwrap();
+        assert_eq!(
+            server,
+            Ed2kLink::Server("176.123.5.89:4725".parse().unwrap())
+        );
+        assert_eq!(server.to_string(), "ed2k://|server|176.123.5.89|4725|/");
+    }
+
+    #[test]
+    pub fn test_serverlist_links_are_parsed_and_generated() {
+        let list = "ed2k://|serverlist|http://upd.emule-security.org/server.met|/";
+        assert_eq!(list.parse::<Ed2kLink>().unwrap().to_string(), list);
+    }
+
+    #[test]
+    pub fn test_bad_links_are_refused() {
+        // Part hashes which do not add up to the file hash.
+        let (text, _) = full_file_link();
+        let bad = text.replacen("p=A4", "p=B4", 1);
+        assert!(bad.parse::<Ed2kLink>().is_err());
+
+        assert!("ed2k://|server|example.com|4661|/"
+            .parse::<Ed2kLink>()
+            .is_err());
+        assert!("ed2k://|file|name|0|31D6CFE0D16AE931B73C59D7E0C089C0|/"
+            .parse::<Ed2kLink>()
+            .is_err());
+        assert!(
+            "ed2k://|file|name|274877906945|31D6CFE0D16AE931B73C59D7E0C089C0|/"
+                .parse::<Ed2kLink>()
+                .is_err()
+        );
+        assert!("http://example.com/".parse::<Ed2kLink>().is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use super::ed2k_link::{url_decode, url_encode};
+use super::Ed2kFileLink;
+use crate::hashing::MAX_FILE_SIZE;
+use anyhow::{bail, Context, Result};
+
+impl Ed2kFileLink {
+    pub const MAGNET_SCHEME: &str = "magnet:?";
+
+    /// Parses a magnet URI for an ed2k file, such as
+    /// `magnet:?xt=urn:ed2k:HASH&xl=SIZE&dn=NAME`. The hash may also be
+    /// given as `urn:ed2khash:`, and the AICH master hash as `urn:aich:`.
+    /// Other parameters, such as BitTorrent hashes, are ignored.
+    pub fn from_magnet(s: &str) -> Result<Self> {
+        Self::parse_magnet(s.trim())
+            .with_context(|| format!("'{s}' is not a valid ed2k magnet link"))
+    }
+
+    fn parse_magnet(s: &str) -> Result<Self> {
+        let Some(query) = s.strip_prefix(Self::MAGNET_SCHEME) else {
+            bail!("It does not start with {}", Self::MAGNET_SCHEME);
+        };
+
+        let (mut name, mut size, mut hash, mut aich) = (None, None, None, None);
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = url_decode(&value.replace('+', " "))?;
+            match key {
+                "dn" => name = Some(value),
+                "xl" => {
+                    size = Some(
+                        value
+                            .parse::<u64>()
+                            .with_context(|| format!("'{value}' is not a valid file size"))?,
+                    )
+                }
+                "xt" => {
+                    let lower = value.to_ascii_lowercase();
+                    if let Some(urn) = lower
+                        .strip_prefix("urn:ed2k:")
+                        .or_else(|| lower.strip_prefix("urn:ed2khash:"))
+                    {
+                        hash = Some(urn.parse()?);
+                    } else if let Some(urn) = lower.strip_prefix("urn:aich:") {
+                        aich = Some(urn.parse()?);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let Some(hash) = hash else {
+            bail!("It has no urn:ed2k hash");
+        };
+        let (name, size) = match (name, size) {
+            (Some(name), Some(size)) if !name.is_empty() && size > 0 => (name, size),
+            _ => bail!("ed2k downloads need both a name (dn) and a size (xl)"),
+        };
+        if size > MAX_FILE_SIZE {
+            bail!("{size} bytes is larger than the largest ed2k file");
+        }
+
+        let mut link = Self::new(name, size, hash);
+        link.aich = aich;
+        Ok(link)
+    }
+
+    /// The magnet URI for the file. Magnet links have nowhere for the part
+    /// hashes or sources, so only the hashes, size and name are included.
+    pub fn magnet(&self) -> String {
+        let mut magnet = format!("{}xt=urn:ed2k:{}", Self::MAGNET_SCHEME, self.hash);
+        if let Some(aich) = self.aich {
+            magnet += &format!("&xt=urn:aich:{aich}");
+        }
+        magnet += &format!("&xl={}&dn={}", self.size, url_encode(&self.name, b"&=+#?"));
+        magnet
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::Ed2kLink;
+
+    const MAGNET: &str = "magnet:?xt=urn:ed2k:31D6CFE0D16AE931B73C59D7E0C089C0\
+        &xt=urn:aich:GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&xl=1234&dn=Fish%20%26+Chips.txt";
+
+    #[test]
+    pub fn test_magnets_are_parsed() {
+        let link = Ed2kFileLink::from_magnet(MAGNET).unwrap();
+        assert_eq!(link.name, "Fish & Chips.txt");
+        assert_eq!(link.size, 1234);
+        assert_eq!(
+            link.hash,
+            "31D6CFE0D16AE931B73C59D7E0C089C0".parse().unwrap()
+        );
+        assert_eq!(
+            link.aich,
+            Some("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".parse().unwrap())
+        );
+    }
+
+    #[test]
+    pub fn test_magnets_are_exported() {
+        let link = Ed2kFileLink::from_magnet(MAGNET).unwrap();
+        // Spaces are always written as %20.
+        assert_eq!(link.magnet(), MAGNET.replace('+', "%20"));
+    }
+
+    #[test]
+    pub fn test_magnets_are_accepted_as_ed2k_links() {
+        let text = "magnet:?dn=a.bin&xl=5&xt=urn:ed2khash:31d6cfe0d16ae931b73c59d7e0c089c0";
+        let Ed2kLink::File(link) = text.parse().unwrap() else {
+            panic!("Expected a file link");
+        };
+        assert_eq!(
+            link.hash,
+            "31D6CFE0D16AE931B73C59D7E0C089C0".parse().unwrap()
+        );
+        assert_eq!(link.aich, None);
+    }
+
+    #[test]
+    pub fn test_magnets_without_an_ed2k_file_are_refused() {
+        assert!(Ed2kFileLink::from_magnet("magnet:?xt=urn:btih:abc&dn=a&xl=5").is_err());
+        assert!(Ed2kFileLink::from_magnet(
+            "magnet:?xt=urn:ed2k:31D6CFE0D16AE931B73C59D7E0C089C0&dn=a"
+        )
+        .is_err());
+        assert!(Ed2kFileLink::from_magnet(
+            "magnet:?xt=urn:ed2k:31D6CFE0D16AE931B73C59D7E0C089C0&dn=a&xl=274877906945"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,27 @@
+//! Types for reading and writing the ed2k wire protocol. This module knows
+//! nothing about connections or managers, it just converts between bytes and
+//! Rust values. See http://wiki.amule.org/wiki/ED2K_Protocol for an overview
+//! (and the aMule/eMule sources for the real details).
+
+mod aich_hash;
+mod ed2k_hash;
+mod ed2k_link;
+mod magnet_link;
+pub mod opcodes;
+mod packet;
+mod tag;
+
+pub use aich_hash::*;
+pub use ed2k_hash::*;
+pub use ed2k_link::*;
+pub use packet::*;
+pub use tag::*;
+
+/// The client version we announce in CT_VERSION. 0x3C is what every
+/// current eMule/aMule sends.
+pub const EDONKEY_VERSION: u32 = 0x3C;
+
+/// Our version in the eMule version tag format: compatible client id in the
+/// top byte, then major (bits 17-23), minor (bits 10-16) and update
+/// (bits 7-9). We are 0.1.0.
+pub const EMULE_VERSION: u32 = (0x55 << 24) | (1 << 10);
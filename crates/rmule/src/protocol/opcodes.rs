@@ -0,0 +1,197 @@
+//! Protocol bytes, opcodes and tag names, named as they are in the
+//! eMule/aMule sources (minus the prefixes where they add nothing) so that
+//! the two can be easily compared.
+
+// Protocol header bytes.
+pub const OP_EDONKEYPROT: u8 = 0xE3;
+pub const OP_PACKEDPROT: u8 = 0xD4;
+pub const OP_EMULEPROT: u8 = 0xC5;
+pub const OP_KADEMLIAHEADER: u8 = 0xE4;
+pub const OP_KADEMLIAPACKEDPROT: u8 = 0xE5;
+// Reserved by eMule so that obfuscated UDP packets never start with them.
+pub const OP_UDPRESERVEDPROT1: u8 = 0xA3;
+pub const OP_UDPRESERVEDPROT2: u8 = 0xB2;
+
+// Client <-> Server TCP opcodes.
+pub const OP_LOGINREQUEST: u8 = 0x01;
+pub const OP_REJECT: u8 = 0x05;
+pub const OP_GETSOURCES: u8 = 0x19;
+pub const OP_CALLBACKREQUEST: u8 = 0x1C;
+pub const OP_GETSOURCES_OBFU: u8 = 0x23;
+pub const OP_SERVERLIST: u8 = 0x32;
+pub const OP_SERVERSTATUS: u8 = 0x34;
+pub const OP_CALLBACKREQUESTED: u8 = 0x35;
+pub const OP_CALLBACK_FAIL: u8 = 0x36;
+pub const OP_SERVERMESSAGE: u8 = 0x38;
+pub const OP_IDCHANGE: u8 = 0x40;
+pub const OP_SERVERIDENT: u8 = 0x41;
+pub const OP_FOUNDSOURCES: u8 = 0x42;
+pub const OP_FOUNDSOURCES_OBFU: u8 = 0x44;
+
+// Client <-> Server UDP opcodes.
+pub const OP_GLOBSEARCHREQ3: u8 = 0x90;
+pub const OP_GLOBSEARCHREQ2: u8 = 0x92;
+pub const OP_GLOBSEARCHREQ: u8 = 0x98;
+pub const OP_GLOBSEARCHRES: u8 = 0x99;
+pub const OP_GLOBGETSOURCES2: u8 = 0x94;
+pub const OP_GLOBFOUNDSOURCES: u8 = 0x9B;
+
+// Client <-> Client TCP opcodes, eDonkey protocol.
+pub const OP_HELLO: u8 = 0x01;
+pub const OP_SENDINGPART: u8 = 0x46;
+pub const OP_REQUESTPARTS: u8 = 0x47;
+pub const OP_FILEREQANSNOFIL: u8 = 0x48;
+pub const OP_HELLOANSWER: u8 = 0x4C;
+pub const OP_SETREQFILEID: u8 = 0x4F;
+pub const OP_FILESTATUS: u8 = 0x50;
+pub const OP_HASHSETREQUEST: u8 = 0x51;
+pub const OP_HASHSETANSWER: u8 = 0x52;
+pub const OP_STARTUPLOADREQ: u8 = 0x54;
+pub const OP_ACCEPTUPLOADREQ: u8 = 0x55;
+pub const OP_CANCELTRANSFER: u8 = 0x56;
+pub const OP_OUTOFPARTREQS: u8 = 0x57;
+pub const OP_REQUESTFILENAME: u8 = 0x58;
+pub const OP_REQFILENAMEANSWER: u8 = 0x59;
+pub const OP_QUEUERANK: u8 = 0x5C;
+
+// Client <-> Client TCP opcodes, eMule protocol.
+pub const OP_COMPRESSEDPART: u8 = 0x40;
+pub const OP_QUEUERANKING: u8 = 0x60;
+pub const OP_COMPRESSEDPART_I64: u8 = 0xA1;
+pub const OP_SENDINGPART_I64: u8 = 0xA2;
+pub const OP_REQUESTPARTS_I64: u8 = 0xA3;
+pub const OP_REQUESTSOURCES2: u8 = 0x83;
+pub const OP_ANSWERSOURCES2: u8 = 0x84;
+pub const OP_CALLBACK: u8 = 0x99;
+pub const OP_AICHREQUEST: u8 = 0x9B;
+pub const OP_AICHANSWER: u8 = 0x9C;
+pub const OP_BUDDYPING: u8 = 0x9F;
+pub const OP_BUDDYPONG: u8 = 0xA0;
+
+// Kademlia 2 UDP opcodes.
+pub const KADEMLIA2_BOOTSTRAP_REQ: u8 = 0x01;
+pub const KADEMLIA2_BOOTSTRAP_RES: u8 = 0x09;
+pub const KADEMLIA2_HELLO_REQ: u8 = 0x11;
+pub const KADEMLIA2_HELLO_RES: u8 = 0x19;
+pub const KADEMLIA2_REQ: u8 = 0x21;
+pub const KADEMLIA2_RES: u8 = 0x29;
+pub const KADEMLIA2_SEARCH_KEY_REQ: u8 = 0x33;
+pub const KADEMLIA2_SEARCH_SOURCE_REQ: u8 = 0x34;
+pub const KADEMLIA2_SEARCH_NOTES_REQ: u8 = 0x35;
+pub const KADEMLIA2_SEARCH_RES: u8 = 0x3B;
+pub const KADEMLIA2_PUBLISH_KEY_REQ: u8 = 0x43;
+pub const KADEMLIA2_PUBLISH_SOURCE_REQ: u8 = 0x44;
+pub const KADEMLIA2_PUBLISH_NOTES_REQ: u8 = 0x45;
+pub const KADEMLIA2_PUBLISH_RES: u8 = 0x4B;
+pub const KADEMLIA2_PING: u8 = 0x60;
+pub const KADEMLIA2_PONG: u8 = 0x61;
+
+// Kademlia UDP opcodes for firewall checks and buddies, which are the same
+// in Kad1 and Kad2.
+pub const KADEMLIA_FINDBUDDY_REQ: u8 = 0x51;
+pub const KADEMLIA_CALLBACK_REQ: u8 = 0x52;
+pub const KADEMLIA_FIREWALLED2_REQ: u8 = 0x53;
+pub const KADEMLIA_FIREWALLED_RES: u8 = 0x58;
+pub const KADEMLIA_FIREWALLED_ACK_RES: u8 = 0x59;
+pub const KADEMLIA_FINDBUDDY_RES: u8 = 0x5A;
+
+// What a KADEMLIA2_REQ is for. The value is also the number of contacts
+// wanted in the answer.
+pub const KADEMLIA_FIND_VALUE: u8 = 0x02;
+pub const KADEMLIA_STORE: u8 = 0x04;
+pub const KADEMLIA_FIND_NODE: u8 = 0x0B;
+
+// Tag types.
+pub const TAGTYPE_HASH16: u8 = 0x01;
+pub const TAGTYPE_STRING: u8 = 0x02;
+pub const TAGTYPE_UINT32: u8 = 0x03;
+pub const TAGTYPE_FLOAT32: u8 = 0x04;
+pub const TAGTYPE_BOOL: u8 = 0x05;
+pub const TAGTYPE_BOOLARRAY: u8 = 0x06;
+pub const TAGTYPE_BLOB: u8 = 0x07;
+pub const TAGTYPE_UINT16: u8 = 0x08;
+pub const TAGTYPE_UINT8: u8 = 0x09;
+pub const TAGTYPE_BSOB: u8 = 0x0A;
+pub const TAGTYPE_UINT64: u8 = 0x0B;
+/// Strings of length 1..=16 can be sent with their length encoded in
+/// the tag type: TAGTYPE_STR1 = 0x11 through TAGTYPE_STR16 = 0x20.
+pub const TAGTYPE_STR1: u8 = 0x11;
+pub const TAGTYPE_STR16: u8 = 0x20;
+
+// Client tag names.
+pub const CT_NAME: u8 = 0x01;
+pub const CT_PORT: u8 = 0x0F;
+pub const CT_VERSION: u8 = 0x11;
+pub const CT_SERVER_FLAGS: u8 = 0x20;
+pub const CT_EMULE_UDPPORTS: u8 = 0xF9;
+pub const CT_EMULE_MISCOPTIONS1: u8 = 0xFA;
+pub const CT_EMULE_VERSION: u8 = 0xFB;
+pub const CT_EMULE_MISCOPTIONS2: u8 = 0xFE;
+
+// Capabilities we announce to servers in CT_SERVER_FLAGS.
+pub const SRVCAP_ZLIB: u32 = 0x0001;
+pub const SRVCAP_NEWTAGS: u32 = 0x0008;
+pub const SRVCAP_UNICODE: u32 = 0x0010;
+pub const SRVCAP_LARGEFILES: u32 = 0x0100;
+pub const SRVCAP_SUPPORTCRYPT: u32 = 0x0200;
+pub const SRVCAP_REQUESTCRYPT: u32 = 0x0400;
+pub const SRVCAP_REQUIRECRYPT: u32 = 0x0800;
+
+// Capabilities servers announce to us in OP_IDCHANGE.
+pub const SRV_TCPFLG_COMPRESSION: u32 = 0x0001;
+pub const SRV_TCPFLG_NEWTAGS: u32 = 0x0008;
+pub const SRV_TCPFLG_UNICODE: u32 = 0x0010;
+pub const SRV_TCPFLG_RELATEDSEARCH: u32 = 0x0040;
+pub const SRV_TCPFLG_TYPETAGINTEGER: u32 = 0x0080;
+pub const SRV_TCPFLG_LARGEFILES: u32 = 0x0100;
+pub const SRV_TCPFLG_TCPOBFUSCATION: u32 = 0x0400;
+
+// Server tag names, as found in server.met files.
+pub const ST_SERVERNAME: u8 = 0x01;
+pub const ST_DESCRIPTION: u8 = 0x0B;
+pub const ST_PING: u8 = 0x0C;
+pub const ST_FAIL: u8 = 0x0D;
+pub const ST_PREFERENCE: u8 = 0x0E;
+pub const ST_DYNIP: u8 = 0x85;
+pub const ST_MAXUSERS: u8 = 0x87;
+pub const ST_SOFTFILES: u8 = 0x88;
+pub const ST_HARDFILES: u8 = 0x89;
+pub const ST_LASTPING: u8 = 0x90;
+pub const ST_VERSION: u8 = 0x91;
+pub const ST_UDPFLAGS: u8 = 0x92;
+pub const ST_AUXPORTSLIST: u8 = 0x93;
+pub const ST_LOWIDUSERS: u8 = 0x94;
+pub const ST_UDPKEY: u8 = 0x95;
+pub const ST_UDPKEYIP: u8 = 0x96;
+pub const ST_TCPPORTOBFUSCATION: u8 = 0x97;
+pub const ST_UDPPORTOBFUSCATION: u8 = 0x98;
+
+// File tag names.
+pub const FT_FILENAME: u8 = 0x01;
+pub const FT_FILESIZE: u8 = 0x02;
+pub const FT_FILETYPE: u8 = 0x03;
+pub const FT_FILEFORMAT: u8 = 0x04;
+pub const FT_SOURCES: u8 = 0x15;
+pub const FT_COMPLETE_SOURCES: u8 = 0x30;
+pub const FT_FILESIZE_HI: u8 = 0x3A;
+
+// Kad tag names. Kad also uses the file tag names above.
+pub const TAG_FILERATING: u8 = 0xF7;
+pub const TAG_BUDDYHASH: u8 = 0xF8;
+pub const TAG_SERVERPORT: u8 = 0xFA;
+pub const TAG_SERVERIP: u8 = 0xFB;
+pub const TAG_DESCRIPTION: u8 = 0x0B;
+pub const TAG_SOURCEUPORT: u8 = 0xFC;
+pub const TAG_SOURCEPORT: u8 = 0xFD;
+pub const TAG_SOURCEIP: u8 = 0xFE;
+pub const TAG_SOURCETYPE: u8 = 0xFF;
+
+// Search tree encoding. See CSearchExpr in eMule.
+pub const SEARCH_TYPE_BOOL: u8 = 0x00;
+pub const SEARCH_TYPE_STRING: u8 = 0x01;
+pub const SEARCH_TYPE_STR_TAG: u8 = 0x02;
+pub const SEARCH_TYPE_UINT32: u8 = 0x03;
+pub const SEARCH_TYPE_UINT64: u8 = 0x08;
+pub const SEARCH_BOOL_AND: u8 = 0x00;
+pub const SEARCH_BOOL_OR: u8 = 0x01;
+pub const SEARCH_BOOL_ANDNOT: u8 = 0x02;
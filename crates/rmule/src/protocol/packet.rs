@@ -0,0 +1,136 @@
+use super::opcodes::*;
+use anyhow::{bail, Context, Result};
+use flate2::read::ZlibDecoder;
+use std::io::Read;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// A single ed2k protocol message: a protocol byte (which says which
+/// "dialect" the opcode belongs to), an opcode and an opaque payload.
+/// Parsing the payload is the job of whoever understands the opcode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub protocol: u8,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    /// Packets larger than this are refused, whatever the transport.
+    pub const MAX_PAYLOAD_SIZE: usize = 2 * 1024 * 1024;
+
+    pub fn new(protocol: u8, opcode: u8, payload: Vec<u8>) -> Self {
+        Self {
+            protocol,
+            opcode,
+            payload,
+        }
+    }
+
+    /// Constructs a packet in the original eDonkey protocol.
+    pub fn edonkey(opcode: u8, payload: Vec<u8>) -> Self {
+        Self::new(OP_EDONKEYPROT, opcode, payload)
+    }
+
+    /// Constructs a packet in the eMule extended protocol.
+    pub fn emule(opcode: u8, payload: Vec<u8>) -> Self {
+        Self::new(OP_EMULEPROT, opcode, payload)
+    }
+
+    /// Constructs a Kademlia packet. These only ever go over UDP.
+    pub fn kad(opcode: u8, payload: Vec<u8>) -> Self {
+        Self::new(OP_KADEMLIAHEADER, opcode, payload)
+    }
+
+    /// UDP packets have no length field, the datagram is the packet.
+    pub fn to_udp_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(self.payload.len() + 2);
+        v.push(self.protocol);
+        v.push(self.opcode);
+        v.extend_from_slice(&self.payload);
+        v
+    }
+
+    /// Parses a datagram. Packed packets are unpacked, so the returned
+    /// packet will never have a protocol of OP_PACKEDPROT.
+    pub fn from_udp_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < 2 {
+            bail!("UDP packet of {} bytes is too short", data.len());
+        }
+
+        Self::new(data[0], data[1], data[2..].to_vec()).unpack()
+    }
+
+    /// If the packet is compressed, decompresses it. Packed packets are
+    /// always in the eMule (or for Kad packets, Kademlia) protocol once
+    /// unpacked.
+    pub fn unpack(self) -> Result<Self> {
+        let protocol = match self.protocol {
+            OP_PACKEDPROT => OP_EMULEPROT,
+            OP_KADEMLIAPACKEDPROT => OP_KADEMLIAHEADER,
+            _ => return Ok(self),
+        };
+
+        let mut payload = Vec::new();
+        ZlibDecoder::new(&self.payload[..])
+            .take(Self::MAX_PAYLOAD_SIZE as u64 + 1)
+            .read_to_end(&mut payload)
+            .with_context(|| format!("Unpacking packet with opcode {:#04x} failed", self.opcode))?;
+
+        if payload.len() > Self::MAX_PAYLOAD_SIZE {
+            bail!(
+                "Unpacked packet with opcode {:#04x} is too large",
+                self.opcode
+            );
+        }
+
+        Ok(Self::new(protocol, self.opcode, payload))
+    }
+
+    /// Reads a packet from a TCP stream. On TCP every packet is preceded by
+    /// a protocol byte and a u32 length, which covers the opcode and payload.
+    /// Packed packets are unpacked.
+    pub async fn read_from<R>(reader: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut header = [0u8; 6];
+        reader.read_exact(&mut header).await?;
+
+        let protocol = header[0];
+        let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let opcode = header[5];
+
+        if !matches!(protocol, OP_EDONKEYPROT | OP_EMULEPROT | OP_PACKEDPROT) {
+            bail!("Unknown protocol byte {protocol:#04x} in TCP packet");
+        }
+
+        if len == 0 || len - 1 > Self::MAX_PAYLOAD_SIZE {
+            bail!("TCP packet with opcode {opcode:#04x} has an invalid length of {len}");
+        }
+
+        let mut payload = vec![0u8; len - 1];
+        reader.read_exact(&mut payload).await?;
+
+        Self::new(protocol, opcode, payload).unpack()
+    }
+
+    /// Writes a packet, including its TCP header, to a stream.
+    pub async fn write_to<W>(&self, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        writer.write_all(&self.to_tcp_bytes()).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// Converts the packet to the bytes that are sent over TCP.
+    pub fn to_tcp_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(self.payload.len() + 6);
+        v.push(self.protocol);
+        v.extend_from_slice(&(self.payload.len() as u32 + 1).to_le_bytes());
+        v.push(self.opcode);
+        v.extend_from_slice(&self.payload);
+        v
+    }
+}
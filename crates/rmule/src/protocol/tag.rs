@@ -0,0 +1,276 @@
+use super::opcodes::*;
+use super::Ed2kHash;
+use anyhow::{bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read};
+
+/// Tags are the ed2k network's way of sending optional, named values.
+/// They turn up everywhere: in server.met files, search results, hello
+/// packets and so on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub name: TagName,
+    pub value: TagValue,
+}
+
+/// A tag is named either by a single byte (the common case, see the FT_xxx
+/// constants) or by a string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagName {
+    Id(u8),
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagValue {
+    Hash(Ed2kHash),
+    String(String),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    Float(f32),
+    Bool(bool),
+    Blob(Vec<u8>),
+}
+
+impl Tag {
+    pub fn new(id: u8, value: TagValue) -> Self {
+        Self {
+            name: TagName::Id(id),
+            value,
+        }
+    }
+
+    /// Returns true if this tag is named by the specified id.
+    pub fn is(&self, id: u8) -> bool {
+        self.name == TagName::Id(id)
+    }
+
+    /// Returns the value of any of the integer types, widened to a u64.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value {
+            TagValue::U8(n) => Some(n as u64),
+            TagValue::U16(n) => Some(n as u64),
+            TagValue::U32(n) => Some(n as u64),
+            TagValue::U64(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        self.as_u64().and_then(|n| u32::try_from(n).ok())
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match &self.value {
+            TagValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Reads a single tag. Both the old format (a u16 length prefixed name)
+    /// and the new compact format (high bit of the type set, 1 byte name)
+    /// are understood.
+    pub fn read(input: &mut Cursor<&[u8]>) -> Result<Self> {
+        let mut tag_type = input.read_u8().context("Could not read tag type")?;
+
+        let name = if tag_type & 0x80 != 0 {
+            tag_type &= 0x7F;
+            TagName::Id(input.read_u8().context("Could not read tag id")?)
+        } else {
+            let name_len = input.read_u16::<LittleEndian>()? as usize;
+            if name_len == 1 {
+                TagName::Id(input.read_u8().context("Could not read tag id")?)
+            } else {
+                TagName::Name(read_string(input, name_len)?)
+            }
+        };
+
+        let value = match tag_type {
+            TAGTYPE_HASH16 => {
+                let mut buf = [0u8; 16];
+                input.read_exact(&mut buf)?;
+                TagValue::Hash(buf.into())
+            }
+            TAGTYPE_STRING => {
+                let len = input.read_u16::<LittleEndian>()? as usize;
+                TagValue::String(read_string(input, len)?)
+            }
+            TAGTYPE_STR1..=TAGTYPE_STR16 => {
+                let len = (tag_type - TAGTYPE_STR1 + 1) as usize;
+                TagValue::String(read_string(input, len)?)
+            }
+            TAGTYPE_UINT8 => TagValue::U8(input.read_u8()?),
+            TAGTYPE_UINT16 => TagValue::U16(input.read_u16::<LittleEndian>()?),
+            TAGTYPE_UINT32 => TagValue::U32(input.read_u32::<LittleEndian>()?),
+            TAGTYPE_UINT64 => TagValue::U64(input.read_u64::<LittleEndian>()?),
+            TAGTYPE_FLOAT32 => TagValue::Float(input.read_f32::<LittleEndian>()?),
+            TAGTYPE_BOOL => TagValue::Bool(input.read_u8()? != 0),
+            TAGTYPE_BOOLARRAY => {
+                // Nobody actually uses these, but we have to skip over them.
+                let num_bits = input.read_u16::<LittleEndian>()? as usize;
+                TagValue::Blob(read_bytes(input, num_bits / 8 + 1)?)
+            }
+            TAGTYPE_BLOB => {
+                let len = input.read_u32::<LittleEndian>()? as usize;
+                TagValue::Blob(read_bytes(input, len)?)
+            }
+            TAGTYPE_BSOB => {
+                let len = input.read_u8()? as usize;
+                TagValue::Blob(read_bytes(input, len)?)
+            }
+            _ => bail!("Invalid tag type of {tag_type:#04x}"),
+        };
+
+        Ok(Self { name, value })
+    }
+
+    /// Writes the tag in the old format, which every server and client
+    /// understands.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let (tag_type, _) = self.type_and_len();
+        out.push(tag_type);
+
+        match &self.name {
+            TagName::Id(id) => {
+                out.write_u16::<LittleEndian>(1).unwrap();
+                out.push(*id);
+            }
+            TagName::Name(name) => {
+                out.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+                out.extend_from_slice(name.as_bytes());
+            }
+        }
+
+        self.write_value(out, false);
+    }
+
+    /// Writes the tag in the new compact format. This is only possible for
+    /// tags with an id (rather than string) name, other tags are written
+    /// in the old format.
+    pub fn write_compact(&self, out: &mut Vec<u8>) {
+        let id = match self.name {
+            TagName::Id(id) => id,
+            TagName::Name(_) => return self.write(out),
+        };
+
+        let (tag_type, short_string) = self.type_and_len();
+        let tag_type = match short_string {
+            Some(len) => TAGTYPE_STR1 + len - 1,
+            None => tag_type,
+        };
+
+        out.push(tag_type | 0x80);
+        out.push(id);
+        self.write_value(out, short_string.is_some());
+    }
+
+    fn type_and_len(&self) -> (u8, Option<u8>) {
+        match &self.value {
+            TagValue::Hash(_) => (TAGTYPE_HASH16, None),
+            TagValue::String(s) if !s.is_empty() && s.len() <= 16 => {
+                (TAGTYPE_STRING, Some(s.len() as u8))
+            }
+            TagValue::String(_) => (TAGTYPE_STRING, None),
+            TagValue::U8(_) => (TAGTYPE_UINT8, None),
+            TagValue::U16(_) => (TAGTYPE_UINT16, None),
+            TagValue::U32(_) => (TAGTYPE_UINT32, None),
+            TagValue::U64(_) => (TAGTYPE_UINT64, None),
+            TagValue::Float(_) => (TAGTYPE_FLOAT32, None),
+            TagValue::Bool(_) => (TAGTYPE_BOOL, None),
+            TagValue::Blob(_) => (TAGTYPE_BLOB, None),
+        }
+    }
+
+    fn write_value(&self, out: &mut Vec<u8>, implicit_string_length: bool) {
+        match &self.value {
+            TagValue::Hash(h) => out.extend_from_slice(h.as_bytes()),
+            TagValue::String(s) => {
+                if !implicit_string_length {
+                    out.write_u16::<LittleEndian>(s.len() as u16).unwrap();
+                }
+                out.extend_from_slice(s.as_bytes());
+            }
+            TagValue::U8(n) => out.push(*n),
+            TagValue::U16(n) => out.write_u16::<LittleEndian>(*n).unwrap(),
+            TagValue::U32(n) => out.write_u32::<LittleEndian>(*n).unwrap(),
+            TagValue::U64(n) => out.write_u64::<LittleEndian>(*n).unwrap(),
+            TagValue::Float(n) => out.write_f32::<LittleEndian>(*n).unwrap(),
+            TagValue::Bool(b) => out.push(*b as u8),
+            TagValue::Blob(blob) => {
+                out.write_u32::<LittleEndian>(blob.len() as u32).unwrap();
+                out.extend_from_slice(blob);
+            }
+        }
+    }
+}
+
+/// Reads a tag list, which is a u32 count followed by that many tags.
+pub fn read_tag_list(input: &mut Cursor<&[u8]>) -> Result<Vec<Tag>> {
+    let count = input.read_u32::<LittleEndian>()?;
+    read_tags(input, count as usize)
+}
+
+/// Reads `count` tags.
+pub fn read_tags(input: &mut Cursor<&[u8]>, count: usize) -> Result<Vec<Tag>> {
+    // Do not trust the count for the allocation, it comes off the wire.
+    let mut tags = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        tags.push(Tag::read(input)?);
+    }
+    Ok(tags)
+}
+
+/// Writes a tag list, which is a u32 count followed by the tags.
+pub fn write_tag_list(out: &mut Vec<u8>, tags: &[Tag], compact: bool) {
+    out.write_u32::<LittleEndian>(tags.len() as u32).unwrap();
+    for tag in tags {
+        if compact {
+            tag.write_compact(out);
+        } else {
+            tag.write(out);
+        }
+    }
+}
+
+/// Reads a string of known length. Strings on the ed2k network are
+/// supposed to be UTF-8 these days, but there is plenty of other stuff
+/// out there so we do not insist on it.
+pub fn read_string(input: &mut Cursor<&[u8]>, length: usize) -> Result<String> {
+    let buf = read_bytes(input, length)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Reads a string prefixed by its u16 length.
+pub fn read_u16_string(input: &mut Cursor<&[u8]>) -> Result<String> {
+    let len = input.read_u16::<LittleEndian>()? as usize;
+    read_string(input, len)
+}
+
+/// Writes a string prefixed by its u16 length.
+pub fn write_u16_string(out: &mut Vec<u8>, s: &str) {
+    out.write_u16::<LittleEndian>(s.len() as u16).unwrap();
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Reads exactly `length` bytes, checking first that they are there so
+/// that a bad length from the network cannot make us allocate gigabytes.
+pub fn read_bytes(input: &mut Cursor<&[u8]>, length: usize) -> Result<Vec<u8>> {
+    let remaining =
+        input.get_ref().len() as u64 - input.position().min(input.get_ref().len() as u64);
+    if length as u64 > remaining {
+        bail!("Attempt to read {length} bytes but only {remaining} remain");
+    }
+
+    let mut buf = vec![0u8; length];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reads a 16 byte hash.
+pub fn read_hash(input: &mut Cursor<&[u8]>) -> Result<Ed2kHash> {
+    let mut buf = [0u8; 16];
+    input.read_exact(&mut buf).context("Could not read hash")?;
+    Ok(buf.into())
+}
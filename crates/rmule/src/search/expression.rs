@@ -0,0 +1,190 @@
+use crate::protocol::opcodes::*;
+use crate::protocol::write_u16_string;
+use anyhow::{bail, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::fmt::Display;
+
+/// A structured search. This is what every frontend builds (directly or
+/// via the query parser) and what the search subsystem encodes into the
+/// various network formats.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchExpression {
+    /// Both sides must match.
+    And(Box<SearchExpression>, Box<SearchExpression>),
+    /// Either side may match.
+    Or(Box<SearchExpression>, Box<SearchExpression>),
+    /// The left side must match and the right side must not. The ed2k
+    /// network has no unary NOT, so this is the only form of negation.
+    AndNot(Box<SearchExpression>, Box<SearchExpression>),
+    /// A word (or phrase) which must appear in the file name.
+    Keyword(String),
+    /// Restricts the search to a type of file.
+    FileType(FileType),
+    /// Restricts the search to files with the given extension (no dot).
+    Extension(String),
+    /// Compares a numeric attribute of the file against a value.
+    Numeric {
+        field: NumericField,
+        comparison: Comparison,
+        value: u64,
+    },
+}
+
+/// The types of file that ed2k servers know about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Audio,
+    Video,
+    Image,
+    Program,
+    Document,
+    Archive,
+    CdImage,
+    Collection,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumericField {
+    Size,
+    Sources,
+    CompleteSources,
+}
+
+/// The numeric comparisons. The discriminants are the values used on the
+/// wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal = 0,
+    Greater = 1,
+    Less = 2,
+    GreaterOrEqual = 3,
+    LessOrEqual = 4,
+    NotEqual = 5,
+}
+
+impl FileType {
+    /// The string the ed2k servers use for this file type.
+    pub fn ed2k_name(&self) -> &'static str {
+        match self {
+            FileType::Audio => "Audio",
+            FileType::Video => "Video",
+            FileType::Image => "Image",
+            FileType::Program => "Pro",
+            FileType::Document => "Doc",
+            FileType::Archive => "Arc",
+            FileType::CdImage => "Iso",
+            FileType::Collection => "EmuleCollection",
+        }
+    }
+}
+
+impl Display for FileType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            FileType::Audio => "audio",
+            FileType::Video => "video",
+            FileType::Image => "image",
+            FileType::Program => "program",
+            FileType::Document => "document",
+            FileType::Archive => "archive",
+            FileType::CdImage => "cdimage",
+            FileType::Collection => "collection",
+        };
+
+        write!(f, "{s}")
+    }
+}
+
+impl NumericField {
+    fn tag_id(&self) -> u8 {
+        match self {
+            NumericField::Size => FT_FILESIZE,
+            NumericField::Sources => FT_SOURCES,
+            NumericField::CompleteSources => FT_COMPLETE_SOURCES,
+        }
+    }
+}
+
+impl SearchExpression {
+    pub fn and(lhs: SearchExpression, rhs: SearchExpression) -> Self {
+        Self::And(Box::new(lhs), Box::new(rhs))
+    }
+
+    pub fn or(lhs: SearchExpression, rhs: SearchExpression) -> Self {
+        Self::Or(Box::new(lhs), Box::new(rhs))
+    }
+
+    pub fn and_not(lhs: SearchExpression, rhs: SearchExpression) -> Self {
+        Self::AndNot(Box::new(lhs), Box::new(rhs))
+    }
+
+    /// Encodes the expression as an ed2k server search tree. The tree is
+    /// written in prefix order, which is how the servers expect it.
+    /// `large_files` says whether the recipient understands 64-bit
+    /// numeric values.
+    pub fn write_ed2k(&self, out: &mut Vec<u8>, large_files: bool) -> Result<()> {
+        match self {
+            SearchExpression::And(lhs, rhs) => {
+                Self::write_bool(out, SEARCH_BOOL_AND, lhs, rhs, large_files)?
+            }
+            SearchExpression::Or(lhs, rhs) => {
+                Self::write_bool(out, SEARCH_BOOL_OR, lhs, rhs, large_files)?
+            }
+            SearchExpression::AndNot(lhs, rhs) => {
+                Self::write_bool(out, SEARCH_BOOL_ANDNOT, lhs, rhs, large_files)?
+            }
+            SearchExpression::Keyword(word) => {
+                out.push(SEARCH_TYPE_STRING);
+                write_u16_string(out, word);
+            }
+            SearchExpression::FileType(ft) => {
+                out.push(SEARCH_TYPE_STR_TAG);
+                write_u16_string(out, ft.ed2k_name());
+                Self::write_tag_name(out, FT_FILETYPE);
+            }
+            SearchExpression::Extension(ext) => {
+                out.push(SEARCH_TYPE_STR_TAG);
+                write_u16_string(out, ext);
+                Self::write_tag_name(out, FT_FILEFORMAT);
+            }
+            SearchExpression::Numeric {
+                field,
+                comparison,
+                value,
+            } => {
+                if let Ok(value) = u32::try_from(*value) {
+                    out.push(SEARCH_TYPE_UINT32);
+                    out.write_u32::<LittleEndian>(value).unwrap();
+                } else if large_files {
+                    out.push(SEARCH_TYPE_UINT64);
+                    out.write_u64::<LittleEndian>(*value).unwrap();
+                } else {
+                    bail!("The value {value} is too large for a server without large file support");
+                }
+
+                out.push(*comparison as u8);
+                Self::write_tag_name(out, field.tag_id());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_bool(
+        out: &mut Vec<u8>,
+        op: u8,
+        lhs: &SearchExpression,
+        rhs: &SearchExpression,
+        large_files: bool,
+    ) -> Result<()> {
+        out.push(SEARCH_TYPE_BOOL);
+        out.push(op);
+        lhs.write_ed2k(out, large_files)?;
+        rhs.write_ed2k(out, large_files)
+    }
+
+    fn write_tag_name(out: &mut Vec<u8>, id: u8) {
+        out.write_u16::<LittleEndian>(1).unwrap();
+        out.push(id);
+    }
+}
@@ -0,0 +1,327 @@
+use super::{
+    SearchEventSender, SearchEvents, SearchExpression, SearchId, SearchResult, SearchResultList,
+};
+use crate::configuration::{ServerList, ServerUdpFlags};
+use crate::listener::ServerUdpSocket;
+use crate::protocol::opcodes::*;
+use crate::protocol::{Packet, Tag, TagValue};
+use crate::server::{ServerUdpTarget, UdpObfuscation};
+use anyhow::Result;
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::Cursor;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
+use tokio::time::{self, Instant};
+use tracing::{info, warn};
+
+/// Tag name and value used in OP_GLOBSEARCHREQ3 to tell the server that we
+/// understand new tags and large files in its answer.
+const CT_SERVER_UDPSEARCH_FLAGS: u8 = 0x0E;
+const SRVCAP_UDP_NEWTAGS_LARGEFILES: u32 = 0x01;
+
+/// Tuning for global searches.
+#[derive(Debug, Clone)]
+pub struct GlobalSearchOptions {
+    /// The time between sending the request to successive servers. This
+    /// is what stops us flooding the network (and our router) with packets.
+    pub request_interval: Duration,
+    /// How long to wait for stragglers after the last request is sent.
+    pub response_timeout: Duration,
+    /// Stop querying further servers once this many files have been found.
+    pub max_results: usize,
+}
+
+impl Default for GlobalSearchOptions {
+    fn default() -> Self {
+        Self {
+            request_interval: Duration::from_millis(750),
+            response_timeout: Duration::from_secs(10),
+            max_results: 1000,
+        }
+    }
+}
+
+/// A search which is sent, via UDP, to every active server in the server
+/// list in turn, merging the results as they arrive.
+pub struct GlobalSearch {
+    search_id: SearchId,
+    expression: SearchExpression,
+    targets: Vec<ServerUdpTarget>,
+    options: GlobalSearchOptions,
+    events_sender: SearchEventSender,
+}
+
+impl GlobalSearch {
+    pub fn new(
+        search_id: SearchId,
+        expression: SearchExpression,
+        servers: &ServerList,
+        obfuscation: &UdpObfuscation,
+        options: GlobalSearchOptions,
+        events_sender: SearchEventSender,
+    ) -> Self {
+        let targets = servers
+            .iter()
+            .filter(|s| s.is_active())
+            .filter_map(|s| ServerUdpTarget::new(s, obfuscation))
+            .collect();
+
+        Self {
+            search_id,
+            expression,
+            targets,
+            options,
+            events_sender,
+        }
+    }
+
+    /// Runs the search to completion, or until `cancel` fires (or its
+    /// sender is dropped). Results are emitted as events as they arrive,
+    /// the final merged list is also returned.
+    pub async fn run(
+        self,
+        socket: ServerUdpSocket,
+        mut cancel: oneshot::Receiver<()>,
+    ) -> Result<SearchResultList> {
+        let mut datagrams = socket.subscribe();
+        let mut results = SearchResultList::default();
+        let mut cancelled = false;
+
+        info!(
+            "Starting global search {} over {} servers",
+            self.search_id,
+            self.targets.len()
+        );
+
+        let mut ticker = time::interval(self.options.request_interval);
+        let mut next_target = 0;
+        let mut deadline = Instant::now();
+
+        loop {
+            let sending =
+                next_target < self.targets.len() && results.len() < self.options.max_results;
+            if !sending && Instant::now() >= deadline {
+                break;
+            }
+
+            tokio::select! {
+                _ = &mut cancel => {
+                    cancelled = true;
+                    break;
+                }
+                _ = ticker.tick(), if sending => {
+                    let target = self.targets[next_target];
+                    next_target += 1;
+                    self.send_request(&socket, &target).await;
+                    deadline = Instant::now() + self.options.response_timeout;
+
+                    self.events_sender.send(SearchEvents::GlobalSearchProgress {
+                        search_id: self.search_id,
+                        servers_queried: next_target,
+                        server_count: self.targets.len(),
+                    })?;
+                }
+                _ = time::sleep_until(deadline), if !sending => {
+                    break;
+                }
+                recv = datagrams.recv() => {
+                    let (from, data) = match recv {
+                        Ok(datagram) => datagram,
+                        Err(RecvError::Lagged(n)) => {
+                            warn!("Global search {}: missed {} answers", self.search_id, n);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+
+                    let queried = &self.targets[..next_target];
+                    let Some(target) = queried.iter().find(|t| t.matches(from)) else {
+                        continue;
+                    };
+                    let Some(data) = target.decode(&data) else {
+                        warn!("Global search {}: cannot deobfuscate answer from {}", self.search_id, from);
+                        continue;
+                    };
+
+                    match Self::parse_response(&data) {
+                        Ok(new_results) if !new_results.is_empty() => {
+                            let merged: Vec<_> = new_results
+                                .into_iter()
+                                .map(|r| results.merge(r).clone())
+                                .collect();
+
+                            self.events_sender.send(SearchEvents::ResultsUpdated {
+                                search_id: self.search_id,
+                                results: merged,
+                            })?;
+                        }
+                        Ok(_) => {}
+                        Err(e) => warn!("Global search {}: bad answer from {}: {}", self.search_id, from, e),
+                    }
+                }
+            }
+        }
+
+        info!(
+            "Global search {} {} with {} results from {} servers",
+            self.search_id,
+            if cancelled { "cancelled" } else { "finished" },
+            results.len(),
+            next_target
+        );
+
+        self.events_sender.send(SearchEvents::Finished {
+            search_id: self.search_id,
+            result_count: results.len(),
+            cancelled,
+        })?;
+
+        Ok(results)
+    }
+
+    async fn send_request(&self, socket: &ServerUdpSocket, target: &ServerUdpTarget) {
+        let packet = match Self::make_request(&self.expression, target.flags) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!(
+                    "Global search {}: cannot search {}: {}",
+                    self.search_id, target.server, e
+                );
+                return;
+            }
+        };
+
+        if let Err(e) = socket
+            .send_to(&target.encode(&packet), target.send_to)
+            .await
+        {
+            warn!(
+                "Global search {}: send to {} failed: {}",
+                self.search_id, target.server, e
+            );
+        }
+    }
+
+    /// Picks the request format based on what the server says it supports.
+    fn make_request(expression: &SearchExpression, flags: ServerUdpFlags) -> Result<Packet> {
+        let mut payload = Vec::new();
+
+        let opcode = if flags.contains(ServerUdpFlags::GET_FILES) {
+            if flags.contains(ServerUdpFlags::LARGE_FILES) {
+                payload.write_u32::<LittleEndian>(1).unwrap();
+                let tag = Tag::new(
+                    CT_SERVER_UDPSEARCH_FLAGS,
+                    TagValue::U32(SRVCAP_UDP_NEWTAGS_LARGEFILES),
+                );
+                if flags.contains(ServerUdpFlags::NEW_TAGS) {
+                    tag.write_compact(&mut payload);
+                } else {
+                    tag.write(&mut payload);
+                }
+                expression.write_ed2k(&mut payload, true)?;
+                OP_GLOBSEARCHREQ3
+            } else {
+                expression.write_ed2k(&mut payload, false)?;
+                OP_GLOBSEARCHREQ2
+            }
+        } else {
+            expression.write_ed2k(&mut payload, false)?;
+            OP_GLOBSEARCHREQ
+        };
+
+        Ok(Packet::edonkey(opcode, payload))
+    }
+
+    /// An OP_GLOBSEARCHRES datagram can contain several results, each after
+    /// the first being preceded by another protocol and opcode byte.
+    fn parse_response(data: &[u8]) -> Result<Vec<SearchResult>> {
+        let packet = Packet::from_udp_bytes(data)?;
+        if packet.protocol != OP_EDONKEYPROT || packet.opcode != OP_GLOBSEARCHRES {
+            return Ok(Vec::new());
+        }
+
+        let mut input = Cursor::new(&packet.payload[..]);
+        let mut results = Vec::new();
+
+        loop {
+            results.push(SearchResult::read(&mut input)?);
+
+            let pos = input.position() as usize;
+            let rest = &packet.payload[pos..];
+            if rest.len() >= 2 && rest[0] == OP_EDONKEYPROT && rest[1] == OP_GLOBSEARCHRES {
+                input.set_position(pos as u64 + 2);
+            } else {
+                break;
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::write_tag_list;
+    use crate::search::{Comparison, NumericField};
+
+    fn make_result(hash_byte: u8, name: &str, sources: u32) -> Vec<u8> {
+        let mut out = vec![hash_byte; 16];
+        out.write_u32::<LittleEndian>(0).unwrap();
+        out.write_u16::<LittleEndian>(0).unwrap();
+        let tags = [
+            Tag::new(FT_FILENAME, TagValue::String(name.to_owned())),
+            Tag::new(FT_FILESIZE, TagValue::U32(1000)),
+            Tag::new(FT_SOURCES, TagValue::U32(sources)),
+        ];
+        write_tag_list(&mut out, &tags, true);
+        out
+    }
+
+    #[test]
+    pub fn test_request_opcode_depends_on_server_flags() {
+        let expr = SearchExpression::Keyword("ubuntu".to_owned());
+
+        let p = GlobalSearch::make_request(&expr, ServerUdpFlags::empty()).unwrap();
+        assert_eq!(p.opcode, OP_GLOBSEARCHREQ);
+        assert_eq!(p.payload, [0x01, 6, 0, b'u', b'b', b'u', b'n', b't', b'u']);
+
+        let p = GlobalSearch::make_request(&expr, ServerUdpFlags::GET_FILES).unwrap();
+        assert_eq!(p.opcode, OP_GLOBSEARCHREQ2);
+
+        let flags = ServerUdpFlags::GET_FILES | ServerUdpFlags::LARGE_FILES;
+        let p = GlobalSearch::make_request(&expr, flags).unwrap();
+        assert_eq!(p.opcode, OP_GLOBSEARCHREQ3);
+
+        // 64-bit sizes can only go to servers which understand them.
+        let big = SearchExpression::Numeric {
+            field: NumericField::Size,
+            comparison: Comparison::Greater,
+            value: 5_000_000_000,
+        };
+        assert!(GlobalSearch::make_request(&big, ServerUdpFlags::GET_FILES).is_err());
+        assert!(GlobalSearch::make_request(&big, flags).is_ok());
+    }
+
+    #[test]
+    pub fn test_parse_response_with_several_results() {
+        let mut data = vec![OP_EDONKEYPROT, OP_GLOBSEARCHRES];
+        data.extend(make_result(1, "first.iso", 5));
+        data.extend([OP_EDONKEYPROT, OP_GLOBSEARCHRES]);
+        data.extend(make_result(2, "second.iso", 7));
+
+        let results = GlobalSearch::parse_response(&data).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, "first.iso");
+        assert_eq!(results[0].size, 1000);
+        assert_eq!(results[1].name, "second.iso");
+        assert_eq!(results[1].sources, 7);
+
+        let mut list = SearchResultList::default();
+        list.merge(results[0].clone());
+        let merged = list.merge(results[0].clone());
+        assert_eq!(merged.sources, 10);
+        assert_eq!(merged.server_count, 2);
+    }
+}
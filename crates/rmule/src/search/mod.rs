@@ -0,0 +1,11 @@
+mod expression;
+mod global_search;
+mod query_parser;
+mod result;
+mod search_manager;
+
+pub use expression::*;
+pub use global_search::*;
+pub use query_parser::*;
+pub use result::*;
+pub use search_manager::*;
@@ -0,0 +1,612 @@
+//! Converts the search strings typed by users, such as
+//! `ubuntu iso -beta size>1G type:program ext:iso`, into a SearchExpression.
+//! Living here rather than in a frontend means the GUI, CLI and web all
+//! get the same syntax.
+//!
+//! The syntax is:
+//!
+//! - Words are keywords which must all appear in the file name. Use double
+//!   quotes for a phrase: `"the matrix"`.
+//! - `OR` (upper case) between two terms means either may match. AND is
+//!   implied between adjacent terms (an explicit `AND` is allowed) and binds
+//!   more tightly than OR.
+//! - `-term` or `NOT term` excludes files matching the term. The ed2k network
+//!   has no unary NOT, so there must be at least one positive term too.
+//! - Parentheses group terms.
+//! - `field:value` or `field<op>value` constrains a property of the file,
+//!   where op is one of `=`, `<`, `>`, `<=`, `>=`, `!=`. The fields are
+//!   `size` (with an optional unit of B, K, M, G or T, e.g. `1.5G`),
+//!   `sources`, `complete`, `type` (audio, video, image, program, document,
+//!   archive, cdimage or collection) and `ext`.
+
+use super::{Comparison, FileType, NumericField, SearchExpression};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// An error found while parsing a query. The position and length identify
+/// the offending part of the query (in chars, not bytes, so that a frontend
+/// can underline it).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryParseError {
+    pub message: String,
+    pub position: usize,
+    pub length: usize,
+}
+
+impl QueryParseError {
+    /// Formats the error as the query with the offending token underlined,
+    /// which is suitable for fixed-width output such as a terminal.
+    pub fn highlight(&self, query: &str) -> String {
+        format!(
+            "{}\n{}{}\n{}",
+            query,
+            " ".repeat(self.position),
+            "^".repeat(self.length.max(1)),
+            self.message
+        )
+    }
+}
+
+impl Display for QueryParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at position {})", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for QueryParseError {}
+
+/// Parses a user-entered search query.
+pub fn parse_query(query: &str) -> Result<SearchExpression, QueryParseError> {
+    let tokens = tokenize(query)?;
+    let mut parser = Parser {
+        query,
+        tokens,
+        pos: 0,
+    };
+
+    if parser.tokens.is_empty() {
+        return Err(QueryParseError {
+            message: "The search is empty".to_owned(),
+            position: 0,
+            length: 0,
+        });
+    }
+
+    let expr = parser.parse_or()?;
+
+    match parser.peek() {
+        None => Ok(expr),
+        Some(t) if t.kind == TokenKind::RParen => Err(parser.error_at(t, "Unmatched ')'")),
+        Some(t) => Err(parser.error_at(t, "Unexpected token")),
+    }
+}
+
+impl FromStr for SearchExpression {
+    type Err = QueryParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_query(s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Keyword(String),
+    Constraint(SearchExpression),
+    LParen,
+    RParen,
+    Minus,
+    And,
+    Or,
+    Not,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    // Byte offsets into the query.
+    start: usize,
+    end: usize,
+}
+
+fn error(query: &str, start: usize, end: usize, message: impl Into<String>) -> QueryParseError {
+    QueryParseError {
+        message: message.into(),
+        position: query[..start].chars().count(),
+        length: query[start..end].chars().count(),
+    }
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, QueryParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = query.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let single = |kind| Token {
+            kind,
+            start,
+            end: start + 1,
+        };
+
+        match c {
+            '(' => {
+                chars.next();
+                tokens.push(single(TokenKind::LParen));
+            }
+            ')' => {
+                chars.next();
+                tokens.push(single(TokenKind::RParen));
+            }
+            '-' => {
+                chars.next();
+                // A minus on its own is not a negation, it is just noise.
+                if matches!(chars.peek(), Some((_, c)) if !c.is_whitespace()) {
+                    tokens.push(single(TokenKind::Minus));
+                }
+            }
+            '"' => {
+                chars.next();
+                let mut phrase = String::new();
+                let mut end = None;
+                for (idx, c) in chars.by_ref() {
+                    if c == '"' {
+                        end = Some(idx + 1);
+                        break;
+                    }
+                    phrase.push(c);
+                }
+
+                let end = match end {
+                    Some(end) => end,
+                    None => return Err(error(query, start, query.len(), "Unterminated quote")),
+                };
+
+                let phrase = phrase.trim();
+                if phrase.is_empty() {
+                    return Err(error(query, start, end, "Empty phrase"));
+                }
+
+                tokens.push(Token {
+                    kind: TokenKind::Keyword(phrase.to_owned()),
+                    start,
+                    end,
+                });
+            }
+            _ => {
+                let mut end = start;
+                while let Some(&(idx, c)) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    end = idx + c.len_utf8();
+                    chars.next();
+                }
+
+                let word = &query[start..end];
+                let kind = match word {
+                    "AND" => TokenKind::And,
+                    "OR" => TokenKind::Or,
+                    "NOT" => TokenKind::Not,
+                    _ => match parse_constraint(query, start, word)? {
+                        Some(expr) => TokenKind::Constraint(expr),
+                        None => TokenKind::Keyword(word.to_owned()),
+                    },
+                };
+
+                tokens.push(Token { kind, start, end });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Checks to see if a word is a field constraint such as `size>1G`.
+/// Returns None if it is just a keyword.
+fn parse_constraint(
+    query: &str,
+    start: usize,
+    word: &str,
+) -> Result<Option<SearchExpression>, QueryParseError> {
+    let op_idx = match word.find([':', '=', '<', '>', '!']) {
+        Some(idx) if idx > 0 => idx,
+        _ => return Ok(None),
+    };
+
+    let field = &word[..op_idx];
+    if !field.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Ok(None);
+    }
+
+    let rest = &word[op_idx..];
+    let (op, op_len) = if rest.starts_with(">=") {
+        (Some(Comparison::GreaterOrEqual), 2)
+    } else if rest.starts_with("<=") {
+        (Some(Comparison::LessOrEqual), 2)
+    } else if rest.starts_with("!=") {
+        (Some(Comparison::NotEqual), 2)
+    } else if rest.starts_with('>') {
+        (Some(Comparison::Greater), 1)
+    } else if rest.starts_with('<') {
+        (Some(Comparison::Less), 1)
+    } else if rest.starts_with('=') {
+        (Some(Comparison::Equal), 1)
+    } else if rest.starts_with(':') {
+        // A colon means "is" for the textual fields and "equals" for the
+        // numeric ones.
+        (None, 1)
+    } else {
+        return Ok(None);
+    };
+
+    let field_start = start;
+    let field_end = start + op_idx;
+    let op_end = field_end + op_len;
+    let value_start = op_end;
+    let value_end = start + word.len();
+    let value = &word[op_len + op_idx..];
+
+    let lc_field = field.to_ascii_lowercase();
+    let numeric_field = match lc_field.as_str() {
+        "size" => Some(NumericField::Size),
+        "sources" | "avail" => Some(NumericField::Sources),
+        "complete" => Some(NumericField::CompleteSources),
+        _ => None,
+    };
+
+    if value.is_empty() {
+        return Err(error(
+            query,
+            field_start,
+            value_end,
+            format!("No value given for '{field}'"),
+        ));
+    }
+
+    if let Some(numeric_field) = numeric_field {
+        let parsed = if numeric_field == NumericField::Size {
+            parse_size(value)
+        } else {
+            value.parse::<u64>().ok()
+        };
+
+        let value = match parsed {
+            Some(v) => v,
+            None if numeric_field == NumericField::Size => {
+                return Err(error(
+                    query,
+                    value_start,
+                    value_end,
+                    format!("'{value}' is not a valid size, expected something like 700M or 1.5G"),
+                ))
+            }
+            None => {
+                return Err(error(
+                    query,
+                    value_start,
+                    value_end,
+                    format!("'{value}' is not a valid number"),
+                ))
+            }
+        };
+
+        return Ok(Some(SearchExpression::Numeric {
+            field: numeric_field,
+            comparison: op.unwrap_or(Comparison::Equal),
+            value,
+        }));
+    }
+
+    let is_textual = matches!(lc_field.as_str(), "type" | "ext");
+    if !is_textual {
+        // "re:zero" is probably a keyword, but "siez>1G" is probably a typo.
+        if op.is_none() {
+            return Ok(None);
+        }
+        return Err(error(
+            query,
+            field_start,
+            field_end,
+            format!(
+                "Unknown field '{field}', expected one of size, sources, complete, type or ext"
+            ),
+        ));
+    }
+
+    if !matches!(op, None | Some(Comparison::Equal)) {
+        return Err(error(
+            query,
+            field_end,
+            op_end,
+            format!("'{field}' can only be compared using ':' or '='"),
+        ));
+    }
+
+    if lc_field == "ext" {
+        let ext = value.trim_start_matches('.');
+        if ext.is_empty() {
+            return Err(error(query, value_start, value_end, "Empty extension"));
+        }
+        return Ok(Some(SearchExpression::Extension(ext.to_ascii_lowercase())));
+    }
+
+    let file_type = match value.to_ascii_lowercase().as_str() {
+        "audio" | "music" => FileType::Audio,
+        "video" | "movie" => FileType::Video,
+        "image" | "picture" => FileType::Image,
+        "program" | "pro" | "software" => FileType::Program,
+        "document" | "doc" => FileType::Document,
+        "archive" | "arc" => FileType::Archive,
+        "cdimage" | "iso" => FileType::CdImage,
+        "collection" => FileType::Collection,
+        _ => {
+            return Err(error(
+                query,
+                value_start,
+                value_end,
+                format!(
+                    "Unknown file type '{value}', expected one of audio, video, image, \
+                    program, document, archive, cdimage or collection"
+                ),
+            ))
+        }
+    };
+
+    Ok(Some(SearchExpression::FileType(file_type)))
+}
+
+/// Parses a size such as "700", "700K", "1.5G" or "2GB". Units are powers
+/// of 1024, as they are everywhere else in eMule.
+fn parse_size(value: &str) -> Option<u64> {
+    let lc = value.to_ascii_lowercase();
+    let number_end = lc
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(lc.len());
+    let (number, unit) = lc.split_at(number_end);
+
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return None,
+    };
+
+    if let Ok(n) = number.parse::<u64>() {
+        return n.checked_mul(multiplier);
+    }
+
+    let n = number.parse::<f64>().ok()?;
+    let bytes = n * multiplier as f64;
+    if bytes.is_finite() && bytes >= 0.0 && bytes < u64::MAX as f64 {
+        Some(bytes.round() as u64)
+    } else {
+        None
+    }
+}
+
+struct Parser<'a> {
+    query: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn error_at(&self, token: &Token, message: impl Into<String>) -> QueryParseError {
+        error(self.query, token.start, token.end, message)
+    }
+
+    fn error_at_end(&self, message: impl Into<String>) -> QueryParseError {
+        error(self.query, self.query.len(), self.query.len(), message)
+    }
+
+    /// or := and ("OR" and)*
+    fn parse_or(&mut self) -> Result<SearchExpression, QueryParseError> {
+        let mut lhs = self.parse_and()?;
+
+        while let Some(t) = self.peek() {
+            if t.kind != TokenKind::Or {
+                break;
+            }
+            let or_token = self.next().unwrap();
+            if self.at_end_of_group() {
+                return Err(self.error_at(&or_token, "'OR' must be followed by a search term"));
+            }
+            let rhs = self.parse_and()?;
+            lhs = SearchExpression::or(lhs, rhs);
+        }
+
+        Ok(lhs)
+    }
+
+    /// and := ["AND"] unary (["AND"] unary)*
+    /// Negated terms are collected and applied to the conjunction of the
+    /// positive terms, because that is the only form of NOT that ed2k has.
+    fn parse_and(&mut self) -> Result<SearchExpression, QueryParseError> {
+        let mut positive: Option<SearchExpression> = None;
+        let mut negative = Vec::new();
+        let mut first_negative: Option<Token> = None;
+
+        while let Some(t) = self.peek().cloned() {
+            match t.kind {
+                TokenKind::Or | TokenKind::RParen => break,
+                TokenKind::And => {
+                    self.next();
+                    if self.at_end_of_group() {
+                        return Err(self.error_at(&t, "'AND' must be followed by a search term"));
+                    }
+                    continue;
+                }
+                TokenKind::Minus | TokenKind::Not => {
+                    self.next();
+                    if self.at_end_of_group() {
+                        return Err(self.error_at(&t, "Nothing to exclude"));
+                    }
+                    negative.push(self.parse_primary()?);
+                    first_negative.get_or_insert(t);
+                }
+                _ => {
+                    let term = self.parse_primary()?;
+                    positive = Some(match positive {
+                        Some(p) => SearchExpression::and(p, term),
+                        None => term,
+                    });
+                }
+            }
+        }
+
+        let mut expr = match positive {
+            Some(p) => p,
+            None => {
+                return Err(match (first_negative, self.peek()) {
+                    (Some(t), _) => self.error_at(
+                        &t,
+                        "A search cannot only exclude things, add a word to search for",
+                    ),
+                    (None, Some(t)) => self.error_at(t, "Expected a search term"),
+                    (None, None) => self.error_at_end("Expected a search term"),
+                });
+            }
+        };
+
+        for n in negative {
+            expr = SearchExpression::and_not(expr, n);
+        }
+
+        Ok(expr)
+    }
+
+    /// primary := keyword | constraint | "(" or ")"
+    fn parse_primary(&mut self) -> Result<SearchExpression, QueryParseError> {
+        let t = match self.next() {
+            Some(t) => t,
+            None => return Err(self.error_at_end("Expected a search term")),
+        };
+
+        match t.kind {
+            TokenKind::Keyword(word) => Ok(SearchExpression::Keyword(word)),
+            TokenKind::Constraint(expr) => Ok(expr),
+            TokenKind::LParen => {
+                if matches!(self.peek(), Some(next) if next.kind == TokenKind::RParen) {
+                    return Err(self.error_at(&t, "Empty parentheses"));
+                }
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(close) if close.kind == TokenKind::RParen => Ok(expr),
+                    _ => Err(self.error_at(&t, "Unmatched '('")),
+                }
+            }
+            TokenKind::Minus | TokenKind::Not => {
+                Err(self.error_at(&t, "Exclusions cannot be nested like this"))
+            }
+            TokenKind::And | TokenKind::Or | TokenKind::RParen => {
+                Err(self.error_at(&t, "Expected a search term"))
+            }
+        }
+    }
+
+    fn at_end_of_group(&self) -> bool {
+        matches!(
+            self.peek(),
+            None | Some(Token {
+                kind: TokenKind::RParen | TokenKind::Or,
+                ..
+            })
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kw(s: &str) -> SearchExpression {
+        SearchExpression::Keyword(s.to_owned())
+    }
+
+    #[test]
+    pub fn test_parse_full_example() {
+        let expr = parse_query("ubuntu iso -beta size>1G type:program ext:iso").unwrap();
+
+        let positive = SearchExpression::and(
+            SearchExpression::and(
+                SearchExpression::and(
+                    SearchExpression::and(kw("ubuntu"), kw("iso")),
+                    SearchExpression::Numeric {
+                        field: NumericField::Size,
+                        comparison: Comparison::Greater,
+                        value: 1 << 30,
+                    },
+                ),
+                SearchExpression::FileType(FileType::Program),
+            ),
+            SearchExpression::Extension("iso".to_owned()),
+        );
+
+        assert_eq!(expr, SearchExpression::and_not(positive, kw("beta")));
+    }
+
+    #[test]
+    pub fn test_or_binds_less_tightly_than_and() {
+        let expr = parse_query("a b OR c").unwrap();
+        assert_eq!(
+            expr,
+            SearchExpression::or(SearchExpression::and(kw("a"), kw("b")), kw("c"))
+        );
+
+        let expr = parse_query("a (b OR c)").unwrap();
+        assert_eq!(
+            expr,
+            SearchExpression::and(kw("a"), SearchExpression::or(kw("b"), kw("c")))
+        );
+    }
+
+    #[test]
+    pub fn test_phrases_and_sizes() {
+        assert_eq!(parse_query("\"the matrix\"").unwrap(), kw("the matrix"));
+        assert_eq!(parse_size("700"), Some(700));
+        assert_eq!(parse_size("1.5G"), Some(1_610_612_736));
+        assert_eq!(parse_size("2mb"), Some(2 << 20));
+        assert_eq!(parse_size("2x"), None);
+    }
+
+    #[test]
+    pub fn test_errors_point_at_the_offending_token() {
+        let err = parse_query("ubuntu size>abc").unwrap_err();
+        assert_eq!((err.position, err.length), (12, 3));
+
+        let err = parse_query("ubuntu siez>1G").unwrap_err();
+        assert_eq!((err.position, err.length), (7, 4));
+        assert!(err.message.contains("siez"));
+
+        let err = parse_query("-beta").unwrap_err();
+        assert_eq!(err.position, 0);
+
+        let err = parse_query("(ubuntu iso").unwrap_err();
+        assert_eq!(err.position, 0);
+        assert_eq!(err.highlight("(ubuntu iso").lines().nth(1), Some("^"));
+
+        let err = parse_query("ubuntu type:spreadsheet").unwrap_err();
+        assert_eq!((err.position, err.length), (12, 11));
+
+        assert!(parse_query("   ").is_err());
+        assert!(parse_query("a OR").is_err());
+        assert!(parse_query("a \"b").is_err());
+    }
+}
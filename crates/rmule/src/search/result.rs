@@ -0,0 +1,116 @@
+use crate::protocol::opcodes::*;
+use crate::protocol::{read_hash, read_tag_list, Ed2kFileLink, Ed2kHash};
+use anyhow::Result;
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::HashMap;
+use std::io::Cursor;
+
+/// A file found by a search. If several servers report the same file
+/// (i.e. the same hash) their results are merged into one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchResult {
+    pub hash: Ed2kHash,
+    pub name: String,
+    pub size: u64,
+    /// The ed2k file type string, e.g. "Audio" or "Pro".
+    pub file_type: Option<String>,
+    /// The number of sources for the file, summed over all servers.
+    pub sources: u32,
+    /// The number of complete sources for the file, summed over all servers.
+    pub complete_sources: u32,
+    /// The number of servers which reported this file.
+    pub server_count: u32,
+}
+
+impl SearchResult {
+    /// Parses a single search result as sent in OP_SEARCHRESULT and
+    /// OP_GLOBSEARCHRES packets: hash, client id, client port, then tags.
+    pub fn read(input: &mut Cursor<&[u8]>) -> Result<Self> {
+        let hash = read_hash(input)?;
+        let _client_id = input.read_u32::<LittleEndian>()?;
+        let _client_port = input.read_u16::<LittleEndian>()?;
+        let tags = read_tag_list(input)?;
+
+        let mut result = Self {
+            hash,
+            name: String::new(),
+            size: 0,
+            file_type: None,
+            sources: 0,
+            complete_sources: 0,
+            server_count: 1,
+        };
+
+        let mut size_hi = 0;
+
+        for tag in &tags {
+            if tag.is(FT_FILENAME) {
+                result.name = tag.as_str().unwrap_or_default().to_owned();
+            } else if tag.is(FT_FILESIZE) {
+                // Some servers send a u64 here rather than FT_FILESIZE_HI.
+                result.size = tag.as_u64().unwrap_or_default();
+            } else if tag.is(FT_FILESIZE_HI) {
+                size_hi = tag.as_u64().unwrap_or_default();
+            } else if tag.is(FT_FILETYPE) {
+                result.file_type = tag.as_str().map(|s| s.to_owned());
+            } else if tag.is(FT_SOURCES) {
+                result.sources = tag.as_u32().unwrap_or_default();
+            } else if tag.is(FT_COMPLETE_SOURCES) {
+                result.complete_sources = tag.as_u32().unwrap_or_default();
+            }
+        }
+
+        result.size |= size_hi << 32;
+        Ok(result)
+    }
+
+    /// Merges in a report of the same file from another server.
+    fn merge(&mut self, other: &SearchResult) {
+        self.sources = self.sources.saturating_add(other.sources);
+        self.complete_sources = self.complete_sources.saturating_add(other.complete_sources);
+        self.server_count += other.server_count;
+        if self.file_type.is_none() {
+            self.file_type = other.file_type.clone();
+        }
+    }
+}
+
+impl From<&SearchResult> for Ed2kFileLink {
+    /// Downloading a search result is the same as downloading its link.
+    fn from(result: &SearchResult) -> Self {
+        Ed2kFileLink::new(result.name.clone(), result.size, result.hash)
+    }
+}
+
+/// The results of a search, keyed by hash.
+#[derive(Debug, Clone, Default)]
+pub struct SearchResultList {
+    results: HashMap<Ed2kHash, SearchResult>,
+}
+
+impl SearchResultList {
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    pub fn get(&self, hash: &Ed2kHash) -> Option<&SearchResult> {
+        self.results.get(hash)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SearchResult> {
+        self.results.values()
+    }
+
+    /// Adds a result to the list, merging it with any existing result for
+    /// the same hash. Returns the merged result.
+    pub fn merge(&mut self, result: SearchResult) -> &SearchResult {
+        self.results
+            .entry(result.hash)
+            .and_modify(|existing| existing.merge(&result))
+            .or_insert(result)
+    }
+}
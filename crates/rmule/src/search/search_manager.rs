@@ -0,0 +1,266 @@
+use super::{GlobalSearch, GlobalSearchOptions, SearchExpression, SearchResult};
+use crate::configuration::{
+    ConfigurationEventReceiver, ConfigurationEvents, ObfuscationMode, ServerList,
+};
+use crate::listener::ServerUdpSocketWatch;
+use crate::server::{ServerEventReceiver, UdpObfuscation};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{info, warn};
+
+pub type SearchCommandSender = mpsc::Sender<SearchCommand>;
+pub type SearchCommandReceiver = mpsc::Receiver<SearchCommand>;
+
+pub type SearchEventSender = broadcast::Sender<SearchEvents>;
+pub type SearchEventReceiver = broadcast::Receiver<SearchEvents>;
+
+/// Identifies a search. Ids are allocated by the handle, so that the caller
+/// knows which events belong to the search it just started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SearchId(u64);
+
+impl Display for SearchId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The handle type allows commands to be sent to and events to be received
+/// from the Search Manager.
+pub struct SearchManagerHandle {
+    cmd_sender: SearchCommandSender,
+    evt_sender: SearchEventSender,
+    // TODO: We need at least one receiver to be alive to
+    // allow us to send events.
+    evt_receiver: SearchEventReceiver,
+    next_search_id: AtomicU64,
+}
+
+impl SearchManagerHandle {
+    /// Starts the Search Manager as a Tokio task. Searches are network
+    /// bound rather than blocking, so unlike the Configuration Manager
+    /// there is no need for a dedicated thread. Global searches go out
+    /// on the server UDP port the Listener Manager opens.
+    pub fn new(
+        cfg_evt_receiver: ConfigurationEventReceiver,
+        server_evt_receiver: ServerEventReceiver,
+        server_udp_socket: ServerUdpSocketWatch,
+        tokio_handle: &tokio::runtime::Handle,
+    ) -> Self {
+        let (cmd_sender, cmd_receiver) = mpsc::channel::<SearchCommand>(32);
+        let (evt_sender, evt_receiver) = broadcast::channel::<SearchEvents>(256);
+
+        let mgr = SearchManager::new(
+            evt_sender.clone(),
+            cmd_receiver,
+            cfg_evt_receiver,
+            server_evt_receiver,
+            server_udp_socket,
+        );
+        tokio_handle.spawn(mgr.run());
+
+        Self {
+            cmd_sender,
+            evt_sender,
+            evt_receiver,
+            next_search_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Sends a command to the Search Manager.
+    pub async fn send_command(&self, cmd: SearchCommand) -> Result<()> {
+        Ok(self.cmd_sender.send(cmd).await?)
+    }
+
+    /// Synchronously send a command to the Search Manager.
+    pub fn send_command_blocking(&self, cmd: SearchCommand) -> Result<()> {
+        Ok(self.cmd_sender.blocking_send(cmd)?)
+    }
+
+    /// Create a new subscription to events sent by the Search Manager.
+    pub fn subscribe_to_events(&self) -> SearchEventReceiver {
+        self.evt_sender.subscribe()
+    }
+
+    /// Creates a new command sender which can be used to send commands
+    /// to the Search Manager.
+    pub fn make_command_sender(&self) -> SearchCommandSender {
+        self.cmd_sender.clone()
+    }
+
+    /// Allocates an id for a new search.
+    pub fn next_search_id(&self) -> SearchId {
+        SearchId(self.next_search_id.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// The set of commands that can be sent to the Search Manager.
+#[derive(Debug)]
+pub enum SearchCommand {
+    /// Starts a search which queries every active server via UDP.
+    StartGlobalSearch {
+        search_id: SearchId,
+        expression: SearchExpression,
+    },
+    /// Cancels a running search. Results found so far are kept by whoever
+    /// was listening to the events.
+    CancelSearch(SearchId),
+    /// Cancels all searches and stops the Search Manager.
+    Stop,
+}
+
+/// The set of events that can be emitted by the Search Manager.
+#[derive(Debug, Clone)]
+pub enum SearchEvents {
+    /// A global search has sent its request to another server.
+    GlobalSearchProgress {
+        search_id: SearchId,
+        servers_queried: usize,
+        server_count: usize,
+    },
+    /// Results have arrived. Each result is the merged view over all
+    /// servers so far, so it replaces any earlier result with the same hash.
+    ResultsUpdated {
+        search_id: SearchId,
+        results: Vec<SearchResult>,
+    },
+    /// The search is over, either because every server has been asked
+    /// or because it was cancelled.
+    Finished {
+        search_id: SearchId,
+        result_count: usize,
+        cancelled: bool,
+    },
+}
+
+/// This is private to the module: all access is via the handle.
+struct SearchManager {
+    events_sender: SearchEventSender,
+    commands_receiver: SearchCommandReceiver,
+    cfg_events_receiver: ConfigurationEventReceiver,
+    server_events_receiver: ServerEventReceiver,
+    server_udp_socket: ServerUdpSocketWatch,
+    // The latest server list we have been told about.
+    servers: Option<ServerList>,
+    // How to obfuscate requests to servers, from the settings and the
+    // server we are connected to.
+    obfuscation: UdpObfuscation,
+    // Dropping (or firing) the sender cancels the corresponding search.
+    running_searches: HashMap<SearchId, oneshot::Sender<()>>,
+}
+
+impl SearchManager {
+    fn new(
+        events_sender: SearchEventSender,
+        commands_receiver: SearchCommandReceiver,
+        cfg_events_receiver: ConfigurationEventReceiver,
+        server_events_receiver: ServerEventReceiver,
+        server_udp_socket: ServerUdpSocketWatch,
+    ) -> Self {
+        Self {
+            events_sender,
+            commands_receiver,
+            cfg_events_receiver,
+            server_events_receiver,
+            server_udp_socket,
+            servers: None,
+            obfuscation: UdpObfuscation {
+                mode: ObfuscationMode::Disabled,
+                public_ip: None,
+            },
+            running_searches: HashMap::new(),
+        }
+    }
+
+    async fn run(mut self) {
+        loop {
+            tokio::select! {
+                cmd = self.commands_receiver.recv() => {
+                    match cmd {
+                        Some(SearchCommand::Stop) | None => break,
+                        Some(cmd) => self.handle_command(cmd),
+                    }
+                }
+                evt = self.cfg_events_receiver.recv() => {
+                    match evt {
+                        Ok(ConfigurationEvents::ServerListChange(servers)) => self.servers = Some(servers),
+                        Ok(ConfigurationEvents::SettingsChange(settings)) => self.obfuscation.mode = settings.obfuscation,
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(n)) => warn!("Search Manager missed {n} configuration events"),
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                Ok(evt) = self.server_events_receiver.recv() => {
+                    if let Some(ip) = evt.public_ip() {
+                        self.obfuscation.public_ip = Some(ip);
+                    }
+                }
+            }
+        }
+
+        info!("Search Manager stopped");
+    }
+
+    fn handle_command(&mut self, cmd: SearchCommand) {
+        // Forget about searches which have finished by themselves.
+        self.running_searches
+            .retain(|_, cancel| !cancel.is_closed());
+
+        match cmd {
+            SearchCommand::StartGlobalSearch {
+                search_id,
+                expression,
+            } => self.start_global_search(search_id, expression),
+            SearchCommand::CancelSearch(search_id) => {
+                if let Some(cancel) = self.running_searches.remove(&search_id) {
+                    let _ = cancel.send(());
+                }
+            }
+            SearchCommand::Stop => unreachable!("Stop is handled by the run loop"),
+        }
+    }
+
+    fn start_global_search(&mut self, search_id: SearchId, expression: SearchExpression) {
+        let socket = self.server_udp_socket.borrow().clone();
+        let (servers, socket) = match (&self.servers, socket) {
+            (Some(servers), Some(socket)) => (servers, socket),
+            (servers, _) => {
+                if servers.is_none() {
+                    warn!("Global search {search_id} requested before the server list was loaded");
+                } else {
+                    warn!(
+                        "Global search {search_id} requested while the server UDP port is not open"
+                    );
+                }
+                let _ = self.events_sender.send(SearchEvents::Finished {
+                    search_id,
+                    result_count: 0,
+                    cancelled: false,
+                });
+                return;
+            }
+        };
+
+        let search = GlobalSearch::new(
+            search_id,
+            expression,
+            servers,
+            &self.obfuscation,
+            GlobalSearchOptions::default(),
+            self.events_sender.clone(),
+        );
+
+        let (cancel_sender, cancel_receiver) = oneshot::channel();
+        self.running_searches.insert(search_id, cancel_sender);
+
+        tokio::spawn(async move {
+            if let Err(e) = search.run(socket, cancel_receiver).await {
+                warn!("Global search {search_id} failed: {e}");
+            }
+        });
+    }
+}
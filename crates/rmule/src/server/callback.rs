@@ -0,0 +1,122 @@
+use super::{is_low_id, LoginResult, SourceObfuscation};
+use crate::protocol::opcodes::*;
+use crate::protocol::Packet;
+use anyhow::{bail, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Cursor;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// Whether other clients can connect to us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirewallStatus {
+    /// We are not connected, so nobody has tested our port.
+    Unknown,
+    /// Other clients can connect to us directly (we have a high id).
+    Open,
+    /// Our TCP port cannot be reached from outside, usually because of a
+    /// firewall or a router which does not forward it. We have a low id,
+    /// and other clients can only reach us by asking our server to pass on
+    /// a callback request.
+    Firewalled,
+}
+
+impl FirewallStatus {
+    /// While logging in, the server tests our port by connecting to the
+    /// port in our login request. If that works it gives us our IP Address
+    /// as our id, otherwise a low id.
+    pub fn from_login(login: &LoginResult) -> Self {
+        if login.is_low_id() {
+            Self::Firewalled
+        } else {
+            Self::Open
+        }
+    }
+}
+
+/// Makes an OP_CALLBACKREQUEST, which asks the server to tell the low id
+/// client to connect to us. Only clients with a high id can do this, and
+/// only for clients on the same server.
+pub fn make_callback_request(client_id: u32) -> Result<Packet> {
+    if !is_low_id(client_id) {
+        bail!("Client id {client_id} is not a low id, connect to it directly");
+    }
+
+    let mut payload = Vec::with_capacity(4);
+    payload.write_u32::<LittleEndian>(client_id).unwrap();
+    Ok(Packet::edonkey(OP_CALLBACKREQUEST, payload))
+}
+
+/// A request, passed on by our server, for us to connect to a client that
+/// could not connect to us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallbackRequest {
+    pub addr: SocketAddrV4,
+    /// How the client wants to be connected to. Older servers do not
+    /// pass this on.
+    pub obfuscation: Option<SourceObfuscation>,
+}
+
+/// Parses OP_CALLBACKREQUESTED. The IP Address is in network byte order.
+pub fn parse_callback_requested(packet: &Packet) -> Result<CallbackRequest> {
+    let mut input = Cursor::new(&packet.payload[..]);
+    let ip = input.read_u32::<LittleEndian>()?;
+    let port = input.read_u16::<LittleEndian>()?;
+
+    let obfuscation = if (input.position() as usize) < packet.payload.len() {
+        Some(SourceObfuscation::read(&mut input)?)
+    } else {
+        None
+    };
+
+    Ok(CallbackRequest {
+        addr: SocketAddrV4::new(Ipv4Addr::from(ip.to_le_bytes()), port),
+        obfuscation,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::UserHash;
+
+    #[test]
+    pub fn test_callback_request_for_low_ids() {
+        let packet = make_callback_request(1234).unwrap();
+        assert_eq!(packet.opcode, OP_CALLBACKREQUEST);
+        assert_eq!(packet.payload, 1234u32.to_le_bytes());
+    }
+
+    #[test]
+    pub fn test_no_callback_request_for_high_ids() {
+        assert!(make_callback_request(0x0A00_000A).is_err());
+    }
+
+    #[test]
+    pub fn test_parse_callback_requests() {
+        let mut payload = vec![192, 168, 1, 20];
+        payload.extend(4662u16.to_le_bytes());
+        let packet = Packet::edonkey(OP_CALLBACKREQUESTED, payload);
+
+        let request = parse_callback_requested(&packet).unwrap();
+        assert_eq!(request.addr, "192.168.1.20:4662".parse().unwrap());
+        assert_eq!(request.obfuscation, None);
+    }
+
+    #[test]
+    pub fn test_parse_callback_requests_with_obfuscation() {
+        // Newer servers add the client's obfuscation settings.
+        let mut payload = vec![192, 168, 1, 20];
+        payload.extend(4662u16.to_le_bytes());
+        payload.push(0x80 | 0x01);
+        payload.extend([9; 16]);
+        let packet = Packet::edonkey(OP_CALLBACKREQUESTED, payload);
+
+        let obfuscation = parse_callback_requested(&packet)
+            .unwrap()
+            .obfuscation
+            .unwrap();
+        assert!(obfuscation.supported);
+        assert!(!obfuscation.required);
+        assert_eq!(obfuscation.user_hash, Some(UserHash::new([9; 16])));
+    }
+}
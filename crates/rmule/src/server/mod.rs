@@ -0,0 +1,11 @@
+mod callback;
+mod server_connection;
+mod server_manager;
+mod sources;
+mod udp_target;
+
+pub use callback::*;
+pub use server_connection::*;
+pub use server_manager::*;
+pub use sources::*;
+pub use udp_target::*;
@@ -0,0 +1,196 @@
+use crate::bandwidth::{BandwidthLimiter, ThrottledStream};
+use crate::configuration::{ObfuscationMode, UserHash};
+use crate::connections::{ConnectionAdmission, ConnectionPermit};
+use crate::obfuscation::{obfuscate_server, ObfuscatedStream};
+use crate::protocol::opcodes::*;
+use crate::protocol::{
+    read_u16_string, write_tag_list, Packet, Tag, TagValue, EDONKEY_VERSION, EMULE_VERSION,
+};
+use anyhow::{bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time;
+use tracing::info;
+
+use super::is_low_id;
+
+/// What we need to tell a server about ourselves when logging in.
+#[derive(Debug, Clone)]
+pub struct LoginInfo {
+    pub user_hash: UserHash,
+    pub nick_name: String,
+    /// The TCP port on which we accept connections from other clients.
+    pub tcp_port: u16,
+    /// Tells the server whether to pass on our user hash to clients, so
+    /// that they can make obfuscated connections to us.
+    pub obfuscation: ObfuscationMode,
+}
+
+/// What the server told us during login.
+#[derive(Debug, Clone)]
+pub struct LoginResult {
+    /// Our id on this server; either our IP address or a low id.
+    pub client_id: u32,
+    /// The SRV_TCPFLG_* capabilities of the server.
+    pub tcp_flags: u32,
+    /// Any OP_SERVERMESSAGE text sent before the id change.
+    pub messages: Vec<String>,
+}
+
+impl LoginResult {
+    pub fn is_low_id(&self) -> bool {
+        is_low_id(self.client_id)
+    }
+}
+
+/// A TCP connection to an ed2k server. Generic over the stream so that the
+/// connection can run over an obfuscated transport or a proxy tunnel, and
+/// so that it can be tested over an in-memory pipe.
+pub struct ServerConnection<S> {
+    stream: S,
+    addr: SocketAddr,
+    /// Our place in the connection limits, freed when the connection is
+    /// dropped.
+    permit: Option<ConnectionPermit>,
+}
+
+impl ServerConnection<ObfuscatedStream<ThrottledStream<TcpStream>>> {
+    /// How long we wait for the server to accept the TCP connection (and
+    /// for the obfuscation handshake, if any).
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Opens a TCP connection to the server. Obfuscated connections must be
+    /// made to the server's obfuscation port, not its usual one.
+    pub async fn connect(
+        addr: SocketAddr,
+        obfuscate: bool,
+        limiter: &BandwidthLimiter,
+        admission: &ConnectionAdmission,
+    ) -> Result<Self> {
+        let connect = async {
+            let (stream, permit) = admission.connect(addr).await?;
+            let stream = limiter.throttle(stream);
+            let stream = if obfuscate {
+                obfuscate_server(stream).await?
+            } else {
+                ObfuscatedStream::plain(stream)
+            };
+            anyhow::Ok((stream, permit))
+        };
+
+        let (stream, permit) = time::timeout(Self::CONNECT_TIMEOUT, connect)
+            .await
+            .with_context(|| format!("Timed out connecting to server {addr}"))??;
+
+        let mut conn = Self::new(stream, addr);
+        conn.permit = Some(permit);
+        Ok(conn)
+    }
+}
+
+impl<S> ServerConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// How long we wait for the server to give us an id after logging in.
+    const LOGIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+    pub fn new(stream: S, addr: SocketAddr) -> Self {
+        Self {
+            stream,
+            addr,
+            permit: None,
+        }
+    }
+
+    /// The address of the server at the other end of the connection.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Sends OP_LOGINREQUEST and waits for the server to answer with
+    /// OP_IDCHANGE. Messages received along the way are collected.
+    pub async fn login(&mut self, info: &LoginInfo) -> Result<LoginResult> {
+        self.send(&make_login_packet(info)).await?;
+
+        time::timeout(Self::LOGIN_TIMEOUT, self.wait_for_id())
+            .await
+            .with_context(|| format!("Timed out logging in to server {}", self.addr))?
+    }
+
+    async fn wait_for_id(&mut self) -> Result<LoginResult> {
+        let mut messages = Vec::new();
+
+        loop {
+            let packet = self.recv().await?;
+            match packet.opcode {
+                OP_IDCHANGE => {
+                    let mut input = Cursor::new(&packet.payload[..]);
+                    let client_id = input.read_u32::<LittleEndian>()?;
+                    // Old servers do not send any flags.
+                    let tcp_flags = input.read_u32::<LittleEndian>().unwrap_or(0);
+                    info!("Logged in to server {} with id {client_id}", self.addr);
+
+                    return Ok(LoginResult {
+                        client_id,
+                        tcp_flags,
+                        messages,
+                    });
+                }
+                OP_SERVERMESSAGE => messages.push(parse_server_message(&packet)?),
+                OP_REJECT => bail!("Server {} rejected our login", self.addr),
+                _ => {}
+            }
+        }
+    }
+
+    /// Sends a packet to the server.
+    pub async fn send(&mut self, packet: &Packet) -> Result<()> {
+        packet.write_to(&mut self.stream).await
+    }
+
+    /// Receives the next packet from the server.
+    pub async fn recv(&mut self) -> Result<Packet> {
+        Packet::read_from(&mut self.stream).await
+    }
+}
+
+fn make_login_packet(info: &LoginInfo) -> Packet {
+    let mut payload = Vec::with_capacity(64);
+    payload.extend_from_slice(info.user_hash.as_bytes());
+    // Our client id, which we do not know yet.
+    payload.write_u32::<LittleEndian>(0).unwrap();
+    payload.write_u16::<LittleEndian>(info.tcp_port).unwrap();
+
+    let server_flags = SRVCAP_ZLIB
+        | SRVCAP_NEWTAGS
+        | SRVCAP_UNICODE
+        | SRVCAP_LARGEFILES
+        | match info.obfuscation {
+            ObfuscationMode::Disabled => 0,
+            ObfuscationMode::Preferred => SRVCAP_SUPPORTCRYPT | SRVCAP_REQUESTCRYPT,
+            ObfuscationMode::Required => {
+                SRVCAP_SUPPORTCRYPT | SRVCAP_REQUESTCRYPT | SRVCAP_REQUIRECRYPT
+            }
+        };
+    let tags = [
+        Tag::new(CT_NAME, TagValue::String(info.nick_name.clone())),
+        Tag::new(CT_VERSION, TagValue::U32(EDONKEY_VERSION)),
+        Tag::new(CT_SERVER_FLAGS, TagValue::U32(server_flags)),
+        Tag::new(CT_EMULE_VERSION, TagValue::U32(EMULE_VERSION)),
+    ];
+    write_tag_list(&mut payload, &tags, false);
+
+    Packet::edonkey(OP_LOGINREQUEST, payload)
+}
+
+/// OP_SERVERMESSAGE is a u16 length-prefixed string, which may contain
+/// several lines.
+pub fn parse_server_message(packet: &Packet) -> Result<String> {
+    let mut input = Cursor::new(&packet.payload[..]);
+    read_u16_string(&mut input)
+}
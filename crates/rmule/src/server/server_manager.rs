@@ -0,0 +1,625 @@
+use super::{
+    make_callback_request, make_get_sources_packet, parse_callback_requested, parse_found_sources,
+    parse_server_message, CallbackRequest, FirewallStatus, FoundSource, GlobalSourceQuery,
+    LoginInfo, LoginResult, ServerConnection, UdpObfuscation,
+};
+use crate::bandwidth::{BandwidthLimiter, ThrottledStream};
+use crate::configuration::{
+    ConfigurationEventReceiver, ConfigurationEvents, ObfuscationMode, Server, ServerList, Settings,
+};
+use crate::connections::ConnectionAdmission;
+use crate::listener::ServerUdpSocketWatch;
+use crate::obfuscation::ObfuscatedStream;
+use crate::protocol::opcodes::*;
+use crate::protocol::{Ed2kHash, Packet};
+use anyhow::{anyhow, Result};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info, warn};
+
+pub type ServerCommandSender = mpsc::Sender<ServerCommand>;
+pub type ServerCommandReceiver = mpsc::Receiver<ServerCommand>;
+
+pub type ServerEventSender = broadcast::Sender<ServerEvents>;
+pub type ServerEventReceiver = broadcast::Receiver<ServerEvents>;
+
+/// The handle type allows commands to be sent to and events to be received
+/// from the Server Manager.
+pub struct ServerManagerHandle {
+    cmd_sender: ServerCommandSender,
+    evt_sender: ServerEventSender,
+    // TODO: We need at least one receiver to be alive to
+    // allow us to send events.
+    evt_receiver: ServerEventReceiver,
+}
+
+impl ServerManagerHandle {
+    /// Starts the Server Manager as a Tokio task. UDP requests go out on
+    /// the server UDP port the Listener Manager opens.
+    pub fn new(
+        cfg_evt_receiver: ConfigurationEventReceiver,
+        server_udp_socket: ServerUdpSocketWatch,
+        limiter: BandwidthLimiter,
+        admission: ConnectionAdmission,
+        tokio_handle: &tokio::runtime::Handle,
+    ) -> Self {
+        let (cmd_sender, cmd_receiver) = mpsc::channel::<ServerCommand>(32);
+        let (evt_sender, evt_receiver) = broadcast::channel::<ServerEvents>(256);
+
+        let mgr = ServerManager::new(
+            evt_sender.clone(),
+            cmd_receiver,
+            cfg_evt_receiver,
+            server_udp_socket,
+            limiter,
+            admission,
+        );
+        tokio_handle.spawn(mgr.run());
+
+        Self {
+            cmd_sender,
+            evt_sender,
+            evt_receiver,
+        }
+    }
+
+    /// Sends a command to the Server Manager.
+    pub async fn send_command(&self, cmd: ServerCommand) -> Result<()> {
+        Ok(self.cmd_sender.send(cmd).await?)
+    }
+
+    /// Synchronously send a command to the Server Manager.
+    pub fn send_command_blocking(&self, cmd: ServerCommand) -> Result<()> {
+        Ok(self.cmd_sender.blocking_send(cmd)?)
+    }
+
+    /// Create a new subscription to events sent by the Server Manager.
+    pub fn subscribe_to_events(&self) -> ServerEventReceiver {
+        self.evt_sender.subscribe()
+    }
+
+    /// Creates a new command sender which can be used to send commands
+    /// to the Server Manager.
+    pub fn make_command_sender(&self) -> ServerCommandSender {
+        self.cmd_sender.clone()
+    }
+}
+
+/// The set of commands that can be sent to the Server Manager.
+#[derive(Debug)]
+pub enum ServerCommand {
+    /// Connects to the given server, or if None, to each active server
+    /// in turn until one accepts us. Any existing connection is dropped.
+    Connect(Option<SocketAddr>),
+    /// Drops the current server connection, if any.
+    Disconnect,
+    /// Asks for sources of a file: from the connected server via TCP, and
+    /// from every other server which supports it via UDP. Sources arrive
+    /// as `SourcesFound` events.
+    GetSources { hash: Ed2kHash, size: u64 },
+    /// Asks the connected server to tell a low id client (usually a source
+    /// it told us about) to connect to us. This only works if we have a
+    /// high id ourselves.
+    RequestCallback(u32),
+    /// Disconnects and stops the Server Manager.
+    Stop,
+}
+
+/// The set of events that can be emitted by the Server Manager.
+#[derive(Debug, Clone)]
+pub enum ServerEvents {
+    Connecting(SocketAddr),
+    ConnectFailed {
+        addr: SocketAddr,
+        reason: String,
+    },
+    Connected {
+        addr: SocketAddr,
+        client_id: u32,
+        low_id: bool,
+    },
+    Disconnected(SocketAddr),
+    ServerMessage {
+        addr: SocketAddr,
+        message: String,
+    },
+    SourcesFound {
+        hash: Ed2kHash,
+        sources: Vec<FoundSource>,
+    },
+    /// Whether other clients can connect to us, as tested by the server
+    /// when we logged in. Reverts to Unknown when we disconnect.
+    FirewallStatus(FirewallStatus),
+    /// We have a low id and a client which could not reach us wants us to
+    /// connect to it instead.
+    CallbackRequested(CallbackRequest),
+    /// A callback we asked for could not be passed on. The server does
+    /// not say which one.
+    CallbackFailed,
+}
+
+impl ServerEvents {
+    /// If this event told us our public IP Address, returns it. Only a
+    /// high id is our IP Address.
+    pub fn public_ip(&self) -> Option<Ipv4Addr> {
+        match self {
+            Self::Connected {
+                client_id,
+                low_id: false,
+                ..
+            } => Some(Ipv4Addr::from(client_id.to_le_bytes())),
+            _ => None,
+        }
+    }
+}
+
+/// This is private to the module: all access is via the handle.
+struct ServerManager {
+    events_sender: ServerEventSender,
+    commands_receiver: ServerCommandReceiver,
+    cfg_events_receiver: ConfigurationEventReceiver,
+    // Our own events, which is how we learn our public IP Address from
+    // the connection task.
+    own_events_receiver: ServerEventReceiver,
+    // The latest settings and server list we have been told about.
+    settings: Option<Settings>,
+    servers: Option<ServerList>,
+    public_ip: Option<Ipv4Addr>,
+    server_udp_socket: ServerUdpSocketWatch,
+    // Shared with every other connection.
+    limiter: BandwidthLimiter,
+    admission: ConnectionAdmission,
+    // Dropping the sender ends the connection task.
+    connection: Option<mpsc::Sender<ServerRequest>>,
+    // Whether the connection task has logged in. Requests are not queued
+    // before then, as the Download Manager asks again once we connect.
+    logged_in: bool,
+}
+
+/// What the manager asks the connection task to send to its server.
+#[derive(Debug)]
+enum ServerRequest {
+    GetSources { hash: Ed2kHash, size: u64 },
+    Callback(Packet),
+}
+
+impl ServerManager {
+    fn new(
+        events_sender: ServerEventSender,
+        commands_receiver: ServerCommandReceiver,
+        cfg_events_receiver: ConfigurationEventReceiver,
+        server_udp_socket: ServerUdpSocketWatch,
+        limiter: BandwidthLimiter,
+        admission: ConnectionAdmission,
+    ) -> Self {
+        Self {
+            server_udp_socket,
+            own_events_receiver: events_sender.subscribe(),
+            events_sender,
+            commands_receiver,
+            cfg_events_receiver,
+            limiter,
+            admission,
+            settings: None,
+            servers: None,
+            public_ip: None,
+            connection: None,
+            logged_in: false,
+        }
+    }
+
+    async fn run(mut self) {
+        loop {
+            // Our own events come first: the Download Manager asks for
+            // sources when it sees that we have connected, and by then we
+            // must know that we have.
+            tokio::select! {
+                biased;
+                Ok(evt) = self.own_events_receiver.recv() => {
+                    if let Some(ip) = evt.public_ip() {
+                        self.public_ip = Some(ip);
+                    }
+                    match evt {
+                        ServerEvents::Connected { .. } => self.logged_in = true,
+                        ServerEvents::Disconnected(_) => self.logged_in = false,
+                        _ => {}
+                    }
+                }
+                evt = self.cfg_events_receiver.recv() => {
+                    match evt {
+                        Ok(ConfigurationEvents::SettingsChange(settings)) => self.settings = Some(settings),
+                        Ok(ConfigurationEvents::ServerListChange(servers)) => self.servers = Some(servers),
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(n)) => warn!("Server Manager missed {n} configuration events"),
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                cmd = self.commands_receiver.recv() => {
+                    match cmd {
+                        Some(ServerCommand::Stop) | None => break,
+                        Some(cmd) => self.handle_command(cmd).await,
+                    }
+                }
+            }
+        }
+
+        info!("Server Manager stopped");
+    }
+
+    async fn handle_command(&mut self, cmd: ServerCommand) {
+        // Forget about a connection which has closed by itself.
+        if self.connection.as_ref().is_some_and(|c| c.is_closed()) {
+            self.disconnect();
+        }
+
+        match cmd {
+            ServerCommand::Connect(addr) => self.connect(addr),
+            ServerCommand::Disconnect => self.disconnect(),
+            ServerCommand::GetSources { hash, size } => self.get_sources(hash, size),
+            ServerCommand::RequestCallback(client_id) => self.request_callback(client_id),
+            ServerCommand::Stop => unreachable!("Stop is handled by the run loop"),
+        }
+    }
+
+    fn connect(&mut self, addr: Option<SocketAddr>) {
+        let (Some(settings), Some(servers)) = (&self.settings, &self.servers) else {
+            warn!("Server connection requested before the configuration was loaded");
+            return;
+        };
+
+        let candidates = match addr {
+            Some(addr) => {
+                // Servers which are not in the list can still be connected
+                // to, we just do not know whether they support obfuscation.
+                let target = servers
+                    .iter()
+                    .map(ServerTarget::from)
+                    .find(|target| target.addr == addr)
+                    .unwrap_or(ServerTarget {
+                        addr,
+                        obfuscation_port: None,
+                    });
+                vec![target]
+            }
+            None => servers
+                .iter()
+                .filter(|s| s.is_active())
+                .map(ServerTarget::from)
+                .collect(),
+        };
+
+        let login_info = LoginInfo {
+            user_hash: settings.user_hash,
+            nick_name: settings.nick_name.clone(),
+            tcp_port: settings.tcp_port,
+            obfuscation: settings.obfuscation,
+        };
+
+        let (packet_sender, packet_receiver) = mpsc::channel(32);
+        self.connection = Some(packet_sender);
+        self.logged_in = false;
+
+        tokio::spawn(run_connection(
+            candidates,
+            login_info,
+            self.limiter.clone(),
+            self.admission.clone(),
+            packet_receiver,
+            self.events_sender.clone(),
+        ));
+    }
+
+    fn disconnect(&mut self) {
+        self.connection = None;
+        self.logged_in = false;
+    }
+
+    /// Hands a request to the connection task. This never waits, so that
+    /// a slow server cannot hold up the manager; when the task is busy
+    /// the request is dropped.
+    fn send_request(&mut self, request: ServerRequest) -> bool {
+        let Some(connection) = &self.connection else {
+            return false;
+        };
+        if !self.logged_in {
+            return false;
+        }
+
+        match connection.try_send(request) {
+            Ok(()) => true,
+            Err(TrySendError::Full(request)) => {
+                warn!("The server connection is busy, dropping {request:?}");
+                false
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.disconnect();
+                false
+            }
+        }
+    }
+
+    fn get_sources(&mut self, hash: Ed2kHash, size: u64) {
+        // The connection task decides how to ask, because only it knows
+        // what the server supports.
+        self.send_request(ServerRequest::GetSources { hash, size });
+
+        let Some(socket) = self.server_udp_socket.borrow().clone() else {
+            warn!(
+                "Not asking servers via UDP for sources of {hash}: the server UDP port is not open"
+            );
+            return;
+        };
+        if let (Some(settings), Some(servers)) = (&self.settings, &self.servers) {
+            let obfuscation = UdpObfuscation {
+                mode: settings.obfuscation,
+                public_ip: self.public_ip,
+            };
+            let query = GlobalSourceQuery::new(
+                hash,
+                size,
+                servers,
+                &obfuscation,
+                self.events_sender.clone(),
+            );
+            tokio::spawn(async move {
+                if let Err(e) = query.run(socket).await {
+                    warn!("UDP source query for {hash} failed: {e}");
+                }
+            });
+        }
+    }
+
+    fn request_callback(&mut self, client_id: u32) {
+        let packet = match make_callback_request(client_id) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Cannot request a callback: {e}");
+                return;
+            }
+        };
+
+        if !self.logged_in {
+            warn!("Callback to {client_id} requested while not connected to a server");
+        }
+        if !self.send_request(ServerRequest::Callback(packet)) {
+            send_event(&self.events_sender, ServerEvents::CallbackFailed);
+        }
+    }
+}
+
+pub(super) fn send_event(sender: &ServerEventSender, evt: ServerEvents) {
+    if let Err(broadcast::error::SendError(evt)) = sender.send(evt) {
+        warn!("Nobody is listening for server events, dropping {:?}", evt);
+    }
+}
+
+/// A server we might connect to. Servers are identified by their address,
+/// but obfuscated connections go to a different port.
+#[derive(Debug, Clone)]
+struct ServerTarget {
+    addr: SocketAddr,
+    obfuscation_port: Option<u16>,
+}
+
+impl From<&Server> for ServerTarget {
+    fn from(server: &Server) -> Self {
+        Self {
+            addr: SocketAddr::new(*server.ip_addr, server.port),
+            obfuscation_port: server.tcp_obfuscation_port(),
+        }
+    }
+}
+
+impl ServerTarget {
+    /// The addresses to try, in order, and whether to obfuscate each.
+    fn attempts(&self, mode: ObfuscationMode) -> Vec<(SocketAddr, bool)> {
+        let obfuscated = self
+            .obfuscation_port
+            .map(|port| (SocketAddr::new(self.addr.ip(), port), true));
+        let plain = (self.addr, false);
+
+        match mode {
+            ObfuscationMode::Disabled => vec![plain],
+            ObfuscationMode::Preferred => obfuscated.into_iter().chain([plain]).collect(),
+            ObfuscationMode::Required => obfuscated.into_iter().collect(),
+        }
+    }
+
+    async fn connect_and_login(
+        &self,
+        login_info: &LoginInfo,
+        limiter: &BandwidthLimiter,
+        admission: &ConnectionAdmission,
+    ) -> Result<(
+        ServerConnection<ObfuscatedStream<ThrottledStream<TcpStream>>>,
+        LoginResult,
+    )> {
+        let mut result = Err(anyhow!("Server does not support obfuscation"));
+
+        for (addr, obfuscate) in self.attempts(login_info.obfuscation) {
+            result = async {
+                let mut conn =
+                    ServerConnection::connect(addr, obfuscate, limiter, admission).await?;
+                let login = conn.login(login_info).await?;
+                Ok((conn, login))
+            }
+            .await;
+
+            match &result {
+                Ok(_) => break,
+                Err(e) => info!("Connecting to server {addr} failed: {e}"),
+            }
+        }
+
+        result
+    }
+}
+
+/// Connects to the first candidate which accepts us, then relays packets
+/// until the server hangs up or the manager drops its sender.
+async fn run_connection(
+    candidates: Vec<ServerTarget>,
+    login_info: LoginInfo,
+    limiter: BandwidthLimiter,
+    admission: ConnectionAdmission,
+    mut requests: mpsc::Receiver<ServerRequest>,
+    events_sender: ServerEventSender,
+) {
+    for target in candidates {
+        // The manager only sends requests once we have logged in, but
+        // this also tells us whether it has given up on us.
+        loop {
+            match requests.try_recv() {
+                Ok(request) => debug!("Dropping {request:?} made before we logged in"),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        let addr = target.addr;
+        send_event(&events_sender, ServerEvents::Connecting(addr));
+
+        let (mut conn, login) = match target
+            .connect_and_login(&login_info, &limiter, &admission)
+            .await
+        {
+            Ok(connected) => connected,
+            Err(e) => {
+                let reason = e.to_string();
+                send_event(&events_sender, ServerEvents::ConnectFailed { addr, reason });
+                continue;
+            }
+        };
+
+        for message in login.messages.iter().cloned() {
+            send_event(
+                &events_sender,
+                ServerEvents::ServerMessage { addr, message },
+            );
+        }
+
+        send_event(
+            &events_sender,
+            ServerEvents::Connected {
+                addr,
+                client_id: login.client_id,
+                low_id: login.is_low_id(),
+            },
+        );
+        send_event(
+            &events_sender,
+            ServerEvents::FirewallStatus(FirewallStatus::from_login(&login)),
+        );
+
+        loop {
+            tokio::select! {
+                request = requests.recv() => {
+                    let Some(request) = request else { break };
+                    let packet = match request {
+                        ServerRequest::GetSources { hash, size } => {
+                            match get_sources_packet(&hash, size, login.tcp_flags) {
+                                Some(packet) => packet,
+                                None => {
+                                    debug!("Server {addr} does not support large files, not asking it for {hash}");
+                                    continue;
+                                }
+                            }
+                        }
+                        // A low id cannot be called back, so the server
+                        // would just ignore the request.
+                        ServerRequest::Callback(_) if login.is_low_id() => {
+                            warn!("Cannot request a callback from server {addr} with a low id");
+                            send_event(&events_sender, ServerEvents::CallbackFailed);
+                            continue;
+                        }
+                        ServerRequest::Callback(packet) => packet,
+                    };
+                    if let Err(e) = conn.send(&packet).await {
+                        warn!("Sending to server {addr} failed: {e}");
+                        break;
+                    }
+                }
+                packet = conn.recv() => {
+                    match packet {
+                        Ok(packet) => handle_server_packet(addr, &packet, &events_sender),
+                        Err(e) => {
+                            warn!("Connection to server {addr} lost: {e}");
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        send_event(&events_sender, ServerEvents::Disconnected(addr));
+        send_event(
+            &events_sender,
+            ServerEvents::FirewallStatus(FirewallStatus::Unknown),
+        );
+        return;
+    }
+}
+
+/// The OP_GETSOURCES request for a server with the SRV_TCPFLG_* flags. If
+/// the server supports obfuscation we ask for OP_GETSOURCES_OBFU instead,
+/// so that we are told which sources want obfuscated connections. None if
+/// the file is too large for the server.
+fn get_sources_packet(hash: &Ed2kHash, size: u64, tcp_flags: u32) -> Option<Packet> {
+    if size > u32::MAX as u64 && tcp_flags & SRV_TCPFLG_LARGEFILES == 0 {
+        return None;
+    }
+    let obfuscated = tcp_flags & SRV_TCPFLG_TCPOBFUSCATION != 0;
+    Some(make_get_sources_packet(hash, size, obfuscated))
+}
+
+fn handle_server_packet(addr: SocketAddr, packet: &Packet, events_sender: &ServerEventSender) {
+    match packet.opcode {
+        OP_FOUNDSOURCES | OP_FOUNDSOURCES_OBFU => match parse_found_sources(packet, addr) {
+            Ok((hash, sources)) => {
+                send_event(events_sender, ServerEvents::SourcesFound { hash, sources })
+            }
+            Err(e) => warn!("Bad found sources packet from server {addr}: {e}"),
+        },
+        OP_CALLBACKREQUESTED => match parse_callback_requested(packet) {
+            Ok(request) => send_event(events_sender, ServerEvents::CallbackRequested(request)),
+            Err(e) => warn!("Bad callback request from server {addr}: {e}"),
+        },
+        OP_CALLBACK_FAIL => send_event(events_sender, ServerEvents::CallbackFailed),
+        OP_SERVERMESSAGE => match parse_server_message(packet) {
+            Ok(message) => send_event(events_sender, ServerEvents::ServerMessage { addr, message }),
+            Err(e) => warn!("Bad message packet from server {addr}: {e}"),
+        },
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LARGE_FILE: u64 = u32::MAX as u64 + 1;
+
+    #[test]
+    pub fn test_large_files_are_not_asked_of_servers_without_support() {
+        let hash = Ed2kHash::new([1; 16]);
+
+        assert!(get_sources_packet(&hash, LARGE_FILE, SRV_TCPFLG_TCPOBFUSCATION).is_none());
+
+        let packet = get_sources_packet(&hash, LARGE_FILE, SRV_TCPFLG_LARGEFILES).unwrap();
+        assert_eq!(packet.opcode, OP_GETSOURCES);
+        assert_eq!(packet.payload.len(), 16 + 4 + 8);
+    }
+
+    #[test]
+    pub fn test_sources_are_asked_obfuscated_of_servers_which_support_it() {
+        let hash = Ed2kHash::new([1; 16]);
+
+        let packet = get_sources_packet(&hash, 1000, 0).unwrap();
+        assert_eq!(packet.opcode, OP_GETSOURCES);
+        let packet = get_sources_packet(&hash, 1000, SRV_TCPFLG_TCPOBFUSCATION).unwrap();
+        assert_eq!(packet.opcode, OP_GETSOURCES_OBFU);
+    }
+}
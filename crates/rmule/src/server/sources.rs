@@ -0,0 +1,413 @@
+use crate::configuration::{ServerList, ServerUdpFlags, UserHash};
+use crate::listener::ServerUdpSocket;
+use crate::protocol::opcodes::*;
+use crate::protocol::{read_hash, Ed2kHash, Packet};
+use anyhow::{bail, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{self, Instant};
+use tracing::{info, warn};
+
+use super::server_manager::send_event;
+use super::{ServerEventSender, ServerEvents, ServerUdpTarget, UdpObfuscation};
+
+/// Client ids below this value are "low ids", i.e. the client is not
+/// directly reachable and must be asked to connect to us via its server.
+/// Ids at or above it are the client's IP address.
+pub const LOW_ID_LIMIT: u32 = 0x0100_0000;
+
+/// Returns true if the client id is a low id.
+pub fn is_low_id(client_id: u32) -> bool {
+    client_id < LOW_ID_LIMIT
+}
+
+/// A client which has (some of) a file we want, as reported by a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundSource {
+    /// Either the IP address of the client (high id) or a low id.
+    pub client_id: u32,
+    pub port: u16,
+    /// The server which told us about the source. Low id sources can only
+    /// be reached by asking this server to pass on a callback request.
+    pub server: SocketAddr,
+    /// How the source wants to be connected to, if the server told us.
+    pub obfuscation: Option<SourceObfuscation>,
+}
+
+/// The obfuscation settings of a source, from OP_FOUNDSOURCES_OBFU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceObfuscation {
+    pub supported: bool,
+    pub requested: bool,
+    pub required: bool,
+    /// Needed to obfuscate a connection to the source.
+    pub user_hash: Option<UserHash>,
+}
+
+impl FoundSource {
+    pub fn is_low_id(&self) -> bool {
+        is_low_id(self.client_id)
+    }
+
+    /// The IP address of a high id source. The id is the IP address in
+    /// network byte order, read as a little-endian u32.
+    pub fn ip_addr(&self) -> Option<Ipv4Addr> {
+        if self.is_low_id() {
+            None
+        } else {
+            Some(Ipv4Addr::from(self.client_id.to_le_bytes()))
+        }
+    }
+}
+
+impl SourceObfuscation {
+    const SUPPORTED: u8 = 0x01;
+    const REQUESTED: u8 = 0x02;
+    const REQUIRED: u8 = 0x04;
+    const HAS_USER_HASH: u8 = 0x80;
+
+    /// Makes the settings from the options byte, as sent in
+    /// OP_FOUNDSOURCES_OBFU and source exchange.
+    pub fn from_options(options: u8, user_hash: Option<UserHash>) -> Self {
+        Self {
+            supported: options & Self::SUPPORTED != 0,
+            requested: options & Self::REQUESTED != 0,
+            required: options & Self::REQUIRED != 0,
+            user_hash,
+        }
+    }
+
+    /// The options byte, without the has-user-hash flag.
+    pub fn options(&self) -> u8 {
+        let mut options = 0;
+        if self.supported {
+            options |= Self::SUPPORTED;
+        }
+        if self.requested {
+            options |= Self::REQUESTED;
+        }
+        if self.required {
+            options |= Self::REQUIRED;
+        }
+        options
+    }
+
+    /// Reads the options byte, followed by the user hash if the options
+    /// say there is one.
+    pub(super) fn read(input: &mut Cursor<&[u8]>) -> Result<Self> {
+        let options = input.read_u8()?;
+        let user_hash = if options & Self::HAS_USER_HASH != 0 {
+            let mut buf = [0u8; 16];
+            input.read_exact(&mut buf)?;
+            Some(UserHash::new(buf))
+        } else {
+            None
+        };
+
+        Ok(Self::from_options(options, user_hash))
+    }
+}
+
+/// Writes a file hash and size as used in source requests. Sizes over 4GB
+/// are sent as a zero u32 followed by a u64.
+fn write_hash_and_size(out: &mut Vec<u8>, hash: &Ed2kHash, size: u64) {
+    out.extend_from_slice(hash.as_bytes());
+    match u32::try_from(size) {
+        Ok(size) => out.write_u32::<LittleEndian>(size).unwrap(),
+        Err(_) => {
+            out.write_u32::<LittleEndian>(0).unwrap();
+            out.write_u64::<LittleEndian>(size).unwrap();
+        }
+    }
+}
+
+/// Makes an OP_GETSOURCES request for the connected server. If the server
+/// supports obfuscation we ask for OP_GETSOURCES_OBFU instead, so that we
+/// are told which sources want obfuscated connections.
+pub fn make_get_sources_packet(hash: &Ed2kHash, size: u64, obfuscated: bool) -> Packet {
+    let mut payload = Vec::with_capacity(28);
+    write_hash_and_size(&mut payload, hash, size);
+    let opcode = if obfuscated {
+        OP_GETSOURCES_OBFU
+    } else {
+        OP_GETSOURCES
+    };
+    Packet::edonkey(opcode, payload)
+}
+
+/// Parses OP_FOUNDSOURCES and OP_FOUNDSOURCES_OBFU, the answers to
+/// OP_GETSOURCES and OP_GETSOURCES_OBFU.
+pub fn parse_found_sources(
+    packet: &Packet,
+    server: SocketAddr,
+) -> Result<(Ed2kHash, Vec<FoundSource>)> {
+    let obfu = match packet.opcode {
+        OP_FOUNDSOURCES => false,
+        OP_FOUNDSOURCES_OBFU => true,
+        op => bail!("Opcode {op:#04x} is not a found sources packet"),
+    };
+
+    let mut input = Cursor::new(&packet.payload[..]);
+    let hash = read_hash(&mut input)?;
+    let count = input.read_u8()?;
+    let mut sources = Vec::with_capacity(count as usize);
+
+    for _ in 0..count {
+        let client_id = input.read_u32::<LittleEndian>()?;
+        let port = input.read_u16::<LittleEndian>()?;
+
+        let obfuscation = if obfu {
+            Some(SourceObfuscation::read(&mut input)?)
+        } else {
+            None
+        };
+
+        sources.push(FoundSource {
+            client_id,
+            port,
+            server,
+            obfuscation,
+        });
+    }
+
+    Ok((hash, sources))
+}
+
+/// Parses OP_GLOBFOUNDSOURCES. Like search results, a datagram can contain
+/// answers for several files, each after the first preceded by another
+/// protocol and opcode byte.
+pub fn parse_global_found_sources(
+    data: &[u8],
+    server: SocketAddr,
+) -> Result<Vec<(Ed2kHash, Vec<FoundSource>)>> {
+    let packet = Packet::from_udp_bytes(data)?;
+    if packet.protocol != OP_EDONKEYPROT || packet.opcode != OP_GLOBFOUNDSOURCES {
+        return Ok(Vec::new());
+    }
+
+    let mut input = Cursor::new(&packet.payload[..]);
+    let mut answers = Vec::new();
+
+    loop {
+        let hash = read_hash(&mut input)?;
+        let count = input.read_u8()?;
+        let mut sources = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let client_id = input.read_u32::<LittleEndian>()?;
+            let port = input.read_u16::<LittleEndian>()?;
+            sources.push(FoundSource {
+                client_id,
+                port,
+                server,
+                obfuscation: None,
+            });
+        }
+        answers.push((hash, sources));
+
+        let pos = input.position() as usize;
+        let rest = &packet.payload[pos..];
+        if rest.len() >= 2 && rest[0] == OP_EDONKEYPROT && rest[1] == OP_GLOBFOUNDSOURCES {
+            input.set_position(pos as u64 + 2);
+        } else {
+            break;
+        }
+    }
+
+    Ok(answers)
+}
+
+/// Asks, via UDP, every active server which supports extended source
+/// queries for sources of a file. Servers are asked in turn at a fixed
+/// interval so that we do not flood the network.
+pub struct GlobalSourceQuery {
+    hash: Ed2kHash,
+    size: u64,
+    targets: Vec<ServerUdpTarget>,
+    events_sender: ServerEventSender,
+}
+
+impl GlobalSourceQuery {
+    /// The time between sending the request to successive servers.
+    const REQUEST_INTERVAL: Duration = Duration::from_millis(750);
+    /// How long to wait for stragglers after the last request is sent.
+    const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new(
+        hash: Ed2kHash,
+        size: u64,
+        servers: &ServerList,
+        obfuscation: &UdpObfuscation,
+        events_sender: ServerEventSender,
+    ) -> Self {
+        let targets = servers
+            .iter()
+            .filter(|s| s.is_active())
+            .filter(|s| s.udp_flags().contains(ServerUdpFlags::GET_EXTENDED_SOURCES))
+            // Servers which cannot handle large files would just misread the request.
+            .filter(|s| {
+                size <= u32::MAX as u64 || s.udp_flags().contains(ServerUdpFlags::LARGE_FILES)
+            })
+            .filter_map(|s| ServerUdpTarget::new(s, obfuscation))
+            .collect();
+
+        Self {
+            hash,
+            size,
+            targets,
+            events_sender,
+        }
+    }
+
+    pub async fn run(self, socket: ServerUdpSocket) -> Result<usize> {
+        let mut datagrams = socket.subscribe();
+        let mut payload = Vec::new();
+        write_hash_and_size(&mut payload, &self.hash, self.size);
+        let request = Packet::edonkey(OP_GLOBGETSOURCES2, payload);
+
+        info!(
+            "Asking {} servers via UDP for sources of {}",
+            self.targets.len(),
+            self.hash
+        );
+
+        let mut ticker = time::interval(Self::REQUEST_INTERVAL);
+        let mut next_target = 0;
+        let mut deadline = Instant::now();
+        let mut total_sources = 0;
+
+        loop {
+            let sending = next_target < self.targets.len();
+            if !sending && Instant::now() >= deadline {
+                break;
+            }
+
+            tokio::select! {
+                _ = ticker.tick(), if sending => {
+                    let target = self.targets[next_target];
+                    next_target += 1;
+                    if let Err(e) = socket.send_to(&target.encode(&request), target.send_to).await {
+                        warn!("Sending source request to {} failed: {e}", target.server);
+                    }
+                    deadline = Instant::now() + Self::RESPONSE_TIMEOUT;
+                }
+                _ = time::sleep_until(deadline), if !sending => break,
+                recv = datagrams.recv() => {
+                    let (from, data) = match recv {
+                        Ok(datagram) => datagram,
+                        Err(RecvError::Lagged(n)) => {
+                            warn!("Missed {n} source answers for {}", self.hash);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+
+                    let Some(target) = self.targets[..next_target].iter().find(|t| t.matches(from)) else {
+                        continue;
+                    };
+                    let Some(data) = target.decode(&data) else {
+                        warn!("Cannot deobfuscate source answer from {from}");
+                        continue;
+                    };
+
+                    match parse_global_found_sources(&data, target.server) {
+                        Ok(answers) => {
+                            for (hash, sources) in answers {
+                                if hash != self.hash || sources.is_empty() {
+                                    continue;
+                                }
+                                total_sources += sources.len();
+                                send_event(&self.events_sender, ServerEvents::SourcesFound { hash, sources });
+                            }
+                        }
+                        Err(e) => warn!("Bad source answer from {from}: {e}"),
+                    }
+                }
+            }
+        }
+
+        info!(
+            "UDP source query for {} found {} sources",
+            self.hash, total_sources
+        );
+
+        Ok(total_sources)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn server() -> SocketAddr {
+        "10.0.0.1:4661".parse().unwrap()
+    }
+
+    #[test]
+    pub fn test_get_sources_uses_u32_size_for_small_files() {
+        let hash = Ed2kHash::new([7; 16]);
+        let small = make_get_sources_packet(&hash, 1000, false);
+        assert_eq!(small.opcode, OP_GETSOURCES);
+        assert_eq!(small.payload.len(), 20);
+        assert_eq!(&small.payload[16..], &1000u32.to_le_bytes());
+    }
+
+    #[test]
+    pub fn test_get_sources_uses_u64_size_for_large_files() {
+        let hash = Ed2kHash::new([7; 16]);
+        let large = make_get_sources_packet(&hash, 5_000_000_000, true);
+        assert_eq!(large.opcode, OP_GETSOURCES_OBFU);
+        assert_eq!(large.payload.len(), 28);
+        assert_eq!(&large.payload[16..20], &[0, 0, 0, 0]);
+        assert_eq!(&large.payload[20..], &5_000_000_000u64.to_le_bytes());
+    }
+
+    #[test]
+    pub fn test_parse_obfuscated_found_sources() {
+        let mut payload = vec![3; 16];
+        payload.push(2);
+        // A high id source which supports obfuscation and sent its hash.
+        payload.extend_from_slice(&[192, 168, 1, 2]);
+        payload.extend_from_slice(&4662u16.to_le_bytes());
+        payload.push(0x80 | 0x01);
+        payload.extend_from_slice(&[9; 16]);
+        // A low id source with no obfuscation.
+        payload.extend_from_slice(&1234u32.to_le_bytes());
+        payload.extend_from_slice(&4672u16.to_le_bytes());
+        payload.push(0);
+
+        let packet = Packet::edonkey(OP_FOUNDSOURCES_OBFU, payload);
+        let (hash, sources) = parse_found_sources(&packet, server()).unwrap();
+
+        assert_eq!(hash, Ed2kHash::new([3; 16]));
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[0].ip_addr(), Some(Ipv4Addr::new(192, 168, 1, 2)));
+        let obfu = sources[0].obfuscation.unwrap();
+        assert!(obfu.supported && !obfu.requested && !obfu.required);
+        assert_eq!(obfu.user_hash, Some(UserHash::new([9; 16])));
+        assert!(sources[1].is_low_id());
+        assert_eq!(sources[1].port, 4672);
+        assert_eq!(sources[1].obfuscation.unwrap().user_hash, None);
+    }
+
+    #[test]
+    pub fn test_parse_concatenated_global_found_sources() {
+        let mut data = vec![OP_EDONKEYPROT, OP_GLOBFOUNDSOURCES];
+        for n in 1..=2u8 {
+            if n == 2 {
+                data.extend_from_slice(&[OP_EDONKEYPROT, OP_GLOBFOUNDSOURCES]);
+            }
+            data.extend_from_slice(&[n; 16]);
+            data.push(1);
+            data.extend_from_slice(&[10, 0, 0, n]);
+            data.extend_from_slice(&4662u16.to_le_bytes());
+        }
+
+        let answers = parse_global_found_sources(&data, server()).unwrap();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[1].0, Ed2kHash::new([2; 16]));
+        assert_eq!(answers[1].1[0].ip_addr(), Some(Ipv4Addr::new(10, 0, 0, 2)));
+    }
+}
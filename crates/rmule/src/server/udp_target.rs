@@ -0,0 +1,82 @@
+use crate::configuration::{ObfuscationMode, Server, ServerUdpFlags};
+use crate::obfuscation::{decrypt_server_packet, encrypt_server_packet, is_plain_udp_packet};
+use crate::protocol::Packet;
+use std::net::{Ipv4Addr, SocketAddr};
+
+/// What is needed to decide whether UDP packets to servers get obfuscated.
+#[derive(Debug, Clone, Copy)]
+pub struct UdpObfuscation {
+    pub mode: ObfuscationMode,
+    /// Our public IP Address, as told to us by the server we are connected
+    /// to. Server UDP keys are only valid for this address.
+    pub public_ip: Option<Ipv4Addr>,
+}
+
+/// A server to which we send UDP requests, such as global searches and
+/// source queries.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerUdpTarget {
+    /// The server's TCP address, which is what identifies it everywhere else.
+    pub server: SocketAddr,
+    /// Where requests go: the obfuscation port if we obfuscate, otherwise
+    /// the TCP port + 4.
+    pub send_to: SocketAddr,
+    pub flags: ServerUdpFlags,
+    key: Option<u32>,
+}
+
+impl ServerUdpTarget {
+    /// Returns None if obfuscation is required but the server does not
+    /// support it (or we cannot use its key).
+    pub fn new(server: &Server, obfuscation: &UdpObfuscation) -> Option<Self> {
+        let obfuscated = match (obfuscation.mode, obfuscation.public_ip) {
+            (ObfuscationMode::Disabled, _) | (_, None) => None,
+            (_, Some(public_ip)) => server.udp_obfuscation(public_ip),
+        };
+
+        if obfuscated.is_none() && obfuscation.mode == ObfuscationMode::Required {
+            return None;
+        }
+
+        let (send_to, key) = match obfuscated {
+            Some((addr, key)) => (addr, Some(key)),
+            None => (server.udp_socket_addr(), None),
+        };
+
+        Some(Self {
+            server: SocketAddr::new(*server.ip_addr, server.port),
+            send_to,
+            flags: server.udp_flags(),
+            key,
+        })
+    }
+
+    pub fn is_obfuscated(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Turns a packet into the datagram to send.
+    pub fn encode(&self, packet: &Packet) -> Vec<u8> {
+        let bytes = packet.to_udp_bytes();
+        match self.key {
+            Some(key) => encrypt_server_packet(&bytes, key),
+            None => bytes,
+        }
+    }
+
+    /// Returns true if a datagram from `from` came from this server. Servers
+    /// may answer obfuscated requests from their normal UDP port.
+    pub fn matches(&self, from: SocketAddr) -> bool {
+        from == self.send_to
+            || (from.ip() == self.server.ip() && from.port() == self.server.port().wrapping_add(4))
+    }
+
+    /// Turns a received datagram back into plain packet bytes. Servers may
+    /// answer obfuscated requests with plain packets.
+    pub fn decode(&self, data: &[u8]) -> Option<Vec<u8>> {
+        match self.key {
+            Some(key) if !is_plain_udp_packet(data) => decrypt_server_packet(data, key),
+            _ => Some(data.to_vec()),
+        }
+    }
+}
@@ -68,3 +68,30 @@ where
         Some(s)
     }
 }
+
+/// Parses exactly `N * 2` hex digits (either case) into bytes.
+pub fn parse_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    // from_str_radix would also take a sign.
+    if s.len() != N * 2 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut bytes = [0u8; N];
+    for (idx, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[idx * 2..idx * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_parse_hex_takes_only_hex_digits() {
+        assert_eq!(parse_hex::<2>("0aFf"), Some([0x0A, 0xFF]));
+        assert_eq!(parse_hex::<2>("+a0f"), None);
+        assert_eq!(parse_hex::<2>("0a-f"), None);
+        assert_eq!(parse_hex::<2>("0af"), None);
+    }
+}
@@ -149,6 +149,8 @@ impl TheApp {
                 AddressListChange(_addr_list) => info!("Got addr list"),
                 TempDirectoryListChange(_temp_dir_list) => info!("Got temp dir list"),
                 ServerListChange(server_list) => self.servers = server_list.into_iter().collect(),
+                KadContactListChange(_kad_contacts) => info!("Got Kad contact list"),
+                CategoryListChange(_categories) => info!("Got category list"),
             }
         }
     }
@@ -166,4 +168,9 @@ impl eframe::App for TheApp {
 
         self.status_bar(ctx);
     }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        info!("Stopping the engine");
+        self.engine.stop_blocking();
+    }
 }